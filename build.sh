@@ -1,7 +1,7 @@
 #!/usr/bin/env bash
 set -eux
 
-for b in recorder shutdown_scheduler clock_offset_viewer time_marker timekeeper; do
+for b in recorder shutdown_scheduler clock_offset_viewer time_marker timekeeper replay logconvert; do
     for a in aarch64-unknown-linux-gnu x86_64-unknown-linux-gnu; do
         cargo build --bin $b --release --target $a
     done
@@ -0,0 +1,149 @@
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use socketcan::{CanAnyFrame, CanDataFrame, EmbeddedFrame, ExtendedId, Frame, Id, StandardId};
+
+use car_logger::candump;
+
+/// IDs of the messages the car sends, see `dbc/car.dbc`
+const CAR_IDS: [u16; 14] = [0x084, 0x091, 0x092, 0x217, 0x352, 0x368, 0x37B, 0x430, 0x43D, 0x465, 0x466, 0x467, 0x472, 0x473];
+
+#[derive(Parser)]
+#[command(name = "log_bench")]
+#[command(version = "1.0")]
+#[command(author)]
+#[command(about = "Compares the speed of the candump log formatters on a synthetic trace of a fully loaded bus. Build with --release for meaningful numbers.")]
+struct Args {
+    #[arg(short = 'b', long, name = "speed", default_value = "500000", value_parser = clap::value_parser!(u64).range(1..), help = "Speed of the simulated bus, in bps")]
+    bus_speed: u64,
+    #[arg(short = 's', long, name = "seconds", default_value = "60", value_parser = clap::value_parser!(u64).range(1..), help = "Length of the trace")]
+    seconds: u64,
+    #[arg(short = 'r', long, name = "runs", default_value = "5", value_parser = clap::value_parser!(u32).range(1..), help = "Times to format the trace with each formatter; the fastest run counts")]
+    runs: u32,
+    #[arg(short = 'i', long, name = "name", default_value = "can0", help = "Interface name written on each line")]
+    interface: String,
+}
+
+/// The formatter `Logger` used before `candump::write_frame`, building each line with `format!`
+fn format_line_alloc(iface: &str, f: &CanAnyFrame, t: Duration) -> String {
+    let lts = t.as_micros();
+    let header: String = format!("({}.{:06}) {}", lts/1_000_000, lts%1_000_000, iface);
+    return match f {
+        CanAnyFrame::Error(_) => format!("{} {:08X}#{}\n", header, f.id_word(), hex::encode_upper(f.data())),
+        CanAnyFrame::Remote(_) => {
+            let dlc: String = if f.dlc() > 0 { f.dlc().to_string() } else { String::new() };
+            if f.is_extended() {
+                format!("{} {:08X}#R{}\n", header, f.raw_id(), dlc)
+            } else {
+                format!("{} {:03X}#R{}\n", header, f.raw_id(), dlc)
+            }
+        },
+        CanAnyFrame::Normal(_) => {
+            if f.is_extended() {
+                format!("{} {:08X}#{}\n", header, f.raw_id(), hex::encode_upper(f.data()))
+            } else {
+                format!("{} {:03X}#{}\n", header, f.raw_id(), hex::encode_upper(f.data()))
+            }
+        },
+        CanAnyFrame::Fd(fd) => {
+            let flags = fd.flags().bits() & 0x0F;
+            if f.is_extended() {
+                format!("{} {:08X}##{:X}{}\n", header, f.raw_id(), flags, hex::encode_upper(f.data()))
+            } else {
+                format!("{} {:03X}##{:X}{}\n", header, f.raw_id(), flags, hex::encode_upper(f.data()))
+            }
+        },
+    };
+}
+
+/// Bits a classic data frame takes on the wire, counting the interframe space but no stuff bits
+fn frame_bits(extended: bool, len: usize) -> u64 {
+    return if extended { 67 } else { 47 } + 8 * len as u64;
+}
+
+/// Frames sent back to back for `length`, mostly the car's messages with a few extended ones in between
+fn make_trace(bus_speed: u64, length: Duration) -> Vec<(CanAnyFrame, Duration)> {
+    let mut trace = Vec::new();
+    let mut t = Duration::from_secs(1_714_566_600);
+    let end = t + length;
+    // xorshift, so every run gets the same data
+    let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        return state;
+    };
+    while t < end {
+        let n = trace.len();
+        let extended = n % 16 == 15;
+        let id: Id = if extended {
+            ExtendedId::new(0x18DA_F100 | (n as u32 & 0xFF)).unwrap().into()
+        } else {
+            StandardId::new(CAR_IDS[n % CAR_IDS.len()]).unwrap().into()
+        };
+        let len = if n % 7 == 6 { (next() % 8) as usize } else { 8 };
+        let data = next().to_le_bytes();
+        let frame = CanDataFrame::new(id, &data[..len]).unwrap();
+        trace.push((CanAnyFrame::Normal(frame), t));
+        t += Duration::from_nanos(frame_bits(extended, len) * 1_000_000_000 / bus_speed);
+    }
+    return trace;
+}
+
+/// Fastest of `runs` runs of `format` over the trace, into a buffer like the one `Logger` writes through
+fn time_formatter<F>(trace: &[(CanAnyFrame, Duration)], runs: u32, mut format: F) -> io::Result<(Duration, usize)>
+    where F: FnMut(&mut BufWriter<io::Sink>, &CanAnyFrame, Duration) -> io::Result<usize>
+{
+    let mut best = Duration::MAX;
+    let mut bytes = 0;
+    for _ in 0..runs {
+        let mut w = BufWriter::with_capacity(1 << 20, io::sink());
+        let start = Instant::now();
+        bytes = 0;
+        for (f, t) in trace.iter() {
+            bytes += format(&mut w, f, *t)?;
+        }
+        w.flush()?;
+        best = best.min(start.elapsed());
+    }
+    return Ok((best, std::hint::black_box(bytes)));
+}
+
+fn main() {
+    let matches = Args::parse();
+    let iface: &str = &matches.interface;
+    let length = Duration::from_secs(matches.seconds);
+
+    let trace = make_trace(matches.bus_speed, length);
+    println!("Trace: {} frames over {} s at {} bps ({} frames/s)", trace.len(), matches.seconds, matches.bus_speed, trace.len() as u64 / matches.seconds);
+
+    // Both formatters have to write the same log for the comparison to mean anything
+    for (f, t) in trace.iter() {
+        let expected = format_line_alloc(iface, f, *t);
+        let mut line = Vec::new();
+        candump::write_frame(&mut line, iface, f, *t).unwrap();
+        if line != expected.as_bytes() {
+            println!("Formatters disagree:\n  format!:     {}  write_frame: {}", expected, String::from_utf8_lossy(&line));
+            std::process::exit(1);
+        }
+    }
+
+    let (alloc_time, alloc_bytes) = time_formatter(&trace, matches.runs, |w, f, t| {
+        let line = format_line_alloc(iface, f, t);
+        w.write_all(line.as_bytes())?;
+        Ok(line.len())
+    }).unwrap();
+    let (direct_time, direct_bytes) = time_formatter(&trace, matches.runs, |w, f, t| candump::write_frame(w, iface, f, t)).unwrap();
+
+    for (name, time, bytes) in [("format!", alloc_time, alloc_bytes), ("write_frame", direct_time, direct_bytes)] {
+        println!("{:<12} {:>8.2} ms, {:>6.1} ns/frame, {:.3}% of a core at full load, {} bytes",
+            name,
+            time.as_secs_f64() * 1e3,
+            time.as_nanos() as f64 / trace.len() as f64,
+            time.as_secs_f64() / length.as_secs_f64() * 100.0,
+            bytes);
+    }
+    println!("Speedup: {:.2}x", alloc_time.as_secs_f64() / direct_time.as_secs_f64());
+}
@@ -0,0 +1,443 @@
+//! Vector ASC logs, as read and written by CANalyzer and CANoe.
+//!
+//! Frame times are written relative to the `date` line, which is the time of the first frame.
+//! Dates are written and read as UTC so a log converts back to the same timestamps on any machine.
+//! ASC only records that an error frame happened, so the SocketCAN error class and data go in a
+//! trailing comment (`ErrorFrame // class 4 data 00 04 00 00 00 00 00 00`), which Vector tools ignore.
+//! CAN FD frames are written as `CANFD` events, with the FD flags in both the BRS/ESI columns and
+//! the flags field.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use libc::CAN_ERR_MASK;
+use socketcan::{CanAnyFrame, CanErrorFrame, CanFdFrame, CanFrame, EmbeddedFrame, ExtendedId, Frame, Id, StandardId};
+use socketcan::id::FdFlags;
+
+use super::candump::{parse_timestamp, ReadError, Record};
+use super::channels::ChannelMap;
+use super::compression::Decoder;
+
+const DATE_FORMAT: &str = "%a %b %d %I:%M:%S%.3f %P %Y";
+/// Other forms of the date line seen in logs from Vector tools
+const DATE_FORMATS: [&str; 3] = ["%a %b %d %I:%M:%S%.f %p %Y", "%a %b %d %H:%M:%S%.f %Y", "%a %b %d %I:%M:%S %p %Y"];
+
+/// Bits of the `CANFD` event flags field
+const FD_FLAG_REMOTE: u32 = 0x0010;
+const FD_FLAG_EDL: u32 = 0x1000;
+const FD_FLAG_BRS: u32 = 0x2000;
+const FD_FLAG_ESI: u32 = 0x4000;
+
+fn format_date(t: Duration) -> String {
+    let date: DateTime<Utc> = DateTime::from_timestamp(t.as_secs() as i64, t.subsec_nanos()).unwrap_or_default();
+    return date.format(DATE_FORMAT).to_string();
+}
+
+/// Time of an event, in seconds from the start of the log
+fn format_offset(start: Duration, t: Duration) -> String {
+    let offset = t.saturating_sub(start).as_micros();
+    return format!("{:4}.{:06}", offset / 1_000_000, offset % 1_000_000);
+}
+
+fn parse_date(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    for format in DATE_FORMATS.iter() {
+        if let Ok(date) = NaiveDateTime::parse_from_str(text, format) {
+            let date = date.and_utc();
+            return Some(Duration::new(date.timestamp().max(0) as u64, date.timestamp_subsec_nanos()));
+        }
+    }
+    return None;
+}
+
+/// Writes frames as ASC events
+pub struct Writer<W: Write> {
+    out: W,
+    channels: ChannelMap,
+    /// Time of the first frame, once the header has been written
+    start: Option<Duration>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W, channels: ChannelMap) -> Writer<W> {
+        Writer { out, channels, start: None }
+    }
+
+    /// Writes the header if it hasn't been yet, with `t` as the start of the log.
+    /// Returns the start and the number of bytes written.
+    fn start(&mut self, t: Duration) -> io::Result<(Duration, usize)> {
+        if let Some(start) = self.start {
+            return Ok((start, 0));
+        }
+        // Whole milliseconds, since that's all the date line holds
+        let start = Duration::from_millis(t.as_millis() as u64);
+        let date = format_date(start);
+        let header = format!("date {}\nbase hex  timestamps absolute\ninternal events logged\n// version 7.0.0\nBegin Triggerblock {}\n   0.000000 Start of measurement\n", date, date);
+        self.out.write_all(header.as_bytes())?;
+        self.start = Some(start);
+        return Ok((start, header.len()));
+    }
+
+    /// Writes a `//` comment line with the time it's about
+    pub fn write_comment(&mut self, t: Duration, text: &str) -> io::Result<usize> {
+        let (start, written) = self.start(t)?;
+        let line = format!("// {} {}\n", format_offset(start, t).trim_start(), text);
+        self.out.write_all(line.as_bytes())?;
+        return Ok(written + line.len());
+    }
+
+    pub fn write_frame(&mut self, iface: &str, frame: &CanAnyFrame, t: Duration) -> io::Result<usize> {
+        let (start, written) = self.start(t)?;
+        let time = format_offset(start, t);
+        let channel = self.channels.channel(iface);
+        let id = if frame.is_extended() { format!("{:X}x", frame.raw_id()) } else { format!("{:X}", frame.raw_id()) };
+        let line = match frame {
+            CanAnyFrame::Error(f) => {
+                let data: Vec<String> = f.data().iter().map(|b| format!("{:02X}", b)).collect();
+                format!("{} {}  ErrorFrame // class {:X} data {}\n", time, channel, f.error_bits(), data.join(" "))
+            },
+            CanAnyFrame::Fd(f) => {
+                let mut flags = FD_FLAG_EDL;
+                if f.is_brs() {
+                    flags |= FD_FLAG_BRS;
+                }
+                if f.is_esi() {
+                    flags |= FD_FLAG_ESI;
+                }
+                let data: Vec<String> = f.data().iter().map(|b| format!("{:02X}", b)).collect();
+                // Message duration, bit count, CRC and bit timings aren't known, so they're left at 0
+                format!("{} CANFD {:>3} Rx {:>9}  {} {} {:x} {:>2} {} {:>8} {:>4} {:>8X} {:>8} {:>8} {:>8} {:>8} {:>8}\n",
+                    time, channel, id, f.is_brs() as u8, f.is_esi() as u8, f.dlc(), f.data().len(), data.join(" "), 0, 0, flags, 0, 0, 0, 0, 0)
+            },
+            _ => {
+                let body = match frame {
+                    CanAnyFrame::Remote(_) => format!("r {:X}", frame.dlc()),
+                    _ => {
+                        let data: Vec<String> = frame.data().iter().map(|b| format!("{:02X}", b)).collect();
+                        format!("d {:X} {}", frame.dlc(), data.join(" "))
+                    },
+                };
+                format!("{} {}  {:<15} Rx   {}\n", time, channel, id, body.trim_end())
+            },
+        };
+        self.out.write_all(line.as_bytes())?;
+        return Ok(written + line.len());
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        return self.out.flush();
+    }
+
+    /// Ends the trigger block and returns the output
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.start.is_some() {
+            self.out.write_all(b"End TriggerBlock\n")?;
+        }
+        self.out.flush()?;
+        return Ok(self.out);
+    }
+}
+
+/// Parses what follows `ErrorFrame`. The class and data are only there if the log was written by
+/// `Writer`; other logs give an error frame without them.
+fn parse_error_frame<'a>(mut fields: impl Iterator<Item = &'a str>) -> Result<CanAnyFrame, String> {
+    let (mut class, mut data) = (0, Vec::new());
+    if fields.next() == Some("//") && fields.next() == Some("class") {
+        let class_text = fields.next().ok_or("missing error class")?;
+        class = u32::from_str_radix(class_text, 16).ok().filter(|c| c & !CAN_ERR_MASK == 0).ok_or_else(|| format!("invalid error class '{}'", class_text))?;
+        if fields.next() == Some("data") {
+            data = fields.map(|b| u8::from_str_radix(b, 16).map_err(|_| format!("invalid data byte '{}'", b))).collect::<Result<Vec<u8>, String>>()?;
+        }
+    }
+    return CanErrorFrame::new_error(class, &data).map(CanAnyFrame::Error).map_err(|e| e.to_string());
+}
+
+/// Streams CAN frames from an ASC log. Events other than CAN and CAN FD frames are skipped.
+pub struct Reader<R> {
+    inner: R,
+    channels: ChannelMap,
+    buf: Vec<u8>,
+    line: usize,
+    start: Duration,
+    radix: u32,
+    /// Times are deltas from the previous event instead of offsets from the start
+    relative: bool,
+    last: Duration,
+    done: bool,
+}
+
+impl Reader<BufReader<Decoder>> {
+    pub fn open<P: AsRef<Path>>(path: P, channels: ChannelMap) -> io::Result<Reader<BufReader<Decoder>>> {
+        return Ok(Reader::new(BufReader::new(Decoder::open(path)?), channels));
+    }
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(inner: R, channels: ChannelMap) -> Reader<R> {
+        Reader { inner, channels, buf: Vec::new(), line: 0, start: Duration::ZERO, radix: 16, relative: false, last: Duration::ZERO, done: false }
+    }
+
+    /// Number of the last line read
+    pub fn line(&self) -> usize {
+        return self.line;
+    }
+
+    /// Parses a line, returning `None` for headers and events that aren't frames
+    fn parse_line(&mut self, text: &str) -> Result<Option<Record>, String> {
+        if let Some(date) = text.strip_prefix("date ") {
+            self.start = parse_date(date).ok_or_else(|| format!("invalid date '{}'", date))?;
+            self.last = self.start;
+            return Ok(None);
+        }
+        if let Some(settings) = text.strip_prefix("base ") {
+            let settings: Vec<&str> = settings.split_whitespace().collect();
+            self.radix = if settings.first() == Some(&"dec") { 10 } else { 16 };
+            self.relative = settings.get(2) == Some(&"relative");
+            return Ok(None);
+        }
+        let mut fields = text.split_whitespace();
+        let offset = match fields.next().map(parse_timestamp) {
+            Some(Ok(t)) => t,
+            // Comments, "Begin Triggerblock" and other header lines
+            _ => return Ok(None),
+        };
+        let time = if self.relative { self.last + offset } else { self.start + offset };
+        self.last = time;
+        let channel = match fields.next() {
+            Some("CANFD") => return self.parse_fd(time, fields),
+            Some(c) => match c.parse::<u16>() {
+                Ok(c) => c,
+                // Events that aren't on a channel, e.g. "Start of measurement"
+                Err(_) => return Ok(None),
+            },
+            None => return Err(String::from("missing channel")),
+        };
+        let iface = self.channels.iface(channel);
+        let id_text = fields.next().ok_or("missing ID")?;
+        if id_text == "ErrorFrame" {
+            return Ok(Some(Record { time, iface, frame: parse_error_frame(fields)? }));
+        }
+        let id = match self.parse_id(id_text)? {
+            Some(id) => id,
+            // Other events on a channel, e.g. "Statistic:" or "Chip status"
+            None => return Ok(None),
+        };
+        let mut kind = fields.next().ok_or("missing frame type")?;
+        if kind == "Rx" || kind == "Tx" {
+            kind = fields.next().ok_or("missing frame type")?;
+        }
+        let dlc: usize = match fields.next() {
+            Some(d) => usize::from_str_radix(d, 16).map_err(|_| format!("invalid length '{}'", d))?,
+            None if kind == "r" => 0,
+            None => return Err(String::from("missing length")),
+        };
+        let frame = match kind {
+            "r" => CanFrame::new_remote(id, dlc).ok_or_else(|| format!("invalid remote frame length {}", dlc))?,
+            "d" => {
+                // Anything after the data bytes (e.g. "Length = ...") is extra detail
+                let data = self.parse_data(&mut fields, dlc)?;
+                CanFrame::new(id, &data).ok_or_else(|| format!("too much data ({} bytes)", data.len()))?
+            },
+            k => return Err(format!("unknown frame type '{}'", k)),
+        };
+        return Ok(Some(Record { time, iface, frame: frame.into() }));
+    }
+
+    /// Parses the rest of a `CANFD` event:
+    /// `channel direction ID [name] BRS ESI DLC length data... duration bits flags ...`
+    fn parse_fd<'a>(&self, time: Duration, mut fields: impl Iterator<Item = &'a str>) -> Result<Option<Record>, String> {
+        let channel: u16 = fields.next().ok_or("missing channel")?.parse().map_err(|_| String::from("invalid channel"))?;
+        let iface = self.channels.iface(channel);
+        let mut id_text = fields.next().ok_or("missing ID")?;
+        if id_text == "Rx" || id_text == "Tx" {
+            id_text = fields.next().ok_or("missing ID")?;
+        }
+        if id_text == "ErrorFrame" {
+            return Ok(Some(Record { time, iface, frame: parse_error_frame(fields)? }));
+        }
+        let id = match self.parse_id(id_text)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        // The symbolic name is left out when the log wasn't written with a database
+        let mut brs = fields.next().ok_or("missing BRS")?;
+        if !brs.bytes().all(|b| b.is_ascii_digit()) {
+            brs = fields.next().ok_or("missing BRS")?;
+        }
+        let esi = fields.next().ok_or("missing ESI")?;
+        let dlc_text = fields.next().ok_or("missing length code")?;
+        let dlc = usize::from_str_radix(dlc_text, 16).map_err(|_| format!("invalid length code '{}'", dlc_text))?;
+        let len_text = fields.next().ok_or("missing length")?;
+        let len: usize = len_text.parse().map_err(|_| format!("invalid length '{}'", len_text))?;
+        let data = self.parse_data(&mut fields, len)?;
+        // The flags say whether this was really a classic frame sent as a CANFD event
+        let flags = fields.nth(2).and_then(|f| u32::from_str_radix(f, 16).ok());
+        let frame: CanAnyFrame = match flags {
+            Some(f) if f & FD_FLAG_EDL == 0 && f & FD_FLAG_REMOTE != 0 => {
+                CanFrame::new_remote(id, dlc).ok_or_else(|| format!("invalid remote frame length {}", dlc))?.into()
+            },
+            Some(f) if f & FD_FLAG_EDL == 0 => {
+                CanFrame::new(id, &data).ok_or_else(|| format!("too much data ({} bytes)", data.len()))?.into()
+            },
+            _ => {
+                if !CanFdFrame::is_valid_data_len(len) {
+                    return Err(format!("invalid CAN FD data length {}", len));
+                }
+                let mut fd_flags = FdFlags::empty();
+                fd_flags.set(FdFlags::BRS, brs == "1");
+                fd_flags.set(FdFlags::ESI, esi == "1");
+                CanFdFrame::with_flags(id, &data, fd_flags).ok_or_else(|| format!("too much data ({} bytes)", data.len()))?.into()
+            },
+        };
+        return Ok(Some(Record { time, iface, frame }));
+    }
+
+    /// Parses an ID, with an `x` suffix for extended IDs. Returns `None` if it isn't a number.
+    fn parse_id(&self, id_text: &str) -> Result<Option<Id>, String> {
+        let (digits, extended) = match id_text.strip_suffix(['x', 'X']) {
+            Some(d) => (d, true),
+            None => (id_text, false),
+        };
+        let id_value = match u32::from_str_radix(digits, self.radix) {
+            Ok(v) => v,
+            Err(_) => return Ok(None),
+        };
+        let id: Id = if extended {
+            ExtendedId::new(id_value).ok_or_else(|| format!("invalid ID '{}'", id_text))?.into()
+        } else {
+            StandardId::new(id_value as u16).filter(|_| id_value <= 0x7FF).ok_or_else(|| format!("invalid ID '{}'", id_text))?.into()
+        };
+        return Ok(Some(id));
+    }
+
+    fn parse_data<'a>(&self, fields: &mut impl Iterator<Item = &'a str>, len: usize) -> Result<Vec<u8>, String> {
+        let data: Vec<u8> = fields.take(len)
+            .map(|b| u8::from_str_radix(b, self.radix).map_err(|_| format!("invalid data byte '{}'", b)))
+            .collect::<Result<Vec<u8>, String>>()?;
+        if data.len() != len {
+            return Err(format!("expected {} data bytes, found {}", len, data.len()));
+        }
+        return Ok(data);
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Record, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.buf.clear();
+            match self.inner.read_until(b'\n', &mut self.buf) {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    self.line += 1;
+                    if self.buf.last() != Some(&b'\n') || self.buf.contains(&0) {
+                        self.done = true;
+                        return Some(Err(ReadError::Truncated { line: self.line }));
+                    }
+                    let text = match std::str::from_utf8(&self.buf) {
+                        Ok(t) => t.trim().to_string(),
+                        Err(_) => return Some(Err(ReadError::Malformed { line: self.line, message: String::from("not valid UTF-8") })),
+                    };
+                    match self.parse_line(&text) {
+                        Ok(Some(record)) => return Some(Ok(record)),
+                        Ok(None) => continue,
+                        Err(message) => return Some(Err(ReadError::Malformed { line: self.line, message })),
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.done = true;
+                    return Some(Err(ReadError::Truncated { line: self.line + 1 }));
+                },
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(ReadError::Io(e)));
+                },
+            }
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read_all(text: &[u8]) -> Vec<Record> {
+        return Reader::new(Cursor::new(text.to_vec()), ChannelMap::new()).map(|r| r.unwrap()).collect();
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let start = Duration::new(1714566600, 250_000_000);
+        let frames: Vec<(&str, CanAnyFrame)> = vec![
+            ("can0", CanFrame::new(StandardId::new(0x465).unwrap(), &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap().into()),
+            ("can1", CanFrame::new(ExtendedId::new(0x18DAF110).unwrap(), &[9]).unwrap().into()),
+            ("can0", CanFrame::new_remote(StandardId::new(0x123).unwrap(), 4).unwrap().into()),
+            ("can1", CanFrame::new_remote(ExtendedId::new(0x123).unwrap(), 0).unwrap().into()),
+            ("can0", CanAnyFrame::Fd(CanFdFrame::with_flags(StandardId::new(0x7FF).unwrap(), &[0xAB; 12], FdFlags::BRS | FdFlags::ESI).unwrap())),
+            ("can1", CanAnyFrame::Fd(CanFdFrame::with_flags(ExtendedId::new(0x1234).unwrap(), &[0xCD; 64], FdFlags::empty()).unwrap())),
+        ];
+        let mut writer = Writer::new(Vec::new(), ChannelMap::new());
+        for (i, (iface, frame)) in frames.iter().enumerate() {
+            writer.write_frame(iface, frame, start + Duration::from_micros(1500 * i as u64)).unwrap();
+        }
+        let log = writer.finish().unwrap();
+        let text = String::from_utf8(log.clone()).unwrap();
+        assert!(text.contains(" 1  123             Rx   r 4\n"), "{}", text);
+        assert!(text.contains(" 2  123x            Rx   r 0\n"), "{}", text);
+        assert!(text.contains(" CANFD   1 Rx       7FF  1 1 9 12 "), "{}", text);
+
+        let records = read_all(&log);
+        assert_eq!(records.len(), frames.len());
+        for (i, (record, (iface, frame))) in records.iter().zip(frames.iter()).enumerate() {
+            assert_eq!(record.time, start + Duration::from_micros(1500 * i as u64));
+            assert_eq!(record.iface, *iface);
+            assert_eq!(format!("{:?}", record.frame), format!("{:?}", frame));
+        }
+    }
+
+    #[test]
+    fn error_frames_keep_their_class_and_data() {
+        let start = Duration::new(1714566600, 250_000_000);
+        let error = CanErrorFrame::new_error(0x004 | 0x010, &[0, 0x04, 0, 0x08, 0, 0, 0x12, 0x34]).unwrap();
+        let data = CanFrame::new(StandardId::new(0x123).unwrap(), &[1, 2, 3]).unwrap();
+        let mut writer = Writer::new(Vec::new(), ChannelMap::new());
+        writer.write_frame("can0", &CanAnyFrame::from(data), start).unwrap();
+        writer.write_frame("can1", &CanAnyFrame::Error(error), start + Duration::from_millis(5)).unwrap();
+        let log = writer.finish().unwrap();
+        let text = String::from_utf8(log.clone()).unwrap();
+        assert!(text.contains("   0.005000 2  ErrorFrame // class 14 data 00 04 00 08 00 00 12 34\n"), "{}", text);
+
+        let records = read_all(&log);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].time, start + Duration::from_millis(5));
+        assert_eq!(records[1].iface, "can1");
+        match &records[1].frame {
+            CanAnyFrame::Error(e) => {
+                assert_eq!(e.error_bits(), 0x014);
+                assert_eq!(e.data(), &[0, 0x04, 0, 0x08, 0, 0, 0x12, 0x34]);
+            },
+            other => panic!("expected an error frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn error_frames_from_vector_tools_have_no_class() {
+        let log = b"date Wed May 01 12:30:00.250 pm 2024\nbase hex  timestamps absolute\n   0.100000 1  ErrorFrame\n   0.200000 1  ErrorFrame ECC: 10100010\n   0.300000 1  ErrorFrame // class G\n";
+        let mut reader = Reader::new(Cursor::new(log.to_vec()), ChannelMap::new());
+        for _ in 0..2 {
+            match reader.next().unwrap().unwrap().frame {
+                CanAnyFrame::Error(e) => assert_eq!(e.error_bits(), 0),
+                other => panic!("expected an error frame, got {:?}", other),
+            }
+        }
+        match reader.next() {
+            Some(Err(ReadError::Malformed { line: 5, .. })) => (),
+            other => panic!("expected a malformed line, got {:?}", other),
+        }
+    }
+}
@@ -1,10 +1,43 @@
-mod carlogger_service;
-
 // Service providing data about the battery
 
-fn main() {
-    let s = carlogger_service::Service::new("battery", 0x40A, 0x7FF);
-    loop {
-        let charge_level = s.read_frame()[5];
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use socketcan::{CanFrame, EmbeddedFrame};
+
+use car_logger::{DecodeError, ParsedFrame};
+use car_logger::config::{self, BusArgs, ConfigArgs};
+use car_logger::service::{Handler, Service};
+use car_logger::systemd::Notifier;
+
+#[derive(Parser)]
+#[command(name = "battery")]
+#[command(version = "1.0")]
+#[command(author)]
+#[command(about = "Provides data about the battery")]
+struct Args {
+    #[command(flatten)]
+    bus: BusArgs,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+struct Battery;
+
+impl Handler for Battery {
+    fn frame(&mut self, _frame: ParsedFrame, _received: DateTime<Utc>, _notifier: &Notifier) {}
+
+    // 0x40A has no decoder yet
+    fn undecoded(&mut self, frame: CanFrame, _error: DecodeError) {
+        let _charge_level = frame.data().get(5);
     }
 }
+
+fn main() {
+    let matches: Args = config::parse("battery");
+
+    let database = matches.bus.database::<Args>();
+    let interface: String = matches.bus.interface;
+    println!("Interface: {}", interface);
+    let mut service = Service::open(&interface, &[0x40A]).unwrap().database(database);
+    service.run(&mut Battery).unwrap();
+}
@@ -1,4 +1,3 @@
-mod carlogger_service;
 // Attempts to correlate diagnostic data from PIDs with general stream of data
 
 fn main() {
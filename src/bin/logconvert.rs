@@ -0,0 +1,242 @@
+use std::fs::{File, OpenOptions};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use clap::{CommandFactory, Parser, ValueEnum};
+use clap::error::ErrorKind;
+use socketcan::{CanAnyFrame, CanFrame};
+
+use car_logger::{asc, binlog, blf, candump, mdf};
+use car_logger::dbc::Database;
+use car_logger::candump::Record;
+use car_logger::channels::{self, ChannelMap};
+use car_logger::compression::{Compression, Encoder};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Format {
+    /// candump-style text, as written by recorder
+    Candump,
+    /// Vector ASC text
+    Asc,
+    /// Vector BLF
+    Blf,
+    /// Binary records, as written by recorder --format binary (input only)
+    Binary,
+    /// ASAM MDF 4 (output only)
+    Mdf,
+}
+
+impl Format {
+    /// Guesses the format from a file name, ignoring any compression suffix. None if the extension isn't one of ours.
+    fn from_path(path: &Path) -> Option<Format> {
+        let name = path.to_string_lossy().to_lowercase();
+        let name = name.trim_end_matches(".gz").trim_end_matches(".zst");
+        if name.ends_with(".log") {
+            return Some(Format::Candump);
+        }
+        if name.ends_with(".asc") {
+            return Some(Format::Asc);
+        }
+        if name.ends_with(".blf") {
+            return Some(Format::Blf);
+        }
+        if name.ends_with(".bin") {
+            return Some(Format::Binary);
+        }
+        if name.ends_with(".mf4") || name.ends_with(".mdf") {
+            return Some(Format::Mdf);
+        }
+        return None;
+    }
+
+    /// The format given on the command line, or else the one guessed from `path`. Exits if there's neither.
+    fn of(given: Option<Format>, path: &Path, option: &str) -> Format {
+        match given.or_else(|| Format::from_path(path)) {
+            Some(format) => return format,
+            None => {
+                let mut cmd = Args::command();
+                let error_msg = format!("Can't tell the format of {} from its name; give it with --{}", path.display(), option);
+                cmd.error(ErrorKind::MissingRequiredArgument, error_msg).exit();
+            },
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(name = "logconvert")]
+#[command(version = "1.0")]
+#[command(author)]
+#[command(about = "Converts CAN logs between candump, Vector ASC and Vector BLF formats, reads binary logs, and exports to MDF4")]
+struct Args {
+    #[arg(name = "input", help = "Log to read. Compressed candump and ASC logs are detected automatically.")]
+    input: PathBuf,
+    #[arg(name = "output", help = "Log to write")]
+    output: PathBuf,
+    #[arg(long, name = "input_format", value_enum, help = "Format of the input log. Guessed from the file name (.log, .asc, .blf, .bin) if not given.")]
+    from: Option<Format>,
+    #[arg(long, name = "output_format", value_enum, help = "Format of the output log. Guessed from the file name (.log, .asc, .blf, .mf4) if not given.")]
+    to: Option<Format>,
+    #[arg(short = 'c', long, name = "iface=channel", value_parser = channels::parse_mapping, help = "Vector channel number for an interface, e.g. can0=1. By default canN is channel N+1.")]
+    channel: Vec<(String, u16)>,
+    #[arg(short = 'z', long, name = "method", value_enum, default_value = "none", help = "Compress the output log (candump and ASC only)")]
+    compression: Compression,
+    #[arg(short = 'f', long, help = "Overwrite the output log if it exists")]
+    force: bool,
+    #[arg(short = 's', long, help = "Also export decoded signal values with their units, one channel group per message named as in dbc/car.dbc (MDF4 only)")]
+    signals: bool,
+    #[arg(short = 'd', long, name = "dbc_file", help = "DBC file for decoding messages, in place of the built-in decoders for the ones it has; implies --signals")]
+    dbc: Option<PathBuf>,
+}
+
+enum Output {
+    Candump(BufWriter<Encoder>),
+    Asc(asc::Writer<BufWriter<Encoder>>),
+    Blf(blf::Writer<BufWriter<File>>),
+    Mdf(mdf::Writer<BufWriter<File>>),
+}
+
+impl Output {
+    fn write(&mut self, record: &Record) -> std::io::Result<usize> {
+        match self {
+            Output::Candump(w) => candump::write_frame(w, &record.iface, &record.frame, record.time),
+            Output::Asc(w) => w.write_frame(&record.iface, &record.frame, record.time),
+            Output::Blf(w) => w.write_frame(&record.iface, &record.frame, record.time),
+            Output::Mdf(w) => w.write_frame(&record.iface, &record.frame, record.time),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        let w = match self {
+            Output::Candump(w) => w,
+            Output::Asc(w) => w.finish()?,
+            Output::Blf(w) => {
+                w.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+                return Ok(());
+            },
+            Output::Mdf(w) => {
+                w.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+                return Ok(());
+            },
+        };
+        w.into_inner().map_err(|e| e.into_error())?.finish()?.sync_all()?;
+        return Ok(());
+    }
+}
+
+/// Opens a log as a stream of records. Errors the reader can continue past come back as `Err`,
+/// and the stream ends after one it can't.
+fn open(path: &Path, format: Format, channels: &ChannelMap) -> Result<Box<dyn Iterator<Item = Result<Record, String>>>, String> {
+    return Ok(match format {
+        Format::Candump => Box::new(candump::Reader::open(path).map_err(|e| e.to_string())?.map(|r| r.map_err(|e| e.to_string()))),
+        Format::Asc => Box::new(asc::Reader::open(path, channels.clone()).map_err(|e| e.to_string())?.map(|r| r.map_err(|e| e.to_string()))),
+        Format::Blf => Box::new(blf::Reader::open(path, channels.clone()).map_err(|e| e.to_string())?.map(|r| r.map_err(|e| e.to_string()))),
+        Format::Binary => Box::new(binlog::Reader::open(path).map_err(|e| e.to_string())?.map(|r| r.map_err(|e| e.to_string()))),
+        Format::Mdf => return Err(String::from("MDF files can't be read")),
+    });
+}
+
+fn main() {
+    let matches = Args::parse();
+
+    let from: Format = Format::of(matches.from, &matches.input, "input-format");
+    let to: Format = Format::of(matches.to, &matches.output, "output-format");
+    let mut channels = ChannelMap::new();
+    for (iface, channel) in matches.channel.iter() {
+        channels.insert(iface, *channel);
+    }
+
+    let signals: bool = matches.signals || matches.dbc.is_some();
+
+    if to == Format::Binary {
+        let mut cmd = Args::command();
+        cmd.error(ErrorKind::InvalidValue, "Binary logs can only be written by recorder").exit();
+    }
+    if (to == Format::Blf || to == Format::Mdf) && matches.compression != Compression::None {
+        let mut cmd = Args::command();
+        let error_msg = format!("{:?} logs can't be compressed", to);
+        cmd.error(ErrorKind::ArgumentConflict, error_msg).exit();
+    }
+    if signals && to != Format::Mdf {
+        let mut cmd = Args::command();
+        cmd.error(ErrorKind::ArgumentConflict, "Signals can only be exported to MDF").exit();
+    }
+    let database: Option<Database> = match &matches.dbc {
+        Some(path) => match Database::from_file(path) {
+            Ok(db) => Some(db),
+            Err(e) => {
+                let mut cmd = Args::command();
+                let error_msg = format!("Could not load {}: {}", path.display(), e);
+                cmd.error(ErrorKind::ValueValidation, error_msg).exit();
+            }
+        },
+        None => None,
+    };
+
+    println!("Input:  {} ({:?})", matches.input.display(), from);
+    println!("Output: {} ({:?})", matches.output.display(), to);
+
+    let input = match open(&matches.input, from, &channels) {
+        Ok(i) => i,
+        Err(e) => {
+            println!("Could not open {}: {}", matches.input.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let file = match OpenOptions::new().write(true).create(true).truncate(true).create_new(!matches.force).open(&matches.output) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Could not create {}: {}", matches.output.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let output = match to {
+        Format::Candump => Encoder::new(file, matches.compression).map(|e| Output::Candump(BufWriter::new(e))),
+        Format::Asc => Encoder::new(file, matches.compression).map(|e| Output::Asc(asc::Writer::new(BufWriter::new(e), channels.clone()))),
+        Format::Blf => blf::Writer::new(BufWriter::new(file), channels.clone()).map(Output::Blf),
+        Format::Mdf => mdf::Writer::new(BufWriter::new(file), channels.clone()).map(Output::Mdf),
+        Format::Binary => unreachable!(),
+    };
+    let mut output = match output {
+        Ok(o) => o,
+        Err(e) => {
+            println!("Could not write {}: {}", matches.output.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut converted: u64 = 0;
+    let mut skipped: u64 = 0;
+    for record in input {
+        match record {
+            Ok(r) => {
+                if let Err(e) = output.write(&r) {
+                    println!("Error writing {}: {}", matches.output.display(), e);
+                    std::process::exit(1);
+                }
+                converted += 1;
+                if let (true, Output::Mdf(w), CanAnyFrame::Normal(f)) = (signals, &mut output, &r.frame) {
+                    let parsed = match &database {
+                        Some(db) => db.parse_frame(CanFrame::Data(*f)),
+                        None => car_logger::parse_frame(CanFrame::Data(*f)),
+                    };
+                    // Frames nothing can decode are still exported raw
+                    if let Ok(p) = parsed {
+                        if let Err(e) = w.write_values(&p.name(), &p.values(), r.time) {
+                            println!("Error writing {}: {}", matches.output.display(), e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            },
+            Err(e) => {
+                println!("Skipping: {}", e);
+                skipped += 1;
+            }
+        }
+    }
+    if let Err(e) = output.finish() {
+        println!("Error writing {}: {}", matches.output.display(), e);
+        std::process::exit(1);
+    }
+    println!("Converted {} frames, skipped {}", converted, skipped);
+}
@@ -1,24 +1,48 @@
 use std::convert::TryInto;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time;
-use chrono;
 use chrono::prelude::*;
-use gpiod;
 use threadpool::Builder;
 use std::sync::mpsc::{self, Receiver, Sender};
 
-pub mod carlogger_service;
+use car_logger::{LogFormat, RotationReason, SyncPolicy};
+use car_logger::compression::Compression;
+use car_logger::config::{self, ConfigArgs};
+use car_logger::dbc::Database;
+use car_logger::recovery;
+use car_logger::retention::Retention;
+use car_logger::systemd::Notifier;
+use car_logger::rotation::{Ignition, Interval, MaxSize, Rotation, RotationPolicy, SignalValue};
+use car_logger::buses::{Buses, Received};
+use car_logger::queue::{self, Disconnected, FrameBatch, Overflow, QueueItem, QueueSender};
+use car_logger::timestamps::ClockSource;
+use car_logger::trigger::{FrameMatch, History, Trigger, TriggerSource};
 
-use clap::Parser;
-use socketcan::{CanError, CanFrame, CanSocket, Socket, SocketOptions};
+use clap::{CommandFactory, Parser};
+use clap::error::ErrorKind;
+use socketcan::{CanAnyFrame, CanError};
+
+/// How often the status shown by systemd is updated while logging
+const STATUS_INTERVAL: time::Duration = time::Duration::from_secs(5);
 
 #[allow(dead_code)]
 enum LogMessage {
     Ping,
-    Frame(CanFrame, time::Duration),
+    /// Frames to write, each with the interface it came from and its time
+    Frames(FrameBatch),
     Flush,
-    Exit,
+    Exit(RotationReason),
+}
+
+impl QueueItem for LogMessage {
+    fn frames(&self) -> Option<usize> {
+        match self {
+            LogMessage::Frames(batch) => Some(batch.len()),
+            _ => None,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -29,14 +53,102 @@ enum WriterError {
     IOError(std::io::Error),
 }
 
+/// Times spent in each part of the read loop, for the last `PROFILE_SAMPLES` frames
+#[cfg(feature = "profile")]
+struct Profile {
+    queue_check: Vec<u128>,
+    can_read: Vec<u128>,
+    log_send: Vec<u128>,
+    index: usize,
+    last_print: time::Instant,
+}
+
+#[cfg(feature = "profile")]
+const PROFILE_SAMPLES: usize = 1<<14;
+
+#[cfg(feature = "profile")]
+impl Profile {
+    fn new() -> Profile {
+        Profile {
+            queue_check: vec![0; PROFILE_SAMPLES],
+            can_read: vec![0; PROFILE_SAMPLES],
+            log_send: vec![0; PROFILE_SAMPLES],
+            index: 0,
+            last_print: time::Instant::now(),
+        }
+    }
+
+    /// Records the times for a frame whose pass through the loop began at `start`, and prints the
+    /// averages every 5 minutes
+    fn record(&mut self, queue_check_time: u128, can_read_time: u128, start: time::Instant) {
+        let log_send_time = start.elapsed().as_nanos() - can_read_time - queue_check_time;
+        self.queue_check[self.index] = queue_check_time;
+        self.can_read[self.index] = can_read_time;
+        self.log_send[self.index] = log_send_time;
+        self.index = (self.index + 1) % PROFILE_SAMPLES;
+        if self.last_print.elapsed().as_secs() >= 300 {
+            self.last_print = time::Instant::now();
+            let average = |times: &[u128]| times.iter().sum::<u128>() / times.len() as u128;
+            println!("----------------------------------------");
+            println!("Average queue check time: {} ns", average(&self.queue_check));
+            println!("Average CAN read time: {} ns", average(&self.can_read));
+            println!("Average log send time: {} ns", average(&self.log_send));
+            println!("Profiling time: {} ns", start.elapsed().as_nanos() - can_read_time - queue_check_time - log_send_time);
+        }
+    }
+}
+
+/// Sending side of the queue to a writer thread. Frames are gathered into batches before they're sent.
+struct LogQueue {
+    tx: QueueSender<LogMessage>,
+    /// Batch being filled
+    batch: FrameBatch,
+    /// Batches the writer has written, to be filled again
+    spare: Receiver<FrameBatch>,
+    /// Frames the kernel dropped
+    dropped: u64,
+}
+
+impl LogQueue {
+    fn new(tx: QueueSender<LogMessage>, spare: Receiver<FrameBatch>) -> LogQueue {
+        LogQueue { tx, batch: FrameBatch::new(), spare, dropped: 0 }
+    }
+
+    /// Adds a frame to the batch, sending the batch once it's ready
+    fn send_frame(&mut self, msg: Received) -> Result<(), Disconnected> {
+        self.dropped += msg.dropped as u64;
+        self.batch.push(msg);
+        if self.batch.is_ready() {
+            return self.send_batch();
+        }
+        return Ok(());
+    }
+
+    /// Sends the frames batched so far
+    fn send_batch(&mut self) -> Result<(), Disconnected> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let next = self.spare.try_recv().unwrap_or_default();
+        let batch = std::mem::replace(&mut self.batch, next);
+        return self.tx.send(LogMessage::Frames(batch));
+    }
+
+    /// Sends a message after the frames batched so far
+    fn send(&mut self, message: LogMessage) -> Result<(), Disconnected> {
+        self.send_batch()?;
+        return self.tx.send(message);
+    }
+}
+
 #[derive(Parser)]
 #[command(name = "recorder")]
 #[command(version = "1.1.1")]
 #[command(author)]
 #[command(about = "Records CAN data to a file")]
 struct Args {
-    #[arg(short = 'i', long, name = "name", default_value = "can0", help = "Interface to listen for traffic")]
-    interface: String,
+    #[arg(short = 'i', long, name = "name", default_value = "can0", value_delimiter = ',', help = "Interface to listen for traffic. Repeat it or separate names with commas to record several interfaces into one log, ordered by time. The bus counts as idle once every interface has gone quiet.")]
+    interface: Vec<String>,
     #[arg(short = 'b', long, name = "speed", default_value = "500000", value_parser = clap::value_parser!(u64).range(1..), help = "The speed of the interface, in bps")]
     bus_speed: u64,
     #[arg(short = 't', long, name = "seconds", default_value = "15", value_parser = clap::value_parser!(u64).range(1..), help = "Number of seconds of bus silence allowed before the program will rotate logs")]
@@ -49,13 +161,131 @@ struct Args {
     buffer_size: u32,
     #[arg(short = 'e', long, name = "pin_number", default_value = "22", value_parser = clap::value_parser!(u32).range(0..), help = "Which output GPIO pin to use for the busy LED. The LED will be lit as long as a log file is still open. Set to 0 to disable the LED function.")]
     busy_led: u32,
+    #[arg(short = 'c', long, name = "method", value_enum, default_value = "none", help = "Compress logs as they are written. Each flush ends a compressed block, so a power loss only loses what was written since the last flush.")]
+    compression: Compression,
+    #[arg(short = 'f', long, name = "format", value_enum, default_value = "candump", help = "Format of the logs. Binary logs are smaller and come with an index file (.idx) for fast seeking. ASC and BLF are for Vector tools, and pcapng for Wireshark. Binary and BLF logs can't be compressed.")]
+    format: LogFormat,
+    #[arg(short = 'T', long, name = "source", value_enum, help = "Where frame times come from. Hardware timestamps come from the CAN adapter's clock, kernel timestamps from when the frame reached the kernel, and userspace timestamps from when the recorder read it. Defaults to the most accurate one the interface supports. Frames without the chosen timestamp fall back to the next most accurate.")]
+    timestamps: Option<ClockSource>,
+    #[arg(short = 'p', long, name = "pre_seconds", default_value = "0", help = "Seconds of frames to keep in memory and write at the start of a log started by a trigger, even if they're in the previous log too")]
+    pre_trigger: u64,
+    #[arg(short = 'g', long, name = "input_pin", help = "GPIO input pin whose rising edge triggers a new log")]
+    trigger_gpio: Option<u32>,
+    #[arg(short = 'r', long, name = "ID[#DATA[/MASK]]", value_parser = FrameMatch::parse, help = "Trigger a new log when frames with this ID (hex, 8 digits for an extended ID) start to match the data, e.g. 1A0#0100/FF00. It fires again only after a frame with the ID that doesn't match or an idle bus. Can be repeated.")]
+    trigger_frame: Vec<FrameMatch>,
+    #[arg(short = 'o', long, help = "Only start logs on a trigger (SIGUSR1, --trigger-gpio or --trigger-frame) instead of when the bus wakes up")]
+    trigger_only: bool,
+    #[arg(short = 'q', long, name = "max_gib", value_parser = parse_gib, help = "Delete the oldest logs when logs in the log location take up more than this many GiB. The newest log is never deleted.")]
+    max_total: Option<u64>,
+    #[arg(short = 'F', long, name = "min_gib", default_value = "0", value_parser = parse_gib, help = "Delete the oldest logs to keep this many GiB free on the disk. Logging pauses when there's less than this (or 64 MiB) free and nothing can be deleted.")]
+    min_free: u64,
+    #[arg(short = 'k', long, name = "marker_file", help = "Time marker file; logs with a marker in them are never deleted. Neither are logs with a .keep file.")]
+    markers: Option<PathBuf>,
+    #[arg(short = 'A', long, name = "minutes", value_parser = clap::value_parser!(u64).range(1..), help = "Rotate logs every this many minutes, on the clock (e.g. 60 rotates on the hour)")]
+    rotate_every: Option<u64>,
+    #[arg(short = 'z', long, name = "bytes", value_parser = clap::value_parser!(u64).range(1..), help = "Rotate logs once they take up this many bytes on disk")]
+    rotate_size: Option<u64>,
+    #[arg(short = 'n', long, name = "MESSAGE.SIGNAL[=VALUE]", value_parser = SignalValue::parse, help = "Rotate logs when this signal changes to VALUE (0 if not given), e.g. when the ignition is switched off. Messages and signals are named as in dbc/car.dbc, e.g. GpsHeading.Speed.")]
+    rotate_ignition: Option<SignalValue>,
+    #[arg(short = 'd', long, name = "dbc_file", help = "DBC file for decoding the --rotate-ignition signal; its messages take the place of the built-in decoders. Ignored without --rotate-ignition.")]
+    dbc: Option<PathBuf>,
+    #[arg(short = 'B', long, name = "rcvbuf_bytes", value_parser = clap::value_parser!(u64).range(1024..), help = "Size of the socket receive buffer. Frames arriving while it's full are dropped by the kernel; the drops are noted in the log and its metadata file. Defaults to the system default (net.core.rmem_default); going past net.core.rmem_max needs root.")]
+    rcvbuf: Option<u64>,
+    #[arg(short = 'Q', long, name = "frames", default_value = "65536", value_parser = clap::value_parser!(u64).range(1..), help = "Most frames to hold in memory while waiting for them to be written")]
+    queue_capacity: u64,
+    #[arg(short = 'O', long, name = "policy", value_enum, default_value = "block", help = "What to do when the writer falls behind and the queue is full. Blocking leaves frames in the socket receive buffer, where the kernel drops them once it's full. The dropped frames are counted in the log's metadata file.")]
+    queue_overflow: Overflow,
+    #[arg(short = 'S', long, name = "sync_seconds", value_parser = clap::value_parser!(u64).range(1..), help = "Sync the log to the disk at least this often while frames are coming in. Logs are always synced when the bus goes quiet and when they're closed.")]
+    sync_every: Option<u64>,
+    #[arg(short = 'N', long, name = "sync_frames", value_parser = clap::value_parser!(u64).range(1..), help = "Sync the log to the disk after this many frames")]
+    sync_frames: Option<u64>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// The LED that's lit while a log is open, on a GPIO output pin. Pin 0 means there's no LED.
+struct BusyLed {
+    pin: u32,
+    lines: Option<gpiod::Lines<gpiod::Output>>,
+}
+
+impl BusyLed {
+    fn open(chip: &gpiod::Chip, pin: u32) -> std::io::Result<BusyLed> {
+        let lines = match pin {
+            0 => None,
+            _ => Some(chip.request_lines(gpiod::Options::output([pin]).values([false]))?),
+        };
+        return Ok(BusyLed { pin, lines });
+    }
+
+    fn is_enabled(&self) -> bool {
+        return self.lines.is_some();
+    }
+
+    fn set(&self, on: bool) {
+        if let Some(lines) = &self.lines {
+            lines.set_values([on]).unwrap();
+        }
+    }
+}
+
+/// Settings that reloading the configuration with SIGUSR2 changes without restarting
+struct Live {
+    /// Seconds of bus silence allowed before the log is rotated
+    timeout: u64,
+    /// Write buffer for the logs started from now on
+    buffer_size: usize,
+    busy_led: BusyLed,
+}
+
+impl Live {
+    /// Reads the configuration file and command line again and takes the new settings, or keeps the
+    /// old ones if the new ones are invalid. Returns whether they were taken.
+    fn reload(&mut self, chip: &gpiod::Chip, trigger_gpio: Option<u32>) -> bool {
+        let args: Args = match config::try_parse("recorder") {
+            Ok(a) => a,
+            Err(e) => {
+                println!("Keeping the old configuration: {}", e);
+                return false;
+            }
+        };
+        if args.busy_led != 0 && trigger_gpio == Some(args.busy_led) {
+            println!("Keeping the old configuration: GPIO pin {} can't be both the busy LED and the trigger input", args.busy_led);
+            return false;
+        }
+        if args.busy_led != self.busy_led.pin {
+            match BusyLed::open(chip, args.busy_led) {
+                Ok(led) => {
+                    // The old LED goes dark and its pin is released
+                    self.busy_led.set(false);
+                    self.busy_led = led;
+                },
+                Err(e) => {
+                    println!("Keeping the old configuration: could not use GPIO pin {} for the busy LED: {}", args.busy_led, e);
+                    return false;
+                }
+            }
+        }
+        self.timeout = args.timeout;
+        self.buffer_size = args.buffer_size.try_into().unwrap();
+        println!("Reloaded configuration; timeout {}s, write buffer {} bytes, busy LED pin {}", self.timeout, self.buffer_size, self.busy_led.pin);
+        println!("Other settings take effect after a restart");
+        return true;
+    }
+}
+
+fn parse_gib(s: &str) -> Result<u64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v >= 0.0 && v.is_finite() => Ok((v * (1u64 << 30) as f64) as u64),
+        _ => Err(String::from("must be a number of GiB, 0 or more")),
+    }
 }
 
 fn main() {
-    let matches = Args::parse();
+    let matches: Args = config::parse("recorder");
 
-    let interface: String = matches.interface;
-    let can = CanSocket::open(&interface).unwrap();
+    // FD sockets receive both classic and CAN FD frames
+    let mut buses = Buses::open(&matches.interface, matches.timestamps, matches.rcvbuf.map(|b| b as usize)).unwrap();
 
     let timeout_value: u64 = matches.timeout;
     let bus_speed: u64     = matches.bus_speed;
@@ -63,129 +293,281 @@ fn main() {
     let max_log_lines: u64 = matches.max_log_lines;
     let buffer_size: usize = matches.buffer_size.try_into().unwrap();
     let busy_led_pin: u32  = matches.busy_led;
+    let compression: Compression = matches.compression;
+    let format: LogFormat = matches.format;
+    let pre_trigger = time::Duration::from_secs(matches.pre_trigger);
+    let trigger_only: bool = matches.trigger_only;
+    let queue_capacity: usize = matches.queue_capacity.try_into().unwrap();
+    let queue_overflow: Overflow = matches.queue_overflow;
+    let sync = SyncPolicy { interval: matches.sync_every.map(time::Duration::from_secs), frames: matches.sync_frames };
 
-    println!("Interface:     {}", interface);
+    if !format.compressible() && compression != Compression::None {
+        let mut cmd = Args::command();
+        let error_msg = format!("{:?} logs can't be compressed", format);
+        cmd.error(ErrorKind::ArgumentConflict, error_msg).exit();
+    }
+    let mut policies: Vec<Box<dyn RotationPolicy>> = Vec::new();
+    if let Some(minutes) = matches.rotate_every {
+        policies.push(Box::new(Interval::new(time::Duration::from_secs(minutes * 60))));
+    }
+    if let Some(bytes) = matches.rotate_size {
+        policies.push(Box::new(MaxSize::new(bytes)));
+    }
+    if let Some(signal) = matches.rotate_ignition.clone() {
+        let database = match &matches.dbc {
+            Some(path) => match Database::from_file(path) {
+                Ok(db) => Some(db),
+                Err(e) => {
+                    let mut cmd = Args::command();
+                    let error_msg = format!("Could not load {}: {}", path.display(), e);
+                    cmd.error(ErrorKind::ValueValidation, error_msg).exit();
+                }
+            },
+            None => None,
+        };
+        if let Err(e) = signal.check(database.as_ref()) {
+            let mut cmd = Args::command();
+            cmd.error(ErrorKind::ValueValidation, format!("Can't rotate on {}: {}", signal, e)).exit();
+        }
+        policies.push(Box::new(Ignition::new(signal, database)));
+    }
+    let mut rotation = Rotation::new(policies);
+    if busy_led_pin != 0 && matches.trigger_gpio == Some(busy_led_pin) {
+        let mut cmd = Args::command();
+        let error_msg = format!("GPIO pin {} can't be both the busy LED and the trigger input", busy_led_pin);
+        cmd.error(ErrorKind::ArgumentConflict, error_msg).exit();
+    }
+
+    println!("Interface:     {}", buses.names().join(", "));
     println!("Bus speed:     {}", bus_speed);
     println!("Log location:  {}", log_location);
     println!("Timeout value: {}", timeout_value);
     println!("Max log lines: {}", max_log_lines);
     println!("Write buffer:  {}", buffer_size);
+    println!("Compression:   {:?}", compression);
+    println!("Format:        {:?}", format);
+    println!("Timestamps:    {}", buses.sources());
+    println!("Pre-trigger:   {}s", pre_trigger.as_secs());
+    if let Some(pin) = matches.trigger_gpio {
+        println!("Trigger input: {}", pin);
+    }
+    for m in matches.trigger_frame.iter() {
+        println!("Trigger frame: {}", m);
+    }
+    println!("Trigger only:  {}", trigger_only);
+    if let Some(max) = matches.max_total {
+        println!("Max total:     {} bytes", max);
+    }
+    println!("Min free:      {} bytes", matches.min_free);
+    if let Some(minutes) = matches.rotate_every {
+        println!("Rotate every:  {} minutes", minutes);
+    }
+    if let Some(bytes) = matches.rotate_size {
+        println!("Rotate size:   {} bytes", bytes);
+    }
+    if let Some(signal) = &matches.rotate_ignition {
+        println!("Ignition off:  {}", signal);
+    }
+    println!("Queue:         {} frames, {:?} on overflow", queue_capacity, queue_overflow);
+    if let Some(seconds) = matches.sync_every {
+        println!("Sync every:    {} seconds", seconds);
+    }
+    if let Some(frames) = matches.sync_frames {
+        println!("Sync every:    {} frames", frames);
+    }
 
     let sig_term = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&sig_term)).unwrap();
     let sig_hup = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&sig_hup)).unwrap();
+    let sig_reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGUSR2, Arc::clone(&sig_reload)).unwrap();
+    let mut trigger = Trigger::new(matches.trigger_frame.clone(), matches.trigger_gpio).unwrap();
+    let mut history = History::new(pre_trigger);
+    let mut notifier = Notifier::from_env();
+    // Logs left partial by a power cut, before any new ones are started
+    notifier.status("Recovering partial logs");
+    if let Err(e) = recovery::recover_dir(Path::new(log_location)) {
+        println!("Could not look for logs to recover in {}: {}", log_location, e);
+    }
+    let mut retention = Retention::new(Path::new(log_location), matches.max_total, matches.min_free, matches.markers.clone());
     //let mut busy_led = gpio::sysfs::SysFsGpioOutput::open(busy_led_pin).unwrap();
     let gpio_chip = gpiod::Chip::new("gpiochip0").unwrap();
-    let gpio_opts = gpiod::Options::output([busy_led_pin]).values([false]);
-    let gpio_output = gpio_chip.request_lines(gpio_opts).unwrap();
+    let mut live = Live { timeout: timeout_value, buffer_size, busy_led: BusyLed::open(&gpio_chip, busy_led_pin).unwrap() };
 
     // Two threads let one finish and close a file while the next starts a new one.
     let pool = Builder::new().num_threads(2).thread_name("Writer".to_string()).build();
 
-    println!("Waiting for first frame");
+    // Time of a trigger that ended the last log, to start the next one from
+    let mut pending_trigger: Option<time::Duration> = None;
+    let waiting_message = if trigger_only { "Waiting for trigger" } else { "Waiting for first frame" };
+    notifier.ready();
+    notifier.status(waiting_message);
+    println!("{}", waiting_message);
     while !sig_term.load(Ordering::Relaxed) {
-        if busy_led_pin != 0 {
-            gpio_output.set_values([false]).unwrap();
+        notifier.watchdog();
+        if sig_reload.swap(false, Ordering::Relaxed) {
+            live.reload(&gpio_chip, matches.trigger_gpio);
         }
-        // Setting a timeout of 0 causes it to not respond to signals, so set it arbitrarily large
-        can.set_read_timeout(time::Duration::from_secs(300)).unwrap();
-        can.set_filter_accept_all().unwrap();
-        // Wait for a CAN frame
+        live.busy_led.set(false);
         let mut current_log_lines: u64 = 0;
 
-        let timestamp: time::Duration;
-        let msg = match can.read_frame() {
-            Ok(message) => {
-                timestamp = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap();
-                message
-            },
-            Err(e) => {
-                if socketcan::ShouldRetry::should_retry(&e) {
-                    // Read timed out; loop back
-                    continue;
-                } else if e.kind() == std::io::ErrorKind::Interrupted {
-                    // Interrupted by signal
-                    continue;
-                } else {
-                    panic!("{}", e);
+        // Frames from this time on go at the start of the log. Only a trigger reaches back into the history.
+        let log_start: time::Duration = match pending_trigger.take() {
+            Some(t) => t.saturating_sub(pre_trigger),
+            None => {
+                // Wait for a CAN frame on any interface. Signals interrupt the wait, but a GPIO trigger doesn't.
+                let msg: Option<Received> = match buses.read(time::Duration::from_millis(500)) {
+                    Ok(message) => Some(message),
+                    Err(e) => {
+                        if socketcan::ShouldRetry::should_retry(&e) {
+                            // Read timed out
+                            None
+                        } else if e.kind() == std::io::ErrorKind::Interrupted {
+                            // Interrupted by signal
+                            None
+                        } else {
+                            panic!("{}", e);
+                        }
+                    }
+                };
+                let mut start: Option<time::Duration> = None;
+                if let Some(msg) = msg {
+                    if trigger.check_frame(&msg.frame) {
+                        println!("Triggered by {}", TriggerSource::Frame);
+                        start = Some(msg.time.saturating_sub(pre_trigger));
+                    } else if !trigger_only {
+                        start = Some(msg.time);
+                    }
+                    history.push(msg);
+                }
+                if let Some((source, t)) = trigger.take() {
+                    println!("Triggered by {}", source);
+                    start = Some(start.unwrap_or(t).min(t.saturating_sub(pre_trigger)));
+                }
+                match start {
+                    Some(s) => s,
+                    None => continue,
                 }
             }
         };
+        if !retention.make_room() {
+            // Frames keep going into the history until there's space again
+            notifier.status("Logging paused; not enough free disk space");
+            continue;
+        }
         {
             // start logging
-            if busy_led_pin != 0 {
-                gpio_output.set_values([true]).unwrap();
-            }
-            let log_name = format!("{}.log", &Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true).replace(":","_"));
+            live.busy_led.set(true);
+            let log_name = format!("{}{}{}", &Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true).replace(":","_"), format.extension(), compression.extension());
             let log_path = format!("{}/{}", log_location, log_name);
             println!("Logging to: {}", log_path);
-            let (tx, rx): (Sender<LogMessage>, Receiver<LogMessage>) = mpsc::channel();
+            let mut logger = match car_logger::Logger::new(log_path.clone(), buses.names(), live.buffer_size, compression, format) {
+                Ok(l) => l,
+                Err(e) => {
+                    // Most likely the disk filled up; try again later like when there's no room
+                    println!("Could not start log {}: {}; logging paused", log_path, e);
+                    notifier.status(&format!("Logging paused; could not start a log: {}", e));
+                    retention.pause();
+                    continue;
+                }
+            };
+            logger.set_recorder(Args::command().get_version().unwrap_or_default(), bus_speed);
+            logger.set_sync(sync);
+            notifier.status(&format!("Logging to {}", log_name));
+            rotation.start(&recovery::partial_path(&log_path));
+            let (tx, rx) = queue::bounded(queue_capacity, queue_overflow);
+            let (spare_tx, spare_rx): (Sender<FrameBatch>, Receiver<FrameBatch>) = mpsc::channel();
+            let mut queue = LogQueue::new(tx, spare_rx);
             let (etx, erx): (Sender<WriterError>, Receiver<WriterError>) = mpsc::channel();
-            let st_iface: String = interface.to_string();
             // Pick up a new thread from the pool
             pool.execute(move|| {
-                let mut logger = carlogger_service::Logger::new(log_path, st_iface, buffer_size);
-                loop {
-                    match rx.recv() {
-                        Ok(message) => match message {
-                            LogMessage::Ping => continue,
-                            LogMessage::Frame(frame, time) => {
-                                if let CanFrame::Error(ef) = frame {
+                // Stays an error unless the main thread says why the log is closing
+                let mut reason = RotationReason::Error;
+                'writer: while let Some(message) = rx.recv() {
+                    match message {
+                        LogMessage::Ping => continue,
+                        LogMessage::Frames(mut batch) => {
+                            for r in batch.iter() {
+                                if r.dropped > 0 {
+                                    if let Err(e) = logger.log_dropped(r.iface, r.dropped as u64, r.time) {
+                                        let _ = etx.send(WriterError::IOError(e));
+                                        break 'writer;
+                                    }
+                                }
+                                if let CanAnyFrame::Error(ef) = r.frame {
                                     // Bubble up the error to the main thread but don't exit
-                                    if let Err(_) = etx.send(WriterError::CANError(CanError::from(ef))) {
-                                        break;
+                                    if etx.send(WriterError::CANError(CanError::from(ef))).is_err() {
+                                        break 'writer;
                                     }
                                 }
-                                match logger.log(frame, time) {
+                                match logger.log(r.iface, r.frame, r.time, r.source) {
                                     Ok(s) => {
                                         if s == 0 {
                                             let _ = etx.send(WriterError::Error(String::from("Wrote 0 bytes to log")));
-                                            break;
+                                            break 'writer;
                                         }
                                     },
                                     Err(e) => {
                                         let _ = etx.send(WriterError::IOError(e));
-                                        break;
+                                        break 'writer;
                                     }
-                                
                                 };
                             }
-                            LogMessage::Flush => {
-                                if let Err(e) = logger.flush() {
-                                    let _ = etx.send(WriterError::IOError(e));
-                                    break;
-                                };
-                            },
-                            LogMessage::Exit => {
+                            batch.clear();
+                            let _ = spare_tx.send(batch);
+                        },
+                        LogMessage::Flush => {
+                            if let Err(e) = logger.flush().and_then(|_| logger.sync()) {
+                                let _ = etx.send(WriterError::IOError(e));
                                 break;
-                            }
+                            };
                         },
-                        Err(_) => {
+                        LogMessage::Exit(r) => {
+                            reason = r;
                             break;
-                        },
-                    };
+                        }
+                    }
+                }
+                logger.queue_stats(rx.max_depth(), rx.dropped());
+                if let Err(e) = logger.close(reason) {
+                    let _ = etx.send(WriterError::IOError(e));
                 }
             });
             // An immediate failure to record a frame is basically unrecoverable, so just unwrap it
-            tx.send(LogMessage::Frame(msg, timestamp)).unwrap();
-            current_log_lines += 1;
+            for msg in history.take_since(log_start) {
+                queue.send_frame(msg).unwrap();
+                current_log_lines += 1;
+            }
             sig_hup.store(false, Ordering::Relaxed);
-            can.set_read_timeout(time::Duration::from_millis(500)).unwrap();
-            let mut timeout: u64 = timeout_value*2;
+            let mut timeout: u64 = live.timeout*2;
             let mut busy_state: bool = false;
             let mut led_state: bool = false;
             let mut frame_counter: u32 = 0;
+            let mut reason = RotationReason::Error;
+            let mut last_status = time::Instant::now();
+            let mut status_lines: u64 = current_log_lines;
             #[cfg(feature = "profile")]
-            {
-                const PROFILE_ARRAY_SIZE: usize = 1<<14;
-                let mut queue_check_time_array: [u128; PROFILE_ARRAY_SIZE] = [0; PROFILE_ARRAY_SIZE];
-                let mut can_read_time_array: [u128; PROFILE_ARRAY_SIZE] = [0; PROFILE_ARRAY_SIZE];
-                let mut log_send_time_array: [u128; PROFILE_ARRAY_SIZE] = [0; PROFILE_ARRAY_SIZE];
-                let mut profile_array_index: usize = 0;
-                let mut last_profile_print = time::Instant::now();
-            }
+            let mut profile = Profile::new();
             while !sig_hup.load(Ordering::Relaxed) && !sig_term.load(Ordering::Relaxed) {
+                notifier.watchdog();
+                if sig_reload.swap(false, Ordering::Relaxed) && live.reload(&gpio_chip, matches.trigger_gpio) {
+                    // The silence allowed starts over, and a new LED is lit for the open log
+                    timeout = live.timeout*2;
+                    live.busy_led.set(true);
+                }
+                if last_status.elapsed() >= STATUS_INTERVAL {
+                    let rate = (current_log_lines - status_lines) as f64 / last_status.elapsed().as_secs_f64();
+                    notifier.status(&format!("Logging to {} at {:.0} frames/s", log_name, rate));
+                    last_status = time::Instant::now();
+                    status_lines = current_log_lines;
+                }
+                if let Some((source, t)) = trigger.take() {
+                    println!("Triggered by {}; rotating log", source);
+                    pending_trigger = Some(t);
+                    reason = RotationReason::Trigger;
+                    break;
+                }
                 #[cfg(feature = "profile")]
                 let start_time = time::Instant::now();
                 // Check the error queue first
@@ -214,24 +596,22 @@ fn main() {
                 // Put the time spent checking the queue into an array
                 #[cfg(feature = "profile")]
                 let queue_check_time = start_time.elapsed().as_nanos();
-                let timestamp: time::Duration;
-                let msg = match can.read_frame() {
+                let msg = match buses.read(time::Duration::from_millis(500)) {
                     Ok(message) => {
-                        timestamp = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap();
-                        if busy_state == false && busy_led_pin != 0 {
+                        if busy_state == false && live.busy_led.is_enabled() {
                             busy_state = true;
                             frame_counter = 0;
                             led_state = true;
-                            gpio_output.set_values([true]).unwrap();
+                            live.busy_led.set(true);
                         }
                         // Flash the LED based on frame count
                         frame_counter += 1;
-                        if frame_counter >= 100 && busy_led_pin != 0 {
+                        if frame_counter >= 100 && live.busy_led.is_enabled() {
                             frame_counter = 0;
                             led_state = !led_state;
-                            gpio_output.set_values([led_state]).unwrap();
+                            live.busy_led.set(led_state);
                         }
-                        timeout = timeout_value*2;
+                        timeout = live.timeout*2;
                         message
                     },
                     Err(e) => {
@@ -239,22 +619,34 @@ fn main() {
                             busy_state = false;
                             frame_counter = 0;
                             if timeout == 0 {
+                                reason = RotationReason::Timeout;
+                                // The bus is asleep, so frames that matched before it went quiet can trigger again
+                                trigger.rearm();
                                 break;
                             }
                             // Flash the LED based on timeout
-                            if busy_led_pin != 0 {
-                                if timeout % 2 == 0 {
+                            if live.busy_led.is_enabled() {
+                                if timeout.is_multiple_of(2) {
                                     led_state = !led_state;
                                 }
-                                gpio_output.set_values([led_state]).unwrap();
+                                live.busy_led.set(led_state);
                             }
                             timeout -= 1;
-                            if timeout == (timeout_value * 2) - 2 {
-                                if let Err(_) = tx.send(LogMessage::Flush) {
-                                    println!("Wrote {} lines to log", current_log_lines);
-                                    println!("Logging thread exited unexpectedly (log queue sender error); rotating log");
-                                    break;
-                                }
+                            // Write out what came in before the bus went quiet
+                            if queue.send_batch().is_err() {
+                                println!("Wrote {} lines to log", current_log_lines);
+                                println!("Logging thread exited unexpectedly (log queue sender error); rotating log");
+                                break;
+                            }
+                            if let Some(r) = rotation.check() {
+                                println!("Rotating log: {}", r);
+                                reason = r;
+                                break;
+                            }
+                            if timeout == (live.timeout * 2) - 2 && queue.send(LogMessage::Flush).is_err() {
+                                println!("Wrote {} lines to log", current_log_lines);
+                                println!("Logging thread exited unexpectedly (log queue sender error); rotating log");
+                                break;
                             }
                             continue;
                         } else if e.kind() == std::io::ErrorKind::Interrupted {
@@ -268,52 +660,56 @@ fn main() {
                 };
                 #[cfg(feature = "profile")]
                 let can_read_time = start_time.elapsed().as_nanos() - queue_check_time;
-                if let Err(_) = tx.send(LogMessage::Frame(msg, timestamp)) {
+                history.push(msg.clone());
+                if trigger.check_frame(&msg.frame) {
+                    // The matching frame starts the new log along with the history before it
+                    println!("Triggered by {}; rotating log", TriggerSource::Frame);
+                    pending_trigger = Some(msg.time);
+                    reason = RotationReason::Trigger;
+                    break;
+                }
+                let frame = msg.frame;
+                if queue.send_frame(msg).is_err() {
                     println!("Wrote {} lines to log", current_log_lines);
                     println!("Logging thread exited unexpectedly (log queue sender error); rotating log");
                     break;
                 }
                 #[cfg(feature = "profile")]
-                {
-                    let log_send_time = start_time.elapsed().as_nanos() - can_read_time - queue_check_time;
-                    can_read_time_array[profile_array_index] = can_read_time;
-                    queue_check_time_array[profile_array_index] = queue_check_time;
-                    log_send_time_array[profile_array_index] = log_send_time;
-                    profile_array_index = (profile_array_index + 1) & (PROFILE_ARRAY_SIZE - 1);
-                    if last_profile_print.elapsed().as_secs() >= 300 {
-                        last_profile_print = time::Instant::now();
-                        // Average arrays and print the result
-                        let mut queue_check_time_sum: u128 = 0;
-                        let mut can_read_time_sum: u128 = 0;
-                        let mut log_send_time_sum: u128 = 0;
-                        for i in 0..PROFILE_ARRAY_SIZE { queue_check_time_sum += queue_check_time_array[i]; }
-                        for i in 0..PROFILE_ARRAY_SIZE { can_read_time_sum += can_read_time_array[i]; }
-                        for i in 0..PROFILE_ARRAY_SIZE { log_send_time_sum += log_send_time_array[i]; }
-                        let queue_check_time_avg: u128 = queue_check_time_sum / (queue_check_time_array.len() as u128);
-                        let can_read_time_avg: u128 = can_read_time_sum / (can_read_time_array.len() as u128);
-                        let log_send_time_avg: u128 = log_send_time_sum / (log_send_time_array.len() as u128);
-                        println!("----------------------------------------");
-                        println!("Average queue check time: {} ns", queue_check_time_avg);
-                        println!("Average CAN read time: {} ns", can_read_time_avg);
-                        println!("Average log send time: {} ns", log_send_time_avg);
-                        println!("Profiling time: {} ns", start_time.elapsed().as_nanos() - can_read_time - queue_check_time - log_send_time);
-                    }
-                }
+                profile.record(queue_check_time, can_read_time, start_time);
                 current_log_lines += 1;
+                if let Some(r) = rotation.frame(&frame) {
+                    println!("Rotating log: {}", r);
+                    reason = r;
+                    break;
+                }
                 if current_log_lines >= max_log_lines {
                     println!("Wrote {} lines to log", current_log_lines);
                     println!("Max log lines reached; rotating log");
-                    let _ = tx.send(LogMessage::Exit);
+                    reason = RotationReason::MaxLines;
                     break;
                 }
             }
-            sig_hup.store(false, Ordering::Relaxed);
-            let _ = tx.send(LogMessage::Exit);
-            if busy_led_pin != 0 {
-                gpio_output.set_values([false]).unwrap();
+            if sig_term.load(Ordering::Relaxed) {
+                reason = RotationReason::Shutdown;
+                // Frames still being merged would otherwise be lost
+                for msg in buses.drain() {
+                    if queue.send_frame(msg).is_err() {
+                        break;
+                    }
+                    current_log_lines += 1;
+                }
+            } else if sig_hup.load(Ordering::Relaxed) {
+                reason = RotationReason::Hangup;
             }
+            sig_hup.store(false, Ordering::Relaxed);
+            let _ = queue.send(LogMessage::Exit(reason));
+            live.busy_led.set(false);
             println!("Wrote {} lines to log", current_log_lines);
-            println!("Waiting for first frame");
+            println!("Timestamps: {}", buses.take_counts());
+            println!("Dropped: {} frames by the kernel, {} by the queue; at most {} frames waiting to be written", queue.dropped, queue.tx.dropped(), queue.tx.max_depth());
+            notifier.status(waiting_message);
+            println!("{}", waiting_message);
         }
     }
+    notifier.stopping();
 }
\ No newline at end of file
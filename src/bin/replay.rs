@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use signal_hook::consts::TERM_SIGNALS;
+use socketcan::{CanAnyFrame, CanFdSocket, Socket};
+
+use car_logger::{binlog, candump};
+use car_logger::candump::Record;
+use car_logger::trigger::FrameMatch;
+
+/// How long the transmit queue can stay full before the interface is given up on
+const TX_STUCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Parser)]
+#[command(name = "replay")]
+#[command(version = "1.0")]
+#[command(author)]
+#[command(about = "Plays recorder logs back onto CAN interfaces with their original timing")]
+struct Args {
+    #[arg(name = "log", required = true, help = "Log files to play, in order. Candump and binary logs are told apart automatically.")]
+    logs: Vec<PathBuf>,
+    #[arg(short = 's', long, name = "multiplier", default_value = "1.0", value_parser = parse_speed, help = "Playback speed; 2.0 plays twice as fast")]
+    speed: f64,
+    #[arg(short = 'l', long = "loop", help = "Start over from the first log after the last one finishes")]
+    repeat: bool,
+    #[arg(short = 'I', long, name = "include_ids", value_delimiter = ',', value_parser = parse_id, help = "Only play these IDs (hex, comma separated). IDs with more than 3 digits are extended.")]
+    include: Vec<FrameMatch>,
+    #[arg(short = 'X', long, name = "exclude_ids", value_delimiter = ',', value_parser = parse_id, help = "Never play these IDs (hex, comma separated). IDs with more than 3 digits are extended.")]
+    exclude: Vec<FrameMatch>,
+    #[arg(short = 'a', long, name = "start_seconds", value_parser = parse_offset, help = "Skip frames earlier than this many seconds after the first frame of each log")]
+    start: Option<Duration>,
+    #[arg(short = 'e', long, name = "end_seconds", value_parser = parse_offset, help = "Skip frames later than this many seconds after the first frame of each log")]
+    end: Option<Duration>,
+    #[arg(short = 'i', long, name = "log_iface=send_iface", value_parser = parse_mapping, help = "Send frames recorded on one interface out of another, e.g. can0=vcan0. Interfaces that aren't mapped keep their recorded name.")]
+    interface: Vec<(String, String)>,
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        _ => Err(String::from("must be a number greater than 0")),
+    }
+}
+
+/// An ID to filter on, standard or extended the way a frame match tells them apart
+fn parse_id(s: &str) -> Result<FrameMatch, String> {
+    let m = FrameMatch::parse(s)?;
+    if m.has_data() {
+        return Err(format!("'{}' is not a hex CAN ID", s));
+    }
+    return Ok(m);
+}
+
+fn parse_offset(s: &str) -> Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v >= 0.0 && v.is_finite() => Ok(Duration::from_secs_f64(v)),
+        _ => Err(String::from("must be a number of seconds, 0 or more")),
+    }
+}
+
+fn parse_mapping(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok((from.to_string(), to.to_string())),
+        _ => Err(String::from("must be in the form log_iface=send_iface")),
+    }
+}
+
+/// A problem reading a log record
+enum Problem {
+    /// A bad line that playback can continue past
+    Skip(String),
+    /// Nothing more can be read from the log
+    Stop(String),
+}
+
+/// Opens a candump or binary log as a stream of records
+fn open(log: &Path) -> Result<Box<dyn Iterator<Item = Result<Record, Problem>>>, String> {
+    if binlog::is_binary(log).map_err(|e| e.to_string())? {
+        let reader = binlog::Reader::open(log).map_err(|e| e.to_string())?;
+        return Ok(Box::new(reader.map(|r| r.map_err(|e| Problem::Stop(e.to_string())))));
+    }
+    let reader = candump::Reader::open(log).map_err(|e| e.to_string())?;
+    return Ok(Box::new(reader.map(|r| r.map_err(|e| match e {
+        candump::ReadError::Malformed { line, message } => Problem::Skip(format!("line {}: {}", line, message)),
+        e => Problem::Stop(e.to_string()),
+    }))));
+}
+
+/// Sleeps until `deadline`, waking up regularly to check for a termination signal
+fn sleep_until(deadline: Instant, sig_term: &AtomicBool) {
+    loop {
+        let now = Instant::now();
+        if now >= deadline || sig_term.load(Ordering::Relaxed) {
+            return;
+        }
+        std::thread::sleep((deadline - now).min(Duration::from_millis(100)));
+    }
+}
+
+fn main() {
+    let matches = Args::parse();
+
+    let mapping: HashMap<String, String> = matches.interface.into_iter().collect();
+
+    println!("Logs:       {}", matches.logs.iter().map(|p| p.display().to_string()).collect::<Vec<String>>().join(", "));
+    println!("Speed:      {}x", matches.speed);
+    println!("Loop:       {}", matches.repeat);
+    if !matches.include.is_empty() {
+        println!("Include:    {}", matches.include.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", "));
+    }
+    if !matches.exclude.is_empty() {
+        println!("Exclude:    {}", matches.exclude.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", "));
+    }
+    for (from, to) in mapping.iter() {
+        println!("Interface:  {} -> {}", from, to);
+    }
+
+    let sig_term = Arc::new(AtomicBool::new(false));
+    for s in TERM_SIGNALS {
+        signal_hook::flag::register(*s, Arc::clone(&sig_term)).unwrap();
+    }
+
+    let mut sockets: HashMap<String, CanFdSocket> = HashMap::new();
+    let mut sent: u64 = 0;
+    'playback: loop {
+        // Frames sent on this pass through the logs
+        let mut pass_sent: u64 = 0;
+        for log in matches.logs.iter() {
+            if sig_term.load(Ordering::Relaxed) {
+                break 'playback;
+            }
+            let reader = match open(log) {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("Could not open {}: {}", log.display(), e);
+                    continue;
+                }
+            };
+            println!("Playing {}", log.display());
+            // Log time of the first frame
+            let mut first_time: Option<Duration> = None;
+            // When the first frame was played, and its offset into the log
+            let mut anchor: Option<(Instant, Duration)> = None;
+            for record in reader {
+                if sig_term.load(Ordering::Relaxed) {
+                    break 'playback;
+                }
+                let record = match record {
+                    Ok(r) => r,
+                    Err(Problem::Skip(e)) => {
+                        println!("Skipping {}", e);
+                        continue;
+                    },
+                    Err(Problem::Stop(e)) => {
+                        println!("Stopped reading {}: {}", log.display(), e);
+                        break;
+                    }
+                };
+                let log_start = *first_time.get_or_insert(record.time);
+                let offset = record.time.saturating_sub(log_start);
+                if let Some(start) = matches.start {
+                    if offset < start {
+                        continue;
+                    }
+                }
+                if let Some(end) = matches.end {
+                    if offset > end {
+                        break;
+                    }
+                }
+                let frame = record.frame;
+                // Error frames are generated by the controller and can't be sent
+                if let CanAnyFrame::Error(_) = frame {
+                    continue;
+                }
+                if (!matches.include.is_empty() && !matches.include.iter().any(|m| m.matches_id(&frame))) || matches.exclude.iter().any(|m| m.matches_id(&frame)) {
+                    continue;
+                }
+                // Time the playback from the first frame played so a start offset doesn't cause a pause
+                let (anchor_instant, anchor_offset) = *anchor.get_or_insert((Instant::now(), offset));
+                sleep_until(anchor_instant + offset.saturating_sub(anchor_offset).div_f64(matches.speed), &sig_term);
+
+                let iface = mapping.get(&record.iface).unwrap_or(&record.iface);
+                if !sockets.contains_key(iface) {
+                    // FD sockets can send both classic and CAN FD frames
+                    match CanFdSocket::open(iface) {
+                        Ok(s) => {
+                            sockets.insert(iface.clone(), s);
+                        },
+                        Err(e) => {
+                            println!("Could not open interface {}: {}", iface, e);
+                            break 'playback;
+                        }
+                    }
+                }
+                let socket = &sockets[iface];
+                let mut full_since: Option<Instant> = None;
+                loop {
+                    if sig_term.load(Ordering::Relaxed) {
+                        break 'playback;
+                    }
+                    match socket.write_frame_insist(&frame) {
+                        Ok(_) => break,
+                        // The transmit queue is full; give the interface a moment to drain it, unless it's stuck,
+                        // e.g. bus-off or with nothing on the bus to acknowledge frames
+                        Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                            if full_since.get_or_insert_with(Instant::now).elapsed() >= TX_STUCK_TIMEOUT {
+                                println!("Error writing to {}: transmit queue has been full for {} s", iface, TX_STUCK_TIMEOUT.as_secs());
+                                break 'playback;
+                            }
+                            std::thread::sleep(Duration::from_millis(1));
+                        },
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                        Err(e) => {
+                            println!("Error writing to {}: {}", iface, e);
+                            break 'playback;
+                        }
+                    }
+                }
+                sent += 1;
+                pass_sent += 1;
+            }
+        }
+        if !matches.repeat || sig_term.load(Ordering::Relaxed) {
+            break;
+        }
+        // Looping would only go through the same logs again without sending anything
+        if pass_sent == 0 {
+            println!("No frames were sent from any of the logs");
+            std::process::exit(1);
+        }
+    }
+    println!("Sent {} frames", sent);
+}
@@ -1,28 +1,28 @@
-use std::fmt::Debug;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use chrono::TimeZone;
+use chrono::{DateTime, TimeZone, Utc};
 use clap::{CommandFactory, Parser};
 use clap::error::ErrorKind;
 use geoutils::{Location, Distance};
-use socketcan::{CanFilter, CanSocket, Frame, Socket, SocketOptions};
+use socketcan::CanFrame;
 
-pub mod carlogger_service;
+use car_logger::{DecodeError, ParsedFrame};
+use car_logger::config::{self, BusArgs, ConfigArgs};
+use car_logger::service::{Handler, Service};
+use car_logger::systemd::Notifier;
+
+/// Bus silence after which the car is taken to be parked
+const QUIET_TIME: Duration = Duration::from_secs(60);
 
 #[derive(Parser)]
 #[command(name = "shutdown scheduler")]
 #[command(version = "1.0")]
 #[command(author)]
 #[command(about = "Writes the shutdown time to a file")]
-
 struct Args {
-    #[arg(short = 'i', long, name = "name", default_value = "can0", help = "Interface to listen for traffic")]
-    interface: String,
-    #[arg(short = 'b', long, name = "speed", default_value = "500000", value_parser = clap::value_parser!(u64).range(1..), help = "The speed of the interface, in bps")]
-    bus_speed: u64,
+    #[command(flatten)]
+    bus: BusArgs,
     #[arg(short = 'a', long, name = "latitude", help = "Latitude of the centerpoint for the shutdown area, in degrees", allow_negative_numbers = true)]
     latitude: f32,
     #[arg(short = 'o', long, name = "longitude", help = "Longitude of the centerpoint for the shutdown area, in degrees", allow_negative_numbers = true)]
@@ -35,19 +35,138 @@ struct Args {
     file: PathBuf,
     #[arg(short = 'd', long, name = "dry_run", help = "If specified, do not write the shutdown time to the file")]
     dry_run: bool,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+/// Checks that the shutdown area is somewhere on Earth
+fn check_area(latitude: f32, longitude: f32, radius: f32) -> Result<(), String> {
+    if !(-90.0..=90.0).contains(&latitude) {
+        return Err(format!("Latitude {} is not between -90 and 90 degrees", latitude));
+    }
+    if !(-180.0..=180.0).contains(&longitude) {
+        return Err(format!("Longitude {} is not between -180 and 180 degrees", longitude));
+    }
+    if !(radius > 0.0 && radius.is_finite()) {
+        return Err(format!("Radius {} is not a distance above 0", radius));
+    }
+    return Ok(());
+}
+
+/// Removes the shutdown file, which may not be there
+fn remove_file(file_name: &Path) {
+    match std::fs::remove_file(file_name) {
+        Ok(_) => (),
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::NotFound => (),
+            _ => panic!("Error removing file: {}", e)
+        }
+    }
+}
+
+struct Scheduler {
+    latitude: f32,
+    longitude: f32,
+    radius: f32,
+    time: u64,
+    file_name: PathBuf,
+    dry_run: bool,
+    shutdown_position: Location,
+    last_position: Location,
+    last_time: Duration,
+    update_last_position: bool,
+    has_left_shutdown_area: bool,
+    file_exists: bool,
+    last_frame: Instant,
+}
+
+impl Scheduler {
+    /// The bus is awake, so the car isn't shutting down any time soon
+    fn activity(&mut self) {
+        self.last_frame = Instant::now();
+        if self.file_exists == true && self.dry_run == false {
+            remove_file(&self.file_name);
+            self.file_exists = false;
+        }
+    }
+}
+
+impl Handler for Scheduler {
+    fn start(&mut self, notifier: &Notifier) {
+        notifier.status("Waiting for GPS position");
+        println!("Waiting for frame...");
+    }
+
+    fn frame(&mut self, frame: ParsedFrame, _received: DateTime<Utc>, notifier: &Notifier) {
+        self.activity();
+        let location = match frame {
+            ParsedFrame::_465(location) => location,
+            _ => return,
+        };
+        self.last_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        self.last_position = location;
+        self.update_last_position = true;
+        notifier.status(&format!("Distance to shutdown area: {:.0}m", self.last_position.distance_to(&self.shutdown_position).unwrap().meters()));
+        if self.has_left_shutdown_area == false && self.last_position.is_in_circle(&self.shutdown_position, Distance::from_meters(self.radius)).unwrap() == false {
+            self.has_left_shutdown_area = true;
+        }
+    }
+
+    fn undecoded(&mut self, _frame: CanFrame, error: DecodeError) {
+        self.activity();
+        println!("Skipping bad frame: {}", error);
+    }
+
+    fn idle(&mut self, _notifier: &Notifier) {
+        // Update the shutdownat file as needed once the bus has gone quiet
+        if self.update_last_position == false || self.last_frame.elapsed() < QUIET_TIME {
+            return;
+        }
+        self.update_last_position = false;
+        println!("Last location: {:?}", self.last_position);
+        println!("Distance to shutdown area: {}m", self.last_position.distance_to(&self.shutdown_position).unwrap().meters());
+        if self.last_position.is_in_circle(&self.shutdown_position, Distance::from_meters(self.radius)).unwrap() && self.has_left_shutdown_area == true {
+            let shutdown_at: u64 = self.last_time.as_secs() + self.time;
+            println!("Shutting down at {}: {}", shutdown_at, chrono::Utc.timestamp_opt(shutdown_at as i64, 0).unwrap());
+            if self.dry_run == false {
+                std::fs::write(&self.file_name, shutdown_at.to_string()).unwrap();
+                self.file_exists = true;
+            }
+        } else {
+            println!("Not in shutdown area; removing file");
+            // Check if the file exists first
+            if self.dry_run == false && self.file_name.exists() {
+                remove_file(&self.file_name);
+                self.file_exists = false;
+            }
+        }
+        println!("Waiting for frame...");
+    }
+
+    fn reload(&mut self) {
+        // Only the shutdown area changes; the rest needs a restart
+        match config::try_parse::<Args>("shutdown_scheduler").and_then(|a| check_area(a.latitude, a.longitude, a.radius).map(|_| a)) {
+            Ok(a) => {
+                self.latitude = a.latitude;
+                self.longitude = a.longitude;
+                self.radius = a.radius;
+                self.shutdown_position = Location::new(self.latitude, self.longitude);
+                println!("Reloaded configuration; shutdown area is {}m around {}, {}", self.radius, self.latitude, self.longitude);
+            },
+            Err(e) => println!("Keeping the old configuration: {}", e),
+        }
+    }
 }
 
 fn main() {
-    let matches = Args::parse();
+    let matches: Args = config::parse("shutdown_scheduler");
 
-    let interface: String = matches.interface;
+    let database = matches.bus.database::<Args>();
+    let interface: String = matches.bus.interface;
     // Open the interface and set up a filter for frames with ID 0x465
-    let can = CanSocket::open(&interface).unwrap();
-    let filter = CanFilter::new(0x465, 0x7FF);
-    can.set_filters(&[filter]).unwrap();
-    can.set_read_timeout(Duration::from_secs(60)).unwrap();
+    let mut service = Service::open(&interface, &[0x465]).unwrap().reload_on_signal().database(database);
 
-    let bus_speed: u64 = matches.bus_speed;
+    let bus_speed: u64 = matches.bus.bus_speed;
     let latitude: f32 = matches.latitude;
     let longitude: f32 = matches.longitude;
     let radius: f32 = matches.radius;
@@ -60,6 +179,10 @@ fn main() {
         let error_msg = format!("Directory {} does not exist", file_name.parent().unwrap().to_str().unwrap());
         cmd.error(ErrorKind::ValueValidation, error_msg).exit();
     }
+    if let Err(error_msg) = check_area(latitude, longitude, radius) {
+        let mut cmd = Args::command();
+        cmd.error(ErrorKind::ValueValidation, error_msg).exit();
+    }
 
     println!("Interface: {}", interface);
     println!("Bus speed: {}", bus_speed);
@@ -70,102 +193,29 @@ fn main() {
     println!("File:      {}", file_name.to_str().unwrap());
     println!("Dry run:   {}", dry_run);
 
-    let sig_term = Arc::new(AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&sig_term)).unwrap();
-
-    let shutdown_position = Location::new(latitude, longitude);
-    let mut last_position: Location = Location::new(0, 0);
-    let mut last_time: Duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    let mut update_last_position: bool = false;
-    let mut print_waiting_message: bool = true;
-    let mut has_left_shutdown_area: bool = false;
-    let mut file_exists: bool = if file_name.exists() { true } else { false };
-
-    while !sig_term.load(Ordering::Relaxed) {
-        // Get the next frame
-        if print_waiting_message == true {
-            println!("Waiting for frame...");
-            print_waiting_message = false;
-        }
-        match can.read_frame() {
-            Ok(msg) => {
-                if file_exists == true && dry_run == false {
-                    match std::fs::remove_file(file_name.clone()) {
-                        Ok(_) => (),
-                        Err(e) => match e.kind() {
-                            std::io::ErrorKind::NotFound => (),
-                            _ => panic!("Error removing file: {}", e)
-                        }
-                    }
-                    file_exists = false;
-                }
-                if msg.id_word() == 0x465 {
-                    update_last_position = true;
-                    last_position = match carlogger_service::parse_frame(msg) {
-                        Some(carlogger_service::ParsedFrame::_465(location)) => {
-                            last_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                            location
-                        }
-                        _ => last_position
-                    };
-                    if has_left_shutdown_area == false && last_position.is_in_circle(&shutdown_position, Distance::from_meters(radius)).unwrap() == false {
-                        has_left_shutdown_area = true;
-                    }
-                } else {
-                    println!("Received frame with ID 0x{:X}", msg.id_word());
-                    continue;
-                }
-            },
-            Err(e) => {
-                if socketcan::ShouldRetry::should_retry(&e) {
-                    // Update the shutdownat file as needed
-                    if update_last_position == true {
-                        update_last_position = false;
-                        println!("Last location: {:?}", last_position);
-                        println!("Distance to shutdown area: {}m", last_position.distance_to(&shutdown_position).unwrap().meters());
-                        if last_position.is_in_circle(&shutdown_position, Distance::from_meters(radius)).unwrap() && has_left_shutdown_area == true {
-                            let shutdown_at: u64 = last_time.as_secs() + time;
-                            println!("Shutting down at {}: {}", shutdown_at, chrono::Utc.timestamp_opt(shutdown_at as i64, 0).unwrap().to_string());
-                            if dry_run == false {
-                                std::fs::write(file_name.clone(), shutdown_at.to_string()).unwrap();
-                                file_exists = true;
-                            }
-                        } else {
-                            println!("Not in shutdown area; removing file");
-                            // Check if the file exists first
-                            if dry_run == false && file_name.exists() {
-                                match std::fs::remove_file(file_name.clone()) {
-                                    Ok(_) => {file_exists = false},
-                                    Err(e) => match e.kind() {
-                                        std::io::ErrorKind::NotFound => (),
-                                        _ => panic!("Error removing file: {}", e)
-                                    }
-                                }
-                            }
-                        }
-                        print_waiting_message = true;
-                    }
-                    continue;
-                } else if e.kind() == std::io::ErrorKind::Interrupted {
-                    println!("Caught interrupt");
-                    continue;
-                } else {
-                    panic!("Error reading from CAN bus: {}", e);
-                }
-            }
-        };
-
+    let file_exists: bool = file_name.exists();
+    let mut scheduler = Scheduler {
+        latitude,
+        longitude,
+        radius,
+        time,
+        file_name,
+        dry_run,
+        shutdown_position: Location::new(latitude, longitude),
+        last_position: Location::new(0, 0),
+        last_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+        update_last_position: false,
+        has_left_shutdown_area: false,
+        file_exists,
+        last_frame: Instant::now(),
+    };
+    if let Err(e) = service.run(&mut scheduler) {
+        panic!("Error reading from CAN bus: {}", e);
     }
     // Remove the file in case the service was stopped manually
     // This way it won't unexpectedly shut down.
     // If the program is terminated due to a system shutdown, it won't matter anyway.
     if dry_run == false {
-        match std::fs::remove_file(file_name) {
-            Ok(_) => (),
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::NotFound => (),
-                _ => panic!("Error removing file: {}", e)
-            }
-        }
+        remove_file(&scheduler.file_name);
     }
-}
\ No newline at end of file
+}
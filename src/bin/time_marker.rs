@@ -6,19 +6,22 @@ use std::sync::atomic::{AtomicBool, Ordering};
 
 use clap::Parser;
 
+use car_logger::config::{self, ConfigArgs};
+
 #[derive(Parser)]
 #[command(name = "Time marker utility")]
 #[command(version = "1.0")]
 #[command(author)]
 #[command(about = "Writes time markers with optional notes to a file")]
-
 struct Args {
     #[arg(short = 'f', long, name = "file", help = "File to write time markers to")]
     file: PathBuf,
+    #[command(flatten)]
+    config: ConfigArgs,
 }
 
 fn main() {
-    let matches = Args::parse();
+    let matches: Args = config::parse("time_marker");
 
     let file_name: PathBuf = matches.file;
 
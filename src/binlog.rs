@@ -0,0 +1,775 @@
+//! Compact binary log format with a seek index.
+//!
+//! A log starts with `MAGIC`, a count of interfaces and the name of each one (length byte then
+//! UTF-8). Every frame after that is a 15 byte little-endian record header followed by its data:
+//!
+//! | bytes | field                                   |
+//! |-------|-----------------------------------------|
+//! | 8     | timestamp, nanoseconds since the epoch  |
+//! | 4     | CAN ID without flags                    |
+//! | 1     | `FLAG_*` bits                           |
+//! | 1     | interface number from the header        |
+//! | 1     | data length (requested length for RTR)  |
+//! | n     | data; none for remote frames            |
+//!
+//! CAN FD frames set `FLAG_FD`, and `FLAG_BRS`/`FLAG_ESI` for their FD flags. Their data length
+//! is one of the valid FD lengths, up to 64 bytes.
+//!
+//! Frames the kernel dropped are noted with a `FLAG_DROPPED` marker record in place of a frame: ID
+//! 0, length 8 and the number dropped on the interface as a 64 bit count. The reader skips markers
+//! and adds them up in `Reader::dropped`.
+//!
+//! The index is kept in a sidecar file (see `index_path`) that is rewritten by `Writer::save_index`
+//! and when the log is finished, so a reader can jump to a point in time or to the frames of one ID
+//! without scanning the log. If it's missing or stale, `Index::build` recreates it from the log.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use socketcan::{CanAnyFrame, CanErrorFrame, CanFdFrame, CanFrame, EmbeddedFrame, ExtendedId, Frame, Id, StandardId};
+use socketcan::id::FdFlags;
+
+use super::candump::Record;
+
+pub const MAGIC: [u8; 8] = *b"CANLOG\x00\x01";
+const INDEX_MAGIC: [u8; 8] = *b"CANIDX\x00\x01";
+const RECORD_HEADER_LEN: usize = 15;
+
+pub const FLAG_EXTENDED: u8 = 0x01;
+pub const FLAG_REMOTE: u8 = 0x02;
+pub const FLAG_ERROR: u8 = 0x04;
+pub const FLAG_FD: u8 = 0x08;
+/// Bit rate switch, for FD frames
+pub const FLAG_BRS: u8 = 0x10;
+/// Error state indicator, for FD frames
+pub const FLAG_ESI: u8 = 0x20;
+/// A marker record counting dropped frames rather than a frame
+pub const FLAG_DROPPED: u8 = 0x40;
+const KNOWN_FLAGS: u8 = FLAG_EXTENDED | FLAG_REMOTE | FLAG_ERROR | FLAG_FD | FLAG_BRS | FLAG_ESI;
+
+/// How much log time passes between entries of the time index
+const INDEX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Key for the ID index; extended IDs have bit 31 set so they don't collide with standard ones
+fn id_key(id: u32, extended: bool) -> u32 {
+    if extended { id | 0x8000_0000 } else { id }
+}
+
+/// Name of the index sidecar for a log
+pub fn index_path<P: AsRef<Path>>(log: P) -> PathBuf {
+    let mut name = log.as_ref().as_os_str().to_owned();
+    name.push(".idx");
+    return PathBuf::from(name);
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IdEntry {
+    pub count: u64,
+    pub first_time: Duration,
+    pub first_offset: u64,
+    pub last_time: Duration,
+    pub last_offset: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Index {
+    /// Time of the first record at or after each `INDEX_INTERVAL`, and its offset in the log
+    pub times: Vec<(Duration, u64)>,
+    /// First and last occurrence of each ID, keyed by `id_key`. Error frames aren't included.
+    ids: BTreeMap<u32, IdEntry>,
+}
+
+impl Index {
+    fn add(&mut self, time: Duration, offset: u64, frame: &CanAnyFrame) {
+        match self.times.last() {
+            Some((last, _)) if time < *last + INDEX_INTERVAL => (),
+            _ => self.times.push((time, offset)),
+        }
+        if let CanAnyFrame::Error(_) = frame {
+            return;
+        }
+        let entry = self.ids.entry(id_key(frame.raw_id(), frame.is_extended())).or_insert(IdEntry {
+            count: 0,
+            first_time: time,
+            first_offset: offset,
+            last_time: time,
+            last_offset: offset,
+        });
+        entry.count += 1;
+        entry.last_time = time;
+        entry.last_offset = offset;
+    }
+
+    /// Time of the first frame in the log
+    pub fn start(&self) -> Option<Duration> {
+        return self.times.first().map(|(t, _)| *t);
+    }
+
+    /// Offset of a record at or before the first frame at time `t`
+    pub fn offset_for_time(&self, t: Duration) -> Option<u64> {
+        let after = self.times.partition_point(|(time, _)| *time <= t);
+        return self.times.get(after.saturating_sub(1)).map(|(_, offset)| *offset);
+    }
+
+    pub fn id(&self, id: u32, extended: bool) -> Option<&IdEntry> {
+        return self.ids.get(&id_key(id, extended));
+    }
+
+    /// Every ID in the log with whether it's extended
+    pub fn ids(&self) -> impl Iterator<Item = (u32, bool, &IdEntry)> {
+        return self.ids.iter().map(|(key, entry)| (key & 0x7FFF_FFFF, key & 0x8000_0000 != 0, entry));
+    }
+
+    /// Loads the sidecar index of a log
+    pub fn load<P: AsRef<Path>>(log: P) -> io::Result<Index> {
+        let mut r = BufReader::new(File::open(index_path(log))?);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if magic != INDEX_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a log index"));
+        }
+        let mut index = Index::default();
+        for _ in 0..read_u32(&mut r)? {
+            let time = Duration::from_nanos(read_u64(&mut r)?);
+            index.times.push((time, read_u64(&mut r)?));
+        }
+        for _ in 0..read_u32(&mut r)? {
+            let key = read_u32(&mut r)?;
+            let entry = IdEntry {
+                count: read_u64(&mut r)?,
+                first_time: Duration::from_nanos(read_u64(&mut r)?),
+                first_offset: read_u64(&mut r)?,
+                last_time: Duration::from_nanos(read_u64(&mut r)?),
+                last_offset: read_u64(&mut r)?,
+            };
+            index.ids.insert(key, entry);
+        }
+        return Ok(index);
+    }
+
+    /// Recreates the index by scanning a log, e.g. when the sidecar was lost with the power
+    pub fn build<P: AsRef<Path>>(log: P) -> Result<Index, ReadError> {
+        let mut reader = Reader::open(log)?;
+        let mut index = Index::default();
+        loop {
+            match reader.next() {
+                Some(Ok(record)) => index.add(record.time, reader.frame_offset, &record.frame),
+                Some(Err(ReadError::Truncated { .. })) | None => break,
+                Some(Err(e)) => return Err(e),
+            }
+        }
+        return Ok(index);
+    }
+
+    /// Writes the sidecar index of a log. The old index stays in place until the new one is complete.
+    pub fn save<P: AsRef<Path>>(&self, log: P) -> io::Result<()> {
+        let path = index_path(log);
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut w = BufWriter::new(File::create(&temp)?);
+        w.write_all(&INDEX_MAGIC)?;
+        w.write_all(&(self.times.len() as u32).to_le_bytes())?;
+        for (time, offset) in self.times.iter() {
+            w.write_all(&(time.as_nanos() as u64).to_le_bytes())?;
+            w.write_all(&offset.to_le_bytes())?;
+        }
+        w.write_all(&(self.ids.len() as u32).to_le_bytes())?;
+        for (key, entry) in self.ids.iter() {
+            w.write_all(&key.to_le_bytes())?;
+            w.write_all(&entry.count.to_le_bytes())?;
+            w.write_all(&(entry.first_time.as_nanos() as u64).to_le_bytes())?;
+            w.write_all(&entry.first_offset.to_le_bytes())?;
+            w.write_all(&(entry.last_time.as_nanos() as u64).to_le_bytes())?;
+            w.write_all(&entry.last_offset.to_le_bytes())?;
+        }
+        w.into_inner().map_err(|e| e.into_error())?;
+        return std::fs::rename(temp, path);
+    }
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    return Ok(u32::from_le_bytes(b));
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    return Ok(u64::from_le_bytes(b));
+}
+
+/// Writes binary log records and keeps the index up to date
+pub struct Writer<W: Write> {
+    out: W,
+    path: PathBuf,
+    /// Offset of the next record
+    position: u64,
+    index: Index,
+}
+
+impl<W: Write> Writer<W> {
+    /// Starts a log at `path`, writing the header to `out`
+    pub fn new<P: AsRef<Path>>(mut out: W, path: P, ifaces: &[&str]) -> io::Result<Writer<W>> {
+        let mut header: Vec<u8> = MAGIC.to_vec();
+        header.push(ifaces.len() as u8);
+        for iface in ifaces {
+            header.push(iface.len() as u8);
+            header.extend_from_slice(iface.as_bytes());
+        }
+        out.write_all(&header)?;
+        return Ok(Writer { out, path: path.as_ref().to_path_buf(), position: header.len() as u64, index: Index::default() });
+    }
+
+    /// Appends a frame received on the interface numbered `channel` in the header
+    pub fn write_frame(&mut self, channel: u8, frame: &CanAnyFrame, t: Duration) -> io::Result<usize> {
+        let mut flags: u8 = 0;
+        let id: u32 = match frame {
+            CanAnyFrame::Error(f) => {
+                flags |= FLAG_ERROR;
+                f.error_bits()
+            },
+            _ => frame.raw_id(),
+        };
+        if frame.is_extended() {
+            flags |= FLAG_EXTENDED;
+        }
+        let data: &[u8] = match frame {
+            CanAnyFrame::Remote(_) => {
+                flags |= FLAG_REMOTE;
+                &[]
+            },
+            _ => frame.data(),
+        };
+        if let CanAnyFrame::Fd(f) = frame {
+            flags |= FLAG_FD;
+            if f.is_brs() {
+                flags |= FLAG_BRS;
+            }
+            if f.is_esi() {
+                flags |= FLAG_ESI;
+            }
+        }
+        let mut record = [0u8; RECORD_HEADER_LEN + 64];
+        record[0..8].copy_from_slice(&(t.as_nanos() as u64).to_le_bytes());
+        record[8..12].copy_from_slice(&id.to_le_bytes());
+        record[12] = flags;
+        record[13] = channel;
+        // FD frames store the length rather than the length code
+        record[14] = if flags & FLAG_FD != 0 { data.len() as u8 } else { frame.dlc() as u8 };
+        record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + data.len()].copy_from_slice(data);
+        let len = RECORD_HEADER_LEN + data.len();
+        self.out.write_all(&record[..len])?;
+        self.index.add(t, self.position, frame);
+        self.position += len as u64;
+        return Ok(len);
+    }
+
+    /// Appends a marker for `count` frames dropped on the interface numbered `channel` just before `t`.
+    /// Markers aren't indexed.
+    pub fn write_dropped(&mut self, channel: u8, count: u64, t: Duration) -> io::Result<usize> {
+        let mut record = [0u8; RECORD_HEADER_LEN + 8];
+        record[0..8].copy_from_slice(&(t.as_nanos() as u64).to_le_bytes());
+        record[12] = FLAG_DROPPED;
+        record[13] = channel;
+        record[14] = 8;
+        record[RECORD_HEADER_LEN..].copy_from_slice(&count.to_le_bytes());
+        self.out.write_all(&record)?;
+        self.position += record.len() as u64;
+        return Ok(record.len());
+    }
+
+    /// Flushes the records. The index on disk isn't updated; see `save_index`.
+    pub fn flush(&mut self) -> io::Result<()> {
+        return self.out.flush();
+    }
+
+    /// Rewrites the index with every record written so far. This writes the whole index, so it's
+    /// best left for quiet moments rather than done on every flush.
+    pub fn save_index(&self) -> io::Result<()> {
+        return self.index.save(&self.path);
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush()?;
+        self.save_index()?;
+        return Ok(self.out);
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    /// The data at `offset` isn't a valid record, or the file isn't a binary log
+    Corrupt { offset: u64, message: String },
+    /// The log ends partway through the record at `offset`, e.g. after a power cut. Nothing follows this.
+    Truncated { offset: u64 },
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "{}", e),
+            ReadError::Corrupt { offset, message } => write!(f, "offset {}: {}", offset, message),
+            ReadError::Truncated { offset } => write!(f, "offset {}: log is truncated", offset),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+/// Reads records from a binary log
+pub struct Reader<R> {
+    inner: R,
+    ifaces: Vec<String>,
+    /// Offset of the first record
+    start: u64,
+    /// Offset of the next record
+    position: u64,
+    /// Offset of the last frame read, which comes after any markers before it
+    frame_offset: u64,
+    /// Frames the markers passed so far say were dropped
+    dropped: u64,
+    done: bool,
+}
+
+/// Length of a log up to the end of its last complete record, e.g. to cut off a record torn by a power cut.
+/// 0 if even the header is incomplete.
+pub fn complete_len<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let mut reader = match Reader::open(path) {
+        Ok(r) => r,
+        Err(ReadError::Io(e)) => return Err(e),
+        Err(_) => return Ok(0),
+    };
+    let mut len = reader.position();
+    loop {
+        match reader.next() {
+            Some(Ok(_)) => len = reader.position(),
+            Some(Err(ReadError::Io(e))) => return Err(e),
+            Some(Err(_)) | None => break,
+        }
+    }
+    return Ok(len);
+}
+
+/// Whether a file starts like a binary log, to tell it apart from text logs
+pub fn is_binary<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let mut magic = [0u8; 8];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => return Ok(magic == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    }
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader<BufReader<File>>, ReadError> {
+        return Reader::new(BufReader::new(File::open(path)?));
+    }
+}
+
+impl<R: Read + Seek> Reader<R> {
+    /// Reads the header from the start of `inner`
+    pub fn new(mut inner: R) -> Result<Reader<R>, ReadError> {
+        let not_binary = || ReadError::Corrupt { offset: 0, message: String::from("not a binary log") };
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic).map_err(|_| not_binary())?;
+        if magic != MAGIC {
+            return Err(not_binary());
+        }
+        let mut count = [0u8; 1];
+        inner.read_exact(&mut count).map_err(|_| not_binary())?;
+        let mut ifaces: Vec<String> = Vec::new();
+        for _ in 0..count[0] {
+            let mut len = [0u8; 1];
+            inner.read_exact(&mut len).map_err(|_| not_binary())?;
+            let mut name = vec![0u8; len[0] as usize];
+            inner.read_exact(&mut name).map_err(|_| not_binary())?;
+            ifaces.push(String::from_utf8(name).map_err(|_| not_binary())?);
+        }
+        let start = inner.stream_position()?;
+        return Ok(Reader { inner, ifaces, start, position: start, frame_offset: start, dropped: 0, done: false });
+    }
+
+    /// Interface names, in the order of their numbers
+    pub fn interfaces(&self) -> &[String] {
+        return &self.ifaces;
+    }
+
+    /// Offset of the next record
+    pub fn position(&self) -> u64 {
+        return self.position;
+    }
+
+    /// Frames dropped according to the markers read so far
+    pub fn dropped(&self) -> u64 {
+        return self.dropped;
+    }
+
+    /// Continues reading from the record at `offset`, which must be the start of a record
+    pub fn seek(&mut self, offset: u64) -> io::Result<()> {
+        let offset = offset.max(self.start);
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.position = offset;
+        self.done = false;
+        return Ok(());
+    }
+
+    /// Positions the reader at the first frame at or after time `t`
+    pub fn seek_time(&mut self, index: &Index, t: Duration) -> Result<(), ReadError> {
+        self.seek(index.offset_for_time(t).unwrap_or(self.start))?;
+        loop {
+            let offset = self.position;
+            match self.next() {
+                Some(Ok(record)) if record.time < t => continue,
+                Some(Err(e)) => return Err(e),
+                _ => return Ok(self.seek(offset)?),
+            }
+        }
+    }
+
+    /// Iterates over the frames with one ID, skipping the parts of the log before its first
+    /// occurrence and after its last
+    pub fn frames_with_id<'a>(&'a mut self, index: &Index, id: u32, extended: bool) -> impl Iterator<Item = Result<Record, ReadError>> + 'a {
+        let range = index.id(id, extended).map(|entry| (entry.first_offset, entry.last_offset));
+        let ok = match range {
+            Some((first, _)) => self.seek(first).is_ok(),
+            None => false,
+        };
+        let last = range.map(|(_, last)| last).unwrap_or(0);
+        let mut finished = !ok;
+        return std::iter::from_fn(move || {
+            while !finished {
+                if self.position > last {
+                    finished = true;
+                    break;
+                }
+                match self.next() {
+                    Some(Ok(record)) => {
+                        if record.frame.raw_id() == id && record.frame.is_extended() == extended && !record.frame.is_error_frame() {
+                            return Some(Ok(record));
+                        }
+                    },
+                    Some(Err(e)) => {
+                        finished = true;
+                        return Some(Err(e));
+                    },
+                    None => finished = true,
+                }
+            }
+            return None;
+        });
+    }
+
+    /// Reads the next frame, counting the markers before it
+    fn read_record(&mut self) -> Result<Option<Record>, ReadError> {
+        loop {
+            match self.read_entry()? {
+                Entry::Frame(record) => return Ok(Some(record)),
+                Entry::Dropped(count) => self.dropped += count,
+                Entry::End => return Ok(None),
+            }
+        }
+    }
+
+    fn read_entry(&mut self) -> Result<Entry, ReadError> {
+        let offset = self.position;
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        let mut len = 0;
+        while len < header.len() {
+            match self.inner.read(&mut header[len..]) {
+                Ok(0) if len == 0 => return Ok(Entry::End),
+                Ok(0) => return Err(ReadError::Truncated { offset }),
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let time = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let id = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let flags = header[12];
+        let channel = header[13] as usize;
+        let dlc = header[14] as usize;
+        // A power cut can leave the end of the file filled with zeros
+        if header.iter().all(|b| *b == 0) {
+            return Err(ReadError::Truncated { offset });
+        }
+        let corrupt = |message: String| ReadError::Corrupt { offset, message };
+        if flags == FLAG_DROPPED && dlc == 8 && channel < self.ifaces.len() {
+            let mut count = [0u8; 8];
+            if let Err(e) = self.inner.read_exact(&mut count) {
+                if e.kind() == io::ErrorKind::UnexpectedEof {
+                    return Err(ReadError::Truncated { offset });
+                }
+                return Err(e.into());
+            }
+            self.position += (RECORD_HEADER_LEN + 8) as u64;
+            return Ok(Entry::Dropped(u64::from_le_bytes(count)));
+        }
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(corrupt(format!("unknown flags {:02X}", flags)));
+        }
+        let iface = self.ifaces.get(channel).ok_or_else(|| corrupt(format!("unknown interface {}", channel)))?.clone();
+        let data_len = if flags & FLAG_REMOTE != 0 { 0 } else { dlc };
+        if flags & FLAG_FD != 0 && (flags & (FLAG_REMOTE | FLAG_ERROR) != 0 || !CanFdFrame::is_valid_data_len(dlc)) {
+            return Err(corrupt(format!("invalid CAN FD frame, flags {:02X} length {}", flags, dlc)));
+        }
+        if flags & FLAG_FD == 0 && dlc > 8 {
+            return Err(corrupt(format!("invalid length {}", dlc)));
+        }
+        let mut data = [0u8; 64];
+        if let Err(e) = self.inner.read_exact(&mut data[..data_len]) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return Err(ReadError::Truncated { offset });
+            }
+            return Err(e.into());
+        }
+        let data = &data[..data_len];
+        let frame: CanAnyFrame = if flags & FLAG_ERROR != 0 {
+            CanAnyFrame::Error(CanErrorFrame::new_error(id, data).map_err(|e| corrupt(e.to_string()))?)
+        } else {
+            let can_id: Id = if flags & FLAG_EXTENDED != 0 {
+                ExtendedId::new(id).ok_or_else(|| corrupt(format!("invalid ID {:X}", id)))?.into()
+            } else {
+                StandardId::new(id as u16).filter(|_| id <= 0x7FF).ok_or_else(|| corrupt(format!("invalid ID {:X}", id)))?.into()
+            };
+            let frame = if flags & FLAG_FD != 0 {
+                let mut fd_flags = FdFlags::empty();
+                fd_flags.set(FdFlags::BRS, flags & FLAG_BRS != 0);
+                fd_flags.set(FdFlags::ESI, flags & FLAG_ESI != 0);
+                CanFdFrame::with_flags(can_id, data, fd_flags).map(CanAnyFrame::Fd)
+            } else if flags & FLAG_REMOTE != 0 {
+                CanFrame::new_remote(can_id, dlc).map(CanAnyFrame::from)
+            } else {
+                CanFrame::new(can_id, data).map(CanAnyFrame::from)
+            };
+            frame.ok_or_else(|| corrupt(String::from("invalid frame")))?
+        };
+        self.frame_offset = offset;
+        self.position += (RECORD_HEADER_LEN + data_len) as u64;
+        return Ok(Entry::Frame(Record { time: Duration::from_nanos(time), iface, frame }));
+    }
+}
+
+/// What a record holds
+enum Entry {
+    Frame(Record),
+    /// A marker with the number of frames dropped
+    Dropped(u64),
+    End,
+}
+
+impl<R: Read + Seek> Iterator for Reader<R> {
+    type Item = Result<Record, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: Duration = Duration::from_secs(1714566600);
+
+    /// A quarter second apart, so the time index gets an entry every fourth frame
+    fn frames() -> Vec<(u8, CanAnyFrame, Duration)> {
+        let mut frames: Vec<(u8, CanAnyFrame, Duration)> = Vec::new();
+        for i in 0..24u32 {
+            let t = START + Duration::from_millis(250) * i;
+            let frame: CanAnyFrame = match i % 6 {
+                0 => CanFrame::new(StandardId::new(0x465).unwrap(), &[i as u8, 1, 2, 3, 4, 5, 6, 7]).unwrap().into(),
+                1 => CanFrame::new(ExtendedId::new(0x123).unwrap(), &[i as u8]).unwrap().into(),
+                2 => CanFrame::new_remote(StandardId::new(0x123).unwrap(), 4).unwrap().into(),
+                3 => CanAnyFrame::Error(CanErrorFrame::new_error(0x04, &[0, 0x08, 0, 0, 0, 0, 0, 0]).unwrap()),
+                4 => CanAnyFrame::Fd(CanFdFrame::with_flags(StandardId::new(0x7FF).unwrap(), &[i as u8; 12], FdFlags::BRS).unwrap()),
+                _ => CanFrame::new(StandardId::new(0x465).unwrap(), &[]).unwrap().into(),
+            };
+            frames.push(((i % 2) as u8, frame, t));
+        }
+        return frames;
+    }
+
+    fn write_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("car_logger_{}_{}.bin", name, std::process::id()));
+        let mut writer = Writer::new(BufWriter::new(File::create(&path).unwrap()), &path, &["can0", "can1"]).unwrap();
+        for (channel, frame, t) in frames() {
+            writer.write_frame(channel, &frame, t).unwrap();
+        }
+        writer.finish().unwrap();
+        return path;
+    }
+
+    fn remove_log(path: &Path) {
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(index_path(path)).unwrap();
+    }
+
+    fn assert_same(record: &Record, expected: &(u8, CanAnyFrame, Duration)) {
+        let (channel, frame, t) = expected;
+        assert_eq!(record.time, *t);
+        assert_eq!(record.iface, ["can0", "can1"][*channel as usize]);
+        assert_eq!(format!("{:?}", record.frame), format!("{:?}", frame));
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let path = write_log("round_trip");
+        assert!(is_binary(&path).unwrap());
+        assert!(!is_binary(index_path(&path)).unwrap());
+
+        let reader = Reader::open(&path).unwrap();
+        assert_eq!(reader.interfaces(), &["can0", "can1"]);
+        let records: Vec<Record> = reader.map(|r| r.unwrap()).collect();
+        let expected = frames();
+        assert_eq!(records.len(), expected.len());
+        for (record, expected) in records.iter().zip(expected.iter()) {
+            assert_same(record, expected);
+        }
+        remove_log(&path);
+    }
+
+    #[test]
+    fn saved_index_matches_a_rebuilt_one() {
+        let path = write_log("index");
+        let saved = Index::load(&path).unwrap();
+        let built = Index::build(&path).unwrap();
+        assert_eq!(saved.times, built.times);
+        assert_eq!(saved.times.len(), 6);
+        assert_eq!(saved.start(), Some(START));
+        let ids = |index: &Index| index.ids().map(|(id, extended, e)| (id, extended, e.count, e.first_time, e.first_offset, e.last_time, e.last_offset)).collect::<Vec<_>>();
+        assert_eq!(ids(&saved), ids(&built));
+
+        // Standard and extended IDs with the same number are kept apart, and error frames are left out
+        assert_eq!(saved.ids().map(|(id, extended, _)| (id, extended)).collect::<Vec<_>>(), [(0x123, false), (0x465, false), (0x7FF, false), (0x123, true)]);
+        assert_eq!(saved.id(0x465, false).unwrap().count, 8);
+        assert_eq!(saved.id(0x123, true).unwrap().first_time, START + Duration::from_millis(250));
+        assert_eq!(saved.id(0x123, true).unwrap().last_time, START + Duration::from_millis(250 * 19));
+        remove_log(&path);
+    }
+
+    #[test]
+    fn flushing_leaves_the_index_for_save_index() {
+        let path = std::env::temp_dir().join(format!("car_logger_flush_{}.bin", std::process::id()));
+        let mut writer = Writer::new(BufWriter::new(File::create(&path).unwrap()), &path, &["can0", "can1"]).unwrap();
+        for (channel, frame, t) in frames() {
+            writer.write_frame(channel, &frame, t).unwrap();
+            writer.flush().unwrap();
+        }
+        assert!(!index_path(&path).exists());
+        assert_eq!(Reader::open(&path).unwrap().count(), frames().len());
+
+        writer.save_index().unwrap();
+        assert_eq!(Index::load(&path).unwrap().times, Index::build(&path).unwrap().times);
+        drop(writer);
+        remove_log(&path);
+    }
+
+    #[test]
+    fn dropped_markers_are_counted_not_read_as_frames() {
+        let path = std::env::temp_dir().join(format!("car_logger_dropped_markers_{}.bin", std::process::id()));
+        let mut writer = Writer::new(BufWriter::new(File::create(&path).unwrap()), &path, &["can0", "can1"]).unwrap();
+        for (n, (channel, frame, t)) in frames().into_iter().enumerate() {
+            if n % 5 == 0 {
+                assert_eq!(writer.write_dropped(channel, n as u64 + 1, t).unwrap(), RECORD_HEADER_LEN + 8);
+            }
+            writer.write_frame(channel, &frame, t).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = Reader::open(&path).unwrap();
+        let records: Vec<Record> = reader.by_ref().map(|r| r.unwrap()).collect();
+        for (record, expected) in records.iter().zip(frames().iter()) {
+            assert_same(record, expected);
+        }
+        assert_eq!(records.len(), frames().len());
+        assert_eq!(reader.dropped(), 1 + 6 + 11 + 16 + 21);
+        // Markers aren't indexed, and seeking still lands on frames
+        let index = Index::load(&path).unwrap();
+        assert_eq!(index.times, Index::build(&path).unwrap().times);
+        reader.seek_time(&index, START + Duration::from_millis(1250)).unwrap();
+        assert_same(&reader.next().unwrap().unwrap(), &frames()[5]);
+        remove_log(&path);
+    }
+
+    #[test]
+    fn seek_time_finds_the_first_frame_at_or_after() {
+        let path = write_log("seek_time");
+        let index = Index::load(&path).unwrap();
+        let expected = frames();
+        let mut reader = Reader::open(&path).unwrap();
+        for ms in [0, 1, 250, 999, 1000, 1100, 3750, 5750] {
+            let t = START + Duration::from_millis(ms);
+            reader.seek_time(&index, t).unwrap();
+            let first = expected.iter().position(|(_, _, time)| *time >= t).unwrap();
+            assert_same(&reader.next().unwrap().unwrap(), &expected[first]);
+        }
+        // Before the log it starts at the beginning, and past the end there's nothing
+        reader.seek_time(&index, START - Duration::from_secs(10)).unwrap();
+        assert_same(&reader.next().unwrap().unwrap(), &expected[0]);
+        reader.seek_time(&index, START + Duration::from_secs(10)).unwrap();
+        assert!(reader.next().is_none());
+        remove_log(&path);
+    }
+
+    #[test]
+    fn frames_with_id_reads_only_that_id() {
+        let path = write_log("frames_with_id");
+        let index = Index::load(&path).unwrap();
+        let mut reader = Reader::open(&path).unwrap();
+        let extended: Vec<Record> = reader.frames_with_id(&index, 0x123, true).map(|r| r.unwrap()).collect();
+        assert_eq!(extended.len(), 4);
+        assert!(extended.iter().all(|r| r.frame.is_extended() && r.frame.raw_id() == 0x123));
+        let remote: Vec<Record> = reader.frames_with_id(&index, 0x123, false).map(|r| r.unwrap()).collect();
+        assert_eq!(remote.len(), 4);
+        assert!(remote.iter().all(|r| r.frame.is_remote_frame()));
+        assert_eq!(reader.frames_with_id(&index, 0x124, false).count(), 0);
+        remove_log(&path);
+    }
+
+    #[test]
+    fn torn_records_end_the_log() {
+        let path = write_log("torn");
+        let full = std::fs::metadata(&path).unwrap().len();
+        let last_offset = Index::load(&path).unwrap().id(0x465, false).unwrap().last_offset;
+        // The last frame is an empty 0x465; cut its header short
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full - 4).unwrap();
+        drop(file);
+
+        let results: Vec<Result<Record, ReadError>> = Reader::open(&path).unwrap().collect();
+        assert_eq!(results.len(), frames().len());
+        match results.last().unwrap() {
+            Err(ReadError::Truncated { offset }) => assert_eq!(*offset, last_offset),
+            other => panic!("expected a truncated log, got {:?}", other),
+        }
+        assert_eq!(complete_len(&path).unwrap(), last_offset);
+        assert_eq!(Index::build(&path).unwrap().id(0x465, false).unwrap().count, 7);
+        remove_log(&path);
+    }
+}
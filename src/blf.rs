@@ -0,0 +1,718 @@
+//! Vector binary logging format (BLF), as read and written by CANalyzer and CANoe.
+//!
+//! A BLF file is a 144 byte header followed by `LOG_CONTAINER` objects, each holding a zlib
+//! compressed run of frame objects. Objects may be split across containers. Frame times are
+//! stored in nanoseconds from the measurement start in the header, which is the time of the first
+//! frame in whole milliseconds. Like ASC, the header dates are UTC.
+//!
+//! Error frames are written as `CAN_ERROR_EXT` objects with the error class in the ID field, so
+//! they convert back to the same frame. CAN FD frames are written as `CAN_FD_MESSAGE` objects, and
+//! both `CAN_FD_MESSAGE` and `CAN_FD_MESSAGE_64` objects are read. Notes like dropped frames are
+//! `APP_TEXT` comment objects, which the reader collects apart from the frames.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Utc};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use libc::CAN_ERR_MASK;
+use socketcan::{CanAnyFrame, CanErrorFrame, CanFdFrame, CanFrame, EmbeddedFrame, ExtendedId, Frame, Id, StandardId};
+use socketcan::id::FdFlags;
+
+use super::candump::Record;
+use super::channels::ChannelMap;
+
+const FILE_SIGNATURE: [u8; 4] = *b"LOGG";
+const OBJECT_SIGNATURE: [u8; 4] = *b"LOBJ";
+const FILE_HEADER_SIZE: usize = 144;
+const OBJECT_BASE_SIZE: usize = 16;
+/// Base header plus the version 1 header with flags and timestamp
+const OBJECT_HEADER_SIZE: usize = 32;
+const CONTAINER_HEADER_SIZE: usize = 16;
+/// Uncompressed size of each container, as written by Vector tools
+const CONTAINER_SIZE: usize = 128 * 1024;
+
+const CAN_MESSAGE: u32 = 1;
+const CAN_ERROR: u32 = 2;
+const LOG_CONTAINER: u32 = 10;
+const APP_TEXT: u32 = 65;
+const CAN_ERROR_EXT: u32 = 73;
+const CAN_MESSAGE2: u32 = 86;
+const CAN_FD_MESSAGE: u32 = 100;
+const CAN_FD_MESSAGE_64: u32 = 101;
+
+const TIME_TEN_MICS: u32 = 1;
+const TIME_ONE_NANS: u32 = 2;
+const NO_COMPRESSION: u16 = 0;
+const ZLIB_DEFLATE: u16 = 2;
+/// `APP_TEXT` source for a measurement comment
+const APP_TEXT_COMMENT: u32 = 0;
+
+const CAN_MSG_EXT: u32 = 0x8000_0000;
+const REMOTE_FLAG: u8 = 0x80;
+/// `CAN_FD_MESSAGE` FD flags
+const FD_EDL: u8 = 0x01;
+const FD_BRS: u8 = 0x02;
+const FD_ESI: u8 = 0x04;
+/// `CAN_FD_MESSAGE_64` flags
+const FD64_REMOTE: u32 = 0x0010;
+const FD64_EDL: u32 = 0x1000;
+const FD64_BRS: u32 = 0x2000;
+const FD64_ESI: u32 = 0x4000;
+
+/// Packs a time into a Windows SYSTEMTIME
+fn system_time(t: Duration) -> [u8; 16] {
+    let date: DateTime<Utc> = DateTime::from_timestamp(t.as_secs() as i64, t.subsec_nanos()).unwrap_or_default();
+    let fields: [u16; 8] = [
+        date.year() as u16,
+        date.month() as u16,
+        date.weekday().num_days_from_sunday() as u16,
+        date.day() as u16,
+        date.hour() as u16,
+        date.minute() as u16,
+        date.second() as u16,
+        (date.nanosecond() / 1_000_000) as u16,
+    ];
+    let mut bytes = [0u8; 16];
+    for (i, field) in fields.iter().enumerate() {
+        bytes[i * 2..i * 2 + 2].copy_from_slice(&field.to_le_bytes());
+    }
+    return bytes;
+}
+
+fn parse_system_time(bytes: &[u8]) -> Option<Duration> {
+    let field = |i: usize| u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]) as u32;
+    let date = NaiveDate::from_ymd_opt(field(0) as i32, field(1), field(3))?
+        .and_hms_milli_opt(field(4), field(5), field(6), field(7))?
+        .and_utc();
+    return Some(Duration::new(date.timestamp().max(0) as u64, date.timestamp_subsec_nanos()));
+}
+
+/// Converts a BLF ID, with `CAN_MSG_EXT` set for extended IDs
+fn parse_id(raw_id: u32) -> Result<Id, String> {
+    if raw_id & CAN_MSG_EXT != 0 {
+        return Ok(ExtendedId::new(raw_id & !CAN_MSG_EXT).ok_or_else(|| format!("invalid ID {:X}", raw_id))?.into());
+    }
+    return Ok(StandardId::new(raw_id as u16).filter(|_| raw_id <= 0x7FF).ok_or_else(|| format!("invalid ID {:X}", raw_id))?.into());
+}
+
+fn fd_frame(id: Id, data: &[u8], brs: bool, esi: bool) -> Result<CanAnyFrame, String> {
+    if !CanFdFrame::is_valid_data_len(data.len()) {
+        return Err(format!("invalid CAN FD data length {}", data.len()));
+    }
+    let mut flags = FdFlags::empty();
+    flags.set(FdFlags::BRS, brs);
+    flags.set(FdFlags::ESI, esi);
+    return CanFdFrame::with_flags(id, data, flags).map(CanAnyFrame::Fd).ok_or_else(|| String::from("invalid CAN FD frame"));
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap());
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    return u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+}
+
+/// Writes frames as BLF objects. The header is rewritten on every flush, so a file cut short
+/// by a power loss is readable up to the last flush.
+pub struct Writer<W: Write + Seek> {
+    out: W,
+    channels: ChannelMap,
+    /// Objects not written to a container yet
+    buf: Vec<u8>,
+    start: Option<Duration>,
+    stop: Duration,
+    objects: u32,
+    uncompressed_size: u64,
+}
+
+impl<W: Write + Seek> Writer<W> {
+    pub fn new(mut out: W, channels: ChannelMap) -> io::Result<Writer<W>> {
+        out.write_all(&[0u8; FILE_HEADER_SIZE])?;
+        let mut writer = Writer { out, channels, buf: Vec::new(), start: None, stop: Duration::ZERO, objects: 0, uncompressed_size: FILE_HEADER_SIZE as u64 };
+        writer.write_header()?;
+        return Ok(writer);
+    }
+
+    pub fn write_frame(&mut self, iface: &str, frame: &CanAnyFrame, t: Duration) -> io::Result<usize> {
+        let channel = self.channels.channel(iface);
+        let mut body: Vec<u8> = Vec::with_capacity(84);
+        let id = if frame.is_extended() { frame.raw_id() | CAN_MSG_EXT } else { frame.raw_id() };
+        let object_type = match frame {
+            CanAnyFrame::Error(_) => {
+                let data = frame.data();
+                body.extend_from_slice(&channel.to_le_bytes());
+                // Length in bits, flags, ECC, position; unknown
+                body.extend_from_slice(&[0u8; 8]);
+                body.push(data.len() as u8);
+                body.push(0);
+                // Frame length in nanoseconds
+                body.extend_from_slice(&0u32.to_le_bytes());
+                body.extend_from_slice(&(frame.id_word() & CAN_ERR_MASK).to_le_bytes());
+                // Extended flags and reserved
+                body.extend_from_slice(&[0u8; 4]);
+                let mut padded = [0u8; 8];
+                padded[..data.len()].copy_from_slice(data);
+                body.extend_from_slice(&padded);
+                CAN_ERROR_EXT
+            },
+            CanAnyFrame::Fd(f) => {
+                body.extend_from_slice(&channel.to_le_bytes());
+                body.push(0);
+                body.push(f.dlc() as u8);
+                body.extend_from_slice(&id.to_le_bytes());
+                // Frame length in nanoseconds and bit count; unknown
+                body.extend_from_slice(&[0u8; 5]);
+                let mut fd_flags = FD_EDL;
+                if f.is_brs() {
+                    fd_flags |= FD_BRS;
+                }
+                if f.is_esi() {
+                    fd_flags |= FD_ESI;
+                }
+                body.push(fd_flags);
+                body.push(f.data().len() as u8);
+                body.extend_from_slice(&[0u8; 5]);
+                let mut padded = [0u8; 64];
+                padded[..f.data().len()].copy_from_slice(f.data());
+                body.extend_from_slice(&padded);
+                CAN_FD_MESSAGE
+            },
+            _ => {
+                body.extend_from_slice(&channel.to_le_bytes());
+                body.push(if frame.is_remote_frame() { REMOTE_FLAG } else { 0 });
+                body.push(frame.dlc() as u8);
+                body.extend_from_slice(&id.to_le_bytes());
+                let mut padded = [0u8; 8];
+                if !frame.is_remote_frame() {
+                    padded[..frame.data().len()].copy_from_slice(frame.data());
+                }
+                body.extend_from_slice(&padded);
+                CAN_MESSAGE
+            },
+        };
+        return self.write_object(object_type, t, &body);
+    }
+
+    /// Writes a comment at `t` as an `APP_TEXT` object
+    pub fn write_comment(&mut self, t: Duration, text: &str) -> io::Result<usize> {
+        let mut body: Vec<u8> = Vec::with_capacity(16 + text.len());
+        body.extend_from_slice(&APP_TEXT_COMMENT.to_le_bytes());
+        body.extend_from_slice(&[0u8; 4]);
+        body.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        body.extend_from_slice(&[0u8; 4]);
+        body.extend_from_slice(text.as_bytes());
+        return self.write_object(APP_TEXT, t, &body);
+    }
+
+    fn write_object(&mut self, object_type: u32, t: Duration, body: &[u8]) -> io::Result<usize> {
+        let start = *self.start.get_or_insert(Duration::from_millis(t.as_millis() as u64));
+        self.stop = self.stop.max(t);
+        let size = OBJECT_HEADER_SIZE + body.len();
+        self.buf.extend_from_slice(&OBJECT_SIGNATURE);
+        self.buf.extend_from_slice(&(OBJECT_HEADER_SIZE as u16).to_le_bytes());
+        self.buf.extend_from_slice(&1u16.to_le_bytes());
+        self.buf.extend_from_slice(&(size as u32).to_le_bytes());
+        self.buf.extend_from_slice(&object_type.to_le_bytes());
+        self.buf.extend_from_slice(&TIME_ONE_NANS.to_le_bytes());
+        // Client index and object version
+        self.buf.extend_from_slice(&[0u8; 4]);
+        self.buf.extend_from_slice(&(t.saturating_sub(start).as_nanos() as u64).to_le_bytes());
+        self.buf.extend_from_slice(body);
+        self.buf.resize(self.buf.len() + size % 4, 0);
+        self.objects += 1;
+        if self.buf.len() >= CONTAINER_SIZE {
+            self.write_containers(false)?;
+        }
+        return Ok(size + size % 4);
+    }
+
+    /// Compresses buffered objects into containers of `CONTAINER_SIZE`, and the remainder too if `all` is set
+    fn write_containers(&mut self, all: bool) -> io::Result<()> {
+        let mut done = 0;
+        while self.buf.len() - done >= CONTAINER_SIZE || (all && done < self.buf.len()) {
+            let chunk = &self.buf[done..(done + CONTAINER_SIZE).min(self.buf.len())];
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(chunk)?;
+            let data = encoder.finish()?;
+            let size = OBJECT_BASE_SIZE + CONTAINER_HEADER_SIZE + data.len();
+            let mut header: Vec<u8> = Vec::with_capacity(OBJECT_BASE_SIZE + CONTAINER_HEADER_SIZE);
+            header.extend_from_slice(&OBJECT_SIGNATURE);
+            header.extend_from_slice(&(OBJECT_BASE_SIZE as u16).to_le_bytes());
+            header.extend_from_slice(&1u16.to_le_bytes());
+            header.extend_from_slice(&(size as u32).to_le_bytes());
+            header.extend_from_slice(&LOG_CONTAINER.to_le_bytes());
+            header.extend_from_slice(&ZLIB_DEFLATE.to_le_bytes());
+            header.extend_from_slice(&[0u8; 6]);
+            header.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            header.extend_from_slice(&[0u8; 4]);
+            self.out.write_all(&header)?;
+            self.out.write_all(&data)?;
+            self.out.write_all(&[0u8; 3][..size % 4])?;
+            self.uncompressed_size += (OBJECT_BASE_SIZE + CONTAINER_HEADER_SIZE + chunk.len()) as u64;
+            done += chunk.len();
+        }
+        self.buf.drain(..done);
+        return Ok(());
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let end = self.out.stream_position()?;
+        let mut header = [0u8; FILE_HEADER_SIZE];
+        header[0..4].copy_from_slice(&FILE_SIGNATURE);
+        header[4..8].copy_from_slice(&(FILE_HEADER_SIZE as u32).to_le_bytes());
+        // Application ID and version, then the BLF version
+        header[8..16].copy_from_slice(&[5, 0, 0, 0, 2, 6, 8, 1]);
+        header[16..24].copy_from_slice(&end.to_le_bytes());
+        header[24..32].copy_from_slice(&self.uncompressed_size.to_le_bytes());
+        header[32..36].copy_from_slice(&self.objects.to_le_bytes());
+        if let Some(start) = self.start {
+            header[40..56].copy_from_slice(&system_time(start));
+            header[56..72].copy_from_slice(&system_time(self.stop));
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header)?;
+        self.out.seek(SeekFrom::Start(end))?;
+        return Ok(());
+    }
+
+    /// Writes all buffered frames in a container and updates the header
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_containers(true)?;
+        self.write_header()?;
+        return self.out.flush();
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush()?;
+        return Ok(self.out);
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    /// An object that couldn't be decoded. Reading can continue with the next object.
+    Malformed { object: u64, message: String },
+    /// The file isn't a BLF log or its structure is damaged. Nothing follows this.
+    Corrupt { offset: u64, message: String },
+    /// The log ends partway through a container, e.g. after a power cut. Nothing follows this.
+    Truncated { offset: u64 },
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "{}", e),
+            ReadError::Malformed { object, message } => write!(f, "object {}: {}", object, message),
+            ReadError::Corrupt { offset, message } => write!(f, "offset {}: {}", offset, message),
+            ReadError::Truncated { offset } => write!(f, "offset {}: log is truncated", offset),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+/// Reads until `buf` is full or the end of the input, returning how much was read
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(len);
+}
+
+/// Streams CAN frames from a BLF log. Objects other than classic CAN frames and errors are skipped.
+pub struct Reader<R> {
+    inner: R,
+    channels: ChannelMap,
+    start: Duration,
+    /// Uncompressed objects from the containers read so far
+    data: Vec<u8>,
+    pos: usize,
+    /// File offset of the next container
+    offset: u64,
+    /// Length of the file, when it's known
+    len: Option<u64>,
+    objects: u64,
+    /// Time and text of the comments read so far
+    comments: Vec<(Duration, String)>,
+    done: bool,
+}
+
+/// Length of a log up to the end of its last complete object, e.g. to cut off a container torn by a power cut.
+/// 0 if even the file header is incomplete.
+pub fn complete_len<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    if read_full(&mut file, &mut header)? != FILE_HEADER_SIZE || header[0..4] != FILE_SIGNATURE {
+        return Ok(0);
+    }
+    let mut len = FILE_HEADER_SIZE as u64;
+    let mut object = [0u8; OBJECT_BASE_SIZE];
+    while read_full(&mut file, &mut object)? == OBJECT_BASE_SIZE && object[0..4] == OBJECT_SIGNATURE {
+        let size = u32_at(&object, 8) as usize;
+        if size < OBJECT_BASE_SIZE {
+            break;
+        }
+        // The rest of the object and its padding
+        let rest = (size - OBJECT_BASE_SIZE + size % 4) as u64;
+        if io::copy(&mut (&mut file).take(rest), &mut io::sink())? != rest {
+            break;
+        }
+        len += rest + OBJECT_BASE_SIZE as u64;
+    }
+    return Ok(len);
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P, channels: ChannelMap) -> Result<Reader<BufReader<File>>, ReadError> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut reader = Reader::new(BufReader::new(file), channels)?;
+        reader.len = Some(len);
+        return Ok(reader);
+    }
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the file header from the start of `inner`
+    pub fn new(mut inner: R, channels: ChannelMap) -> Result<Reader<R>, ReadError> {
+        let not_blf = || ReadError::Corrupt { offset: 0, message: String::from("not a BLF log") };
+        let mut header = vec![0u8; 8];
+        if read_full(&mut inner, &mut header)? != header.len() || header[0..4] != FILE_SIGNATURE {
+            return Err(not_blf());
+        }
+        let size = u32_at(&header, 4) as usize;
+        if size < 72 {
+            return Err(not_blf());
+        }
+        header.resize(size, 0);
+        if read_full(&mut inner, &mut header[8..])? != size - 8 {
+            return Err(ReadError::Truncated { offset: 0 });
+        }
+        let start = parse_system_time(&header[40..56]).unwrap_or_default();
+        return Ok(Reader { inner, channels, start, data: Vec::new(), pos: 0, offset: size as u64, len: None, objects: 0, comments: Vec::new(), done: false });
+    }
+
+    /// Time of the measurement start from the header
+    pub fn start(&self) -> Duration {
+        return self.start;
+    }
+
+    /// Comments passed so far, e.g. notes of dropped frames
+    pub fn comments(&self) -> &[(Duration, String)] {
+        return &self.comments;
+    }
+
+    /// Reads the next top level object, adding its frames to `data`. Returns false at the end of the file.
+    fn read_container(&mut self) -> Result<bool, ReadError> {
+        let offset = self.offset;
+        let mut header = [0u8; OBJECT_BASE_SIZE];
+        match read_full(&mut self.inner, &mut header)? {
+            0 => return Ok(false),
+            OBJECT_BASE_SIZE => (),
+            _ => return Err(ReadError::Truncated { offset }),
+        }
+        if header[0..4] != OBJECT_SIGNATURE {
+            // A power cut can leave the end of the file filled with zeros
+            if header.iter().all(|b| *b == 0) {
+                return Err(ReadError::Truncated { offset });
+            }
+            return Err(ReadError::Corrupt { offset, message: String::from("missing object signature") });
+        }
+        let size = u32_at(&header, 8) as usize;
+        if size < OBJECT_BASE_SIZE {
+            return Err(ReadError::Corrupt { offset, message: format!("invalid object size {}", size) });
+        }
+        // A torn or corrupt size can claim gigabytes, so it's checked against the file before reading,
+        // and the body only grows as far as there's data when the file's length isn't known
+        if self.len.is_some_and(|len| offset + size as u64 > len) {
+            return Err(ReadError::Truncated { offset });
+        }
+        let mut body = Vec::new();
+        let rest = (size - OBJECT_BASE_SIZE) as u64;
+        if (&mut self.inner).take(rest).read_to_end(&mut body)? as u64 != rest {
+            return Err(ReadError::Truncated { offset });
+        }
+        let mut padding = [0u8; 3];
+        let padding_len = read_full(&mut self.inner, &mut padding[..size % 4])?;
+        self.offset += (size + padding_len) as u64;
+        // Drop the objects that have been read so there's only the start of a split one left
+        self.data.drain(..self.pos);
+        self.pos = 0;
+        if u32_at(&header, 12) != LOG_CONTAINER {
+            // An object outside of a container, as in very old files
+            self.data.extend_from_slice(&header);
+            self.data.extend_from_slice(&body);
+            return Ok(true);
+        }
+        if body.len() < CONTAINER_HEADER_SIZE {
+            return Err(ReadError::Corrupt { offset, message: String::from("container is too short") });
+        }
+        let contents = &body[CONTAINER_HEADER_SIZE..];
+        match u16_at(&body, 0) {
+            NO_COMPRESSION => self.data.extend_from_slice(contents),
+            ZLIB_DEFLATE => {
+                if let Err(e) = ZlibDecoder::new(contents).read_to_end(&mut self.data) {
+                    return Err(ReadError::Corrupt { offset, message: format!("container doesn't decompress: {}", e) });
+                }
+            },
+            method => return Err(ReadError::Corrupt { offset, message: format!("unknown compression method {}", method) }),
+        }
+        return Ok(true);
+    }
+
+    /// Decodes the object at the start of `object`, if it's a frame. Comments are kept in `comments`.
+    fn decode(&mut self, object: &[u8]) -> Result<Option<Record>, String> {
+        let header_size = u16_at(object, 4) as usize;
+        let object_type = u32_at(object, 12);
+        if header_size < OBJECT_HEADER_SIZE || header_size > object.len() {
+            return Err(format!("invalid header size {}", header_size));
+        }
+        let body = &object[header_size..];
+        let time = match u32_at(object, 16) {
+            TIME_TEN_MICS => Duration::from_micros(u64_at(object, 24) * 10),
+            TIME_ONE_NANS => Duration::from_nanos(u64_at(object, 24)),
+            flags => return Err(format!("unknown timestamp flags {}", flags)),
+        };
+        let time = self.start + time;
+        let too_short = || format!("object type {} is too short", object_type);
+        let frame: CanAnyFrame = match object_type {
+            CAN_MESSAGE | CAN_MESSAGE2 => {
+                if body.len() < 16 {
+                    return Err(too_short());
+                }
+                let flags = body[2];
+                let dlc = body[3] as usize;
+                let id = parse_id(u32_at(body, 4))?;
+                if flags & REMOTE_FLAG != 0 {
+                    CanFrame::new_remote(id, dlc.min(8)).ok_or("invalid remote frame")?.into()
+                } else {
+                    CanFrame::new(id, &body[8..8 + dlc.min(8)]).ok_or("invalid data frame")?.into()
+                }
+            },
+            CAN_FD_MESSAGE => {
+                if body.len() < 84 {
+                    return Err(too_short());
+                }
+                let flags = body[2];
+                let dlc = body[3] as usize;
+                let id = parse_id(u32_at(body, 4))?;
+                let fd_flags = body[13];
+                let len = (body[14] as usize).min(64);
+                if fd_flags & FD_EDL != 0 {
+                    fd_frame(id, &body[20..20 + len], fd_flags & FD_BRS != 0, fd_flags & FD_ESI != 0)?
+                } else if flags & REMOTE_FLAG != 0 {
+                    CanFrame::new_remote(id, dlc.min(8)).ok_or("invalid remote frame")?.into()
+                } else {
+                    CanFrame::new(id, &body[20..20 + len.min(8)]).ok_or("invalid data frame")?.into()
+                }
+            },
+            CAN_FD_MESSAGE_64 => {
+                if body.len() < 40 {
+                    return Err(too_short());
+                }
+                let dlc = body[1] as usize;
+                let len = body[2] as usize;
+                if body.len() < 40 + len {
+                    return Err(too_short());
+                }
+                let id = parse_id(u32_at(body, 4))?;
+                let flags = u32_at(body, 12);
+                let data = &body[40..40 + len];
+                // The channel is a single byte in this object
+                let iface = self.channels.iface(body[0] as u16);
+                let frame: CanAnyFrame = if flags & FD64_EDL != 0 {
+                    fd_frame(id, data, flags & FD64_BRS != 0, flags & FD64_ESI != 0)?
+                } else if flags & FD64_REMOTE != 0 {
+                    CanFrame::new_remote(id, dlc.min(8)).ok_or("invalid remote frame")?.into()
+                } else {
+                    CanFrame::new(id, &data[..len.min(8)]).ok_or("invalid data frame")?.into()
+                };
+                return Ok(Some(Record { time, iface, frame }));
+            },
+            CAN_ERROR_EXT => {
+                if body.len() < 32 {
+                    return Err(too_short());
+                }
+                let dlc = (body[10] as usize).min(8);
+                CanAnyFrame::Error(CanErrorFrame::new_error(u32_at(body, 16) & CAN_ERR_MASK, &body[24..24 + dlc]).map_err(|e| e.to_string())?)
+            },
+            CAN_ERROR => {
+                if body.len() < 2 {
+                    return Err(too_short());
+                }
+                CanAnyFrame::Error(CanErrorFrame::new_error(0, &[]).map_err(|e| e.to_string())?)
+            },
+            APP_TEXT => {
+                if body.len() < 16 || body.len() < 16 + u32_at(body, 8) as usize {
+                    return Err(too_short());
+                }
+                if u32_at(body, 0) == APP_TEXT_COMMENT {
+                    let text = &body[16..16 + u32_at(body, 8) as usize];
+                    self.comments.push((time, String::from_utf8_lossy(text).into_owned()));
+                }
+                return Ok(None);
+            },
+            _ => return Ok(None),
+        };
+        let iface = self.channels.iface(u16_at(body, 0));
+        return Ok(Some(Record { time, iface, frame }));
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Record, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            // Objects are padded to a multiple of four bytes, except where they aren't
+            let search_end = (self.pos + 8).min(self.data.len());
+            match self.data[self.pos..search_end].windows(4).position(|w| w == OBJECT_SIGNATURE) {
+                Some(skip) => self.pos += skip,
+                None if self.data.len() - self.pos >= 8 => {
+                    self.done = true;
+                    return Some(Err(ReadError::Corrupt { offset: self.offset, message: String::from("missing object signature in container") }));
+                },
+                None => (),
+            }
+            let available = self.data.len() - self.pos;
+            let size = if available >= OBJECT_BASE_SIZE { u32_at(&self.data, self.pos + 8) as usize } else { usize::MAX };
+            if size < OBJECT_HEADER_SIZE && size != usize::MAX {
+                self.done = true;
+                return Some(Err(ReadError::Corrupt { offset: self.offset, message: format!("invalid object size {}", size) }));
+            }
+            if size == usize::MAX || available < size {
+                // The rest of the object is in the next container
+                match self.read_container() {
+                    Ok(true) => continue,
+                    Ok(false) => {
+                        self.done = true;
+                        if self.data[self.pos..].iter().any(|b| *b != 0) {
+                            return Some(Err(ReadError::Truncated { offset: self.offset }));
+                        }
+                        return None;
+                    },
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    },
+                }
+            }
+            self.objects += 1;
+            // Taken out for the call, since decoding a comment adds to `comments`
+            let data = std::mem::take(&mut self.data);
+            let decoded = self.decode(&data[self.pos..self.pos + size]);
+            self.data = data;
+            self.pos += size;
+            match decoded {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => continue,
+                Err(message) => return Some(Err(ReadError::Malformed { object: self.objects, message })),
+            }
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frames_round_trip() {
+        let t = Duration::new(1714566600, 123_456_789);
+        let kinds: Vec<(&str, CanAnyFrame)> = vec![
+            ("can0", CanFrame::new(StandardId::new(0x465).unwrap(), &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap().into()),
+            ("can1", CanFrame::new(ExtendedId::new(0x18DAF110).unwrap(), &[9]).unwrap().into()),
+            ("can0", CanFrame::new_remote(StandardId::new(0x123).unwrap(), 4).unwrap().into()),
+            ("can1", CanAnyFrame::Error(CanErrorFrame::new_error(0x04, &[0, 0x08, 0, 0, 0, 0, 0, 0]).unwrap())),
+            ("can0", CanAnyFrame::Fd(CanFdFrame::with_flags(StandardId::new(0x7FF).unwrap(), &[0xAB; 12], FdFlags::BRS | FdFlags::ESI).unwrap())),
+            ("can1", CanAnyFrame::Fd(CanFdFrame::with_flags(ExtendedId::new(0x1234).unwrap(), &[0xCD; 64], FdFlags::empty()).unwrap())),
+        ];
+        // Enough objects to fill several containers
+        let frames: Vec<(&str, CanAnyFrame, Duration)> = (0..3000).flat_map(|_| kinds.iter())
+            .enumerate()
+            .map(|(n, (iface, frame))| (*iface, *frame, t + Duration::from_nanos(n as u64 * 1_000_123)))
+            .collect();
+        let mut writer = Writer::new(Cursor::new(Vec::new()), ChannelMap::new()).unwrap();
+        let mut written = 0;
+        for (iface, frame, time) in frames.iter() {
+            written += writer.write_frame(iface, frame, *time).unwrap();
+        }
+        assert!(written > 2 * CONTAINER_SIZE);
+        let log = writer.finish().unwrap().into_inner();
+
+        let reader = Reader::new(Cursor::new(log), ChannelMap::new()).unwrap();
+        // The header start is the first frame's time in whole milliseconds
+        assert_eq!(reader.start(), Duration::new(1714566600, 123_000_000));
+        let records: Vec<Record> = reader.map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), frames.len());
+        for (record, (iface, frame, time)) in records.iter().zip(frames.iter()) {
+            assert_eq!(record.time, *time);
+            assert_eq!(record.iface, *iface);
+            assert_eq!(format!("{:?}", record.frame), format!("{:?}", frame));
+        }
+    }
+
+    /// A log holding one frame, followed by the start of an object claiming to be almost 4 GiB
+    fn log_with_huge_object() -> (Vec<u8>, u64) {
+        let frame = CanFrame::new(StandardId::new(0x123).unwrap(), &[1, 2, 3, 4]).unwrap();
+        let mut writer = Writer::new(Cursor::new(Vec::new()), ChannelMap::new()).unwrap();
+        writer.write_frame("can0", &CanAnyFrame::from(frame), Duration::new(1714566600, 0)).unwrap();
+        let mut log = writer.finish().unwrap().into_inner();
+        let end = log.len() as u64;
+        log.extend_from_slice(&OBJECT_SIGNATURE);
+        log.extend_from_slice(&(OBJECT_BASE_SIZE as u16).to_le_bytes());
+        log.extend_from_slice(&1u16.to_le_bytes());
+        log.extend_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        log.extend_from_slice(&LOG_CONTAINER.to_le_bytes());
+        log.extend_from_slice(&[0u8; 64]);
+        return (log, end);
+    }
+
+    fn check(reader: Reader<impl Read>, end: u64) {
+        let results: Vec<Result<Record, ReadError>> = reader.collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap().frame.data(), &[1, 2, 3, 4]);
+        match results[1] {
+            Err(ReadError::Truncated { offset }) => assert_eq!(offset, end),
+            ref other => panic!("expected a truncated log, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_objects_are_truncated_not_allocated() {
+        let (log, end) = log_with_huge_object();
+        check(Reader::new(Cursor::new(log.clone()), ChannelMap::new()).unwrap(), end);
+
+        let path = std::env::temp_dir().join(format!("car_logger_huge_object_{}.blf", std::process::id()));
+        std::fs::write(&path, &log).unwrap();
+        check(Reader::open(&path, ChannelMap::new()).unwrap(), end);
+        assert_eq!(complete_len(&path).unwrap(), end);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
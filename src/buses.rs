@@ -0,0 +1,289 @@
+//! Receiving from several CAN interfaces at once, merged into one stream in timestamp order.
+//!
+//! Each interface's frames arrive in order, so the earliest pending frame can be handed out as
+//! soon as every interface has a frame pending behind it. Otherwise it's held for up to
+//! `MERGE_WINDOW` after it was read, in case a quiet interface has an earlier one on its way.
+//! With a single interface nothing needs to be held.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use socketcan::{CanAnyFrame, CanFdSocket, Socket, SocketOptions};
+
+use super::timestamps::{ClockSource, Timestamper};
+
+/// How long a frame is held back for frames from other interfaces that may be older
+const MERGE_WINDOW: Duration = Duration::from_millis(20);
+
+/// A frame from one of the interfaces
+#[derive(Clone)]
+pub struct Received {
+    /// Index of the interface in the list given to `Buses::open`
+    pub iface: usize,
+    pub frame: CanAnyFrame,
+    /// Time since the UNIX epoch
+    pub time: Duration,
+    pub source: ClockSource,
+    /// Frames the kernel dropped on this interface just before this one, because the receive buffer was full
+    pub dropped: u32,
+}
+
+struct Pending {
+    received: Received,
+    /// Order frames were read in, to keep frames with the same time in order
+    sequence: u64,
+    read_at: Instant,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Pending) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Pending) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Pending) -> Ordering {
+        return (self.received.time, self.sequence).cmp(&(other.received.time, other.sequence));
+    }
+}
+
+/// What `Merge::release` found
+enum Release {
+    Frame(Received),
+    /// The earliest frame is held for this much longer
+    Held(Duration),
+    Empty,
+}
+
+/// Frames read from the interfaces that haven't been handed out yet, earliest first
+struct Merge {
+    pending: BinaryHeap<Reverse<Pending>>,
+    /// Frames pending from each interface
+    counts: Vec<usize>,
+    sequence: u64,
+}
+
+impl Merge {
+    fn new(ifaces: usize) -> Merge {
+        Merge { pending: BinaryHeap::new(), counts: vec![0; ifaces], sequence: 0 }
+    }
+
+    fn push(&mut self, received: Received, read_at: Instant) {
+        self.counts[received.iface] += 1;
+        self.sequence += 1;
+        self.pending.push(Reverse(Pending { received, sequence: self.sequence, read_at }));
+    }
+
+    /// Hands out the earliest frame if every interface has one pending behind it or it's been held
+    /// for `MERGE_WINDOW` by `now`
+    fn release(&mut self, now: Instant) -> Release {
+        let held = match self.pending.peek() {
+            Some(Reverse(first)) => first.read_at + MERGE_WINDOW,
+            None => return Release::Empty,
+        };
+        if self.counts.len() == 1 || self.counts.iter().all(|c| *c > 0) || now >= held {
+            return Release::Frame(self.pop().unwrap());
+        }
+        return Release::Held(held - now);
+    }
+
+    fn pop(&mut self) -> Option<Received> {
+        let Reverse(first) = self.pending.pop()?;
+        self.counts[first.received.iface] -= 1;
+        return Some(first.received);
+    }
+}
+
+struct Bus {
+    name: String,
+    socket: CanFdSocket,
+    timestamper: Timestamper,
+    /// Timestamp source of the last frame, to report when it changes
+    last_source: Option<ClockSource>,
+}
+
+/// Sets the receive buffer size. Root can go past `net.core.rmem_max`; anyone else is capped at it.
+fn set_receive_buffer(name: &str, socket: &CanFdSocket, size: usize) -> io::Result<()> {
+    let size = size.min(i32::MAX as usize) as libc::c_int;
+    if socket.set_socket_option(libc::SOL_SOCKET, libc::SO_RCVBUFFORCE, &size).is_err() {
+        socket.set_socket_option(libc::SOL_SOCKET, libc::SO_RCVBUF, &size)?;
+    }
+    let mut actual: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if unsafe { libc::getsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF, &mut actual as *mut libc::c_int as *mut libc::c_void, &mut len) } == 0 {
+        // The kernel doubles the size asked for to leave room for its own bookkeeping
+        if actual / 2 < size {
+            println!("{}: receive buffer limited to {} bytes by net.core.rmem_max", name, actual / 2);
+        }
+    }
+    return Ok(());
+}
+
+pub struct Buses {
+    buses: Vec<Bus>,
+    merge: Merge,
+}
+
+impl Buses {
+    /// Opens every interface in CAN FD mode with timestamps from `timestamps`, or the best each one supports.
+    /// `rcvbuf` sets the size of each socket's receive buffer in bytes.
+    pub fn open(ifaces: &[String], timestamps: Option<ClockSource>, rcvbuf: Option<usize>) -> io::Result<Buses> {
+        let mut buses = Vec::with_capacity(ifaces.len());
+        for name in ifaces {
+            let socket = CanFdSocket::open(name).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+            socket.set_filter_accept_all()?;
+            if let Some(size) = rcvbuf {
+                set_receive_buffer(name, &socket, size)?;
+            }
+            let timestamper = Timestamper::new(&socket, timestamps);
+            buses.push(Bus { name: name.clone(), socket, timestamper, last_source: None });
+        }
+        return Ok(Buses { buses, merge: Merge::new(ifaces.len()) });
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        return self.buses.iter().map(|b| b.name.clone()).collect();
+    }
+
+    /// Timestamp source of each interface, e.g. "can0 hardware, can1 kernel"
+    pub fn sources(&self) -> String {
+        return self.buses.iter().map(|b| format!("{} {}", b.name, b.timestamper.source())).collect::<Vec<String>>().join(", ");
+    }
+
+    /// How many frames each timestamp source stamped on each interface since the last call
+    pub fn take_counts(&mut self) -> String {
+        if self.buses.len() == 1 {
+            return self.buses[0].timestamper.take_counts();
+        }
+        return self.buses.iter_mut().map(|b| format!("{} {}", b.name, b.timestamper.take_counts())).collect::<Vec<String>>().join("; ");
+    }
+
+    /// Next frame in timestamp order. If none arrives within `timeout`, the error is `WouldBlock`.
+    pub fn read(&mut self, timeout: Duration) -> io::Result<Received> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            let wait = match self.merge.release(now) {
+                Release::Frame(received) => return Ok(received),
+                // A frame is already waiting, so keep going past the timeout until it's released
+                Release::Held(wait) => wait,
+                Release::Empty if now >= deadline => return Err(io::ErrorKind::WouldBlock.into()),
+                Release::Empty => deadline - now,
+            };
+            self.poll(wait)?;
+        }
+    }
+
+    /// Frames still held back for merging, in order
+    pub fn drain(&mut self) -> Vec<Received> {
+        let mut frames = Vec::with_capacity(self.merge.pending.len());
+        while let Some(r) = self.merge.pop() {
+            frames.push(r);
+        }
+        return frames;
+    }
+
+    /// Waits up to `timeout` for any interface to have a frame, then reads one frame from each that does
+    fn poll(&mut self, timeout: Duration) -> io::Result<()> {
+        let mut fds: Vec<libc::pollfd> = self.buses.iter().map(|b| libc::pollfd { fd: b.socket.as_raw_fd(), events: libc::POLLIN, revents: 0 }).collect();
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (i, fd) in fds.iter().enumerate() {
+            if fd.revents == 0 {
+                continue;
+            }
+            let bus = &mut self.buses[i];
+            let (frame, time, source, dropped) = bus.timestamper.read_frame(&bus.socket)?;
+            if let Some(last) = bus.last_source.filter(|l| *l != source) {
+                println!("{}: timestamps now from {} clock (were from {})", bus.name, source, last);
+            }
+            bus.last_source = Some(source);
+            self.merge.push(Received { iface: i, frame, time, source, dropped }, Instant::now());
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socketcan::{CanFrame, EmbeddedFrame, StandardId};
+
+    const START: Duration = Duration::from_secs(1714566600);
+
+    fn received(iface: usize, ms: u64) -> Received {
+        let frame = CanFrame::new(StandardId::new(0x100 + iface as u16).unwrap(), &[ms as u8]).unwrap().into();
+        return Received { iface, frame, time: START + Duration::from_millis(ms), source: ClockSource::Kernel, dropped: 0 };
+    }
+
+    /// Interface and time in ms of the frame released at `now`, if there is one
+    fn release(merge: &mut Merge, now: Instant) -> Option<(usize, u64)> {
+        match merge.release(now) {
+            Release::Frame(r) => return Some((r.iface, (r.time - START).as_millis() as u64)),
+            _ => return None,
+        }
+    }
+
+    #[test]
+    fn frames_from_two_interfaces_come_out_in_time_order() {
+        let mut merge = Merge::new(2);
+        let start = Instant::now();
+        merge.push(received(0, 10), start);
+        merge.push(received(0, 30), start);
+        // can1 has nothing pending yet, so can0's frames are held
+        match merge.release(start) {
+            Release::Held(wait) => assert_eq!(wait, MERGE_WINDOW),
+            _ => panic!("expected the frame to be held"),
+        }
+        // An earlier frame read later from can1 goes first
+        merge.push(received(1, 5), start);
+        assert_eq!(release(&mut merge, start), Some((1, 5)));
+        assert_eq!(release(&mut merge, start), None);
+        merge.push(received(1, 20), start);
+        merge.push(received(1, 40), start);
+        assert_eq!(release(&mut merge, start), Some((0, 10)));
+        assert_eq!(release(&mut merge, start), Some((1, 20)));
+        assert_eq!(release(&mut merge, start), Some((0, 30)));
+        // Only can1 is left with a frame, so it waits out the window
+        assert_eq!(release(&mut merge, start), None);
+        assert_eq!(release(&mut merge, start + MERGE_WINDOW), Some((1, 40)));
+        assert!(matches!(merge.release(start + MERGE_WINDOW), Release::Empty));
+    }
+
+    #[test]
+    fn an_idle_interface_only_holds_frames_for_the_window() {
+        let mut merge = Merge::new(2);
+        let start = Instant::now();
+        merge.push(received(0, 10), start);
+        merge.push(received(0, 11), start + Duration::from_millis(5));
+        merge.push(received(0, 12), start + Duration::from_millis(15));
+        assert_eq!(release(&mut merge, start + MERGE_WINDOW - Duration::from_millis(1)), None);
+        assert_eq!(release(&mut merge, start + MERGE_WINDOW), Some((0, 10)));
+        // The second was read 5 ms later, so it's held until its own window is up
+        match merge.release(start + MERGE_WINDOW) {
+            Release::Held(wait) => assert_eq!(wait, Duration::from_millis(5)),
+            _ => panic!("expected the frame to be held"),
+        }
+        assert_eq!(release(&mut merge, start + MERGE_WINDOW * 2), Some((0, 11)));
+        assert_eq!(release(&mut merge, start + MERGE_WINDOW * 2), Some((0, 12)));
+
+        // With a single interface nothing is held
+        let mut single = Merge::new(1);
+        single.push(received(0, 7), start);
+        assert_eq!(release(&mut single, start), Some((0, 7)));
+    }
+}
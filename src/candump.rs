@@ -0,0 +1,429 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use libc::{CAN_EFF_MASK, CAN_ERR_FLAG, CAN_SFF_MASK};
+use socketcan::{CanAnyFrame, CanErrorFrame, CanFdFrame, CanFrame, EmbeddedFrame, ExtendedId, Frame, Id, StandardId};
+use socketcan::id::FdFlags;
+
+use super::compression::Decoder;
+
+/// One line of a candump-style log
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// Time since the UNIX epoch
+    pub time: Duration,
+    pub iface: String,
+    pub frame: CanAnyFrame,
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    /// A line that isn't a valid log entry. Reading can continue with the next line.
+    Malformed { line: usize, message: String },
+    /// The log ends partway through a line, e.g. after a power cut. Nothing follows this.
+    Truncated { line: usize },
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "{}", e),
+            ReadError::Malformed { line, message } => write!(f, "line {}: {}", line, message),
+            ReadError::Truncated { line } => write!(f, "line {}: log is truncated", line),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+/// Streams records from a log written by `Logger`, or by candump's `-l` option.
+/// Compressed logs are decompressed transparently.
+pub struct Reader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    line: usize,
+    done: bool,
+}
+
+impl Reader<BufReader<Decoder>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Reader<BufReader<Decoder>>> {
+        return Ok(Reader::new(BufReader::new(Decoder::open(path)?)));
+    }
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(inner: R) -> Reader<R> {
+        Reader { inner, buf: Vec::new(), line: 0, done: false }
+    }
+
+    /// Number of the last line read
+    pub fn line(&self) -> usize {
+        return self.line;
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Record, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.buf.clear();
+            match self.inner.read_until(b'\n', &mut self.buf) {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    self.line += 1;
+                    // Logger ends every line with a newline, so a missing one means the write was cut short.
+                    // A power cut can also leave the end of the file filled with zeros.
+                    if self.buf.last() != Some(&b'\n') || self.buf.contains(&0) {
+                        self.done = true;
+                        return Some(Err(ReadError::Truncated { line: self.line }));
+                    }
+                    let text = match std::str::from_utf8(&self.buf) {
+                        Ok(t) => t.trim(),
+                        Err(_) => return Some(Err(ReadError::Malformed { line: self.line, message: String::from("not valid UTF-8") })),
+                    };
+                    // Comments, e.g. the recorder noting dropped frames
+                    if text.is_empty() || text.starts_with('#') {
+                        continue;
+                    }
+                    return Some(parse_line(text).map_err(|message| ReadError::Malformed { line: self.line, message }));
+                },
+                // A compressed stream that stops partway through a block
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.done = true;
+                    return Some(Err(ReadError::Truncated { line: self.line + 1 }));
+                },
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(ReadError::Io(e)));
+                },
+            }
+        }
+        return None;
+    }
+}
+
+/// Longest part of a line after the interface name: an extended ID, the FD separator and flags, 64 data bytes and the newline
+const FRAME_TEXT_MAX: usize = 1 + 8 + 3 + 128 + 1;
+/// Longest timestamp: `(`, 20 digits of seconds, the fraction and `) `
+const TIME_TEXT_MAX: usize = 1 + 20 + 7 + 2;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Text built up on the stack, so formatting a line doesn't allocate
+struct LineBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> LineBuf<N> {
+    fn new() -> LineBuf<N> {
+        LineBuf { buf: [0; N], len: 0 }
+    }
+
+    fn push(&mut self, b: u8) {
+        self.buf[self.len] = b;
+        self.len += 1;
+    }
+
+    /// `value` as exactly `digits` upper case hex digits
+    fn push_hex(&mut self, value: u32, digits: usize) {
+        for i in (0..digits).rev() {
+            self.push(HEX_DIGITS[((value >> (i * 4)) & 0xF) as usize]);
+        }
+    }
+
+    fn push_hex_bytes(&mut self, data: &[u8]) {
+        for b in data {
+            self.push(HEX_DIGITS[(b >> 4) as usize]);
+            self.push(HEX_DIGITS[(b & 0xF) as usize]);
+        }
+    }
+
+    /// `value` in decimal, padded with zeros to at least `width` digits
+    fn push_decimal(&mut self, mut value: u64, width: usize) {
+        let mut digits = [0u8; 20];
+        let mut count = 0;
+        while value > 0 || count < width.max(1) {
+            digits[count] = b'0' + (value % 10) as u8;
+            value /= 10;
+            count += 1;
+        }
+        for d in digits[..count].iter().rev() {
+            self.push(*d);
+        }
+    }
+
+    fn push_id(&mut self, f: &CanAnyFrame) {
+        if f.is_extended() {
+            self.push_hex(f.raw_id(), 8);
+        } else {
+            self.push_hex(f.raw_id(), 3);
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        return &self.buf[..self.len];
+    }
+}
+
+/// Writes a frame as a log line, including the newline, straight into `w`. Nothing is allocated,
+/// so this is cheap enough to run for every frame at full bus load. Returns the bytes written.
+pub fn write_frame<W: Write>(w: &mut W, iface: &str, f: &CanAnyFrame, t: Duration) -> io::Result<usize> {
+    let lts = t.as_micros();
+    let mut time: LineBuf<TIME_TEXT_MAX> = LineBuf::new();
+    time.push(b'(');
+    time.push_decimal((lts / 1_000_000) as u64, 1);
+    time.push(b'.');
+    time.push_decimal((lts % 1_000_000) as u64, 6);
+    time.push(b')');
+    time.push(b' ');
+    let mut text: LineBuf<FRAME_TEXT_MAX> = LineBuf::new();
+    text.push(b' ');
+    match f {
+        CanAnyFrame::Error(_) => {
+            // Just write a plain python canutils-style error to the log.
+            // The error flag stays in the ID so readers can tell it apart from a data frame.
+            text.push_hex(f.id_word(), 8);
+            text.push(b'#');
+            text.push_hex_bytes(f.data());
+        },
+        CanAnyFrame::Remote(_) => {
+            // Return request frame, with the requested length like candump if there is one
+            text.push_id(f);
+            text.push(b'#');
+            text.push(b'R');
+            if f.dlc() > 0 {
+                text.push_decimal(f.dlc() as u64, 1);
+            }
+        },
+        CanAnyFrame::Normal(_) => {
+            // Regular data frame
+            text.push_id(f);
+            text.push(b'#');
+            text.push_hex_bytes(f.data());
+        },
+        CanAnyFrame::Fd(fd) => {
+            // CAN FD frame, with the FD flags (BRS, ESI, FDF) as a single hex digit like candump
+            text.push_id(f);
+            text.push(b'#');
+            text.push(b'#');
+            text.push_hex(fd.flags().bits() as u32, 1);
+            text.push_hex_bytes(f.data());
+        },
+    }
+    text.push(b'\n');
+    w.write_all(time.as_bytes())?;
+    w.write_all(iface.as_bytes())?;
+    w.write_all(text.as_bytes())?;
+    return Ok(time.len + iface.len() + text.len);
+}
+
+/// Formats a frame as a log line, including the newline
+pub fn format_line(iface: &str, f: &CanAnyFrame, t: Duration) -> String {
+    let mut line = Vec::with_capacity(64);
+    // Writing to a Vec can't fail, and only ASCII is added to the interface name
+    let _ = write_frame(&mut line, iface, f, t);
+    return String::from_utf8(line).unwrap();
+}
+
+/// Formats a comment line, e.g. `# (1714566600.250000) can0: 3 frames dropped`
+pub fn format_comment(t: Duration, text: &str) -> String {
+    let lts = t.as_micros();
+    return format!("# ({}.{:06}) {}\n", lts/1_000_000, lts%1_000_000, text);
+}
+
+/// Parses one `(seconds.fraction) iface frame` log line
+pub fn parse_line(line: &str) -> Result<Record, String> {
+    let rest = line.strip_prefix('(').ok_or("missing timestamp")?;
+    let (timestamp, rest) = rest.split_once(')').ok_or("unterminated timestamp")?;
+    let time = parse_timestamp(timestamp)?;
+    let mut fields = rest.split_whitespace();
+    let iface = fields.next().ok_or("missing interface")?;
+    let frame = parse_frame(fields.next().ok_or("missing frame")?)?;
+    // Newer candump versions append the frame direction (R or T); anything more is an error
+    match fields.next() {
+        None | Some("R") | Some("T") => (),
+        Some(extra) => return Err(format!("unexpected '{}' after frame", extra)),
+    }
+    if fields.next().is_some() {
+        return Err(String::from("unexpected text after frame"));
+    }
+    return Ok(Record { time, iface: iface.to_string(), frame });
+}
+
+/// Parses a `seconds.fraction` timestamp without losing precision
+pub fn parse_timestamp(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid timestamp '{}'", text);
+    let (seconds, fraction) = text.split_once('.').ok_or_else(invalid)?;
+    if fraction.is_empty() || fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let seconds: u64 = seconds.parse().map_err(|_| invalid())?;
+    let nanos: u32 = fraction.parse::<u32>().map_err(|_| invalid())? * 10u32.pow(9 - fraction.len() as u32);
+    return Ok(Duration::new(seconds, nanos));
+}
+
+/// Parses the `ID#DATA`, `ID#R` or `ID##<flags>DATA` part of a line
+pub fn parse_frame(text: &str) -> Result<CanAnyFrame, String> {
+    let (id_text, payload) = text.split_once('#').ok_or_else(|| format!("missing '#' in '{}'", text))?;
+    let id_value = u32::from_str_radix(id_text, 16).map_err(|_| format!("invalid ID '{}'", id_text))?;
+    let id: Id = match id_text.len() {
+        3 if id_value <= CAN_SFF_MASK => StandardId::new(id_value as u16).unwrap().into(),
+        8 if id_value & CAN_ERR_FLAG != 0 => {
+            // Error frames keep the error flag and class in the ID word
+            let data = parse_data(payload)?;
+            return CanErrorFrame::new_error(id_value, &data).map(CanAnyFrame::Error).map_err(|e| e.to_string());
+        },
+        // The EFF flag is masked off since older versions of Logger wrote it as part of the ID
+        8 => ExtendedId::new(id_value & CAN_EFF_MASK).unwrap().into(),
+        _ => return Err(format!("invalid ID '{}'", id_text)),
+    };
+    if let Some(fd) = payload.strip_prefix('#') {
+        let mut chars = fd.chars();
+        let flags = chars.next().and_then(|c| c.to_digit(16)).ok_or_else(|| format!("invalid CAN FD flags in '{}'", text))?;
+        let data = parse_data(chars.as_str())?;
+        if !CanFdFrame::is_valid_data_len(data.len()) {
+            return Err(format!("invalid CAN FD data length {}", data.len()));
+        }
+        return CanFdFrame::with_flags(id, &data, FdFlags::from_bits_truncate(flags as u8))
+            .map(CanAnyFrame::Fd)
+            .ok_or_else(|| format!("too much data ({} bytes)", data.len()));
+    }
+    if let Some(dlc) = payload.strip_prefix('R') {
+        let dlc: usize = match dlc {
+            "" => 0,
+            d => d.parse().map_err(|_| format!("invalid remote frame length '{}'", d))?,
+        };
+        return CanFrame::new_remote(id, dlc).map(CanAnyFrame::from).ok_or_else(|| format!("invalid remote frame length {}", dlc));
+    }
+    let data = parse_data(payload)?;
+    return CanFrame::new(id, &data).map(CanAnyFrame::from).ok_or_else(|| format!("too much data ({} bytes)", data.len()));
+}
+
+/// Decodes hex data, allowing '.' between bytes and ignoring a trailing `_<dlc>` length code
+fn parse_data(text: &str) -> Result<Vec<u8>, String> {
+    let text = match text.split_once('_') {
+        Some((data, _dlc)) => data,
+        None => text,
+    };
+    let digits: String = text.chars().filter(|c| *c != '.').collect();
+    return hex::decode(&digits).map_err(|_| format!("invalid data '{}'", text));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read_all(text: &[u8]) -> Vec<Result<Record, ReadError>> {
+        return Reader::new(Cursor::new(text.to_vec())).collect();
+    }
+
+    fn malformed_line(result: &Result<Record, ReadError>) -> usize {
+        match result {
+            Err(ReadError::Malformed { line, .. }) => return *line,
+            other => panic!("expected a malformed line, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_bad_lines_and_keeps_going() {
+        let log = b"(1714566600.250000) can0 123#DEADBEEF\n\
+            # (1714566600.260000) can0: 3 frames dropped\n\
+            (1714566600.300000) can0 12G#00\n\
+            (1714566600.400000) can0 123#00112233445566778899\n\
+            (1714566600.500000) can0 123#R9\n\
+            (1714566600.600000) can0 1234#00\n\
+            (1714566600.700000) can0 00000456#0102 R\n";
+        let records = read_all(log);
+        assert_eq!(records.len(), 6);
+        let first = records[0].as_ref().unwrap();
+        assert_eq!(first.time, Duration::new(1714566600, 250_000_000));
+        assert_eq!(first.iface, "can0");
+        assert_eq!(first.frame.raw_id(), 0x123);
+        assert_eq!(first.frame.data(), &[0xDE, 0xAD, 0xBE, 0xEF]);
+        // Bad hex ID, too much data for a classic frame, too long a remote request and a 4 digit ID
+        assert_eq!(malformed_line(&records[1]), 3);
+        assert_eq!(malformed_line(&records[2]), 4);
+        assert_eq!(malformed_line(&records[3]), 5);
+        assert_eq!(malformed_line(&records[4]), 6);
+        let last = records[5].as_ref().unwrap();
+        assert!(last.frame.is_extended());
+        assert_eq!(last.frame.raw_id(), 0x456);
+    }
+
+    #[test]
+    fn torn_last_line_ends_the_log() {
+        let records = read_all(b"(1714566600.250000) can0 123#DEADBEEF\n(1714566600.300000) can0 123#DE");
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_ok());
+        match records[1] {
+            Err(ReadError::Truncated { line: 2 }) => (),
+            ref other => panic!("expected a truncated line, got {:?}", other),
+        }
+
+        // A power cut can leave zeros where the last lines should be
+        let records = read_all(b"(1714566600.250000) can0 123#DEADBEEF\n\0\0\0\0\n(1714566600.300000) can0 123#00\n");
+        assert_eq!(records.len(), 2);
+        match records[1] {
+            Err(ReadError::Truncated { line: 2 }) => (),
+            ref other => panic!("expected a truncated line, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fd_frames_keep_their_flags() {
+        let record = parse_line("(1714566600.250000) can1 18FEF100##3000102030405060708090A0B").unwrap();
+        match &record.frame {
+            CanAnyFrame::Fd(fd) => {
+                assert!(fd.is_extended());
+                assert_eq!(fd.raw_id(), 0x18FEF100);
+                // BRS and ESI as given, plus FDF, which every FD frame has
+                assert_eq!(fd.flags().bits() & 0x0F, 0x7);
+                assert_eq!(fd.data().len(), 12);
+            },
+            other => panic!("expected an FD frame, got {:?}", other),
+        }
+        assert_eq!(format_line("can1", &record.frame, record.time), "(1714566600.250000) can1 18FEF100##7000102030405060708090A0B\n");
+        // FD frames carry 0 to 8, 12, 16, 20, 24, 32, 48 or 64 bytes
+        assert!(parse_frame("123##1000102030405060708").is_err());
+        assert!(parse_frame("123##G00").is_err());
+        assert!(parse_frame("123##").is_err());
+    }
+
+    #[test]
+    fn error_frames_keep_their_class_and_data() {
+        let line = "(1714566600.250000) can0 20000004#0004000000000000\n";
+        let record = parse_line(line.trim()).unwrap();
+        match &record.frame {
+            CanAnyFrame::Error(e) => {
+                assert_eq!(e.id_word() & CAN_ERR_FLAG, CAN_ERR_FLAG);
+                assert_eq!(e.id_word() & !CAN_ERR_FLAG, 0x004);
+                assert_eq!(e.data(), &[0, 4, 0, 0, 0, 0, 0, 0]);
+            },
+            other => panic!("expected an error frame, got {:?}", other),
+        }
+        assert_eq!(format_line("can0", &record.frame, record.time), line);
+    }
+
+    #[test]
+    fn lines_round_trip() {
+        for line in ["(0.000001) can0 000#", "(1714566600.250000) vcan0 7FF#R", "(1714566600.250000) can0 123#R8", "(1714566600.999999) can0 1FFFFFFF#0011223344556677"] {
+            let record = parse_line(line).unwrap();
+            assert_eq!(format_line(&record.iface, &record.frame, record.time), format!("{}\n", line));
+        }
+        assert_eq!(parse_timestamp("12.5").unwrap(), Duration::from_millis(12500));
+        assert!(parse_timestamp("12").is_err());
+        assert!(parse_timestamp("12.1234567890").is_err());
+    }
+}
@@ -0,0 +1,47 @@
+/// Maps interface names to the channel numbers used by Vector tools, which start at 1.
+/// Unless told otherwise, `canN` (or any name ending in N) is channel N+1 and channel N is `can{N-1}`.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelMap {
+    map: Vec<(String, u16)>,
+}
+
+impl ChannelMap {
+    pub fn new() -> ChannelMap {
+        ChannelMap { map: Vec::new() }
+    }
+
+    /// Uses `channel` for `iface` in both directions
+    pub fn insert(&mut self, iface: &str, channel: u16) {
+        self.map.retain(|(i, c)| i != iface && *c != channel);
+        self.map.push((iface.to_string(), channel));
+    }
+
+    pub fn channel(&self, iface: &str) -> u16 {
+        if let Some((_, c)) = self.map.iter().find(|(i, _)| i == iface) {
+            return *c;
+        }
+        let digits = iface.len() - iface.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        return match iface[iface.len() - digits..].parse::<u16>() {
+            Ok(n) => n.saturating_add(1),
+            Err(_) => 1,
+        };
+    }
+
+    pub fn iface(&self, channel: u16) -> String {
+        if let Some((i, _)) = self.map.iter().find(|(_, c)| *c == channel) {
+            return i.clone();
+        }
+        return format!("can{}", channel.saturating_sub(1));
+    }
+}
+
+/// Parses an `iface=channel` mapping given on the command line
+pub fn parse_mapping(s: &str) -> Result<(String, u16), String> {
+    match s.split_once('=') {
+        Some((iface, channel)) if !iface.is_empty() => match channel.parse::<u16>() {
+            Ok(c) if c > 0 => Ok((iface.to_string(), c)),
+            _ => Err(format!("'{}' is not a channel number (1 or more)", channel)),
+        },
+        _ => Err(String::from("must be in the form iface=channel")),
+    }
+}
@@ -0,0 +1,217 @@
+//! Compressing logs as they're written and decompressing them as they're read.
+//!
+//! `Encoder` writes a log through gzip or zstd, or neither, and `Decoder` reads any of them back,
+//! telling them apart by their magic bytes rather than the file name.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Compression applied to log files as they are written
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Suffix added to the names of logs written with this compression
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// A file written through a streaming compressor.
+/// Flushing ends the current compressed block so everything written so far can be read back
+/// even if the file is never finished.
+pub enum Encoder {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Encoder {
+    pub fn new(file: File, compression: Compression) -> io::Result<Encoder> {
+        return Ok(match compression {
+            Compression::None => Encoder::Plain(file),
+            Compression::Gzip => Encoder::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+        });
+    }
+
+    /// Writes the end of the compressed stream and returns the file
+    pub fn finish(self) -> io::Result<File> {
+        match self {
+            Encoder::Plain(file) => Ok(file),
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(f) => f.write(buf),
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(f) => f.flush(),
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// A log file that is decompressed as it's read.
+/// A compressed stream that ends early reads as an `UnexpectedEof` error.
+pub enum Decoder {
+    Plain(File),
+    Gzip(MultiGzDecoder<File>),
+    Zstd(zstd::Decoder<'static, BufReader<File>>),
+}
+
+impl Decoder {
+    /// Opens a log, detecting the compression from the start of the file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Decoder> {
+        let mut file = File::open(&path)?;
+        let mut magic = [0u8; 4];
+        let mut len = 0;
+        while len < magic.len() {
+            match file.read(&mut magic[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        file.seek(SeekFrom::Start(0))?;
+        if len >= GZIP_MAGIC.len() && magic[..GZIP_MAGIC.len()] == GZIP_MAGIC {
+            return Ok(Decoder::Gzip(MultiGzDecoder::new(file)));
+        }
+        if len == ZSTD_MAGIC.len() && magic == ZSTD_MAGIC {
+            return Ok(Decoder::Zstd(zstd::Decoder::new(file)?));
+        }
+        return Ok(Decoder::Plain(file));
+    }
+
+    pub fn compression(&self) -> Compression {
+        match self {
+            Decoder::Plain(_) => Compression::None,
+            Decoder::Gzip(_) => Compression::Gzip,
+            Decoder::Zstd(_) => Compression::Zstd,
+        }
+    }
+}
+
+impl Read for Decoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Plain(f) => f.read(buf),
+            Decoder::Gzip(d) => d.read(buf),
+            Decoder::Zstd(d) => d.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use socketcan::{CanAnyFrame, CanFrame, EmbeddedFrame, StandardId};
+
+    use crate::candump::{self, ReadError};
+
+    fn temp_path(name: &str) -> PathBuf {
+        return std::env::temp_dir().join(format!("car_logger_compression_{}_{}", name, std::process::id()));
+    }
+
+    fn write_lines<W: Write>(w: &mut W, range: std::ops::Range<u8>) {
+        for i in range {
+            let frame: CanAnyFrame = CanFrame::new(StandardId::new(0x123).unwrap(), &[i]).unwrap().into();
+            candump::write_frame(w, "can0", &frame, Duration::new(1714566600, i as u32 * 1000)).unwrap();
+        }
+    }
+
+    /// A finished log of 100 frames with `compression`, and the same log uncompressed
+    fn finished_log(name: &str, compression: Compression) -> (PathBuf, Vec<u8>) {
+        let path = temp_path(name);
+        let mut encoder = Encoder::new(File::create(&path).unwrap(), compression).unwrap();
+        write_lines(&mut encoder, 0..100);
+        encoder.finish().unwrap();
+        let mut plain: Vec<u8> = Vec::new();
+        write_lines(&mut plain, 0..100);
+        return (path, plain);
+    }
+
+    #[test]
+    fn flushed_lines_survive_an_unfinished_stream() {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let path = temp_path(&format!("unfinished{}", compression.extension()));
+            let mut encoder = Encoder::new(File::create(&path).unwrap(), compression).unwrap();
+            write_lines(&mut encoder, 0..50);
+            encoder.flush().unwrap();
+            // What's on the disk if the power goes now, before the rest is written or the stream finished
+            let on_disk = std::fs::read(&path).unwrap();
+            write_lines(&mut encoder, 50..60);
+            drop(encoder);
+            std::fs::write(&path, on_disk).unwrap();
+
+            let mut reader = candump::Reader::open(&path).unwrap();
+            let data: Vec<u8> = reader.by_ref().take(50).map(|r| r.unwrap().frame.data()[0]).collect();
+            assert_eq!(data, (0..50).collect::<Vec<u8>>(), "{:?}", compression);
+            match reader.next() {
+                None | Some(Err(ReadError::Truncated { line: 51 })) => (),
+                other => panic!("{:?}: expected the end of the log, got {:?}", compression, other),
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn decoder_detects_compression_by_magic_bytes() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            // Named without the extension, so only the contents tell
+            let (path, plain) = finished_log(&format!("detect_{:?}", compression), compression);
+            let mut decoder = Decoder::open(&path).unwrap();
+            assert_eq!(decoder.compression(), compression);
+            let mut text: Vec<u8> = Vec::new();
+            decoder.read_to_end(&mut text).unwrap();
+            assert_eq!(text, plain);
+            std::fs::remove_file(&path).unwrap();
+        }
+        // Too short to hold any magic
+        let path = temp_path("detect_short");
+        std::fs::write(&path, [0x1F]).unwrap();
+        assert_eq!(Decoder::open(&path).unwrap().compression(), Compression::None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_streams_end_with_unexpected_eof() {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let (path, _) = finished_log(&format!("truncated_{:?}", compression), compression);
+            let log = std::fs::read(&path).unwrap();
+            std::fs::write(&path, &log[..log.len() / 2]).unwrap();
+            let mut text: Vec<u8> = Vec::new();
+            let error = Decoder::open(&path).unwrap().read_to_end(&mut text).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof, "{:?}", compression);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
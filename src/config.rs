@@ -0,0 +1,371 @@
+//! Settings for the services from `/etc/car_logger.toml`, merged with their command lines.
+//!
+//! The file has a section per service, named after its binary, holding the service's long
+//! options with `_` in place of `-`. The bus settings, `interface`, `bus_speed` and `dbc`, can also go at
+//! the top of the file, before any section, where they apply to every service that listens to a
+//! bus, so they only need to be given once.
+//! The command line wins over the file, and a service's section wins over the top of the file.
+//!
+//! ```toml
+//! interface = "can0"
+//! bus_speed = 500000
+//! dbc = "/etc/car.dbc"
+//!
+//! [recorder]
+//! interface = ["can0", "can1"]
+//! log_location = "/var/log/car"
+//! compression = "zstd"
+//!
+//! [shutdown_scheduler]
+//! latitude = 47.6205
+//! longitude = -122.3493
+//! radius = 50
+//! ```
+//!
+//! Settings from the file are handed to the service's parser as if they were options on the
+//! command line, so they're checked the same way. Switches take `true` or `false`, and one turned
+//! on in the file can't be turned off on the command line.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgAction, ArgMatches, Command, Parser};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use toml::{Table, Value};
+
+use super::dbc::Database;
+
+/// Where the services look for the configuration file
+pub const DEFAULT_PATH: &str = "/etc/car_logger.toml";
+
+/// Services that have a section in the file
+pub const SERVICES: [&str; 7] = ["recorder", "shutdown_scheduler", "timekeeper", "clock_offset_viewer", "time_marker", "battery", "car_logger"];
+
+/// Options every service has for its configuration file
+#[derive(clap::Args)]
+pub struct ConfigArgs {
+    #[arg(long, value_name = "path", help = "Configuration file to read settings from; options given on the command line win over it [default: /etc/car_logger.toml]")]
+    pub config: Option<PathBuf>,
+    #[arg(long, help = "Print the settings in effect, with where each one came from, and exit")]
+    pub print_config: bool,
+}
+
+/// The CAN interface a service listens to
+#[derive(clap::Args)]
+pub struct BusArgs {
+    #[arg(short = 'i', long, name = "name", default_value = "can0", help = "Interface to listen for traffic")]
+    pub interface: String,
+    #[arg(short = 'b', long, name = "speed", default_value = "500000", value_parser = clap::value_parser!(u64).range(1..), help = "The speed of the interface, in bps")]
+    pub bus_speed: u64,
+    #[arg(long, name = "dbc_file", help = "DBC file to decode the messages the built-in decoders don't know with")]
+    pub dbc: Option<PathBuf>,
+}
+
+impl BusArgs {
+    /// Loads the `--dbc` file if one was given, exiting with a usage error for `P` if it can't be
+    pub fn database<P: Parser>(&self) -> Option<Database> {
+        let path = self.dbc.as_ref()?;
+        match Database::from_file(path) {
+            Ok(db) => return Some(db),
+            Err(e) => {
+                let error_msg = format!("Could not load {}: {}", path.display(), e);
+                P::command().error(ErrorKind::ValueValidation, error_msg).exit();
+            },
+        }
+    }
+}
+
+/// Reads the configuration file at `path`, or at `DEFAULT_PATH`, where a missing file is the same as an empty one
+pub fn load(path: Option<&Path>) -> Result<Table, String> {
+    let shown = path.map_or(String::from(DEFAULT_PATH), |p| p.display().to_string());
+    let text = match fs::read_to_string(path.unwrap_or(Path::new(DEFAULT_PATH))) {
+        Ok(text) => text,
+        Err(e) if path.is_none() && e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Could not read {}: {}", shown, e)),
+    };
+    let file: Table = text.parse().map_err(|e| format!("Could not parse {}: {}", shown, e))?;
+    let shared = shared_keys();
+    for (name, value) in file.iter() {
+        if value.is_table() && !SERVICES.contains(&name.as_str()) {
+            return Err(format!("Unknown section [{}] in {}", name, shown));
+        }
+        if !value.is_table() && !shared.contains(name) {
+            return Err(format!("Unknown setting `{}` before the first section in {}; only {} can go there", name, shown, shared.join(", ")));
+        }
+    }
+    return Ok(file);
+}
+
+/// Settings that can go at the top of the file: the ones in `BusArgs`
+fn shared_keys() -> Vec<String> {
+    let command = <BusArgs as clap::Args>::augment_args(Command::new("bus"));
+    return command.get_arguments().filter_map(key).collect();
+}
+
+/// Key for `arg` in the file
+fn key(arg: &Arg) -> Option<String> {
+    let id = arg.get_id().as_str();
+    if arg.is_hide_set() || matches!(id, "help" | "version" | "config" | "print_config") {
+        return None;
+    }
+    return arg.get_long().map(|l| l.replace('-', "_"));
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => return Some(s.clone()),
+        Value::Integer(i) => return Some(i.to_string()),
+        Value::Float(f) => return Some(f.to_string()),
+        Value::Boolean(b) => return Some(b.to_string()),
+        _ => return None,
+    }
+}
+
+/// Adds the options that give `arg` the value `value` from the file
+fn push_value(arg: &Arg, key: &str, value: &Value, args: &mut Vec<OsString>) -> Result<(), String> {
+    let long = arg.get_long().unwrap_or_default();
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Boolean(set)) => {
+            if *set {
+                args.push(format!("--{}", long).into());
+            }
+        },
+        (ArgAction::SetTrue, _) => return Err(format!("`{}` must be true or false", key)),
+        (ArgAction::Append, Value::Array(values)) => {
+            for v in values {
+                let v = scalar(v).ok_or_else(|| format!("`{}` must be a list of strings or numbers", key))?;
+                args.push(format!("--{}={}", long, v).into());
+            }
+        },
+        _ => {
+            let v = scalar(value).ok_or_else(|| format!("`{}` must be a string or a number", key))?;
+            args.push(format!("--{}={}", long, v).into());
+        },
+    }
+    return Ok(());
+}
+
+/// Options for the settings in the file that `service` has and the command line doesn't give,
+/// along with the IDs of the arguments they set
+fn file_args(command: &Command, file: &Table, service: &str, given: &ArgMatches) -> Result<(Vec<OsString>, Vec<String>), String> {
+    let empty = Table::new();
+    let section = file.get(service).and_then(Value::as_table).unwrap_or(&empty);
+    for name in section.keys() {
+        if command.get_arguments().filter_map(key).all(|k| &k != name) {
+            return Err(format!("Unknown setting `{}` in [{}]", name, service));
+        }
+    }
+    let mut args: Vec<OsString> = Vec::new();
+    let mut ids: Vec<String> = Vec::new();
+    for arg in command.get_arguments() {
+        let key = match key(arg) {
+            Some(k) => k,
+            None => continue,
+        };
+        let id = arg.get_id().as_str();
+        if given.value_source(id) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let value = match section.get(&key).or_else(|| file.get(&key).filter(|v| !v.is_table())) {
+            Some(v) => v,
+            None => continue,
+        };
+        push_value(arg, &key, value, &mut args)?;
+        ids.push(id.to_string());
+    }
+    return Ok((args, ids));
+}
+
+/// How a value from the parser is written in the file
+fn toml_value(raw: &str) -> Value {
+    if let Ok(i) = raw.parse::<i64>() {
+        return Value::Integer(i);
+    }
+    if let Ok(f) = raw.parse::<f64>() {
+        if f.is_finite() {
+            return Value::Float(f);
+        }
+    }
+    if let Ok(b) = raw.parse::<bool>() {
+        return Value::Boolean(b);
+    }
+    return Value::String(raw.to_string());
+}
+
+/// Prints the section of the file that would give the settings in `matches`
+fn print_config(command: &Command, matches: &ArgMatches, service: &str, path: &str, from_file: &[String]) {
+    println!("[{}]", service);
+    for arg in command.get_arguments() {
+        let key = match key(arg) {
+            Some(k) => k,
+            None => continue,
+        };
+        let id = arg.get_id().as_str();
+        let raw: Vec<String> = match matches.get_raw(id) {
+            Some(values) => values.map(|v| v.to_string_lossy().into_owned()).collect(),
+            None => continue,
+        };
+        let value = match arg.get_action() {
+            ArgAction::Append => Value::Array(raw.iter().map(|r| toml_value(r)).collect()),
+            _ => match raw.first() {
+                Some(r) => toml_value(r),
+                None => continue,
+            },
+        };
+        let source = match matches.value_source(id) {
+            Some(ValueSource::DefaultValue) => "default",
+            _ if from_file.iter().any(|f| f == id) => path,
+            _ => "command line",
+        };
+        println!("{} = {}  # {}", key, value, source);
+    }
+}
+
+/// The settings of a service, along with where they came from
+struct Parsed<A> {
+    args: A,
+    command: Command,
+    matches: ArgMatches,
+    /// Path of the configuration file, as it's shown in messages
+    path: String,
+    /// IDs of the arguments set by the file
+    from_file: Vec<String>,
+}
+
+/// Parses `cli`, the whole command line including the program name, over the file
+fn parse_with<A: Parser>(service: &str, cli: Vec<OsString>) -> Result<Parsed<A>, clap::Error> {
+    let mut command = A::command();
+    // What the command line gives, ignoring what it's missing until the file has filled it in
+    let given = command.clone().ignore_errors(true).try_get_matches_from(&cli)?;
+    let path = given.get_one::<PathBuf>("config").cloned();
+    let shown = path.as_ref().map_or(String::from(DEFAULT_PATH), |p| p.display().to_string());
+    let file = load(path.as_deref()).map_err(|e| command.error(ErrorKind::Io, e))?;
+    let (file_args, from_file) = file_args(&command, &file, service, &given)
+        .map_err(|e| command.error(ErrorKind::ValueValidation, format!("{} ({})", e, shown)))?;
+
+    // The file's options go first so the command line can't be mistaken for their values
+    let mut args: Vec<OsString> = cli.first().cloned().into_iter().collect();
+    args.extend(file_args);
+    args.extend(cli.iter().skip(1).cloned());
+    let matches = command.try_get_matches_from_mut(&args)?;
+    let parsed = A::from_arg_matches(&matches).map_err(|e| e.format(&mut command))?;
+    return Ok(Parsed { args: parsed, command, matches, path: shown, from_file });
+}
+
+/// Parses the command line of `service` over its settings in the configuration file, exiting on
+/// errors like `Parser::parse` does. With `--print-config`, prints the settings in effect and exits.
+pub fn parse<A: Parser>(service: &str) -> A {
+    let parsed: Parsed<A> = match parse_with(service, env::args_os().collect()) {
+        Ok(p) => p,
+        Err(e) => e.exit(),
+    };
+    if parsed.matches.get_flag("print_config") {
+        print_config(&parsed.command, &parsed.matches, service, &parsed.path, &parsed.from_file);
+        std::process::exit(0);
+    }
+    return parsed.args;
+}
+
+/// Parses the settings of `service` again, e.g. to pick up changes to the configuration file.
+/// The command line still wins over the file. Fails with what's wrong with the settings.
+pub fn try_parse<A: Parser>(service: &str) -> Result<A, String> {
+    match parse_with(service, env::args_os().collect()) {
+        Ok(p) => return Ok(p.args),
+        // Without the usage clap adds after a blank line
+        Err(e) => {
+            let message = e.to_string();
+            let error = message.split("\n\n").next().unwrap_or_default();
+            return Err(error.trim_start_matches("error: ").trim_end().to_string());
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        bus: BusArgs,
+        #[arg(short = 'd', long, default_value = "2")]
+        max_drift: f64,
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long)]
+        tag: Vec<String>,
+        #[command(flatten)]
+        config: ConfigArgs,
+    }
+
+    /// Parses `cli` as the timekeeper over a configuration file holding `text`
+    fn parse_file(name: &str, text: &str, cli: &[&str]) -> Result<Parsed<Args>, String> {
+        let path = env::temp_dir().join(format!("car_logger_config_{}_{}.toml", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let mut args: Vec<OsString> = vec!["timekeeper".into(), "--config".into(), path.clone().into()];
+        args.extend(cli.iter().map(OsString::from));
+        let parsed = parse_with::<Args>("timekeeper", args).map_err(|e| e.to_string());
+        fs::remove_file(&path).unwrap();
+        return parsed;
+    }
+
+    #[test]
+    fn command_line_wins_over_section_over_top_of_file() {
+        let text = "interface = \"can1\"\nbus_speed = 250000\n\n[timekeeper]\ninterface = \"can2\"\nmax_drift = 5.5\ndry_run = true\ntag = [\"a\", \"b\"]\n\n[recorder]\nbus_speed = 1000000\n";
+        let parsed = parse_file("precedence", text, &[]).unwrap();
+        assert_eq!(parsed.args.bus.interface, "can2");
+        assert_eq!(parsed.args.bus.bus_speed, 250000);
+        assert_eq!(parsed.args.max_drift, 5.5);
+        assert!(parsed.args.dry_run);
+        assert_eq!(parsed.args.tag, vec!["a", "b"]);
+        assert_eq!(parsed.from_file, vec!["name", "speed", "max_drift", "dry_run", "tag"]);
+
+        let parsed = parse_file("precedence_cli", text, &["-i", "vcan0", "--max-drift", "3", "--tag", "c"]).unwrap();
+        assert_eq!(parsed.args.bus.interface, "vcan0");
+        assert_eq!(parsed.args.bus.bus_speed, 250000);
+        assert_eq!(parsed.args.max_drift, 3.0);
+        assert_eq!(parsed.args.tag, vec!["c"]);
+        assert_eq!(parsed.from_file, vec!["speed", "dry_run"]);
+
+        let parsed = parse_file("precedence_dbc", "dbc = \"/etc/car.dbc\"\n", &[]).unwrap();
+        assert_eq!(parsed.args.bus.dbc, Some(PathBuf::from("/etc/car.dbc")));
+    }
+
+    #[test]
+    fn defaults_apply_without_settings() {
+        let parsed = parse_file("defaults", "", &[]).unwrap();
+        assert_eq!(parsed.args.bus.interface, "can0");
+        assert_eq!(parsed.args.bus.bus_speed, 500000);
+        assert_eq!(parsed.args.max_drift, 2.0);
+        assert!(!parsed.args.dry_run);
+        assert!(parsed.from_file.is_empty());
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        let error = parse_file("unknown_setting", "[timekeeper]\nmax_drfit = 5\n", &[]).err().unwrap();
+        assert!(error.contains("Unknown setting `max_drfit` in [timekeeper]"), "{}", error);
+        let error = parse_file("unknown_top", "interfce = \"can1\"\n", &[]).err().unwrap();
+        assert!(error.contains("Unknown setting `interfce` before the first section"), "{}", error);
+        // Only the bus settings are shared, even if this service has the option
+        let error = parse_file("service_option_at_top", "max_drift = 5\n", &[]).err().unwrap();
+        assert!(error.contains("Unknown setting `max_drift` before the first section"), "{}", error);
+        let error = parse_file("unknown_section", "[timekeeprr]\nmax_drift = 5\n", &[]).err().unwrap();
+        assert!(error.contains("Unknown section [timekeeprr]"), "{}", error);
+        let error = parse_file("bad_value", "[timekeeper]\ndry_run = \"yes\"\n", &[]).err().unwrap();
+        assert!(error.contains("`dry_run` must be true or false"), "{}", error);
+        let error = parse_file("invalid", "bus_speed = 0\n", &[]).err().unwrap();
+        assert!(error.contains("bus-speed"), "{}", error);
+    }
+
+    #[test]
+    fn missing_file_given_explicitly_is_an_error() {
+        let path = env::temp_dir().join(format!("car_logger_config_missing_{}.toml", std::process::id()));
+        let args: Vec<OsString> = vec!["timekeeper".into(), "--config".into(), path.into()];
+        assert!(parse_with::<Args>("timekeeper", args).is_err());
+    }
+}
@@ -0,0 +1,658 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use socketcan::{CanFrame, EmbeddedFrame, ExtendedId, Frame, Id, StandardId};
+
+use super::{DecodeError, ParsedFrame};
+
+/// Bit 31 of a DBC message ID marks it as an extended (29-bit) ID
+const DBC_EXTENDED_FLAG: u32 = 0x8000_0000;
+/// Pseudo-message CANdb++ uses to hold signals that aren't assigned to a real message
+const DBC_INDEPENDENT_SIGNALS: u32 = 0xC000_0000;
+
+/// Statements that may span several lines and always end with a semicolon
+const MULTILINE_KEYWORDS: [&str; 27] = [
+    "CM_", "BA_DEF_", "BA_DEF_DEF_", "BA_", "VAL_", "VAL_TABLE_", "SIG_VALTYPE_", "BO_TX_BU_",
+    "SIG_GROUP_", "EV_", "ENVVAR_DATA_", "SGTYPE_", "SGTYPE_VAL_", "SIG_TYPE_REF_", "BA_DEF_SGTYPE_",
+    "BA_SGTYPE_", "SG_MUL_VAL_", "BA_DEF_REL_", "BA_REL_", "BA_DEF_DEF_REL_", "BU_SG_REL_",
+    "BU_EV_REL_", "BU_BO_REL_", "CAT_DEF_", "CAT_", "FILTER", "SIGTYPE_VALTYPE_",
+];
+
+#[derive(Debug)]
+pub enum DbcError {
+    Io(std::io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for DbcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbcError::Io(e) => write!(f, "{}", e),
+            DbcError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for DbcError {}
+
+impl From<std::io::Error> for DbcError {
+    fn from(e: std::io::Error) -> DbcError {
+        DbcError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteOrder {
+    /// Intel; the start bit is the least significant bit
+    LittleEndian,
+    /// Motorola; the start bit is the most significant bit
+    BigEndian,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueType {
+    Unsigned,
+    Signed,
+    Float32,
+    Float64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Multiplex {
+    /// Always present
+    None,
+    /// Selects which multiplexed signals are present
+    Multiplexor,
+    /// Only present when the multiplexor has this value
+    Multiplexed(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct Signal {
+    pub name: String,
+    pub start_bit: u16,
+    pub size: u16,
+    pub byte_order: ByteOrder,
+    pub value_type: ValueType,
+    pub factor: f64,
+    pub offset: f64,
+    pub minimum: f64,
+    pub maximum: f64,
+    pub unit: String,
+    pub multiplex: Multiplex,
+    /// Descriptions for specific raw values, from `VAL_`
+    pub values: HashMap<i64, String>,
+    /// Number of payload bytes the signal needs to be present
+    min_len: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub id: u32,
+    pub extended: bool,
+    pub name: String,
+    pub size: usize,
+    pub transmitter: String,
+    pub signals: Vec<Signal>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DecodedSignal {
+    pub name: String,
+    /// Raw integer value, sign extended for signed signals
+    pub raw: i64,
+    /// Physical value after factor and offset
+    pub value: f64,
+    pub unit: String,
+    /// Value table description for the raw value, if any
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DecodedMessage {
+    pub id: u32,
+    pub extended: bool,
+    pub name: String,
+    pub signals: Vec<DecodedSignal>,
+}
+
+/// Message and signal definitions loaded from a Vector DBC file
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+    /// Keyed by the DBC message ID, which includes `DBC_EXTENDED_FLAG` for extended IDs
+    messages: HashMap<u32, Message>,
+    /// Named value tables from `VAL_TABLE_`
+    pub value_tables: HashMap<String, HashMap<i64, String>>,
+}
+
+/// Walks the bit positions of a signal, most significant bit first.
+/// Positions are numbered LSB0 within each byte, so bit 0 is the lowest bit of byte 0.
+fn signal_bits(start_bit: u16, size: u16, byte_order: ByteOrder) -> impl Iterator<Item = usize> {
+    let start = start_bit as usize;
+    let size = size as usize;
+    let mut pos = start;
+    (0..size).map(move |i| {
+        match byte_order {
+            ByteOrder::LittleEndian => start + size - 1 - i,
+            ByteOrder::BigEndian => {
+                let current = pos;
+                // Motorola signals run down a byte and continue at the top of the next one
+                pos = if pos.is_multiple_of(8) { pos + 15 } else { pos - 1 };
+                current
+            },
+        }
+    })
+}
+
+impl Signal {
+    /// Extracts the raw bits of the signal from a payload
+    pub fn raw_bits(&self, data: &[u8]) -> u64 {
+        let mut value: u64 = 0;
+        for pos in signal_bits(self.start_bit, self.size, self.byte_order) {
+            value = (value << 1) | ((data[pos / 8] >> (pos % 8)) & 1) as u64;
+        }
+        return value;
+    }
+
+    /// Writes the low `size` bits of `value` into a payload; the inverse of `raw_bits`
+    pub fn set_raw_bits(&self, data: &mut [u8], value: u64) {
+        for (i, pos) in signal_bits(self.start_bit, self.size, self.byte_order).enumerate() {
+            let bit = ((value >> (self.size as usize - 1 - i)) & 1) as u8;
+            data[pos / 8] = (data[pos / 8] & !(1 << (pos % 8))) | (bit << (pos % 8));
+        }
+    }
+
+    /// Interprets raw bits as an integer according to the signal's signedness
+    fn raw_integer(&self, bits: u64) -> i64 {
+        if self.value_type == ValueType::Signed && self.size < 64 && (bits >> (self.size - 1)) & 1 == 1 {
+            return (bits | (u64::MAX << self.size)) as i64;
+        }
+        return bits as i64;
+    }
+
+    /// Decodes the signal from a payload. Returns `None` if the payload is too short to hold it.
+    pub fn decode(&self, data: &[u8]) -> Option<DecodedSignal> {
+        if data.len() < self.min_len {
+            return None;
+        }
+        let bits = self.raw_bits(data);
+        let raw = self.raw_integer(bits);
+        let scaled: f64 = match self.value_type {
+            ValueType::Unsigned => bits as f64,
+            ValueType::Signed => raw as f64,
+            ValueType::Float32 => f32::from_bits(bits as u32) as f64,
+            ValueType::Float64 => f64::from_bits(bits),
+        };
+        return Some(DecodedSignal {
+            name: self.name.clone(),
+            raw,
+            value: scaled * self.factor + self.offset,
+            unit: self.unit.clone(),
+            label: self.values.get(&raw).cloned(),
+        });
+    }
+}
+
+impl Message {
+    /// Decodes every signal present in the payload, honoring multiplexing
+    pub fn decode(&self, data: &[u8]) -> DecodedMessage {
+        let mux: Option<u64> = self.signals.iter()
+            .find(|s| s.multiplex == Multiplex::Multiplexor && data.len() >= s.min_len)
+            .map(|s| s.raw_bits(data));
+        let signals = self.signals.iter()
+            .filter(|s| match s.multiplex {
+                Multiplex::Multiplexed(value) => mux == Some(value),
+                _ => true,
+            })
+            .filter_map(|s| s.decode(data))
+            .collect();
+        return DecodedMessage { id: self.id, extended: self.extended, name: self.name.clone(), signals };
+    }
+}
+
+impl Database {
+    /// The messages the built-in decoders in `parse_frame` handle, as `dbc/car.dbc` describes them
+    pub fn built_in() -> Database {
+        return Database::parse(include_str!("../dbc/car.dbc")).expect("dbc/car.dbc is valid");
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Database, DbcError> {
+        let text = std::fs::read_to_string(path)?;
+        return Database::parse(&text);
+    }
+
+    /// Parses the text of a DBC file
+    pub fn parse(text: &str) -> Result<Database, DbcError> {
+        let mut db = Database::default();
+        // The message that following SG_ lines belong to; None while skipping one
+        let mut current: Option<u32> = None;
+        let mut in_namespace = false;
+        let mut pending = String::new();
+        let mut pending_line: usize = 0;
+
+        for (index, raw_line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = raw_line.trim();
+            if !pending.is_empty() {
+                pending.push('\n');
+                pending.push_str(raw_line);
+                if statement_complete(&pending) {
+                    let statement = std::mem::take(&mut pending);
+                    db.parse_statement(&statement, pending_line)?;
+                }
+                continue;
+            }
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            // The NS_ section is a list of indented keywords that must not be parsed as statements
+            if in_namespace {
+                if raw_line.starts_with(char::is_whitespace) {
+                    continue;
+                }
+                in_namespace = false;
+            }
+            let keyword = line.split(|c: char| c.is_whitespace() || c == ':').next().unwrap_or("");
+            match keyword {
+                "NS_" => in_namespace = true,
+                "VERSION" | "BS_" | "BU_" => (),
+                "BO_" => current = db.parse_message(line, line_number)?,
+                "SG_" => {
+                    let signal = parse_signal(line, line_number)?;
+                    if let Some(id) = current {
+                        let message = db.messages.get_mut(&id).unwrap();
+                        if signal.min_len > message.size {
+                            return Err(parse_error(line_number, format!("signal {} does not fit in {} bytes", signal.name, message.size)));
+                        }
+                        message.signals.push(signal);
+                    }
+                },
+                k if MULTILINE_KEYWORDS.contains(&k) => {
+                    pending_line = line_number;
+                    if statement_complete(line) {
+                        db.parse_statement(line, line_number)?;
+                    } else {
+                        pending.push_str(line);
+                    }
+                },
+                _ => return Err(parse_error(line_number, format!("unknown keyword {}", keyword))),
+            }
+        }
+        if !pending.is_empty() {
+            return Err(parse_error(pending_line, String::from("unterminated statement")));
+        }
+        return Ok(db);
+    }
+
+    fn parse_message(&mut self, line: &str, line_number: usize) -> Result<Option<u32>, DbcError> {
+        // BO_ <id> <name>: <size> <transmitter>
+        let (head, tail) = line.split_once(':').ok_or_else(|| parse_error(line_number, String::from("expected ':' in message")))?;
+        let head: Vec<&str> = head.split_whitespace().collect();
+        let tail: Vec<&str> = tail.split_whitespace().collect();
+        if head.len() != 3 || tail.is_empty() {
+            return Err(parse_error(line_number, String::from("malformed message definition")));
+        }
+        let dbc_id: u32 = parse_number(head[1], line_number)?;
+        if dbc_id == DBC_INDEPENDENT_SIGNALS {
+            return Ok(None);
+        }
+        let size: usize = parse_number(tail[0], line_number)?;
+        if self.messages.contains_key(&dbc_id) {
+            return Err(parse_error(line_number, format!("duplicate message ID {}", dbc_id)));
+        }
+        self.messages.insert(dbc_id, Message {
+            id: dbc_id & !DBC_EXTENDED_FLAG,
+            extended: dbc_id & DBC_EXTENDED_FLAG != 0,
+            name: head[2].to_string(),
+            size,
+            transmitter: tail.get(1).unwrap_or(&"").to_string(),
+            signals: Vec::new(),
+        });
+        return Ok(Some(dbc_id));
+    }
+
+    fn parse_statement(&mut self, statement: &str, line_number: usize) -> Result<(), DbcError> {
+        let tokens = tokenize(statement, line_number)?;
+        match tokens[0].as_str() {
+            "VAL_" => {
+                // VAL_ <message id> <signal> (<value> "<description>")* ;
+                // Value descriptions for environment variables have no message ID and are ignored
+                if tokens.len() < 3 || tokens[1].parse::<u32>().is_err() {
+                    return Ok(());
+                }
+                let dbc_id: u32 = parse_number(&tokens[1], line_number)?;
+                if dbc_id == DBC_INDEPENDENT_SIGNALS {
+                    return Ok(());
+                }
+                let values = parse_value_pairs(&tokens[3..], line_number)?;
+                let signal = self.signal_mut(dbc_id, &tokens[2], line_number)?;
+                signal.values = values;
+            },
+            "VAL_TABLE_" => {
+                if tokens.len() < 2 {
+                    return Err(parse_error(line_number, String::from("value table has no name")));
+                }
+                let values = parse_value_pairs(&tokens[2..], line_number)?;
+                self.value_tables.insert(tokens[1].clone(), values);
+            },
+            "SIG_VALTYPE_" => {
+                // SIG_VALTYPE_ <message id> <signal> : <1 = float, 2 = double> ;
+                let fields: Vec<&String> = tokens.iter().filter(|t| *t != ":" && *t != ";").collect();
+                if fields.len() != 4 {
+                    return Err(parse_error(line_number, String::from("malformed SIG_VALTYPE_")));
+                }
+                let dbc_id: u32 = parse_number(fields[1], line_number)?;
+                if dbc_id == DBC_INDEPENDENT_SIGNALS {
+                    return Ok(());
+                }
+                let value_type = match fields[3].as_str() {
+                    "0" => None,
+                    "1" => Some((ValueType::Float32, 32)),
+                    "2" => Some((ValueType::Float64, 64)),
+                    other => return Err(parse_error(line_number, format!("unknown signal value type {}", other))),
+                };
+                let signal = self.signal_mut(dbc_id, fields[2], line_number)?;
+                if let Some((value_type, size)) = value_type {
+                    if signal.size != size {
+                        return Err(parse_error(line_number, format!("signal {} is {} bits but declared {}-bit float", signal.name, signal.size, size)));
+                    }
+                    signal.value_type = value_type;
+                }
+            },
+            // Comments, attributes and the rest carry nothing needed for decoding
+            _ => (),
+        }
+        return Ok(());
+    }
+
+    fn signal_mut(&mut self, dbc_id: u32, name: &str, line_number: usize) -> Result<&mut Signal, DbcError> {
+        let message = self.messages.get_mut(&dbc_id)
+            .ok_or_else(|| parse_error(line_number, format!("unknown message ID {}", dbc_id)))?;
+        return message.signals.iter_mut().find(|s| s.name == name)
+            .ok_or_else(|| parse_error(line_number, format!("unknown signal {} in message ID {}", name, dbc_id)));
+    }
+
+    /// Looks up a message by its CAN ID
+    pub fn message(&self, id: u32, extended: bool) -> Option<&Message> {
+        let dbc_id = if extended { id | DBC_EXTENDED_FLAG } else { id };
+        return self.messages.get(&dbc_id);
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &Message> {
+        return self.messages.values();
+    }
+
+    /// Decodes every signal of a data frame whose ID is in the database
+    pub fn decode(&self, frame: &CanFrame) -> Option<DecodedMessage> {
+        match frame {
+            CanFrame::Data(_) => (),
+            _ => return None,
+        }
+        let message = self.message(frame.raw_id(), frame.is_extended())?;
+        return Some(message.decode(frame.data()));
+    }
+
+    /// Packs decoded signals back into a frame using their raw values.
+    /// Signals missing from `decoded` are left as zero. Returns `None` if the message
+    /// isn't in the database or doesn't fit in a classic frame.
+    pub fn encode(&self, decoded: &DecodedMessage) -> Option<CanFrame> {
+        let message = self.message(decoded.id, decoded.extended)?;
+        if message.size > 8 {
+            return None;
+        }
+        let mut data = vec![0u8; message.size];
+        for signal in message.signals.iter() {
+            if let Some(value) = decoded.signals.iter().find(|s| s.name == signal.name) {
+                signal.set_raw_bits(&mut data, value.raw as u64);
+            }
+        }
+        let id: Id = if message.extended {
+            ExtendedId::new(message.id)?.into()
+        } else {
+            StandardId::new(message.id as u16)?.into()
+        };
+        return CanFrame::new(id, &data);
+    }
+
+    /// Parses a frame with the database, falling back to the built-in decoders for messages it doesn't have.
+    /// A message in the database is always decoded from it, even when there's a built-in decoder for its ID.
+    pub fn parse_frame(&self, frame: CanFrame) -> Result<ParsedFrame, DecodeError> {
+        match self.decode(&frame) {
+            Some(message) => return Ok(ParsedFrame::Signals(message)),
+            None => return super::parse_frame(frame),
+        }
+    }
+
+    /// Parses a frame with the built-in decoders, falling back to the database for IDs they don't know.
+    /// Messages with a built-in decoder keep their typed variant even when the database has them too.
+    pub fn parse_unknown(&self, frame: CanFrame) -> Result<ParsedFrame, DecodeError> {
+        match super::parse_frame(frame) {
+            Err(e @ DecodeError::UnknownId(_)) => match self.decode(&frame) {
+                Some(message) => return Ok(ParsedFrame::Signals(message)),
+                None => return Err(e),
+            },
+            parsed => return parsed,
+        }
+    }
+}
+
+fn parse_error(line: usize, message: String) -> DbcError {
+    return DbcError::Parse { line, message };
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str, line_number: usize) -> Result<T, DbcError> {
+    return text.trim().parse::<T>().map_err(|_| parse_error(line_number, format!("invalid number '{}'", text.trim())));
+}
+
+/// Returns true once a statement has a semicolon outside of any quoted string
+fn statement_complete(statement: &str) -> bool {
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in statement.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => return true,
+            _ => (),
+        }
+    }
+    return false;
+}
+
+/// Splits a statement into words, quoted strings (without quotes), ':' and ';'
+fn tokenize(statement: &str, line_number: usize) -> Result<Vec<String>, DbcError> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut chars = statement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            ':' | ';' => tokens.push(c.to_string()),
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => if let Some(e) = chars.next() { s.push(e) },
+                        Some('"') => break,
+                        Some(e) => s.push(e),
+                        None => return Err(parse_error(line_number, String::from("unterminated string"))),
+                    }
+                }
+                tokens.push(s);
+            },
+            _ => {
+                let mut s = c.to_string();
+                while let Some(&n) = chars.peek() {
+                    if n.is_whitespace() || n == ':' || n == ';' || n == '"' {
+                        break;
+                    }
+                    s.push(n);
+                    chars.next();
+                }
+                tokens.push(s);
+            },
+        }
+    }
+    return Ok(tokens);
+}
+
+/// Parses `<value> "<description>"` pairs up to the closing semicolon
+fn parse_value_pairs(tokens: &[String], line_number: usize) -> Result<HashMap<i64, String>, DbcError> {
+    let mut values = HashMap::new();
+    let mut iter = tokens.iter().take_while(|t| *t != ";");
+    while let Some(value) = iter.next() {
+        let description = iter.next().ok_or_else(|| parse_error(line_number, format!("value {} has no description", value)))?;
+        // Some tools write values as floats, e.g. "1.0"
+        let value: i64 = match value.parse::<i64>() {
+            Ok(v) => v,
+            Err(_) => parse_number::<f64>(value, line_number)? as i64,
+        };
+        values.insert(value, description.clone());
+    }
+    return Ok(values);
+}
+
+/// Parses `SG_ <name> [M|m<n>] : <start>|<size>@<order><sign> (<factor>,<offset>) [<min>|<max>] "<unit>" <receivers>`
+fn parse_signal(line: &str, line_number: usize) -> Result<Signal, DbcError> {
+    let malformed = || parse_error(line_number, String::from("malformed signal definition"));
+    let (head, tail) = line.split_once(':').ok_or_else(malformed)?;
+    let head: Vec<&str> = head.split_whitespace().collect();
+    let multiplex = match head.len() {
+        2 => Multiplex::None,
+        3 if head[2] == "M" => Multiplex::Multiplexor,
+        // Extended multiplexing ("m1M") is treated as plain multiplexing
+        3 if head[2].starts_with('m') => {
+            let value = head[2][1..].trim_end_matches('M');
+            Multiplex::Multiplexed(parse_number(value, line_number)?)
+        },
+        _ => return Err(malformed()),
+    };
+    let name = head[1].to_string();
+
+    let (start_bit, tail) = tail.split_once('|').ok_or_else(malformed)?;
+    let (size, tail) = tail.split_once('@').ok_or_else(malformed)?;
+    let mut rest = tail.chars();
+    let byte_order = match rest.next() {
+        Some('0') => ByteOrder::BigEndian,
+        Some('1') => ByteOrder::LittleEndian,
+        _ => return Err(malformed()),
+    };
+    let value_type = match rest.next() {
+        Some('+') => ValueType::Unsigned,
+        Some('-') => ValueType::Signed,
+        _ => return Err(malformed()),
+    };
+    let tail = rest.as_str().trim_start().strip_prefix('(').ok_or_else(malformed)?;
+    let (factor, tail) = tail.split_once(',').ok_or_else(malformed)?;
+    let (offset, tail) = tail.split_once(')').ok_or_else(malformed)?;
+    let tail = tail.trim_start().strip_prefix('[').ok_or_else(malformed)?;
+    let (minimum, tail) = tail.split_once('|').ok_or_else(malformed)?;
+    let (maximum, tail) = tail.split_once(']').ok_or_else(malformed)?;
+    let tail = tail.trim_start().strip_prefix('"').ok_or_else(malformed)?;
+    let (unit, _receivers) = tail.split_once('"').ok_or_else(malformed)?;
+
+    let start_bit: u16 = parse_number(start_bit, line_number)?;
+    let size: u16 = parse_number(size, line_number)?;
+    if size == 0 || size > 64 {
+        return Err(parse_error(line_number, format!("signal {} has unsupported size {}", name, size)));
+    }
+    let mut min_len: usize = 0;
+    for pos in signal_bits(start_bit, size, byte_order) {
+        min_len = min_len.max(pos / 8 + 1);
+    }
+    return Ok(Signal {
+        name,
+        start_bit,
+        size,
+        byte_order,
+        value_type,
+        factor: parse_number(factor, line_number)?,
+        offset: parse_number(offset, line_number)?,
+        minimum: parse_number(minimum, line_number)?,
+        maximum: parse_number(maximum, line_number)?,
+        unit: unit.to_string(),
+        multiplex,
+        values: HashMap::new(),
+        min_len,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uom::si::electric_potential::volt;
+
+    fn car() -> Database {
+        return Database::built_in();
+    }
+
+    fn frame(id: u16, data: [u8; 8]) -> CanFrame {
+        return CanFrame::new(StandardId::new(id).unwrap(), &data).unwrap();
+    }
+
+    fn signal(parsed: &ParsedFrame, name: &str) -> f64 {
+        match parsed {
+            ParsedFrame::Signals(m) => return m.signals.iter().find(|s| s.name == name).unwrap().value,
+            other => panic!("expected signals, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn database_messages_take_the_place_of_built_in_decoders() {
+        let odometer = frame(0x430, [0x00, 0x01, 0xE2, 0x40, 0, 0, 0, 0]);
+        let parsed = car().parse_frame(odometer).unwrap();
+        assert_eq!(parsed.name(), "Odometer");
+        assert_eq!(signal(&parsed, "Odometer"), 123456.0);
+        match super::super::parse_frame(odometer).unwrap() {
+            ParsedFrame::_430 { .. } => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn built_in_decoders_handle_messages_the_database_lacks() {
+        let db = Database::parse("BO_ 1536 Other: 1 Vector__XXX\n SG_ Value : 7|8@0+ (1,0) [0|255] \"\" Vector__XXX\n").unwrap();
+        match db.parse_frame(frame(0x43D, [0, 0, 0, 0, 0, 0, 127, 0])).unwrap() {
+            ParsedFrame::_43D { accessory_battery_v } => assert!((accessory_battery_v.get::<volt>() - 12.7).abs() < 1e-4),
+            other => panic!("unexpected {:?}", other),
+        }
+        match db.parse_frame(frame(0x123, [0; 8])) {
+            Err(DecodeError::UnknownId(0x123)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_unknown_keeps_built_in_decoders_first() {
+        let db = Database::parse(concat!(
+            "BO_ 1085 Battery: 8 Vector__XXX\n SG_ Volts : 55|8@0+ (1,0) [0|255] \"V\" Vector__XXX\n",
+            "BO_ 1536 Other: 1 Vector__XXX\n SG_ Value : 7|8@0+ (1,0) [0|255] \"\" Vector__XXX\n",
+        )).unwrap();
+        match db.parse_unknown(frame(0x43D, [0, 0, 0, 0, 0, 0, 127, 0])).unwrap() {
+            ParsedFrame::_43D { .. } => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(signal(&db.parse_unknown(frame(0x600, [42, 0, 0, 0, 0, 0, 0, 0])).unwrap(), "Value"), 42.0);
+        match db.parse_unknown(frame(0x123, [0; 8])) {
+            Err(DecodeError::UnknownId(0x123)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn car_database_agrees_with_built_in_decoders() {
+        let db = car();
+        let battery = db.parse_frame(frame(0x43D, [0, 0, 0, 0, 0, 0, 127, 0])).unwrap();
+        assert_eq!(battery.name(), "AccessoryBattery");
+        assert!((signal(&battery, "AccessoryBattery") - 12.7).abs() < 1e-9);
+
+        // Negative coordinates subtract their minutes from the degrees
+        let location = super::super::encode_frame(&ParsedFrame::_465(geoutils::Location::new(-33.5, 150.75))).unwrap();
+        let parsed = db.parse_frame(location).unwrap();
+        assert_eq!(signal(&parsed, "LatitudeDegrees"), -33.0);
+        assert_eq!(signal(&parsed, "LatitudeMinutes"), 30.0);
+        assert_eq!(signal(&parsed, "LongitudeDegrees"), 150.0);
+        assert_eq!(signal(&parsed, "LongitudeMinutes"), 45.0);
+    }
+}
@@ -0,0 +1,1296 @@
+//! Decoding, logging and the common parts of the CAN bus services.
+//!
+//! `parse_frame` and `encode_frame` translate the car's messages, `Logger` writes logs in the
+//! formats in `LogFormat`, and `service` runs a service that reacts to decoded frames from one
+//! interface. The binaries in `src/bin` are built on these.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::io::{self, BufWriter, Result, Write};
+use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc};
+use geoutils::Location;
+use serde::Serialize;
+use socketcan::{CanAnyFrame, CanFrame, EmbeddedFrame, StandardId, Frame};
+use uom::si::acceleration::meter_per_second_squared;
+use uom::si::angle::degree;
+use uom::si::angular_velocity::{radian_per_second, revolution_per_minute};
+use uom::si::electric_potential::{decivolt, volt};
+use uom::si::f32::*;
+use uom::si::length::{hectometer, kilometer};
+use uom::si::power::watt;
+use uom::si::velocity::mile_per_hour;
+
+pub mod asc;
+pub mod binlog;
+pub mod blf;
+pub mod buses;
+pub mod candump;
+pub mod channels;
+pub mod compression;
+pub mod config;
+pub mod dbc;
+pub mod mdf;
+pub mod meta;
+pub mod pcapng;
+pub mod queue;
+pub mod recovery;
+pub mod retention;
+pub mod rotation;
+pub mod service;
+pub mod systemd;
+pub mod timestamps;
+pub mod trigger;
+
+use channels::ChannelMap;
+use compression::{Compression, Encoder};
+use meta::Summary;
+use timestamps::ClockSource;
+
+/// Format of the logs written by `Logger`
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// candump-style text
+    Candump,
+    /// Compact binary records with a seek index, see `binlog`
+    Binary,
+    /// Vector ASC text
+    Asc,
+    /// Vector BLF
+    Blf,
+    /// pcapng for Wireshark
+    Pcapng,
+}
+
+impl LogFormat {
+    /// Extension of logs written in this format, before any compression suffix
+    pub fn extension(self) -> &'static str {
+        match self {
+            LogFormat::Candump => ".log",
+            LogFormat::Binary => ".bin",
+            LogFormat::Asc => ".asc",
+            LogFormat::Blf => ".blf",
+            LogFormat::Pcapng => ".pcapng",
+        }
+    }
+
+    /// Whether logs in this format can be compressed with `Compression`
+    pub fn compressible(self) -> bool {
+        match self {
+            LogFormat::Candump | LogFormat::Asc | LogFormat::Pcapng => true,
+            // The binary index refers to offsets in the file, and BLF is already compressed
+            LogFormat::Binary | LogFormat::Blf => false,
+        }
+    }
+}
+
+enum Output {
+    Candump(BufWriter<Encoder>),
+    Binary(binlog::Writer<BufWriter<Encoder>>),
+    Asc(asc::Writer<BufWriter<Encoder>>),
+    Blf(blf::Writer<BufWriter<File>>),
+    Pcapng(pcapng::Writer<BufWriter<Encoder>>),
+}
+
+/// Why a log was closed
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RotationReason {
+    /// The bus went quiet
+    Timeout,
+    /// The log reached its maximum number of lines
+    MaxLines,
+    /// SIGHUP asked for a new log
+    Hangup,
+    /// A trigger started a new log
+    Trigger,
+    /// The clock reached the next rotation interval
+    Interval,
+    /// The log reached its maximum size
+    MaxSize,
+    /// The ignition was switched off
+    Ignition,
+    /// The recorder is shutting down
+    Shutdown,
+    /// Writing the log failed
+    Error,
+    /// The recorder stopped without closing the log, which was repaired when it started again
+    Recovered,
+}
+
+impl fmt::Display for RotationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RotationReason::Timeout => write!(f, "bus timeout"),
+            RotationReason::MaxLines => write!(f, "maximum lines reached"),
+            RotationReason::Hangup => write!(f, "SIGHUP"),
+            RotationReason::Trigger => write!(f, "trigger"),
+            RotationReason::Interval => write!(f, "rotation interval"),
+            RotationReason::MaxSize => write!(f, "maximum size reached"),
+            RotationReason::Ignition => write!(f, "ignition off"),
+            RotationReason::Shutdown => write!(f, "shutdown"),
+            RotationReason::Error => write!(f, "write error"),
+            RotationReason::Recovered => write!(f, "recovered after an unclean stop"),
+        }
+    }
+}
+
+/// When `Logger` makes sure what it's written is on the disk, besides when the log is flushed or closed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SyncPolicy {
+    /// Time between syncs
+    pub interval: Option<Duration>,
+    /// Frames between syncs
+    pub frames: Option<u64>,
+}
+
+/// Writes a log under its name with `recovery::PARTIAL_SUFFIX` added, and renames it once it's closed
+pub struct Logger {
+    /// Only taken by `close`
+    out: Option<Output>,
+    path: String,
+    /// The log file, for syncing it to the disk
+    file: File,
+    sync: SyncPolicy,
+    last_sync: Instant,
+    /// Frames written since the last sync
+    unsynced: u64,
+    /// What's been logged, for the metadata file
+    summary: Summary,
+    /// Interfaces frames can come from, in the order the recorder numbers them
+    ifaces: Vec<String>,
+}
+
+/// Creates the partial file of a new log at `path`, failing rather than writing over or into
+/// another log, finished or not
+fn create_log(path: &str, partial: &str) -> Result<File> {
+    if Path::new(path).exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path)));
+    }
+    return OpenOptions::new().write(true).create_new(true).open(partial);
+}
+
+/// Starts the log's format in `file`, written through a buffer of `buf_size` bytes
+fn open_output(file: File, path: &str, ifaces: &[String], buf_size: usize, compression: Compression, format: LogFormat) -> Result<Output> {
+    match format {
+        LogFormat::Candump => {
+            return Ok(Output::Candump(BufWriter::with_capacity(buf_size, Encoder::new(file, compression)?)));
+        },
+        LogFormat::Binary => {
+            // The index is named after the finished log
+            let fd = BufWriter::with_capacity(buf_size, Encoder::new(file, compression)?);
+            let names: Vec<&str> = ifaces.iter().map(|i| i.as_str()).collect();
+            return Ok(Output::Binary(binlog::Writer::new(fd, path, &names)?));
+        },
+        LogFormat::Asc => {
+            let fd = BufWriter::with_capacity(buf_size, Encoder::new(file, compression)?);
+            return Ok(Output::Asc(asc::Writer::new(fd, ChannelMap::new())));
+        },
+        LogFormat::Blf => {
+            // The header at the start is rewritten as the log grows
+            return Ok(Output::Blf(blf::Writer::new(BufWriter::with_capacity(buf_size, file), ChannelMap::new())?));
+        },
+        LogFormat::Pcapng => {
+            // Every log starts a new section
+            let fd = BufWriter::with_capacity(buf_size, Encoder::new(file, compression)?);
+            return Ok(Output::Pcapng(pcapng::Writer::new(fd)?));
+        },
+    }
+}
+
+impl Logger {
+    /// Starts a new log at `path`. Fails if the log can't be created, e.g. when the disk is full or
+    /// there's already a log by that name; nothing is left behind when it does.
+    pub fn new(path: String, ifaces: Vec<String>, buf_size: usize, compression: Compression, format: LogFormat) -> Result<Logger> {
+        let partial = recovery::partial_path(&path);
+        let file = create_log(&path, &partial)?;
+        let opened = file.try_clone().and_then(|sync_file| Ok((sync_file, open_output(file, &path, &ifaces, buf_size, compression, format)?)));
+        let (sync_file, out) = match opened {
+            Ok(o) => o,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            },
+        };
+        return Ok(Logger {
+            summary: Summary::new(ifaces.clone()),
+            ifaces,
+            out: Some(out),
+            path,
+            file: sync_file,
+            sync: SyncPolicy::default(),
+            last_sync: Instant::now(),
+            unsynced: 0,
+        });
+    }
+
+    /// Sets how often the log is synced to the disk while frames are written
+    pub fn set_sync(&mut self, sync: SyncPolicy) {
+        self.sync = sync;
+    }
+
+    /// Records the version and bus speed of the recorder in the metadata file
+    pub fn set_recorder(&mut self, version: &str, bitrate: u64) {
+        self.summary.set_recorder(version, bitrate);
+    }
+
+    /// Writes a frame received on the interface numbered `iface` in the list given to `new`, with its time from `source`
+    pub fn log(&mut self, iface: usize, f: CanAnyFrame, t: Duration, source: ClockSource) -> Result<usize> {
+        self.summary.frame(&f, t);
+        self.summary.timestamp(iface, source);
+        let name = &self.ifaces[iface];
+        let written = match self.out.as_mut() {
+            Some(Output::Candump(fd)) => candump::write_frame(fd, name, &f, t)?,
+            Some(Output::Binary(w)) => w.write_frame(iface as u8, &f, t)?,
+            Some(Output::Asc(w)) => w.write_frame(name, &f, t)?,
+            Some(Output::Blf(w)) => w.write_frame(name, &f, t)?,
+            Some(Output::Pcapng(w)) => w.write_frame(name, &f, t)?,
+            None => return Ok(0),
+        };
+        self.unsynced += 1;
+        if self.sync.frames.is_some_and(|n| self.unsynced >= n) || self.sync.interval.is_some_and(|i| self.last_sync.elapsed() >= i) {
+            self.sync()?;
+        }
+        return Ok(written);
+    }
+
+    /// Notes that the kernel dropped `count` frames on interface `iface` just before `t`, in the metadata file and
+    /// in the log: a comment in text and BLF logs, a marker record in binary logs and a statistics block in pcapng.
+    pub fn log_dropped(&mut self, iface: usize, count: u64, t: Duration) -> Result<usize> {
+        self.summary.dropped(count);
+        let name = &self.ifaces[iface];
+        let text = format!("{}: {} frames dropped", name, count);
+        match self.out.as_mut() {
+            Some(Output::Candump(fd)) => {
+                let line = candump::format_comment(t, &text);
+                fd.write_all(line.as_bytes())?;
+                return Ok(line.len());
+            },
+            Some(Output::Binary(w)) => return w.write_dropped(iface as u8, count, t),
+            Some(Output::Asc(w)) => return w.write_comment(t, &text),
+            Some(Output::Blf(w)) => return w.write_comment(t, &text),
+            Some(Output::Pcapng(w)) => return w.write_drops(name, count, t),
+            None => return Ok(0),
+        }
+    }
+
+    /// Records the most frames that waited for the writer and how many the queue dropped, for the metadata file
+    pub fn queue_stats(&mut self, max_depth: usize, dropped: u64) {
+        self.summary.queue_stats(max_depth, dropped);
+    }
+
+    /// Writes out buffered frames and updates the index of binary logs. Compressed logs end the current block
+    /// so they can be read up to this point. Rewriting the index takes longer the longer the log is, so this
+    /// is meant for when the bus goes quiet.
+    pub fn flush(&mut self) -> Result<()> {
+        self.flush_frames()?;
+        if let Some(Output::Binary(w)) = self.out.as_ref() {
+            w.save_index()?;
+        }
+        return Ok(());
+    }
+
+    /// Writes out buffered frames, ending the current block of compressed logs
+    fn flush_frames(&mut self) -> Result<()> {
+        match self.out.as_mut() {
+            Some(Output::Candump(fd)) => fd.flush(),
+            Some(Output::Binary(w)) => w.flush(),
+            Some(Output::Asc(w)) => w.flush(),
+            Some(Output::Blf(w)) => w.flush(),
+            Some(Output::Pcapng(w)) => w.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes out buffered frames and waits for them to reach the disk. The index of a binary log isn't
+    /// updated, since recovery rebuilds it if the log isn't closed.
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush_frames()?;
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        self.unsynced = 0;
+        return Ok(());
+    }
+
+    /// Flushes the log and finishes the compressed stream, syncs it and gives it its real name, then writes the
+    /// metadata file. Candump, ASC and BLF logs end with a comment giving `reason`, and pcapng logs with
+    /// statistics blocks that do, at the time of the last frame.
+    pub fn close(mut self, reason: RotationReason) -> Result<()> {
+        let out = match self.out.take() {
+            Some(out) => out,
+            None => return Ok(()),
+        };
+        // A log that couldn't be finished keeps its partial name, to be recovered on the next start
+        // Logs without frames note the time they were closed instead
+        let end = self.summary.end().unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default());
+        let finished = Logger::finish(out, &format!("Log closed: {}", reason), end)
+            .and_then(|_| self.file.sync_all())
+            .and_then(|_| fs::rename(recovery::partial_path(&self.path), &self.path));
+        // The metadata is still worth having if the log couldn't be finished cleanly
+        let meta = self.summary.meta(reason).write(&self.path);
+        return finished.and(meta);
+    }
+
+    fn finish(out: Output, comment: &str, end: Duration) -> Result<()> {
+        let fd = match out {
+            Output::Candump(mut fd) => {
+                fd.write_all(candump::format_comment(end, comment).as_bytes())?;
+                fd
+            },
+            Output::Binary(w) => w.finish()?,
+            Output::Asc(mut w) => {
+                w.write_comment(end, comment)?;
+                w.finish()?
+            },
+            Output::Blf(mut w) => {
+                w.write_comment(end, comment)?;
+                w.finish()?.into_inner().map_err(|e| e.into_error())?;
+                return Ok(());
+            },
+            Output::Pcapng(w) => w.finish(comment)?,
+        };
+        let encoder = fd.into_inner().map_err(|e| e.into_error())?;
+        encoder.finish()?;
+        return Ok(());
+    }
+}
+
+impl Drop for Logger {
+    /// A log that's dropped without being closed, e.g. when its writer thread panics, still gets what was
+    /// buffered onto the disk. It keeps its partial name, so it's recovered on the next start.
+    fn drop(&mut self) {
+        if self.out.is_some() {
+            let _ = self.sync();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompassDirection {
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+}
+#[derive(Clone, Debug)]
+pub enum ParsedFrame {
+    _084 (NaiveDateTime),
+    _091 {pitch: AngularVelocity, roll: AngularVelocity, yaw: AngularVelocity},
+    _092 {lateral: Acceleration, longitudinal: Acceleration, vertical: Acceleration},
+    _217 {fl: AngularVelocity, fr: AngularVelocity, rl: AngularVelocity, rr: AngularVelocity},
+    _352 {electric_range: Length},
+    _368 {ac_power_w: Power, other_power_w: Power},
+    _37B {gas_range: Length},
+    _430 {odometer: Length},
+    _43D {accessory_battery_v: ElectricPotential},
+    _465 (Location),
+    _466 (DateTime<Utc>),
+    _467 {direction: CompassDirection, compass_heading: Angle, gps_vehicle_speed: Velocity},
+    _472 (NaiveDateTime),
+    _473 (NaiveDateTime),
+    /// Signals of a message decoded through a DBC database
+    Signals(dbc::DecodedMessage),
+}
+
+/// A decoded value as a plain number, e.g. for exporting
+#[derive(Clone, Debug)]
+pub struct PhysicalValue {
+    pub name: String,
+    pub value: f64,
+    pub unit: String,
+}
+
+impl PhysicalValue {
+    fn new(name: &str, value: f64, unit: &str) -> PhysicalValue {
+        PhysicalValue { name: name.to_string(), value, unit: unit.to_string() }
+    }
+}
+
+impl ParsedFrame {
+    /// Name of the message, as used in `dbc/car.dbc`
+    pub fn name(&self) -> String {
+        return String::from(match self {
+            ParsedFrame::_084(_) => "Clock",
+            ParsedFrame::_091 { .. } => "Gyroscope",
+            ParsedFrame::_092 { .. } => "Accelerometer",
+            ParsedFrame::_217 { .. } => "WheelSpeed",
+            ParsedFrame::_352 { .. } => "ElectricRange",
+            ParsedFrame::_368 { .. } => "PowerUsage",
+            ParsedFrame::_37B { .. } => "GasRange",
+            ParsedFrame::_430 { .. } => "Odometer",
+            ParsedFrame::_43D { .. } => "AccessoryBattery",
+            ParsedFrame::_465(_) => "GpsPosition",
+            ParsedFrame::_466(_) => "GpsTime",
+            ParsedFrame::_467 { .. } => "GpsHeading",
+            ParsedFrame::_472(_) => "ChargeFinishTime",
+            ParsedFrame::_473(_) => "ChargeStartTime",
+            ParsedFrame::Signals(m) => return m.name.clone(),
+        });
+    }
+
+    /// The decoded values, named and scaled like the signals of `dbc/car.dbc` so a signal is spelled
+    /// the same whichever decoder handled the frame
+    pub fn values(&self) -> Vec<PhysicalValue> {
+        match self {
+            ParsedFrame::_084(t) => vec![
+                PhysicalValue::new("Year", t.year() as f64, ""),
+                PhysicalValue::new("DayOfYear", t.ordinal() as f64, ""),
+                PhysicalValue::new("Minute", t.minute() as f64, "min"),
+                PhysicalValue::new("Second", t.second() as f64, "s"),
+                PhysicalValue::new("Hour", t.hour() as f64, "h"),
+            ],
+            ParsedFrame::_091 { pitch, roll, yaw } => vec![
+                PhysicalValue::new("Pitch", pitch.get::<radian_per_second>() as f64, "rad/s"),
+                PhysicalValue::new("Roll", roll.get::<radian_per_second>() as f64, "rad/s"),
+                PhysicalValue::new("Yaw", yaw.get::<radian_per_second>() as f64, "rad/s"),
+            ],
+            ParsedFrame::_092 { lateral, longitudinal, vertical } => vec![
+                PhysicalValue::new("Lateral", lateral.get::<meter_per_second_squared>() as f64, "m/s^2"),
+                PhysicalValue::new("Longitudinal", longitudinal.get::<meter_per_second_squared>() as f64, "m/s^2"),
+                PhysicalValue::new("Vertical", vertical.get::<meter_per_second_squared>() as f64, "m/s^2"),
+            ],
+            ParsedFrame::_217 { fl, fr, rl, rr } => vec![
+                PhysicalValue::new("FrontLeft", fl.get::<revolution_per_minute>() as f64, "rpm"),
+                PhysicalValue::new("FrontRight", fr.get::<revolution_per_minute>() as f64, "rpm"),
+                PhysicalValue::new("RearLeft", rl.get::<revolution_per_minute>() as f64, "rpm"),
+                PhysicalValue::new("RearRight", rr.get::<revolution_per_minute>() as f64, "rpm"),
+            ],
+            ParsedFrame::_352 { electric_range } => vec![PhysicalValue::new("ElectricRange", electric_range.get::<kilometer>() as f64, "km")],
+            ParsedFrame::_368 { ac_power_w, other_power_w } => vec![
+                PhysicalValue::new("AirConditioning", ac_power_w.get::<watt>() as f64, "W"),
+                PhysicalValue::new("Other", other_power_w.get::<watt>() as f64, "W"),
+            ],
+            ParsedFrame::_37B { gas_range } => vec![PhysicalValue::new("GasRange", gas_range.get::<kilometer>() as f64, "km")],
+            ParsedFrame::_430 { odometer } => vec![PhysicalValue::new("Odometer", odometer.get::<kilometer>() as f64, "km")],
+            ParsedFrame::_43D { accessory_battery_v } => vec![PhysicalValue::new("AccessoryBattery", accessory_battery_v.get::<volt>() as f64, "V")],
+            ParsedFrame::_465(location) => {
+                let (lat_degrees, lat_minutes, lat_fraction) = split_coordinate(location.latitude());
+                let (lon_degrees, lon_minutes, lon_fraction) = split_coordinate(location.longitude());
+                vec![
+                    PhysicalValue::new("LatitudeDegrees", lat_degrees as f64, "deg"),
+                    PhysicalValue::new("LatitudeMinutes", lat_minutes as f64, "min"),
+                    PhysicalValue::new("LatitudeMinuteFraction", lat_fraction as f64 / 10000.0, "min"),
+                    PhysicalValue::new("LongitudeDegrees", lon_degrees as f64, "deg"),
+                    PhysicalValue::new("LongitudeMinutes", lon_minutes as f64, "min"),
+                    PhysicalValue::new("LongitudeMinuteFraction", lon_fraction as f64 / 10000.0, "min"),
+                ]
+            },
+            ParsedFrame::_466(t) => vec![
+                PhysicalValue::new("Hour", t.hour() as f64, "h"),
+                PhysicalValue::new("Minute", t.minute() as f64, "min"),
+                PhysicalValue::new("Second", t.second() as f64, "s"),
+                PhysicalValue::new("Day", t.day() as f64, ""),
+                PhysicalValue::new("Month", t.month() as f64, ""),
+                PhysicalValue::new("Year", t.year() as f64, ""),
+            ],
+            ParsedFrame::_467 { direction, compass_heading, gps_vehicle_speed } => vec![
+                PhysicalValue::new("Direction", *direction as u8 as f64, ""),
+                PhysicalValue::new("Heading", compass_heading.get::<degree>() as f64, "deg"),
+                PhysicalValue::new("Speed", gps_vehicle_speed.get::<mile_per_hour>() as f64, "mph"),
+            ],
+            ParsedFrame::_472(t) | ParsedFrame::_473(t) => vec![
+                PhysicalValue::new("Minute", t.minute() as f64, "min"),
+                PhysicalValue::new("Hour", t.hour() as f64, "h"),
+                PhysicalValue::new("Day", t.day() as f64, ""),
+                PhysicalValue::new("Month", t.month() as f64, ""),
+                PhysicalValue::new("Year", t.year() as f64, ""),
+            ],
+            ParsedFrame::Signals(m) => m.signals.iter().map(|s| PhysicalValue::new(&s.name, s.value, &s.unit)).collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The frame doesn't carry as many data bytes as the message needs
+    WrongDlc { id: u32, expected: usize, actual: usize },
+    /// The date/time fields don't form a valid date or time
+    InvalidDateTime { id: u32 },
+    /// A field holds a value outside of its valid range
+    OutOfRange { id: u32, field: &'static str, value: u64 },
+    /// There is no decoder for the frame's ID
+    UnknownId(u32),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::WrongDlc { id, expected, actual } => write!(f, "frame 0x{:03X} has {} data bytes; expected {}", id, actual, expected),
+            DecodeError::InvalidDateTime { id } => write!(f, "frame 0x{:03X} has an invalid date or time", id),
+            DecodeError::OutOfRange { id, field, value } => write!(f, "frame 0x{:03X} has {} out of range ({})", id, field, value),
+            DecodeError::UnknownId(id) => write!(f, "no decoder for frame 0x{:03X}", id),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Position of a field within the payload, read as a big-endian u64.
+/// The offset counts bits from the most significant bit of the first byte.
+#[derive(Clone, Copy)]
+struct Field {
+    offset: u8,
+    size: u8,
+}
+
+impl Field {
+    const fn new(offset: u8, size: u8) -> Field {
+        Field { offset, size }
+    }
+
+    fn get(self, data: u64) -> u64 {
+        return get_number(data, self.offset, self.size);
+    }
+
+    fn set(self, data: u64, value: u64) -> u64 {
+        return set_number(data, self.offset, self.size, value);
+    }
+
+    /// Returns `value` if the field is wide enough to hold it
+    fn fit(self, value: i64) -> Option<u64> {
+        return in_range(value, (1 << self.size) - 1);
+    }
+}
+
+fn in_range(value: i64, max: u64) -> Option<u64> {
+    if value < 0 || value as u64 > max {
+        return None;
+    }
+    return Some(value as u64);
+}
+
+// 0x084 local clock time
+const CLOCK_YEAR: Field = Field::new(0, 8);
+const CLOCK_ORDINAL: Field = Field::new(16, 16);
+const CLOCK_MINUTE: Field = Field::new(32, 8);
+const CLOCK_SECOND: Field = Field::new(40, 8);
+const CLOCK_HOUR: Field = Field::new(48, 8);
+// 0x091 gyroscope
+const GYRO_PITCH: Field = Field::new(7, 16);
+const GYRO_ROLL: Field = Field::new(23, 16);
+const GYRO_YAW: Field = Field::new(39, 16);
+// 0x092 accelerometer
+const ACCEL_LATERAL: Field = Field::new(4, 13);
+const ACCEL_LONGITUDINAL: Field = Field::new(20, 13);
+const ACCEL_VERTICAL: Field = Field::new(36, 13);
+// 0x217 wheel rotation speed
+const WHEEL_FL: Field = Field::new(0, 16);
+const WHEEL_FR: Field = Field::new(16, 16);
+const WHEEL_RL: Field = Field::new(32, 16);
+const WHEEL_RR: Field = Field::new(48, 16);
+// 0x352 electric range
+const ELECTRIC_RANGE: Field = Field::new(12, 12);
+// 0x368 power usage
+const POWER_AC: Field = Field::new(6, 10);
+const POWER_OTHER: Field = Field::new(38, 10);
+// 0x37B gas range
+const GAS_RANGE: Field = Field::new(48, 14);
+// 0x430 odometer
+const ODOMETER: Field = Field::new(8, 24);
+// 0x43D accessory battery voltage
+const ACCESSORY_VOLTAGE: Field = Field::new(48, 8);
+// 0x465 GPS position
+const GPS_LAT_DEGREES: Field = Field::new(0, 8);
+const GPS_LAT_MINUTES: Field = Field::new(8, 6);
+const GPS_LAT_MINUTE_FRACTION: Field = Field::new(16, 14);
+const GPS_LON_DEGREES: Field = Field::new(32, 9);
+const GPS_LON_MINUTES: Field = Field::new(41, 6);
+const GPS_LON_MINUTE_FRACTION: Field = Field::new(48, 14);
+// 0x466 GPS time
+const GPS_HOUR: Field = Field::new(0, 5);
+const GPS_MINUTE: Field = Field::new(8, 6);
+const GPS_SECOND: Field = Field::new(16, 6);
+const GPS_DAY: Field = Field::new(34, 5);
+const GPS_MONTH: Field = Field::new(39, 5);
+const GPS_YEAR: Field = Field::new(45, 8);
+// 0x467 GPS heading/speed
+const GPS_DIRECTION: Field = Field::new(17, 3);
+const GPS_HEADING: Field = Field::new(24, 16);
+const GPS_SPEED: Field = Field::new(40, 8);
+// 0x472/0x473 charge finish/start time
+const CHARGE_MINUTE: Field = Field::new(24, 8);
+const CHARGE_HOUR: Field = Field::new(32, 8);
+const CHARGE_DAY: Field = Field::new(40, 8);
+const CHARGE_MONTH: Field = Field::new(48, 8);
+const CHARGE_YEAR: Field = Field::new(56, 8);
+
+/// Reads a field and rejects raw values above `max`
+fn get_checked(data: u64, field: Field, max: u64, id: u32, name: &'static str) -> std::result::Result<u64, DecodeError> {
+    let value = field.get(data);
+    if value > max {
+        return Err(DecodeError::OutOfRange { id, field: name, value });
+    }
+    return Ok(value);
+}
+
+fn get_number(data: u64, offset: u8, size: u8) -> u64{
+    return (data >> (64 - offset - size)) & ((1 << size) - 1);
+}
+
+/// Inverse of `get_number`; returns `data` with the field replaced by `value`, truncated to `size` bits
+fn set_number(data: u64, offset: u8, size: u8, value: u64) -> u64 {
+    let mask: u64 = ((1 << size) - 1) << (64 - offset - size);
+    return (data & !mask) | ((value << (64 - offset - size)) & mask);
+}
+
+/// Number of data bytes each message `parse_frame` recognizes must carry
+fn message_len(id: u32) -> Option<usize> {
+    match id {
+        0x084 | 0x091 | 0x092 | 0x217 | 0x352 | 0x368 | 0x37B | 0x430 | 0x43D | 0x465 | 0x466 | 0x467 | 0x472 | 0x473 => Some(8),
+        _ => None,
+    }
+}
+
+/// Parses a CAN frame based on the arbitration ID.
+/// Returns `DecodeError::UnknownId` if the ID isn't recognized.
+pub fn parse_frame(frame: CanFrame) -> std::result::Result<ParsedFrame, DecodeError> {
+    let id: u32 = frame.id_word();
+    let expected = message_len(id).ok_or(DecodeError::UnknownId(id))?;
+    let data: u64 = match frame.data().try_into() {
+        Ok(bytes) => u64::from_be_bytes(bytes),
+        Err(_) => return Err(DecodeError::WrongDlc { id, expected, actual: frame.data().len() }),
+    };
+    match id {
+        0x084 => {
+            // Local clock time
+            let year: i32 = CLOCK_YEAR.get(data) as i32 + 2000;
+            let ordinal: u32 = CLOCK_ORDINAL.get(data) as u32;
+            let hour: u32 = CLOCK_HOUR.get(data) as u32;
+            let min: u32 = CLOCK_MINUTE.get(data) as u32;
+            let sec: u32 = CLOCK_SECOND.get(data) as u32;
+            let date = NaiveDate::from_yo_opt(year, ordinal).ok_or(DecodeError::InvalidDateTime { id })?;
+            let time = NaiveTime::from_hms_opt(hour, min, sec).ok_or(DecodeError::InvalidDateTime { id })?;
+            return Ok(ParsedFrame::_084 (NaiveDateTime::new(date, time)));
+        },
+        0x091 => {
+            // Gyroscope data
+            let pitch: i16 = GYRO_PITCH.get(data) as i16;
+            let roll: i16 = GYRO_ROLL.get(data) as i16;
+            let yaw: i16 = GYRO_YAW.get(data) as i16;
+            return Ok(ParsedFrame::_091 { pitch: AngularVelocity::new::<radian_per_second>((pitch as f32 - 6.5) / 10000.0), roll: AngularVelocity::new::<radian_per_second>((roll as f32 - 6.5) / 10000.0), yaw: AngularVelocity::new::<radian_per_second>((yaw as f32 - 6.5) / 10000.0) });
+        },
+        0x092 => {
+            // Accelerometer data
+            let lateral: i16 = ACCEL_LATERAL.get(data) as i16 - 40;
+            let longitudinal: i16 = ACCEL_LONGITUDINAL.get(data) as i16 - 40;
+            let vertical: i16 = ACCEL_VERTICAL.get(data) as i16 - 40;
+            return Ok(ParsedFrame::_092 { lateral: Acceleration::new::<meter_per_second_squared>((lateral as f32) / 100.0), longitudinal: Acceleration::new::<meter_per_second_squared>((longitudinal as f32) / 100.0), vertical: Acceleration::new::<meter_per_second_squared>((vertical as f32) / 100.0) });
+        },
+        0x217 => {
+            // Wheel rotation speed
+            let fl: f32 = WHEEL_FL.get(data) as f32 / 10.0;
+            let fr: f32 = WHEEL_FR.get(data) as f32 / 10.0;
+            let rl: f32 = WHEEL_RL.get(data) as f32 / 10.0;
+            let rr: f32 = WHEEL_RR.get(data) as f32 / 10.0;
+            return Ok(ParsedFrame::_217 { fl: AngularVelocity::new::<revolution_per_minute>(fl), fr: AngularVelocity::new::<revolution_per_minute>(fr), rl: AngularVelocity::new::<revolution_per_minute>(rl), rr: AngularVelocity::new::<revolution_per_minute>(rr) });
+        },
+        0x352 => {
+            let electric_range: f32 = ELECTRIC_RANGE.get(data) as f32;
+            return Ok(ParsedFrame::_352 {electric_range: Length::new::<hectometer>(electric_range)});
+        },
+        0x368 => {
+            // Power usage
+            let ac: f32 = (POWER_AC.get(data) * 5) as f32;
+            let other: f32 = (POWER_OTHER.get(data) * 5) as f32;
+            return Ok(ParsedFrame::_368 { ac_power_w: Power::new::<watt>(ac), other_power_w: Power::new::<watt>(other) });
+        },
+        0x37B => {
+            // Gas range
+            let range: f32 = GAS_RANGE.get(data) as f32;
+            return Ok(ParsedFrame::_37B {gas_range: Length::new::<hectometer>(range)});
+        },
+        0x430 => {
+            // Odometer
+            let distance: f32 = ODOMETER.get(data) as f32;
+            return Ok(ParsedFrame::_430 {odometer: Length::new::<kilometer>(distance)});
+        },
+        0x43D => {
+            // Accessory battery voltage
+            let voltage: f32 = ACCESSORY_VOLTAGE.get(data) as f32;
+            return Ok(ParsedFrame::_43D {accessory_battery_v: ElectricPotential::new::<decivolt>(voltage)});
+        },
+        0x465 => {
+            // GPS position
+            let lat: i16 = get_checked(data, GPS_LAT_DEGREES, 179, id, "latitude degrees")? as i16 - 89;
+            let mut lat_minutes: f32 = get_checked(data, GPS_LAT_MINUTES, 59, id, "latitude minutes")? as f32;
+            lat_minutes += (get_checked(data, GPS_LAT_MINUTE_FRACTION, 9999, id, "latitude minute fraction")? as f32) * 0.0001;
+            let lat_mins = lat_minutes / 60.0;
+            let latitude: f32 = if lat < 0 {
+                lat as f32 - lat_mins
+            } else {
+                lat as f32 + lat_mins
+            };
+            let lon: i16 = get_checked(data, GPS_LON_DEGREES, 359, id, "longitude degrees")? as i16 - 179;
+            let mut lon_minutes: f32 = get_checked(data, GPS_LON_MINUTES, 59, id, "longitude minutes")? as f32;
+            lon_minutes += (get_checked(data, GPS_LON_MINUTE_FRACTION, 9999, id, "longitude minute fraction")? as f32) * 0.0001;
+            let lon_mins = lon_minutes / 60.0;
+            let longitude: f32 = if lon < 0 {
+                lon as f32 - lon_mins
+            } else {
+                lon as f32 + lon_mins
+            };
+            return Ok(ParsedFrame::_465 (Location::new(latitude, longitude)));
+        },
+        0x466 => {
+            // GPS time
+            let hour: u32 = GPS_HOUR.get(data) as u32;
+            let min: u32 = GPS_MINUTE.get(data) as u32;
+            let sec: u32 = GPS_SECOND.get(data) as u32;
+            let day: u32 = GPS_DAY.get(data) as u32 + 1;
+            let month: u32 = GPS_MONTH.get(data) as u32 + 1;
+            let year: i32 = GPS_YEAR.get(data) as i32 + 2010;
+            let time = Utc.with_ymd_and_hms(year, month, day, hour, min, sec).single().ok_or(DecodeError::InvalidDateTime { id })?;
+            return Ok(ParsedFrame::_466 (time));
+        },
+        0x467 => {
+            // GPS heading/speed
+            let direction: CompassDirection = match GPS_DIRECTION.get(data) {
+                0 => CompassDirection::North,
+                1 => CompassDirection::NorthEast,
+                2 => CompassDirection::East,
+                3 => CompassDirection::SouthEast,
+                4 => CompassDirection::South,
+                5 => CompassDirection::SouthWest,
+                6 => CompassDirection::West,
+                7 => CompassDirection::NorthWest,
+                _ => CompassDirection::North,
+            };
+            let heading: f32 = get_checked(data, GPS_HEADING, 35999, id, "compass heading")? as f32 / 100.0;
+            let speed: f32 = GPS_SPEED.get(data) as f32; // MPH
+            return Ok(ParsedFrame::_467 {direction, compass_heading: Angle::new::<degree>(heading), gps_vehicle_speed: Velocity::new::<mile_per_hour>(speed)});
+        },
+        0x472 => {
+            // Charge finish time
+            return Ok(ParsedFrame::_472 (parse_charge_time(data, id)?));
+        },
+        0x473 => {
+            // Charge start time
+            return Ok(ParsedFrame::_473 (parse_charge_time(data, id)?));
+        }
+        _ => return Err(DecodeError::UnknownId(id)),
+    }
+}
+
+fn parse_charge_time(data: u64, id: u32) -> std::result::Result<NaiveDateTime, DecodeError> {
+    let min: u32 = CHARGE_MINUTE.get(data) as u32;
+    let hour: u32 = CHARGE_HOUR.get(data) as u32;
+    let day: u32 = CHARGE_DAY.get(data) as u32;
+    let month: u32 = CHARGE_MONTH.get(data) as u32;
+    let year: i32 = CHARGE_YEAR.get(data) as i32 + 2010;
+    let date = NaiveDate::from_ymd_opt(year, month, day).ok_or(DecodeError::InvalidDateTime { id })?;
+    let time = NaiveTime::from_hms_opt(hour, min, 0).ok_or(DecodeError::InvalidDateTime { id })?;
+    return Ok(NaiveDateTime::new(date, time));
+}
+
+/// Splits a coordinate into whole degrees, whole minutes and ten-thousandths of a minute,
+/// the way 0x465 carries it. The minutes have the same sign as the degrees, so a coordinate
+/// between -1 and 0, where the degrees are 0, comes out positive.
+fn split_coordinate(coordinate: f64) -> (i64, u64, u64) {
+    let mut degrees = coordinate.trunc();
+    let minutes = (coordinate - degrees).abs() * 60.0;
+    let mut whole = minutes.floor();
+    let mut fraction = ((minutes - whole) * 10000.0).round();
+    if fraction >= 10000.0 {
+        whole += 1.0;
+        fraction = 0.0;
+    }
+    if whole >= 60.0 {
+        degrees += coordinate.signum();
+        whole = 0.0;
+    }
+    return (degrees as i64, whole as u64, fraction as u64);
+}
+
+/// Splits a coordinate for `encode_frame`, or None if it's between -1 and 0 and wouldn't round to 0,
+/// since 0x465 has no way to carry its sign
+fn encode_coordinate(coordinate: f64) -> Option<(i64, u64, u64)> {
+    let (degrees, minutes, fraction) = split_coordinate(coordinate);
+    if coordinate < 0.0 && degrees == 0 && (minutes, fraction) != (0, 0) {
+        return None;
+    }
+    return Some((degrees, minutes, fraction));
+}
+
+fn encode_charge_time(time: &NaiveDateTime) -> Option<u64> {
+    let mut data: u64 = 0;
+    data = CHARGE_MINUTE.set(data, time.minute() as u64);
+    data = CHARGE_HOUR.set(data, time.hour() as u64);
+    data = CHARGE_DAY.set(data, time.day() as u64);
+    data = CHARGE_MONTH.set(data, time.month() as u64);
+    data = CHARGE_YEAR.set(data, CHARGE_YEAR.fit(time.year() as i64 - 2010)?);
+    return Some(data);
+}
+
+/// Packs a `ParsedFrame` back into the CAN frame it would have been parsed from.
+/// Returns `None` for `ParsedFrame::Signals` (use `dbc::Database::encode` for those) and when a
+/// value is outside of what its field can carry, e.g. a negative odometer or a year before the
+/// message's base year. 0x465 only carries the sign in its degrees, so a latitude or longitude
+/// between -1 and 0 can't be encoded either.
+pub fn encode_frame(parsed: &ParsedFrame) -> Option<CanFrame> {
+    let mut data: u64 = 0;
+    let id: u16 = match parsed {
+        ParsedFrame::_084(time) => {
+            data = CLOCK_YEAR.set(data, CLOCK_YEAR.fit(time.year() as i64 - 2000)?);
+            data = CLOCK_ORDINAL.set(data, time.ordinal() as u64);
+            data = CLOCK_HOUR.set(data, time.hour() as u64);
+            data = CLOCK_MINUTE.set(data, time.minute() as u64);
+            data = CLOCK_SECOND.set(data, time.second() as u64);
+            0x084
+        },
+        ParsedFrame::_091 { pitch, roll, yaw } => {
+            let raw = |v: &AngularVelocity| -> Option<u64> {
+                let raw: i16 = ((v.get::<radian_per_second>() * 10000.0 + 6.5).round() as i64).try_into().ok()?;
+                return Some(raw as u16 as u64);
+            };
+            data = GYRO_PITCH.set(data, raw(pitch)?);
+            data = GYRO_ROLL.set(data, raw(roll)?);
+            data = GYRO_YAW.set(data, raw(yaw)?);
+            0x091
+        },
+        ParsedFrame::_092 { lateral, longitudinal, vertical } => {
+            let raw = |a: &Acceleration| (a.get::<meter_per_second_squared>() * 100.0).round() as i64 + 40;
+            data = ACCEL_LATERAL.set(data, ACCEL_LATERAL.fit(raw(lateral))?);
+            data = ACCEL_LONGITUDINAL.set(data, ACCEL_LONGITUDINAL.fit(raw(longitudinal))?);
+            data = ACCEL_VERTICAL.set(data, ACCEL_VERTICAL.fit(raw(vertical))?);
+            0x092
+        },
+        ParsedFrame::_217 { fl, fr, rl, rr } => {
+            let raw = |w: &AngularVelocity| (w.get::<revolution_per_minute>() * 10.0).round() as i64;
+            data = WHEEL_FL.set(data, WHEEL_FL.fit(raw(fl))?);
+            data = WHEEL_FR.set(data, WHEEL_FR.fit(raw(fr))?);
+            data = WHEEL_RL.set(data, WHEEL_RL.fit(raw(rl))?);
+            data = WHEEL_RR.set(data, WHEEL_RR.fit(raw(rr))?);
+            0x217
+        },
+        ParsedFrame::_352 { electric_range } => {
+            data = ELECTRIC_RANGE.set(data, ELECTRIC_RANGE.fit(electric_range.get::<hectometer>().round() as i64)?);
+            0x352
+        },
+        ParsedFrame::_368 { ac_power_w, other_power_w } => {
+            data = POWER_AC.set(data, POWER_AC.fit((ac_power_w.get::<watt>() / 5.0).round() as i64)?);
+            data = POWER_OTHER.set(data, POWER_OTHER.fit((other_power_w.get::<watt>() / 5.0).round() as i64)?);
+            0x368
+        },
+        ParsedFrame::_37B { gas_range } => {
+            data = GAS_RANGE.set(data, GAS_RANGE.fit(gas_range.get::<hectometer>().round() as i64)?);
+            0x37B
+        },
+        ParsedFrame::_430 { odometer } => {
+            data = ODOMETER.set(data, ODOMETER.fit(odometer.get::<kilometer>().round() as i64)?);
+            0x430
+        },
+        ParsedFrame::_43D { accessory_battery_v } => {
+            data = ACCESSORY_VOLTAGE.set(data, ACCESSORY_VOLTAGE.fit(accessory_battery_v.get::<decivolt>().round() as i64)?);
+            0x43D
+        },
+        ParsedFrame::_465(location) => {
+            let (lat, lat_minutes, lat_fraction) = encode_coordinate(location.latitude())?;
+            let (lon, lon_minutes, lon_fraction) = encode_coordinate(location.longitude())?;
+            data = GPS_LAT_DEGREES.set(data, in_range(lat + 89, 179)?);
+            data = GPS_LAT_MINUTES.set(data, lat_minutes);
+            data = GPS_LAT_MINUTE_FRACTION.set(data, lat_fraction);
+            data = GPS_LON_DEGREES.set(data, in_range(lon + 179, 359)?);
+            data = GPS_LON_MINUTES.set(data, lon_minutes);
+            data = GPS_LON_MINUTE_FRACTION.set(data, lon_fraction);
+            0x465
+        },
+        ParsedFrame::_466(time) => {
+            data = GPS_HOUR.set(data, time.hour() as u64);
+            data = GPS_MINUTE.set(data, time.minute() as u64);
+            data = GPS_SECOND.set(data, time.second() as u64);
+            data = GPS_DAY.set(data, (time.day() - 1) as u64);
+            data = GPS_MONTH.set(data, (time.month() - 1) as u64);
+            data = GPS_YEAR.set(data, GPS_YEAR.fit(time.year() as i64 - 2010)?);
+            0x466
+        },
+        ParsedFrame::_467 { direction, compass_heading, gps_vehicle_speed } => {
+            data = GPS_DIRECTION.set(data, *direction as u64);
+            data = GPS_HEADING.set(data, in_range((compass_heading.get::<degree>() * 100.0).round() as i64, 35999)?);
+            data = GPS_SPEED.set(data, GPS_SPEED.fit(gps_vehicle_speed.get::<mile_per_hour>().round() as i64)?);
+            0x467
+        },
+        ParsedFrame::_472(time) => {
+            data = encode_charge_time(time)?;
+            0x472
+        },
+        ParsedFrame::_473(time) => {
+            data = encode_charge_time(time)?;
+            0x473
+        },
+        ParsedFrame::Signals(_) => return None,
+    };
+    return CanFrame::new(StandardId::new(id).unwrap(), &data.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(parsed: &ParsedFrame) -> ParsedFrame {
+        let frame = encode_frame(parsed).expect("value should fit");
+        return parse_frame(frame).expect("encoded frame should parse");
+    }
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        return (a - b).abs() <= tolerance;
+    }
+
+    fn time(year: i32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> NaiveDateTime {
+        return NaiveDate::from_ymd_opt(year, month, day).unwrap().and_hms_opt(hour, min, sec).unwrap();
+    }
+
+    #[test]
+    fn clock_times_round_trip() {
+        let t = time(2024, 2, 29, 23, 59, 58);
+        match round_trip(&ParsedFrame::_084(t)) {
+            ParsedFrame::_084(parsed) => assert_eq!(parsed, t),
+            other => panic!("unexpected {:?}", other),
+        }
+        let gps = Utc.with_ymd_and_hms(2031, 12, 31, 0, 0, 1).unwrap();
+        match round_trip(&ParsedFrame::_466(gps)) {
+            ParsedFrame::_466(parsed) => assert_eq!(parsed, gps),
+            other => panic!("unexpected {:?}", other),
+        }
+        let charge = time(2019, 7, 4, 6, 30, 0);
+        match round_trip(&ParsedFrame::_472(charge)) {
+            ParsedFrame::_472(parsed) => assert_eq!(parsed, charge),
+            other => panic!("unexpected {:?}", other),
+        }
+        match round_trip(&ParsedFrame::_473(charge)) {
+            ParsedFrame::_473(parsed) => assert_eq!(parsed, charge),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn motion_round_trips() {
+        let gyro = ParsedFrame::_091 {
+            pitch: AngularVelocity::new::<radian_per_second>(-0.25),
+            roll: AngularVelocity::new::<radian_per_second>(0.0),
+            yaw: AngularVelocity::new::<radian_per_second>(1.5),
+        };
+        match round_trip(&gyro) {
+            ParsedFrame::_091 { pitch, roll, yaw } => {
+                assert!(close(pitch.get::<radian_per_second>(), -0.25, 1e-4));
+                assert!(close(roll.get::<radian_per_second>(), 0.0, 1e-4));
+                assert!(close(yaw.get::<radian_per_second>(), 1.5, 1e-4));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        let accel = ParsedFrame::_092 {
+            lateral: Acceleration::new::<meter_per_second_squared>(-0.4),
+            longitudinal: Acceleration::new::<meter_per_second_squared>(2.35),
+            vertical: Acceleration::new::<meter_per_second_squared>(9.81),
+        };
+        match round_trip(&accel) {
+            ParsedFrame::_092 { lateral, longitudinal, vertical } => {
+                assert!(close(lateral.get::<meter_per_second_squared>(), -0.4, 1e-3));
+                assert!(close(longitudinal.get::<meter_per_second_squared>(), 2.35, 1e-3));
+                assert!(close(vertical.get::<meter_per_second_squared>(), 9.81, 1e-3));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        let wheels = ParsedFrame::_217 {
+            fl: AngularVelocity::new::<revolution_per_minute>(812.3),
+            fr: AngularVelocity::new::<revolution_per_minute>(812.4),
+            rl: AngularVelocity::new::<revolution_per_minute>(0.0),
+            rr: AngularVelocity::new::<revolution_per_minute>(6553.5),
+        };
+        match round_trip(&wheels) {
+            ParsedFrame::_217 { fl, fr, rl, rr } => {
+                assert!(close(fl.get::<revolution_per_minute>(), 812.3, 0.01));
+                assert!(close(fr.get::<revolution_per_minute>(), 812.4, 0.01));
+                assert!(close(rl.get::<revolution_per_minute>(), 0.0, 0.01));
+                assert!(close(rr.get::<revolution_per_minute>(), 6553.5, 0.01));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        let heading = ParsedFrame::_467 {
+            direction: CompassDirection::SouthWest,
+            compass_heading: Angle::new::<degree>(225.5),
+            gps_vehicle_speed: Velocity::new::<mile_per_hour>(65.0),
+        };
+        match round_trip(&heading) {
+            ParsedFrame::_467 { direction, compass_heading, gps_vehicle_speed } => {
+                assert_eq!(direction, CompassDirection::SouthWest);
+                assert!(close(compass_heading.get::<degree>(), 225.5, 0.01));
+                assert!(close(gps_vehicle_speed.get::<mile_per_hour>(), 65.0, 0.01));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn vehicle_state_round_trips() {
+        match round_trip(&ParsedFrame::_352 { electric_range: Length::new::<hectometer>(512.0) }) {
+            ParsedFrame::_352 { electric_range } => assert!(close(electric_range.get::<hectometer>(), 512.0, 0.01)),
+            other => panic!("unexpected {:?}", other),
+        }
+        let power = ParsedFrame::_368 { ac_power_w: Power::new::<watt>(1500.0), other_power_w: Power::new::<watt>(5115.0) };
+        match round_trip(&power) {
+            ParsedFrame::_368 { ac_power_w, other_power_w } => {
+                assert!(close(ac_power_w.get::<watt>(), 1500.0, 0.01));
+                assert!(close(other_power_w.get::<watt>(), 5115.0, 0.01));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        match round_trip(&ParsedFrame::_37B { gas_range: Length::new::<hectometer>(4321.0) }) {
+            ParsedFrame::_37B { gas_range } => assert!(close(gas_range.get::<hectometer>(), 4321.0, 0.01)),
+            other => panic!("unexpected {:?}", other),
+        }
+        match round_trip(&ParsedFrame::_430 { odometer: Length::new::<kilometer>(123456.0) }) {
+            ParsedFrame::_430 { odometer } => assert!(close(odometer.get::<kilometer>(), 123456.0, 0.01)),
+            other => panic!("unexpected {:?}", other),
+        }
+        match round_trip(&ParsedFrame::_43D { accessory_battery_v: ElectricPotential::new::<decivolt>(127.0) }) {
+            ParsedFrame::_43D { accessory_battery_v } => assert!(close(accessory_battery_v.get::<decivolt>(), 127.0, 0.01)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accessory_battery_is_read_in_tenths_of_a_volt() {
+        let frame = CanFrame::new(StandardId::new(0x43D).unwrap(), &[0, 0, 0, 0, 0, 0, 127, 0]).unwrap();
+        match parse_frame(frame) {
+            Ok(ParsedFrame::_43D { accessory_battery_v }) => assert!(close(accessory_battery_v.get::<volt>(), 12.7, 1e-4)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn locations_round_trip_in_every_quadrant() {
+        let locations = [
+            (47.6205, -122.3493),
+            (-33.8568, 151.2153),
+            (-1.5, -1.25),
+            (0.25, 0.9999),
+            (0.0, 0.0),
+            (-89.5, -179.5),
+            (90.5, 180.5),
+            (12.999999, -45.999999),
+        ];
+        for &(latitude, longitude) in locations.iter() {
+            match round_trip(&ParsedFrame::_465(Location::new(latitude, longitude))) {
+                ParsedFrame::_465(parsed) => {
+                    assert!((parsed.latitude() - latitude).abs() < 1e-4, "latitude {} came back as {}", latitude, parsed.latitude());
+                    assert!((parsed.longitude() - longitude).abs() < 1e-4, "longitude {} came back as {}", longitude, parsed.longitude());
+                },
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn locations_between_minus_one_and_zero_are_not_encoded() {
+        assert!(encode_frame(&ParsedFrame::_465(Location::new(-0.5, 139.6917))).is_none());
+        assert!(encode_frame(&ParsedFrame::_465(Location::new(35.6762, -0.25))).is_none());
+        // Close enough to 0 to be encoded as 0
+        assert!(encode_frame(&ParsedFrame::_465(Location::new(-0.000_000_1, -0.000_000_1))).is_some());
+    }
+
+    #[test]
+    fn values_are_named_like_the_car_database() {
+        let db = dbc::Database::parse(include_str!("../dbc/car.dbc")).unwrap();
+        let frames = [
+            ParsedFrame::_084(time(2024, 5, 1, 12, 30, 15)),
+            ParsedFrame::_091 {
+                pitch: AngularVelocity::new::<radian_per_second>(-0.25),
+                roll: AngularVelocity::new::<radian_per_second>(0.0),
+                yaw: AngularVelocity::new::<radian_per_second>(1.5),
+            },
+            ParsedFrame::_092 {
+                lateral: Acceleration::new::<meter_per_second_squared>(-0.4),
+                longitudinal: Acceleration::new::<meter_per_second_squared>(2.35),
+                vertical: Acceleration::new::<meter_per_second_squared>(9.81),
+            },
+            ParsedFrame::_217 {
+                fl: AngularVelocity::new::<revolution_per_minute>(812.3),
+                fr: AngularVelocity::new::<revolution_per_minute>(812.4),
+                rl: AngularVelocity::new::<revolution_per_minute>(0.0),
+                rr: AngularVelocity::new::<revolution_per_minute>(6553.5),
+            },
+            ParsedFrame::_352 { electric_range: Length::new::<kilometer>(123.4) },
+            ParsedFrame::_368 { ac_power_w: Power::new::<watt>(1500.0), other_power_w: Power::new::<watt>(250.0) },
+            ParsedFrame::_37B { gas_range: Length::new::<kilometer>(456.7) },
+            ParsedFrame::_430 { odometer: Length::new::<kilometer>(123456.0) },
+            ParsedFrame::_43D { accessory_battery_v: ElectricPotential::new::<volt>(12.7) },
+            ParsedFrame::_465(Location::new(-35.6762, 139.6917)),
+            ParsedFrame::_466(Utc.with_ymd_and_hms(2024, 5, 1, 12, 30, 15).unwrap()),
+            ParsedFrame::_467 {
+                direction: CompassDirection::SouthWest,
+                compass_heading: Angle::new::<degree>(225.5),
+                gps_vehicle_speed: Velocity::new::<mile_per_hour>(65.0),
+            },
+            ParsedFrame::_472(time(2024, 5, 1, 23, 45, 0)),
+            ParsedFrame::_473(time(2024, 5, 1, 22, 0, 0)),
+        ];
+        for parsed in frames.iter() {
+            let frame = encode_frame(parsed).expect("value should fit");
+            let built_in = parse_frame(frame).unwrap();
+            let decoded = db.parse_frame(frame).unwrap();
+            assert_eq!(built_in.name(), decoded.name());
+            let names = |values: &[PhysicalValue]| values.iter().map(|v| (v.name.clone(), v.unit.clone())).collect::<Vec<_>>();
+            assert_eq!(names(&built_in.values()), names(&decoded.values()), "{}", built_in.name());
+            for (a, b) in built_in.values().iter().zip(decoded.values().iter()) {
+                assert!((a.value - b.value).abs() < 1e-3, "{}.{}: {} and {}", built_in.name(), a.name, a.value, b.value);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_values_are_not_encoded() {
+        assert!(encode_frame(&ParsedFrame::_430 { odometer: Length::new::<kilometer>(-1.0) }).is_none());
+        assert!(encode_frame(&ParsedFrame::_430 { odometer: Length::new::<kilometer>(16777216.0) }).is_none());
+        assert!(encode_frame(&ParsedFrame::_091 {
+            pitch: AngularVelocity::new::<radian_per_second>(4.0),
+            roll: AngularVelocity::new::<radian_per_second>(0.0),
+            yaw: AngularVelocity::new::<radian_per_second>(0.0),
+        }).is_none());
+        assert!(encode_frame(&ParsedFrame::_465(Location::new(-91.0, 0.0))).is_none());
+        assert!(encode_frame(&ParsedFrame::_465(Location::new(0.0, 181.5))).is_none());
+        assert!(encode_frame(&ParsedFrame::_467 {
+            direction: CompassDirection::North,
+            compass_heading: Angle::new::<degree>(360.0),
+            gps_vehicle_speed: Velocity::new::<mile_per_hour>(0.0),
+        }).is_none());
+        assert!(encode_frame(&ParsedFrame::_466(Utc.with_ymd_and_hms(2009, 1, 1, 0, 0, 0).unwrap())).is_none());
+        assert!(encode_frame(&ParsedFrame::_472(time(1999, 1, 1, 0, 0, 0))).is_none());
+        assert!(encode_frame(&ParsedFrame::_084(time(2256, 1, 1, 0, 0, 0))).is_none());
+    }
+
+    #[test]
+    fn logs_never_reuse_a_name() {
+        let dir = std::env::temp_dir().join(format!("car_logger_create_log_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("2024-05-01T12_30_00.250Z.log").to_string_lossy().into_owned();
+        let partial = recovery::partial_path(&path);
+        create_log(&path, &partial).unwrap();
+        assert_eq!(create_log(&path, &partial).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        fs::rename(&partial, &path).unwrap();
+        assert_eq!(create_log(&path, &partial).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        let ifaces = vec![String::from("can0")];
+        assert!(Logger::new(path.clone(), ifaces.clone(), 4096, Compression::None, LogFormat::Candump).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        // The directory is gone, so the log can't be created
+        assert_eq!(Logger::new(path, ifaces, 4096, Compression::None, LogFormat::Blf).err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn metadata_counts_the_timestamp_sources_of_each_interface() {
+        let dir = std::env::temp_dir().join(format!("car_logger_timestamp_sources_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("2024-05-01T12_30_00.250Z.log").to_string_lossy().into_owned();
+        let mut logger = Logger::new(path.clone(), vec![String::from("can0"), String::from("can1")], 4096, Compression::None, LogFormat::Candump).unwrap();
+        let frame: CanAnyFrame = CanFrame::new(StandardId::new(0x123).unwrap(), &[1]).unwrap().into();
+        let t = Duration::new(1714566600, 250_000_000);
+        for source in [ClockSource::Hardware, ClockSource::Hardware, ClockSource::Kernel] {
+            logger.log(0, frame, t, source).unwrap();
+        }
+        logger.log(1, frame, t, ClockSource::Userspace).unwrap();
+        logger.close(RotationReason::Shutdown).unwrap();
+
+        let meta: toml::Table = fs::read_to_string(meta::LogMeta::path(&path)).unwrap().parse().unwrap();
+        let sources = meta["timestamp_sources"].as_table().unwrap();
+        assert_eq!(sources["can0"]["hardware"].as_integer(), Some(2));
+        assert_eq!(sources["can0"]["kernel"].as_integer(), Some(1));
+        assert!(sources["can0"].get("userspace").is_none());
+        assert_eq!(sources["can1"]["userspace"].as_integer(), Some(1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn binary_and_blf_logs_note_dropped_frames() {
+        let dir = std::env::temp_dir().join(format!("car_logger_dropped_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let frame: CanAnyFrame = CanFrame::new(StandardId::new(0x123).unwrap(), &[1]).unwrap().into();
+        let t = Duration::new(1714566600, 250_000_000);
+        let ifaces = vec![String::from("can0"), String::from("can1")];
+        for (name, format) in [("binary.bin", LogFormat::Binary), ("blf.blf", LogFormat::Blf)] {
+            let path = dir.join(name).to_string_lossy().into_owned();
+            let mut logger = Logger::new(path.clone(), ifaces.clone(), 4096, Compression::None, format).unwrap();
+            logger.log(0, frame, t, ClockSource::Kernel).unwrap();
+            assert!(logger.log_dropped(1, 7, t + Duration::from_millis(1)).unwrap() > 0);
+            logger.log(1, frame, t + Duration::from_millis(2), ClockSource::Kernel).unwrap();
+            logger.close(RotationReason::Shutdown).unwrap();
+
+            // The frames read back as before, with the drops beside them
+            if format == LogFormat::Binary {
+                let mut reader = binlog::Reader::open(&path).unwrap();
+                assert_eq!(reader.by_ref().map(|r| r.unwrap().iface).collect::<Vec<_>>(), ["can0", "can1"]);
+                assert_eq!(reader.dropped(), 7);
+            } else {
+                let mut reader = blf::Reader::open(&path, ChannelMap::new()).unwrap();
+                assert_eq!(reader.by_ref().map(|r| r.unwrap().iface).collect::<Vec<_>>(), ["can0", "can1"]);
+                assert_eq!(reader.comments()[0], (t + Duration::from_millis(1), String::from("can1: 7 frames dropped")));
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn logs_end_with_the_close_reason() {
+        let dir = std::env::temp_dir().join(format!("car_logger_close_reason_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let frame: CanAnyFrame = CanFrame::new(StandardId::new(0x123).unwrap(), &[1]).unwrap().into();
+        let t = Duration::new(1714566600, 250_000_000);
+        for (name, format) in [("candump.log", LogFormat::Candump), ("asc.asc", LogFormat::Asc), ("blf.blf", LogFormat::Blf)] {
+            let path = dir.join(name).to_string_lossy().into_owned();
+            let mut logger = Logger::new(path.clone(), vec![String::from("can0")], 4096, Compression::None, format).unwrap();
+            logger.log(0, frame, t, ClockSource::Kernel).unwrap();
+            logger.close(RotationReason::Ignition).unwrap();
+
+            match format {
+                LogFormat::Candump => {
+                    assert!(fs::read_to_string(&path).unwrap().ends_with("# (1714566600.250000) Log closed: ignition off\n"));
+                    assert_eq!(candump::Reader::open(&path).unwrap().map(|r| r.unwrap().frame.raw_id()).collect::<Vec<_>>(), [0x123]);
+                },
+                LogFormat::Asc => {
+                    assert!(fs::read_to_string(&path).unwrap().ends_with("// 0.000000 Log closed: ignition off\nEnd TriggerBlock\n"));
+                    assert_eq!(asc::Reader::open(&path, ChannelMap::new()).unwrap().map(|r| r.unwrap().frame.raw_id()).collect::<Vec<_>>(), [0x123]);
+                },
+                _ => {
+                    let mut reader = blf::Reader::open(&path, ChannelMap::new()).unwrap();
+                    assert_eq!(reader.by_ref().map(|r| r.unwrap().frame.raw_id()).collect::<Vec<_>>(), [0x123]);
+                    assert_eq!(reader.comments(), [(t, String::from("Log closed: ignition off"))]);
+                },
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn out_of_range_fields_are_rejected() {
+        let frame = |id: u16, data: u64| CanFrame::new(StandardId::new(id).unwrap(), &data.to_be_bytes()).unwrap();
+        match parse_frame(frame(0x465, GPS_LAT_DEGREES.set(0, 180))) {
+            Err(DecodeError::OutOfRange { id: 0x465, field: "latitude degrees", value: 180 }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_frame(frame(0x465, GPS_LON_MINUTES.set(0, 60))) {
+            Err(DecodeError::OutOfRange { id: 0x465, field: "longitude minutes", value: 60 }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_frame(frame(0x467, GPS_HEADING.set(0, 36000))) {
+            Err(DecodeError::OutOfRange { id: 0x467, field: "compass heading", value: 36000 }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_frame(frame(0x472, 0)) {
+            Err(DecodeError::InvalidDateTime { id: 0x472 }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_frame(CanFrame::new(StandardId::new(0x430).unwrap(), &[0; 4]).unwrap()) {
+            Err(DecodeError::WrongDlc { id: 0x430, expected: 8, actual: 4 }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_frame(frame(0x123, 0)) {
+            Err(DecodeError::UnknownId(0x123)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,49 +1,64 @@
-use socketcan::{CanFilter, CanSocket, Socket, SocketOptions, Frame, EmbeddedFrame};
+// Work in progress; the decoded values are not used yet
+#![allow(unused_variables)]
 
+use chrono::{DateTime, Timelike, Utc};
+use clap::Parser;
 use log::{info, warn, debug};
+use socketcan::CanFrame;
 
-fn main() {
-    info!("Starting up");
-    // Open the interface and set the filter.
-    let s = CanSocket::open("can0").unwrap();
-    let filter: CanFilter = CanFilter::new(0x465, 0x7FF);
-    s.set_filters(&[filter]).unwrap();
-    loop {
-        let f = s.read_frame().unwrap();
-        // Process the frame
-        if f.is_error_frame() == false {
-            let data: &[u8] = f.data();
-            match f.id_word() {
-                0x084 => {
-                    // Clock
-                    let minute: u8 = data[4];
-                    let second: u8 = data[5];
-                    let hour: u8   = data[6];
-                }
-                0x465 => {
-                    // Get the lat/lon as degrees only
-                    // Data comes in as degrees, minutes, and minute fraction
-                    let mut latitude: f32 = (data[0] - 89).into();
-                    // minutes
-                    latitude += ((data[1] >> 2 & 0x3F) as f32) / 60.;
-                    // minutes frac
-                    latitude += (((data[2] << 6) + (data[3] >> 2 & 0x3F)) as f32) * (0.0001 / 60.);
-                    let mut longitude: f32 = ((data[4] << 1) + (data[5] >> 7 & 0x1)) as f32;
-                    // minutes
-                    longitude += ((data[5] >> 1 & 0x3F) as f32) / 60.;
-                    // miunutes frac
-                    longitude += (((data[6] << 6) + (data[7] >> 2 & 0x3F)) as f32) * (0.0001 / 60.);
-                }
-                0x472 => {
-                    // Charging finish time estimate
-                }
-                0x473 => {
-                    // Charging start time
-                }
-                _ => debug!("Ignoring frame")
+use car_logger::{DecodeError, ParsedFrame};
+use car_logger::config::{self, BusArgs, ConfigArgs};
+use car_logger::service::{Handler, Service};
+use car_logger::systemd::Notifier;
+
+#[derive(Parser)]
+#[command(name = "car_logger")]
+#[command(version = "1.0")]
+#[command(author)]
+#[command(about = "Decodes the clock, position and charging times from the car")]
+struct Args {
+    #[command(flatten)]
+    bus: BusArgs,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+struct CarLogger;
+
+impl Handler for CarLogger {
+    fn frame(&mut self, frame: ParsedFrame, received: DateTime<Utc>, _notifier: &Notifier) {
+        match frame {
+            ParsedFrame::_084(clock) => {
+                // Clock
+                let minute = clock.minute();
+                let second = clock.second();
+                let hour = clock.hour();
+            }
+            ParsedFrame::_465(location) => {
+                // Position in degrees
+                let latitude = location.latitude();
+                let longitude = location.longitude();
+            }
+            ParsedFrame::_472(finish) => {
+                // Charging finish time estimate
             }
-        } else {
-            warn!("Ignored error frame");
+            ParsedFrame::_473(start) => {
+                // Charging start time
+            }
+            _ => debug!("Ignoring frame")
         }
     }
+
+    fn undecoded(&mut self, frame: CanFrame, error: DecodeError) {
+        warn!("Ignored frame: {}", error);
+    }
+}
+
+fn main() {
+    let matches: Args = config::parse("car_logger");
+
+    info!("Starting up");
+    // Open the interface and set the filter. It takes every ID handled below, not only 0x465.
+    let mut service = Service::open(&matches.bus.interface, &[0x084, 0x465, 0x472, 0x473]).unwrap().database(matches.bus.database::<Args>());
+    service.run(&mut CarLogger).unwrap();
 }
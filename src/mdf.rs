@@ -0,0 +1,462 @@
+//! ASAM MDF 4.1 measurement files, following the ASAM bus logging layout for raw CAN frames.
+//!
+//! Records are streamed into a single data block as they arrive, each prefixed with the record
+//! ID of its channel group (an "unsorted" file). The channel groups and the rest of the
+//! structure are written when the file is finished, since the groups needed aren't known until
+//! then. A file that's never finished is still marked as unfinalized and can't be opened.
+//!
+//! Raw frames go into the `CAN_DataFrame`, `CAN_RemoteFrame` and `CAN_ErrorFrame` groups, with
+//! the Vector channel number from `ChannelMap` as the bus channel. CAN FD frames get a second
+//! `CAN_DataFrame` group with room for 64 data bytes and the EDL, BRS and ESI bits set. Decoded values get a group per
+//! message with one `f64` channel per value. Timestamps are seconds from the header start time,
+//! which is the time of the first frame.
+
+use std::collections::HashMap;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use libc::CAN_ERR_MASK;
+use socketcan::{CanAnyFrame, EmbeddedFrame, Frame};
+
+use super::PhysicalValue;
+use super::channels::ChannelMap;
+
+const ID_BLOCK_SIZE: u64 = 64;
+const HD_BLOCK_SIZE: usize = 104;
+const DT_HEADER_SIZE: u64 = 24;
+/// Size of the record ID at the start of each record
+const RECORD_ID_SIZE: usize = 2;
+
+const CN_TYPE_FIXED: u8 = 0;
+const CN_TYPE_MASTER: u8 = 2;
+const SYNC_TIME: u8 = 1;
+const DATA_UINT_LE: u8 = 0;
+const DATA_FLOAT_LE: u8 = 4;
+const DATA_BYTES: u8 = 10;
+
+const CG_BUS_EVENT: u16 = 0x0002;
+const CG_PLAIN_BUS_EVENT: u16 = 0x0004;
+const SI_TYPE_BUS: u8 = 2;
+const SI_BUS_CAN: u8 = 2;
+
+/// Unfinalized flags: cycle counters and the length of the last data block need updating
+const UNFINISHED_FLAGS: u16 = 0x0001 | 0x0004;
+
+struct Channel {
+    name: String,
+    unit: String,
+    channel_type: u8,
+    sync_type: u8,
+    data_type: u8,
+    byte_offset: u32,
+    bit_offset: u8,
+    bit_count: u32,
+    /// Members of a structure channel
+    children: Vec<Channel>,
+}
+
+impl Channel {
+    fn new(name: &str, data_type: u8, byte_offset: u32, bit_count: u32) -> Channel {
+        Channel { name: name.to_string(), unit: String::new(), channel_type: CN_TYPE_FIXED, sync_type: 0, data_type, byte_offset, bit_offset: 0, bit_count, children: Vec::new() }
+    }
+
+    fn master() -> Channel {
+        Channel { name: String::from("Timestamp"), unit: String::from("s"), channel_type: CN_TYPE_MASTER, sync_type: SYNC_TIME, data_type: DATA_FLOAT_LE, byte_offset: 0, bit_offset: 0, bit_count: 64, children: Vec::new() }
+    }
+}
+
+struct Group {
+    record_id: u16,
+    name: String,
+    /// Raw bus frames, which get a CAN source and the bus event flags
+    bus: bool,
+    channels: Vec<Channel>,
+    /// Size of a record without the record ID
+    data_bytes: u32,
+    cycles: u64,
+}
+
+impl Group {
+    /// A bus logging group for one kind of CAN frame. Members are named `<name>.<member>`.
+    /// FD groups hold 64 data bytes and have the FD flags after the IDE bit.
+    fn frame(record_id: u16, name: &str, with_data: bool, fd: bool) -> Group {
+        let member = |m: &str, data_type: u8, byte_offset: u32, bit_count: u32| Channel::new(&format!("{}.{}", name, m), data_type, byte_offset, bit_count);
+        let mut members = vec![
+            member("BusChannel", DATA_UINT_LE, 8, 8),
+            member("ID", DATA_UINT_LE, 9, 29),
+            member("IDE", DATA_UINT_LE, 13, 1),
+            member("DLC", DATA_UINT_LE, 14, 4),
+            member("DataLength", DATA_UINT_LE, 15, 7),
+        ];
+        if fd {
+            for (bit, m) in ["EDL", "BRS", "ESI"].iter().enumerate() {
+                let mut flag = member(m, DATA_UINT_LE, 13, 1);
+                flag.bit_offset = bit as u8 + 1;
+                members.push(flag);
+            }
+        }
+        let data_len: u32 = if fd { 64 } else { 8 };
+        if with_data {
+            members.push(member("DataBytes", DATA_BYTES, 16, data_len * 8));
+        }
+        let size: u32 = if with_data { 8 + data_len } else { 8 };
+        let mut frame = Channel::new(name, DATA_BYTES, 8, size * 8);
+        frame.children = members;
+        return Group { record_id, name: String::from("CAN"), bus: true, channels: vec![Channel::master(), frame], data_bytes: 8 + size, cycles: 0 };
+    }
+}
+
+/// Collects blocks to be written at `base`, handing out their addresses
+struct Blocks {
+    base: u64,
+    buf: Vec<u8>,
+}
+
+impl Blocks {
+    fn add(&mut self, id: &[u8; 4], links: &[u64], data: &[u8]) -> u64 {
+        let address = self.base + self.buf.len() as u64;
+        let len = 24 + links.len() * 8 + data.len();
+        let padded = len.div_ceil(8) * 8;
+        self.buf.extend_from_slice(id);
+        self.buf.extend_from_slice(&[0u8; 4]);
+        self.buf.extend_from_slice(&(padded as u64).to_le_bytes());
+        self.buf.extend_from_slice(&(links.len() as u64).to_le_bytes());
+        for link in links {
+            self.buf.extend_from_slice(&link.to_le_bytes());
+        }
+        self.buf.extend_from_slice(data);
+        self.buf.resize(self.buf.len() + padded - len, 0);
+        return address;
+    }
+
+    /// Adds a text block, or returns a nil link for empty text
+    fn text(&mut self, text: &str) -> u64 {
+        if text.is_empty() {
+            return 0;
+        }
+        let mut data = text.as_bytes().to_vec();
+        data.push(0);
+        return self.add(b"##TX", &[], &data);
+    }
+
+    fn metadata(&mut self, xml: &str) -> u64 {
+        let mut data = xml.as_bytes().to_vec();
+        data.push(0);
+        return self.add(b"##MD", &[], &data);
+    }
+
+    /// Adds a chain of channels, returning the address of the first
+    fn channels(&mut self, channels: &[Channel]) -> u64 {
+        let mut next: u64 = 0;
+        for channel in channels.iter().rev() {
+            let composition = self.channels(&channel.children);
+            let name = self.text(&channel.name);
+            let unit = self.text(&channel.unit);
+            let mut data: Vec<u8> = vec![channel.channel_type, channel.sync_type, channel.data_type, channel.bit_offset];
+            data.extend_from_slice(&channel.byte_offset.to_le_bytes());
+            data.extend_from_slice(&channel.bit_count.to_le_bytes());
+            // Flags, invalidation bit, precision, reserved, attachment count
+            data.extend_from_slice(&[0u8; 12]);
+            // Value range and limits
+            data.extend_from_slice(&[0u8; 48]);
+            next = self.add(b"##CN", &[next, composition, name, 0, 0, 0, unit, 0], &data);
+        }
+        return next;
+    }
+}
+
+/// Writes raw frames and decoded values to an MDF file
+pub struct Writer<W: Write + Seek> {
+    out: W,
+    channels: ChannelMap,
+    groups: Vec<Group>,
+    /// Index into `groups` for each message and set of value names
+    value_groups: HashMap<(String, Vec<String>), usize>,
+    start: Option<Duration>,
+    /// Offset of the data block holding every record
+    data_block: u64,
+    record: Vec<u8>,
+}
+
+impl<W: Write + Seek> Writer<W> {
+    pub fn new(mut out: W, channels: ChannelMap) -> io::Result<Writer<W>> {
+        let mut id = [0u8; ID_BLOCK_SIZE as usize];
+        id[0..8].copy_from_slice(b"UnFinMF ");
+        id[8..16].copy_from_slice(b"4.10    ");
+        id[16..24].copy_from_slice(b"carlog  ");
+        id[28..30].copy_from_slice(&410u16.to_le_bytes());
+        id[60..62].copy_from_slice(&UNFINISHED_FLAGS.to_le_bytes());
+        out.write_all(&id)?;
+        // The header is filled in by finish()
+        out.write_all(&[0u8; HD_BLOCK_SIZE])?;
+        let data_block = ID_BLOCK_SIZE + HD_BLOCK_SIZE as u64;
+        out.write_all(b"##DT\0\0\0\0")?;
+        out.write_all(&DT_HEADER_SIZE.to_le_bytes())?;
+        out.write_all(&0u64.to_le_bytes())?;
+        let groups = vec![
+            Group::frame(1, "CAN_DataFrame", true, false),
+            Group::frame(2, "CAN_RemoteFrame", false, false),
+            Group::frame(3, "CAN_ErrorFrame", true, false),
+            Group::frame(4, "CAN_DataFrame", true, true),
+        ];
+        return Ok(Writer { out, channels, groups, value_groups: HashMap::new(), start: None, data_block, record: Vec::new() });
+    }
+
+    /// Starts a record for `group` at time `t`
+    fn begin_record(&mut self, group: usize, t: Duration) {
+        let start = *self.start.get_or_insert(t);
+        let time = t.saturating_sub(start).as_secs_f64();
+        self.record.clear();
+        self.record.extend_from_slice(&self.groups[group].record_id.to_le_bytes());
+        self.record.extend_from_slice(&time.to_le_bytes());
+    }
+
+    fn end_record(&mut self, group: usize) -> io::Result<usize> {
+        self.groups[group].cycles += 1;
+        self.out.write_all(&self.record)?;
+        return Ok(self.record.len());
+    }
+
+    pub fn write_frame(&mut self, iface: &str, frame: &CanAnyFrame, t: Duration) -> io::Result<usize> {
+        let (group, id) = match frame {
+            CanAnyFrame::Normal(_) => (0, frame.raw_id()),
+            CanAnyFrame::Remote(_) => (1, frame.raw_id()),
+            // The error class takes the place of the ID
+            CanAnyFrame::Error(_) => (2, frame.id_word() & CAN_ERR_MASK),
+            CanAnyFrame::Fd(_) => (3, frame.raw_id()),
+        };
+        let mut flags = frame.is_extended() as u8;
+        if let CanAnyFrame::Fd(f) = frame {
+            flags |= 0x02 | (f.is_brs() as u8) << 2 | (f.is_esi() as u8) << 3;
+        }
+        self.begin_record(group, t);
+        self.record.push(self.channels.channel(iface).min(u8::MAX as u16) as u8);
+        self.record.extend_from_slice(&id.to_le_bytes());
+        self.record.push(flags);
+        self.record.push(frame.dlc() as u8);
+        self.record.push(frame.data().len() as u8);
+        if group != 1 {
+            let mut data = [0u8; 64];
+            data[..frame.data().len()].copy_from_slice(frame.data());
+            let data_len = if group == 3 { 64 } else { 8 };
+            self.record.extend_from_slice(&data[..data_len]);
+        }
+        return self.end_record(group);
+    }
+
+    /// Writes the decoded values of a message. Each message, and each different set of values
+    /// from a multiplexed message, gets its own channel group.
+    pub fn write_values(&mut self, message: &str, values: &[PhysicalValue], t: Duration) -> io::Result<usize> {
+        let key = (message.to_string(), values.iter().map(|v| v.name.clone()).collect::<Vec<String>>());
+        let group = match self.value_groups.get(&key) {
+            Some(g) => *g,
+            None => {
+                if self.groups.len() >= u16::MAX as usize {
+                    return Err(io::Error::other("too many channel groups"));
+                }
+                let mut channels = vec![Channel::master()];
+                for (i, value) in values.iter().enumerate() {
+                    let mut channel = Channel::new(&value.name, DATA_FLOAT_LE, 8 + 8 * i as u32, 64);
+                    channel.unit = value.unit.clone();
+                    channels.push(channel);
+                }
+                let record_id = self.groups.len() as u16 + 1;
+                self.groups.push(Group { record_id, name: message.to_string(), bus: false, channels, data_bytes: 8 + 8 * values.len() as u32, cycles: 0 });
+                self.value_groups.insert(key, self.groups.len() - 1);
+                self.groups.len() - 1
+            },
+        };
+        self.begin_record(group, t);
+        for value in values {
+            self.record.extend_from_slice(&value.value.to_le_bytes());
+        }
+        return self.end_record(group);
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        return self.out.flush();
+    }
+
+    /// Writes the file structure after the data and marks the file as finished
+    pub fn finish(mut self) -> io::Result<W> {
+        let end = self.out.stream_position()?;
+        let base = end.div_ceil(8) * 8;
+        let mut blocks = Blocks { base, buf: Vec::new() };
+
+        let tool = format!("<FHcomment><TX>Created</TX><tool_id>{}</tool_id><tool_vendor>{}</tool_vendor><tool_version>{}</tool_version></FHcomment>", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_AUTHORS"), env!("CARGO_PKG_VERSION"));
+        let fh_comment = blocks.metadata(&tool);
+        let start_ns = self.start.unwrap_or_default().as_nanos() as u64;
+        let mut fh_data: Vec<u8> = start_ns.to_le_bytes().to_vec();
+        fh_data.extend_from_slice(&[0u8; 8]);
+        let fh = blocks.add(b"##FH", &[0, fh_comment], &fh_data);
+
+        let mut next_group: u64 = 0;
+        let can_source_name = blocks.text("CAN");
+        let can_source = blocks.add(b"##SI", &[can_source_name, 0, 0], &[SI_TYPE_BUS, SI_BUS_CAN, 0, 0, 0, 0, 0, 0]);
+        for group in self.groups.iter().rev().filter(|g| g.cycles > 0) {
+            let first_channel = blocks.channels(&group.channels);
+            let name = blocks.text(&group.name);
+            let flags: u16 = if group.bus { CG_BUS_EVENT | CG_PLAIN_BUS_EVENT } else { 0 };
+            let mut data: Vec<u8> = (group.record_id as u64).to_le_bytes().to_vec();
+            data.extend_from_slice(&group.cycles.to_le_bytes());
+            data.extend_from_slice(&flags.to_le_bytes());
+            data.extend_from_slice(&(b'.' as u16).to_le_bytes());
+            data.extend_from_slice(&[0u8; 4]);
+            data.extend_from_slice(&group.data_bytes.to_le_bytes());
+            // Invalidation bytes
+            data.extend_from_slice(&0u32.to_le_bytes());
+            let source = if group.bus { can_source } else { 0 };
+            next_group = blocks.add(b"##CG", &[next_group, first_channel, name, source, 0, 0], &data);
+        }
+        let dg = if next_group != 0 {
+            blocks.add(b"##DG", &[0, next_group, self.data_block, 0], &[RECORD_ID_SIZE as u8, 0, 0, 0, 0, 0, 0, 0])
+        } else {
+            0
+        };
+
+        self.out.write_all(&vec![0u8; (base - end) as usize])?;
+        self.out.write_all(&blocks.buf)?;
+        // Length of the data block
+        self.out.seek(SeekFrom::Start(self.data_block + 8))?;
+        self.out.write_all(&(end - self.data_block).to_le_bytes())?;
+        // Header
+        let mut hd: Vec<u8> = Vec::with_capacity(HD_BLOCK_SIZE);
+        hd.extend_from_slice(b"##HD\0\0\0\0");
+        hd.extend_from_slice(&(HD_BLOCK_SIZE as u64).to_le_bytes());
+        hd.extend_from_slice(&6u64.to_le_bytes());
+        for link in [dg, fh, 0, 0, 0, 0] {
+            hd.extend_from_slice(&link.to_le_bytes());
+        }
+        hd.extend_from_slice(&start_ns.to_le_bytes());
+        // Time zone and DST offsets, time flags (UTC), time class, flags, reserved, start angle and distance
+        hd.extend_from_slice(&[0u8; 24]);
+        self.out.seek(SeekFrom::Start(ID_BLOCK_SIZE))?;
+        self.out.write_all(&hd)?;
+        // Mark the file as finished
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(b"MDF     ")?;
+        self.out.seek(SeekFrom::Start(60))?;
+        self.out.write_all(&0u16.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        return Ok(self.out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+    use std::io::Cursor;
+    use socketcan::{CanErrorFrame, CanFdFrame, CanFrame, ExtendedId, StandardId};
+    use socketcan::id::FdFlags;
+
+    const START: Duration = Duration::from_secs(1714566600);
+
+    fn u64_at(file: &[u8], offset: u64) -> u64 {
+        return u64::from_le_bytes(file[offset as usize..offset as usize + 8].try_into().unwrap());
+    }
+
+    /// The ID, links and data of the block at `address`
+    fn block(file: &[u8], address: u64) -> (&[u8], Vec<u64>, &[u8]) {
+        let len = u64_at(file, address + 8);
+        let link_count = u64_at(file, address + 16);
+        let links = (0..link_count).map(|i| u64_at(file, address + 24 + i * 8)).collect();
+        let data = &file[(address + 24 + link_count * 8) as usize..(address + len) as usize];
+        return (&file[address as usize..address as usize + 4], links, data);
+    }
+
+    fn text(file: &[u8], address: u64) -> String {
+        let (id, _, data) = block(file, address);
+        assert_eq!(id, b"##TX");
+        return String::from_utf8(data.split(|b| *b == 0).next().unwrap().to_vec()).unwrap();
+    }
+
+    fn channel_names(file: &[u8], mut address: u64) -> Vec<String> {
+        let mut names = Vec::new();
+        while address != 0 {
+            let (id, links, _) = block(file, address);
+            assert_eq!(id, b"##CN");
+            names.push(text(file, links[2]));
+            address = links[0];
+        }
+        return names;
+    }
+
+    #[test]
+    fn finished_files_describe_the_records_written() {
+        let mut writer = Writer::new(Cursor::new(Vec::new()), ChannelMap::new()).unwrap();
+        let frames: Vec<CanAnyFrame> = vec![
+            CanFrame::new(StandardId::new(0x430).unwrap(), &[0, 1, 0xE2, 0x40, 0, 0, 0, 0]).unwrap().into(),
+            CanFrame::new(ExtendedId::new(0x18DAF110).unwrap(), &[1, 2, 3]).unwrap().into(),
+            CanFrame::new_remote(StandardId::new(0x123).unwrap(), 4).unwrap().into(),
+            CanAnyFrame::Error(CanErrorFrame::new_error(0x04, &[0, 0x08, 0, 0, 0, 0, 0, 0]).unwrap()),
+            CanAnyFrame::Fd(CanFdFrame::with_flags(StandardId::new(0x7FF).unwrap(), &[0xAA; 12], FdFlags::BRS | FdFlags::ESI).unwrap()),
+        ];
+        // Record lengths written for each record ID
+        let mut written: BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+        for (i, frame) in frames.iter().enumerate() {
+            let record_id = match frame {
+                CanAnyFrame::Normal(_) => 1,
+                CanAnyFrame::Remote(_) => 2,
+                CanAnyFrame::Error(_) => 3,
+                CanAnyFrame::Fd(_) => 4,
+            };
+            let len = writer.write_frame(["can0", "can1"][i % 2], frame, START + Duration::from_millis(i as u64)).unwrap();
+            written.entry(record_id).or_default().push(len);
+        }
+        for i in 0..3 {
+            let values = [PhysicalValue::new("Odometer", 123456.0 + i as f64, "km")];
+            let len = writer.write_values("Odometer", &values, START + Duration::from_secs(1 + i)).unwrap();
+            written.entry(5).or_default().push(len);
+        }
+        let total: usize = written.values().flatten().sum();
+        let file = writer.finish().unwrap().into_inner();
+
+        assert_eq!(&file[0..8], b"MDF     ");
+        assert_eq!(&file[60..62], &[0, 0]);
+
+        let (id, hd_links, hd_data) = block(&file, ID_BLOCK_SIZE);
+        assert_eq!(id, b"##HD");
+        assert_eq!(u64_at(hd_data, 0), START.as_nanos() as u64);
+        let (id, dg_links, dg_data) = block(&file, hd_links[0]);
+        assert_eq!(id, b"##DG");
+        assert_eq!(dg_links[0], 0);
+        assert_eq!(dg_data[0] as usize, RECORD_ID_SIZE);
+
+        let (id, _, _) = block(&file, dg_links[2]);
+        assert_eq!(id, b"##DT");
+        assert_eq!(u64_at(&file, dg_links[2] + 8), DT_HEADER_SIZE + total as u64);
+
+        let mut address = dg_links[1];
+        let mut record_ids: Vec<u16> = Vec::new();
+        while address != 0 {
+            let (id, links, data) = block(&file, address);
+            assert_eq!(id, b"##CG");
+            let record_id = u64_at(data, 0) as u16;
+            let cycles = u64_at(data, 8);
+            let data_bytes = u32::from_le_bytes(data[24..28].try_into().unwrap()) as usize;
+            let lens = &written[&record_id];
+            assert_eq!(cycles, lens.len() as u64, "record ID {}", record_id);
+            assert!(lens.iter().all(|len| *len == data_bytes + RECORD_ID_SIZE), "record ID {}: {} + 2 bytes, wrote {:?}", record_id, data_bytes, lens);
+
+            let channels = channel_names(&file, links[1]);
+            assert_eq!(channels[0], "Timestamp");
+            match record_id {
+                1 | 3 | 4 => {
+                    let (_, frame_links, _) = block(&file, links[1]);
+                    let (_, member_links, _) = block(&file, frame_links[0]);
+                    let members = channel_names(&file, member_links[1]);
+                    assert!(members.iter().any(|m| m.ends_with(".DataBytes")), "{:?}", members);
+                    assert_eq!(members.iter().any(|m| m.ends_with(".BRS")), record_id == 4, "{:?}", members);
+                },
+                2 => assert_eq!(channels[1], "CAN_RemoteFrame"),
+                _ => assert_eq!(channels[1..], ["Odometer"]),
+            }
+            record_ids.push(record_id);
+            address = links[0];
+        }
+        assert_eq!(record_ids, [1, 2, 3, 4, 5]);
+    }
+}
@@ -0,0 +1,223 @@
+//! The `<log>.meta.toml` file written next to each log when it's closed, so a log can be
+//! described without reading it.
+//!
+//! `Summary` collects what goes in it as frames are logged: the time span, frame counts, and
+//! where the car was and how far it went, from the GPS, odometer and range messages when they
+//! decode.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use socketcan::{CanAnyFrame, CanFrame, EmbeddedFrame, Frame};
+use uom::si::length::kilometer;
+
+use super::{ParsedFrame, RotationReason};
+use super::timestamps::ClockSource;
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Serialize)]
+pub struct Positions {
+    pub first: Position,
+    pub last: Position,
+}
+
+/// First and last value of a reading during the log
+#[derive(Serialize)]
+pub struct Change {
+    pub first: f64,
+    pub last: f64,
+    pub delta: f64,
+}
+
+/// Simple values come before tables, as TOML needs
+#[derive(Serialize)]
+pub struct LogMeta {
+    pub recorder_version: String,
+    pub interfaces: Vec<String>,
+    /// Bus speed in bits per second
+    pub bitrate: u64,
+    /// Time of the first and last frame, in RFC 3339
+    pub start: Option<String>,
+    pub end: Option<String>,
+    /// Why the log was closed
+    pub rotation_reason: RotationReason,
+    pub frames: u64,
+    pub error_frames: u64,
+    /// Frames the kernel dropped because the receive buffer was full
+    pub dropped_frames: u64,
+    /// Frames dropped because the writer fell behind and the queue was full
+    pub queue_dropped_frames: u64,
+    /// Most frames waiting to be written at once
+    pub max_queue_depth: u64,
+    pub gps_position: Option<Positions>,
+    pub odometer_km: Option<Change>,
+    pub electric_range_km: Option<Change>,
+    pub gas_range_km: Option<Change>,
+    /// Frames of each ID, in hex
+    pub frames_per_id: BTreeMap<String, u64>,
+    /// Frames on each interface whose time came from each clock source. Left out for recovered logs,
+    /// where it isn't known.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub timestamp_sources: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl LogMeta {
+    /// Path of the metadata file for the log at `log_path`
+    pub fn path(log_path: &str) -> String {
+        return format!("{}.meta.toml", log_path);
+    }
+
+    pub fn write(&self, log_path: &str) -> io::Result<()> {
+        let text = toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        return fs::write(LogMeta::path(log_path), text);
+    }
+}
+
+fn format_time(t: Duration) -> String {
+    let time: DateTime<Utc> = DateTime::from_timestamp(t.as_secs() as i64, t.subsec_nanos()).unwrap_or_default();
+    return time.to_rfc3339_opts(SecondsFormat::Micros, true);
+}
+
+fn change(span: Option<(f64, f64)>) -> Option<Change> {
+    return span.map(|(first, last)| Change { first, last, delta: last - first });
+}
+
+/// Updates the first and last value of a reading
+fn update<T: Copy>(span: &mut Option<(T, T)>, value: T) {
+    match span {
+        Some((_, last)) => *last = value,
+        None => *span = Some((value, value)),
+    }
+}
+
+/// What's been logged so far
+#[derive(Default)]
+pub struct Summary {
+    recorder_version: String,
+    interfaces: Vec<String>,
+    bitrate: u64,
+    start: Option<Duration>,
+    end: Option<Duration>,
+    frames: u64,
+    error_frames: u64,
+    dropped_frames: u64,
+    queue_dropped_frames: u64,
+    max_queue_depth: u64,
+    /// Frame counts by ID, with whether the ID is extended
+    per_id: HashMap<(u32, bool), u64>,
+    /// Frame counts for each interface in `interfaces`, by timestamp source
+    sources: Vec<[u64; 3]>,
+    position: Option<(Position, Position)>,
+    odometer: Option<(f64, f64)>,
+    electric_range: Option<(f64, f64)>,
+    gas_range: Option<(f64, f64)>,
+}
+
+impl Summary {
+    pub fn new(interfaces: Vec<String>) -> Summary {
+        Summary { interfaces, ..Default::default() }
+    }
+
+    /// Adds an interface the log has frames from, if it's not listed yet
+    pub fn interface(&mut self, name: &str) {
+        if !self.interfaces.iter().any(|i| i == name) {
+            self.interfaces.push(name.to_string());
+        }
+    }
+
+    /// Records what wrote the log
+    pub fn set_recorder(&mut self, version: &str, bitrate: u64) {
+        self.recorder_version = version.to_string();
+        self.bitrate = bitrate;
+    }
+
+    pub fn frame(&mut self, frame: &CanAnyFrame, t: Duration) {
+        self.start.get_or_insert(t);
+        self.end = Some(t);
+        self.frames += 1;
+        let f = match frame {
+            CanAnyFrame::Error(_) => {
+                self.error_frames += 1;
+                return;
+            },
+            CanAnyFrame::Normal(f) => Some(CanFrame::Data(*f)),
+            _ => None,
+        };
+        *self.per_id.entry((frame.raw_id(), frame.is_extended())).or_insert(0) += 1;
+        let f = match f {
+            Some(f) if !f.is_extended() && matches!(f.raw_id(), 0x352 | 0x37B | 0x430 | 0x465) => f,
+            _ => return,
+        };
+        match super::parse_frame(f) {
+            Ok(ParsedFrame::_465(location)) => update(&mut self.position, Position { latitude: location.latitude(), longitude: location.longitude() }),
+            Ok(ParsedFrame::_430 { odometer }) => update(&mut self.odometer, odometer.get::<kilometer>() as f64),
+            Ok(ParsedFrame::_352 { electric_range }) => update(&mut self.electric_range, electric_range.get::<kilometer>() as f64),
+            Ok(ParsedFrame::_37B { gas_range }) => update(&mut self.gas_range, gas_range.get::<kilometer>() as f64),
+            _ => (),
+        }
+    }
+
+    /// Counts a frame on the interface numbered `iface` in the list given to `new` whose time came from `source`
+    pub fn timestamp(&mut self, iface: usize, source: ClockSource) {
+        if self.sources.len() <= iface {
+            self.sources.resize(iface + 1, [0; 3]);
+        }
+        self.sources[iface][source as usize] += 1;
+    }
+
+    /// Time of the last frame
+    pub fn end(&self) -> Option<Duration> {
+        return self.end;
+    }
+
+    pub fn dropped(&mut self, count: u64) {
+        self.dropped_frames += count;
+    }
+
+    pub fn queue_stats(&mut self, max_depth: usize, dropped: u64) {
+        self.max_queue_depth = max_depth as u64;
+        self.queue_dropped_frames = dropped;
+    }
+
+    pub fn meta(&self, reason: RotationReason) -> LogMeta {
+        let frames_per_id = self.per_id.iter()
+            .map(|((id, extended), count)| (if *extended { format!("{:08X}", id) } else { format!("{:03X}", id) }, *count))
+            .collect();
+        let mut timestamp_sources: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+        for (iface, counts) in self.interfaces.iter().zip(self.sources.iter()) {
+            let sources = [ClockSource::Hardware, ClockSource::Kernel, ClockSource::Userspace];
+            let counts: BTreeMap<String, u64> = sources.iter().filter(|s| counts[**s as usize] > 0).map(|s| (s.to_string(), counts[*s as usize])).collect();
+            if !counts.is_empty() {
+                timestamp_sources.insert(iface.clone(), counts);
+            }
+        }
+        LogMeta {
+            recorder_version: self.recorder_version.clone(),
+            interfaces: self.interfaces.clone(),
+            bitrate: self.bitrate,
+            start: self.start.map(format_time),
+            end: self.end.map(format_time),
+            rotation_reason: reason,
+            frames: self.frames,
+            error_frames: self.error_frames,
+            dropped_frames: self.dropped_frames,
+            queue_dropped_frames: self.queue_dropped_frames,
+            max_queue_depth: self.max_queue_depth,
+            gps_position: self.position.map(|(first, last)| Positions { first, last }),
+            odometer_km: change(self.odometer),
+            electric_range_km: change(self.electric_range),
+            gas_range_km: change(self.gas_range),
+            frames_per_id,
+            timestamp_sources,
+        }
+    }
+}
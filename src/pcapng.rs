@@ -0,0 +1,450 @@
+//! pcapng capture files with the LINKTYPE_CAN_SOCKETCAN encapsulation, for opening drives in Wireshark.
+//!
+//! Each interface gets an Interface Description Block the first time it shows up, with
+//! nanosecond timestamp resolution. Frames are stored as the kernel's 16 byte `struct can_frame`,
+//! or 72 byte `struct canfd_frame` for CAN FD, the same as a capture taken with libpcap. When the file is finished, each interface gets an
+//! Interface Statistics Block carrying a comment on why the log was closed.
+//! Frames the kernel dropped are noted with a statistics block too, at the time of the drop.
+//!
+//! `Reader` reads the frames back from captures in this layout, e.g. to rebuild a recovered log's summary.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use libc::{CAN_EFF_FLAG, CAN_EFF_MASK, CAN_ERR_FLAG, CAN_RTR_FLAG, CAN_SFF_MASK};
+use socketcan::{CanAnyFrame, CanErrorFrame, CanFdFrame, CanFrame, EmbeddedFrame, ExtendedId, Frame, Id, StandardId};
+use socketcan::id::FdFlags;
+
+use super::candump::Record;
+use super::compression::Decoder;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const INTERFACE_STATISTICS: u32 = 0x0000_0005;
+const ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+/// Size of `struct can_frame`
+const CAN_MTU: usize = 16;
+/// Size of `struct canfd_frame`
+const CANFD_MTU: usize = 72;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const ISB_ENDTIME: u16 = 3;
+const ISB_IFRECV: u16 = 4;
+const ISB_OSDROP: u16 = 7;
+
+/// Largest block the reader accepts. Ours are far smaller; anything bigger is damage.
+const MAX_BLOCK_LEN: usize = 64 * 1024;
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len().div_ceil(4) * 4, 0);
+}
+
+fn push_end_of_options(body: &mut Vec<u8>) {
+    body.extend_from_slice(&OPT_END.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+}
+
+/// Splits a time into the high and low halves of a nanosecond pcapng timestamp
+fn timestamp(t: Duration) -> [u8; 8] {
+    let nanos = t.as_nanos() as u64;
+    let mut bytes = [0u8; 8];
+    bytes[0..4].copy_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+    bytes[4..8].copy_from_slice(&(nanos as u32).to_le_bytes());
+    return bytes;
+}
+
+struct Interface {
+    name: String,
+    received: u64,
+    /// Frames the kernel dropped
+    dropped: u64,
+    last: Duration,
+}
+
+/// Length of the complete blocks at the start of `data`, and whether what follows them can't be the start of
+/// a block, e.g. the zeros a power cut can leave at the end of a file
+pub fn complete_len(data: &[u8]) -> (usize, bool) {
+    let mut len = 0;
+    while data.len() - len >= 8 {
+        let block = u32::from_le_bytes(data[len + 4..len + 8].try_into().unwrap()) as usize;
+        if block < 12 || !block.is_multiple_of(4) {
+            return (len, true);
+        }
+        if data.len() - len < block {
+            break;
+        }
+        len += block;
+    }
+    return (len, false);
+}
+
+/// Writes frames as pcapng enhanced packets
+pub struct Writer<W: Write> {
+    out: W,
+    ifaces: Vec<Interface>,
+    /// Scratch space for the block being written
+    block: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Starts a capture, writing the section header to `out`
+    pub fn new(out: W) -> io::Result<Writer<W>> {
+        let mut writer = Writer { out, ifaces: Vec::new(), block: Vec::new() };
+        let mut body: Vec<u8> = BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        // Version 1.0, section length unknown
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        push_option(&mut body, SHB_USERAPPL, format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")).as_bytes());
+        push_end_of_options(&mut body);
+        writer.write_block(SECTION_HEADER, &body)?;
+        return Ok(writer);
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<usize> {
+        let len = (12 + body.len()) as u32;
+        self.block.clear();
+        self.block.extend_from_slice(&block_type.to_le_bytes());
+        self.block.extend_from_slice(&len.to_le_bytes());
+        self.block.extend_from_slice(body);
+        self.block.extend_from_slice(&len.to_le_bytes());
+        self.out.write_all(&self.block)?;
+        return Ok(self.block.len());
+    }
+
+    /// Number of the interface, describing it first if it's new
+    fn interface(&mut self, iface: &str) -> io::Result<(u32, usize)> {
+        if let Some(i) = self.ifaces.iter().position(|i| i.name == iface) {
+            return Ok((i as u32, 0));
+        }
+        let mut body: Vec<u8> = LINKTYPE_CAN_SOCKETCAN.to_le_bytes().to_vec();
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit
+        body.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut body, IF_NAME, iface.as_bytes());
+        push_option(&mut body, IF_TSRESOL, &[9]);
+        push_end_of_options(&mut body);
+        let written = self.write_block(INTERFACE_DESCRIPTION, &body)?;
+        self.ifaces.push(Interface { name: iface.to_string(), received: 0, dropped: 0, last: Duration::ZERO });
+        return Ok((self.ifaces.len() as u32 - 1, written));
+    }
+
+    pub fn write_frame(&mut self, iface: &str, frame: &CanAnyFrame, t: Duration) -> io::Result<usize> {
+        let (id, mut written) = self.interface(iface)?;
+        let mut packet = [0u8; CANFD_MTU];
+        // The CAN ID word, flags included, is in network byte order
+        packet[0..4].copy_from_slice(&frame.id_word().to_be_bytes());
+        let mtu = match frame {
+            CanAnyFrame::Fd(f) => {
+                packet[4] = f.data().len() as u8;
+                packet[5] = f.flags().bits();
+                CANFD_MTU
+            },
+            _ => {
+                packet[4] = frame.dlc() as u8;
+                CAN_MTU
+            },
+        };
+        packet[8..8 + frame.data().len()].copy_from_slice(frame.data());
+        let mut body: Vec<u8> = Vec::with_capacity(20 + mtu);
+        body.extend_from_slice(&id.to_le_bytes());
+        body.extend_from_slice(&timestamp(t));
+        body.extend_from_slice(&(mtu as u32).to_le_bytes());
+        body.extend_from_slice(&(mtu as u32).to_le_bytes());
+        body.extend_from_slice(&packet[..mtu]);
+        written += self.write_block(ENHANCED_PACKET, &body)?;
+        let interface = &mut self.ifaces[id as usize];
+        interface.received += 1;
+        interface.last = t;
+        return Ok(written);
+    }
+
+    /// Notes that the kernel dropped `count` frames on `iface` just before `t`
+    pub fn write_drops(&mut self, iface: &str, count: u64, t: Duration) -> io::Result<usize> {
+        let (id, written) = self.interface(iface)?;
+        self.ifaces[id as usize].dropped += count;
+        return Ok(written + self.write_statistics(id, t, &format!("{} frames dropped", count))?);
+    }
+
+    /// Writes the counts so far for an interface
+    fn write_statistics(&mut self, id: u32, t: Duration, comment: &str) -> io::Result<usize> {
+        let interface = &self.ifaces[id as usize];
+        let mut body: Vec<u8> = id.to_le_bytes().to_vec();
+        body.extend_from_slice(&timestamp(t));
+        push_option(&mut body, OPT_COMMENT, comment.as_bytes());
+        push_option(&mut body, ISB_ENDTIME, &timestamp(t));
+        push_option(&mut body, ISB_IFRECV, &interface.received.to_le_bytes());
+        push_option(&mut body, ISB_OSDROP, &interface.dropped.to_le_bytes());
+        push_end_of_options(&mut body);
+        return self.write_block(INTERFACE_STATISTICS, &body);
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        return self.out.flush();
+    }
+
+    /// Writes the statistics for each interface with `comment`, e.g. the reason the log rotated,
+    /// and returns the output
+    pub fn finish(mut self, comment: &str) -> io::Result<W> {
+        for i in 0..self.ifaces.len() {
+            self.write_statistics(i as u32, self.ifaces[i].last, comment)?;
+        }
+        self.out.flush()?;
+        return Ok(self.out);
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    /// A packet that isn't a valid CAN frame. Reading can continue with the next block.
+    Malformed { offset: u64, message: String },
+    /// The file isn't a capture this reader understands or its structure is damaged. Nothing follows this.
+    Corrupt { offset: u64, message: String },
+    /// The capture ends partway through a block, e.g. after a power cut. Nothing follows this.
+    Truncated { offset: u64 },
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "{}", e),
+            ReadError::Malformed { offset, message } | ReadError::Corrupt { offset, message } => write!(f, "offset {}: {}", offset, message),
+            ReadError::Truncated { offset } => write!(f, "offset {}: log is truncated", offset),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+/// An interface described in the capture
+struct ReadInterface {
+    name: String,
+    /// Length of a timestamp tick
+    resolution: Duration,
+}
+
+/// Reads the frames of a little-endian SocketCAN capture. Blocks other than interface descriptions
+/// and enhanced packets are skipped.
+pub struct Reader<R> {
+    inner: R,
+    ifaces: Vec<ReadInterface>,
+    /// Offset of the next block, after decompression
+    position: u64,
+    done: bool,
+}
+
+impl Reader<BufReader<Decoder>> {
+    /// Opens a capture, decompressing it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader<BufReader<Decoder>>, ReadError> {
+        return Reader::new(BufReader::new(Decoder::open(path)?));
+    }
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the section header from the start of `inner`
+    pub fn new(inner: R) -> Result<Reader<R>, ReadError> {
+        let mut reader = Reader { inner, ifaces: Vec::new(), position: 0, done: false };
+        let not_pcapng = || ReadError::Corrupt { offset: 0, message: String::from("not a little-endian pcapng capture") };
+        match reader.read_block() {
+            Ok(Some((SECTION_HEADER, body))) if body.len() >= 4 && u32_at(&body, 0) == BYTE_ORDER_MAGIC => (),
+            Err(ReadError::Io(e)) => return Err(ReadError::Io(e)),
+            _ => return Err(not_pcapng()),
+        }
+        return Ok(reader);
+    }
+
+    /// Reads the next block's type and body, or `None` at the end of the capture
+    fn read_block(&mut self) -> Result<Option<(u32, Vec<u8>)>, ReadError> {
+        let offset = self.position;
+        let mut header = [0u8; 8];
+        match read_full(&mut self.inner, &mut header)? {
+            0 => return Ok(None),
+            8 => (),
+            _ => return Err(ReadError::Truncated { offset }),
+        }
+        let block_type = u32_at(&header, 0);
+        let len = u32_at(&header, 4) as usize;
+        // A power cut can leave the end of the file filled with zeros
+        if header.iter().all(|b| *b == 0) {
+            return Err(ReadError::Truncated { offset });
+        }
+        if len < 12 || !len.is_multiple_of(4) || len > MAX_BLOCK_LEN {
+            return Err(ReadError::Corrupt { offset, message: format!("invalid block length {}", len) });
+        }
+        let mut rest = vec![0u8; len - 8];
+        if read_full(&mut self.inner, &mut rest)? != rest.len() {
+            return Err(ReadError::Truncated { offset });
+        }
+        if u32_at(&rest, rest.len() - 4) as usize != len {
+            return Err(ReadError::Corrupt { offset, message: String::from("block lengths don't match") });
+        }
+        rest.truncate(len - 12);
+        self.position += len as u64;
+        return Ok(Some((block_type, rest)));
+    }
+
+    fn add_interface(&mut self, body: &[u8]) {
+        let mut name = format!("can{}", self.ifaces.len());
+        let mut resolution = Duration::from_micros(1);
+        let mut options = body.get(8..).unwrap_or(&[]);
+        while options.len() >= 4 {
+            let code = u16::from_le_bytes([options[0], options[1]]);
+            let len = u16::from_le_bytes([options[2], options[3]]) as usize;
+            let value = match options.get(4..4 + len) {
+                Some(v) => v,
+                None => break,
+            };
+            match code {
+                OPT_END => break,
+                IF_NAME => name = String::from_utf8_lossy(value).to_string(),
+                // Powers of 10 only; the top bit would make it a power of 2
+                IF_TSRESOL if len == 1 && value[0] < 10 => resolution = Duration::from_nanos(10u64.pow(9 - value[0] as u32)),
+                _ => (),
+            }
+            options = options.get(4 + len.div_ceil(4) * 4..).unwrap_or(&[]);
+        }
+        self.ifaces.push(ReadInterface { name, resolution });
+    }
+
+    fn packet(&self, body: &[u8]) -> Result<Record, String> {
+        if body.len() < 20 {
+            return Err(String::from("packet block too short"));
+        }
+        let iface = self.ifaces.get(u32_at(body, 0) as usize).ok_or_else(|| format!("unknown interface {}", u32_at(body, 0)))?;
+        let ticks = ((u32_at(body, 4) as u64) << 32) | u32_at(body, 8) as u64;
+        let captured = u32_at(body, 12) as usize;
+        let packet = body.get(20..20 + captured).ok_or_else(|| String::from("packet longer than its block"))?;
+        let time = Duration::from_nanos(ticks.saturating_mul(iface.resolution.as_nanos() as u64));
+        return Ok(Record { time, iface: iface.name.clone(), frame: parse_packet(packet)? });
+    }
+}
+
+/// Decodes a `struct can_frame` or `struct canfd_frame`
+fn parse_packet(packet: &[u8]) -> Result<CanAnyFrame, String> {
+    if packet.len() != CAN_MTU && packet.len() != CANFD_MTU {
+        return Err(format!("{} bytes is neither a CAN nor a CAN FD frame", packet.len()));
+    }
+    let id_word = u32::from_be_bytes(packet[0..4].try_into().unwrap());
+    let len = packet[4] as usize;
+    let invalid = || format!("invalid frame {:08X} with length {}", id_word, len);
+    if len > packet.len() - 8 {
+        return Err(invalid());
+    }
+    let data_len = if id_word & CAN_RTR_FLAG != 0 { 0 } else { len };
+    let data = &packet[8..8 + data_len];
+    if id_word & CAN_ERR_FLAG != 0 {
+        return CanErrorFrame::new_error(id_word, data).map(CanAnyFrame::Error).map_err(|e| e.to_string());
+    }
+    let id: Id = if id_word & CAN_EFF_FLAG != 0 {
+        ExtendedId::new(id_word & CAN_EFF_MASK).ok_or_else(invalid)?.into()
+    } else {
+        StandardId::new((id_word & CAN_SFF_MASK) as u16).ok_or_else(invalid)?.into()
+    };
+    let frame = if packet.len() == CANFD_MTU {
+        CanFdFrame::with_flags(id, data, FdFlags::from_bits_truncate(packet[5])).map(CanAnyFrame::Fd)
+    } else if id_word & CAN_RTR_FLAG != 0 {
+        CanFrame::new_remote(id, len).map(CanAnyFrame::from)
+    } else {
+        CanFrame::new(id, data).map(CanAnyFrame::from)
+    };
+    return frame.ok_or_else(invalid);
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+}
+
+/// Reads until `buf` is full or the end of the input, returning how much was read
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(len);
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Record, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let offset = self.position;
+            match self.read_block() {
+                Ok(Some((INTERFACE_DESCRIPTION, body))) => self.add_interface(&body),
+                Ok(Some((ENHANCED_PACKET, body))) => {
+                    return Some(self.packet(&body).map_err(|message| ReadError::Malformed { offset, message }));
+                },
+                Ok(Some(_)) => (),
+                Ok(None) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frames_round_trip() {
+        let t = Duration::new(1714566600, 123_456_789);
+        let frames: Vec<(&str, CanAnyFrame)> = vec![
+            ("can0", CanFrame::new(StandardId::new(0x465).unwrap(), &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap().into()),
+            ("can1", CanFrame::new(ExtendedId::new(0x18DAF110).unwrap(), &[9]).unwrap().into()),
+            ("can0", CanFrame::new_remote(StandardId::new(0x123).unwrap(), 4).unwrap().into()),
+            ("can1", CanAnyFrame::Error(CanErrorFrame::new_error(0x04, &[0, 0x08, 0, 0, 0, 0, 0, 0]).unwrap())),
+            ("can0", CanAnyFrame::Fd(CanFdFrame::with_flags(StandardId::new(0x7FF).unwrap(), &[0xAB; 12], FdFlags::BRS | FdFlags::ESI).unwrap())),
+        ];
+        let mut writer = Writer::new(Cursor::new(Vec::new())).unwrap();
+        for (i, (iface, frame)) in frames.iter().enumerate() {
+            writer.write_frame(iface, frame, t + Duration::from_millis(i as u64)).unwrap();
+        }
+        writer.write_drops("can0", 3, t).unwrap();
+        let capture = writer.finish("shutdown").unwrap().into_inner();
+
+        let records: Vec<Record> = Reader::new(Cursor::new(capture.clone())).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), frames.len());
+        for (i, (record, (iface, frame))) in records.iter().zip(frames.iter()).enumerate() {
+            assert_eq!(record.time, t + Duration::from_millis(i as u64));
+            assert_eq!(record.iface, *iface);
+            assert_eq!(format!("{:?}", record.frame), format!("{:?}", frame));
+        }
+
+        // A torn final block ends the capture
+        let torn = &capture[..capture.len() - 10];
+        let results: Vec<Result<Record, ReadError>> = Reader::new(Cursor::new(torn.to_vec())).unwrap().collect();
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), frames.len());
+        assert!(matches!(results.last(), Some(Err(ReadError::Truncated { .. }))));
+    }
+}
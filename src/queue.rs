@@ -0,0 +1,332 @@
+//! Bounded queue between the thread reading frames and the thread writing them to a log.
+//!
+//! The queue holds up to a number of frames. When the writer falls behind, e.g. on a stalled SD
+//! card, `Overflow` decides what gives: the reader waits, leaving frames in the socket's receive
+//! buffer, or frames are dropped and counted. Only items holding frames count towards the
+//! capacity or are ever dropped; control messages always get through.
+//!
+//! Frames travel in `FrameBatch`es, which the writer hands back once they're written so the
+//! reader can fill them again without allocating.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::buses::Received;
+
+/// Frames in a full batch
+pub const BATCH_FRAMES: usize = 256;
+/// Longest a frame waits in a batch before it's sent on anyway
+pub const BATCH_AGE: Duration = Duration::from_millis(100);
+
+/// What to do with frames that don't fit in the queue
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Overflow {
+    /// Wait for the writer to make room
+    Block,
+    /// Make room by dropping the oldest frames waiting to be written
+    DropOldest,
+    /// Drop the frames that don't fit
+    DropNewest,
+}
+
+/// Frames read together, to be written in one go
+pub struct FrameBatch {
+    frames: Vec<Received>,
+    /// When the first frame went in
+    started: Option<Instant>,
+}
+
+impl FrameBatch {
+    pub fn new() -> FrameBatch {
+        FrameBatch { frames: Vec::with_capacity(BATCH_FRAMES), started: None }
+    }
+
+    pub fn push(&mut self, received: Received) {
+        self.started.get_or_insert_with(Instant::now);
+        self.frames.push(received);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.frames.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.frames.is_empty();
+    }
+
+    /// Whether the batch is full or has held a frame for `BATCH_AGE`
+    pub fn is_ready(&self) -> bool {
+        return self.frames.len() >= BATCH_FRAMES || self.started.is_some_and(|s| s.elapsed() >= BATCH_AGE);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Received> {
+        return self.frames.iter();
+    }
+
+    /// Empties the batch, keeping its memory
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.started = None;
+    }
+}
+
+impl Default for FrameBatch {
+    fn default() -> FrameBatch {
+        return FrameBatch::new();
+    }
+}
+
+/// The other end of the queue is gone
+#[derive(Debug)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "queue disconnected")
+    }
+}
+
+/// Something that can be sent through the queue
+pub trait QueueItem {
+    /// Number of frames held, or `None` for items that must never be dropped
+    fn frames(&self) -> Option<usize>;
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    /// Frames in `items`
+    frames: usize,
+    max_frames: usize,
+    dropped: u64,
+    sender: bool,
+    receiver: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    overflow: Overflow,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+/// Creates a queue holding up to `capacity` frames
+pub fn bounded<T: QueueItem>(capacity: usize, overflow: Overflow) -> (QueueSender<T>, QueueReceiver<T>) {
+    let state = State { items: VecDeque::new(), frames: 0, max_frames: 0, dropped: 0, sender: true, receiver: true };
+    let shared = Arc::new(Shared { state: Mutex::new(state), capacity, overflow, not_empty: Condvar::new(), not_full: Condvar::new() });
+    return (QueueSender { shared: Arc::clone(&shared) }, QueueReceiver { shared });
+}
+
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: QueueItem> QueueSender<T> {
+    /// Queues an item, unless it's dropped for lack of space. Fails if the receiver is gone.
+    pub fn send(&self, item: T) -> Result<(), Disconnected> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        let frames = item.frames();
+        if let Some(frames) = frames {
+            // An empty queue takes any batch, however large
+            let full = |s: &State<T>| s.frames > 0 && s.frames + frames > shared.capacity;
+            match shared.overflow {
+                Overflow::Block => {
+                    while full(&state) && state.receiver {
+                        state = shared.not_full.wait(state).unwrap();
+                    }
+                },
+                Overflow::DropNewest => {
+                    if full(&state) {
+                        state.dropped += frames as u64;
+                        return Ok(());
+                    }
+                },
+                Overflow::DropOldest => {
+                    while full(&state) {
+                        let oldest = match state.items.iter().position(|i| i.frames().is_some()) {
+                            Some(o) => o,
+                            None => break,
+                        };
+                        let dropped = state.items.remove(oldest).and_then(|i| i.frames()).unwrap_or(0);
+                        state.frames -= dropped;
+                        state.dropped += dropped as u64;
+                    }
+                },
+            }
+        }
+        if !state.receiver {
+            return Err(Disconnected);
+        }
+        state.frames += frames.unwrap_or(0);
+        state.max_frames = state.max_frames.max(state.frames);
+        state.items.push_back(item);
+        shared.not_empty.notify_one();
+        return Ok(());
+    }
+
+    /// Frames dropped for lack of space so far
+    pub fn dropped(&self) -> u64 {
+        return self.shared.state.lock().unwrap().dropped;
+    }
+
+    /// Most frames that have been waiting at once
+    pub fn max_depth(&self) -> usize {
+        return self.shared.state.lock().unwrap().max_frames;
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().sender = false;
+        self.shared.not_empty.notify_all();
+    }
+}
+
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: QueueItem> QueueReceiver<T> {
+    /// Waits for the next item. Returns `None` once the queue is empty and the sender is gone.
+    pub fn recv(&self) -> Option<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                state.frames -= item.frames().unwrap_or(0);
+                self.shared.not_full.notify_one();
+                return Some(item);
+            }
+            if !state.sender {
+                return None;
+            }
+            state = self.shared.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Frames dropped for lack of space so far
+    pub fn dropped(&self) -> u64 {
+        return self.shared.state.lock().unwrap().dropped;
+    }
+
+    /// Most frames that have been waiting at once
+    pub fn max_depth(&self) -> usize {
+        return self.shared.state.lock().unwrap().max_frames;
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver = false;
+        self.shared.not_full.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[derive(Debug, PartialEq)]
+    enum Item {
+        /// A named batch of some number of frames
+        Batch(&'static str, usize),
+        Control(&'static str),
+    }
+
+    impl QueueItem for Item {
+        fn frames(&self) -> Option<usize> {
+            match self {
+                Item::Batch(_, frames) => Some(*frames),
+                Item::Control(_) => None,
+            }
+        }
+    }
+
+    fn drain(rx: &QueueReceiver<Item>) -> Vec<Item> {
+        let mut items = Vec::new();
+        while let Some(item) = rx.recv() {
+            items.push(item);
+        }
+        return items;
+    }
+
+    #[test]
+    fn drop_newest_keeps_what_is_queued() {
+        let (tx, rx) = bounded(10, Overflow::DropNewest);
+        tx.send(Item::Batch("a", 6)).unwrap();
+        tx.send(Item::Batch("b", 4)).unwrap();
+        tx.send(Item::Batch("c", 3)).unwrap();
+        tx.send(Item::Control("flush")).unwrap();
+        tx.send(Item::Batch("d", 1)).unwrap();
+        assert_eq!(tx.dropped(), 4);
+        assert_eq!(tx.max_depth(), 10);
+        drop(tx);
+        assert_eq!(drain(&rx), vec![Item::Batch("a", 6), Item::Batch("b", 4), Item::Control("flush")]);
+        assert_eq!(rx.dropped(), 4);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_for_new_frames() {
+        let (tx, rx) = bounded(10, Overflow::DropOldest);
+        tx.send(Item::Batch("a", 6)).unwrap();
+        tx.send(Item::Control("flush")).unwrap();
+        tx.send(Item::Batch("b", 4)).unwrap();
+        tx.send(Item::Batch("c", 5)).unwrap();
+        assert_eq!(tx.dropped(), 6);
+        // Both older batches have to go to fit this one; the control message stays
+        tx.send(Item::Batch("d", 8)).unwrap();
+        assert_eq!(tx.dropped(), 15);
+        assert_eq!(tx.max_depth(), 10);
+        drop(tx);
+        assert_eq!(drain(&rx), vec![Item::Control("flush"), Item::Batch("d", 8)]);
+    }
+
+    #[test]
+    fn empty_queue_takes_an_oversized_batch() {
+        for overflow in [Overflow::Block, Overflow::DropOldest, Overflow::DropNewest] {
+            let (tx, rx) = bounded(10, overflow);
+            tx.send(Item::Batch("big", 25)).unwrap();
+            assert_eq!(tx.dropped(), 0);
+            assert_eq!(rx.recv(), Some(Item::Batch("big", 25)));
+        }
+    }
+
+    #[test]
+    fn block_waits_for_room_without_dropping() {
+        let (tx, rx) = bounded(10, Overflow::Block);
+        tx.send(Item::Batch("a", 8)).unwrap();
+        // Control messages never wait, even with the queue full
+        tx.send(Item::Control("ping")).unwrap();
+        let sent = Arc::new(AtomicBool::new(false));
+        let sender = {
+            let sent = Arc::clone(&sent);
+            thread::spawn(move || {
+                tx.send(Item::Batch("b", 5)).unwrap();
+                sent.store(true, Ordering::SeqCst);
+                tx
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!sent.load(Ordering::SeqCst), "sender should wait while the queue is full");
+        assert_eq!(rx.recv(), Some(Item::Batch("a", 8)));
+        let tx = sender.join().unwrap();
+        assert!(sent.load(Ordering::SeqCst));
+        assert_eq!(tx.dropped(), 0);
+        drop(tx);
+        assert_eq!(drain(&rx), vec![Item::Control("ping"), Item::Batch("b", 5)]);
+    }
+
+    #[test]
+    fn blocked_sender_fails_once_the_receiver_is_gone() {
+        let (tx, rx) = bounded(10, Overflow::Block);
+        tx.send(Item::Batch("a", 10)).unwrap();
+        let sender = thread::spawn(move || tx.send(Item::Batch("b", 1)));
+        thread::sleep(Duration::from_millis(20));
+        drop(rx);
+        assert!(sender.join().unwrap().is_err());
+    }
+}
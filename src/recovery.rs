@@ -0,0 +1,213 @@
+//! Repairing logs left behind when the recorder stopped without closing them, e.g. after a power cut.
+//!
+//! `Logger` writes each log as `<name>.partial` and renames it once it's closed cleanly, so a
+//! `.partial` file at startup is a log that was never finished. Its end may be torn partway
+//! through a line, record or block, and a compressed log's stream is never ended. `recover`
+//! cuts the log back to its last complete line, record or block, rewrites compressed logs as a
+//! complete stream, and gives the log its real name. Its metadata file is rebuilt from what's
+//! left, with `recovered` as the rotation reason.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use super::{asc, binlog, blf, candump, pcapng, LogFormat, RotationReason};
+use super::candump::Record;
+use super::channels::ChannelMap;
+use super::compression::{Compression, Decoder, Encoder};
+use super::meta::Summary;
+
+/// Added to the name of a log while it's being written
+pub const PARTIAL_SUFFIX: &str = ".partial";
+/// Added to the name of a partial log while `recover` rewrites it as a complete stream
+const TEMP_SUFFIX: &str = ".tmp";
+
+const FORMATS: [LogFormat; 5] = [LogFormat::Candump, LogFormat::Binary, LogFormat::Asc, LogFormat::Blf, LogFormat::Pcapng];
+const COMPRESSIONS: [Compression; 3] = [Compression::None, Compression::Gzip, Compression::Zstd];
+
+/// Name of the log at `path` while it's being written
+pub fn partial_path(path: &str) -> String {
+    return format!("{}{}", path, PARTIAL_SUFFIX);
+}
+
+/// Format and compression of a log, from the extensions on its name
+fn log_kind(name: &str) -> Option<(LogFormat, Compression)> {
+    for format in FORMATS {
+        for compression in COMPRESSIONS {
+            if name.ends_with(&format!("{}{}", format.extension(), compression.extension())) {
+                return Some((format, compression));
+            }
+        }
+    }
+    return None;
+}
+
+/// Length of the complete lines or blocks at the start of `data`, and whether what follows can't be completed
+fn complete_len(format: LogFormat, data: &[u8]) -> (usize, bool) {
+    match format {
+        LogFormat::Pcapng => return pcapng::complete_len(data),
+        _ => return (data.iter().rposition(|b| *b == b'\n').map_or(0, |p| p + 1), false),
+    }
+}
+
+/// Bytes at the start of a text or pcapng log, after decompression, that hold complete lines or blocks
+fn stream_len(path: &Path, format: LogFormat) -> io::Result<u64> {
+    let mut decoder = Decoder::open(path)?;
+    let mut pending: Vec<u8> = Vec::new();
+    let mut chunk = vec![0u8; 64 * 1024];
+    let mut len: u64 = 0;
+    loop {
+        let n = match decoder.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // Where a compressed stream was cut off
+            Err(_) => break,
+        };
+        pending.extend_from_slice(&chunk[..n]);
+        let (complete, damaged) = complete_len(format, &pending);
+        len += complete as u64;
+        pending.drain(..complete);
+        if damaged {
+            break;
+        }
+    }
+    return Ok(len);
+}
+
+/// Rebuilds the summary of a log from its frames
+fn summarize(path: &Path, format: LogFormat) -> Summary {
+    let mut summary = Summary::new(Vec::new());
+    let mut add = |record: Record| {
+        summary.interface(&record.iface);
+        summary.frame(&record.frame, record.time);
+    };
+    match format {
+        LogFormat::Candump => if let Ok(r) = candump::Reader::open(path) {
+            r.map_while(Result::ok).for_each(&mut add);
+        },
+        LogFormat::Binary => if let Ok(r) = binlog::Reader::open(path) {
+            r.map_while(Result::ok).for_each(&mut add);
+        },
+        LogFormat::Asc => if let Ok(r) = asc::Reader::open(path, ChannelMap::new()) {
+            r.map_while(Result::ok).for_each(&mut add);
+        },
+        LogFormat::Blf => if let Ok(r) = blf::Reader::open(path, ChannelMap::new()) {
+            r.map_while(Result::ok).for_each(&mut add);
+        },
+        LogFormat::Pcapng => if let Ok(r) = pcapng::Reader::open(path) {
+            r.map_while(Result::ok).for_each(&mut add);
+        },
+    }
+    return summary;
+}
+
+/// Repairs the partial log at `partial`, giving it its real name. Returns the bytes cut off the end,
+/// counted after decompression, or `None` if nothing was worth keeping and the log was deleted.
+pub fn recover(partial: &Path) -> io::Result<Option<u64>> {
+    let name = partial.to_string_lossy();
+    let path = name.strip_suffix(PARTIAL_SUFFIX).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a partial log"))?.to_string();
+    let (format, compression) = log_kind(&path).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unknown log format"))?;
+    let (len, total) = match format {
+        LogFormat::Binary => (binlog::complete_len(partial)?, fs::metadata(partial)?.len()),
+        LogFormat::Blf => (blf::complete_len(partial)?, fs::metadata(partial)?.len()),
+        LogFormat::Candump | LogFormat::Asc | LogFormat::Pcapng => {
+            let mut total: u64 = 0;
+            if compression == Compression::None {
+                total = fs::metadata(partial)?.len();
+            } else if let Ok(mut d) = Decoder::open(partial) {
+                // Count what decompresses, up to where the stream was cut off
+                total = io::copy(&mut d, &mut io::sink()).unwrap_or(0);
+            }
+            (stream_len(partial, format)?, total)
+        },
+    };
+    if len == 0 {
+        fs::remove_file(partial)?;
+        return Ok(None);
+    }
+    if compression == Compression::None {
+        OpenOptions::new().write(true).open(partial)?.set_len(len)?;
+    } else {
+        // A compressed stream can't be cut, so write the complete part again as a finished stream
+        let temp = format!("{}{}", partial.display(), TEMP_SUFFIX);
+        let mut encoder = Encoder::new(File::create(&temp)?, compression)?;
+        io::copy(&mut Decoder::open(partial)?.take(len), &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+        fs::rename(&temp, partial)?;
+    }
+    fs::rename(partial, &path)?;
+    if format == LogFormat::Binary {
+        // The index may be from before the last records, or point past the end of the log
+        if let Ok(index) = binlog::Index::build(&path) {
+            index.save(&path)?;
+        }
+    }
+    summarize(Path::new(&path), format).meta(RotationReason::Recovered).write(&path)?;
+    return Ok(Some(total.saturating_sub(len)));
+}
+
+/// Recovers every partial log in `dir`, e.g. at startup before anything else writes there.
+/// Rewrites that were cut short are deleted; the partial log they came from is still there to recover.
+pub fn recover_dir(dir: &Path) -> io::Result<()> {
+    let mut partials: Vec<PathBuf> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.to_string_lossy();
+        if name.ends_with(PARTIAL_SUFFIX) {
+            partials.push(path);
+        } else if name.ends_with(&format!("{}{}", PARTIAL_SUFFIX, TEMP_SUFFIX)) {
+            match fs::remove_file(&path) {
+                Ok(()) => println!("Deleted {}, left over from an earlier recovery", path.display()),
+                Err(e) => println!("Could not delete {}: {}", path.display(), e),
+            }
+        }
+    }
+    partials.sort();
+    for partial in partials {
+        match recover(&partial) {
+            Ok(Some(0)) => println!("Recovered log {}", partial.display()),
+            Ok(Some(cut)) => println!("Recovered log {}, cutting off {} bytes torn at the end", partial.display(), cut),
+            Ok(None) => println!("Deleted log {}, which had nothing complete in it", partial.display()),
+            Err(e) => println!("Could not recover log {}: {}", partial.display(), e),
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use super::super::meta::LogMeta;
+    use socketcan::{CanAnyFrame, CanFrame, EmbeddedFrame, StandardId};
+
+    #[test]
+    fn partial_pcapng_logs_are_recovered_with_a_summary() {
+        let dir = std::env::temp_dir().join(format!("car_logger_recovery_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("2024-05-01T12_30_00.000Z.pcapng.gz").to_string_lossy().to_string();
+        let partial = partial_path(&path);
+
+        let mut writer = pcapng::Writer::new(Encoder::new(File::create(&partial).unwrap(), Compression::Gzip).unwrap()).unwrap();
+        for i in 0..10u8 {
+            let frame = CanFrame::new(StandardId::new(0x430).unwrap(), &[0, 0, 0, i, 0, 0, 0, 0]).unwrap();
+            writer.write_frame("can0", &CanAnyFrame::from(frame), Duration::new(1714566600 + i as u64, 0)).unwrap();
+        }
+        // Never finished, as after a power cut
+        writer.flush().unwrap();
+        drop(writer);
+        // A rewrite from an earlier recovery that was cut short
+        let temp = format!("{}{}", partial, TEMP_SUFFIX);
+        fs::write(&temp, b"torn").unwrap();
+
+        recover_dir(&dir).unwrap();
+        assert!(!Path::new(&temp).exists());
+        assert!(!Path::new(&partial).exists());
+        assert_eq!(pcapng::Reader::open(&path).unwrap().count(), 10);
+        let meta = fs::read_to_string(LogMeta::path(&path)).unwrap();
+        assert!(meta.contains("frames = 10\n"), "{}", meta);
+        assert!(meta.contains("interfaces = [\"can0\"]\n"), "{}", meta);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
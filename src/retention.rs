@@ -0,0 +1,284 @@
+//! Keeping the log directory within its disk space limits.
+//!
+//! Logs are named after the time they were started (`2024-05-01T12_30_00.250Z.log.gz`, or without the
+//! milliseconds for logs from older recorders), and every file
+//! whose name starts with the same time belongs to the same log: the log itself and sidecars like
+//! the binary log index. Logs are deleted oldest first, a whole log at a time, until the directory
+//! holds no more than the maximum and the disk has the minimum free. Without either limit nothing
+//! is deleted; logging is paused instead while the disk is nearly full.
+//!
+//! Some logs are never deleted:
+//! - the newest one, which may still be being closed by a writer thread
+//! - ones with a `.keep` file, e.g. `2024-05-01T12_30_00.250Z.keep`
+//! - ones with a note from `time_marker` between their start and their last write
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use chrono::NaiveDateTime;
+
+/// Length of the time at the start of a log's name, up to the seconds
+const NAME_TIME_LEN: usize = "2024-05-01T12_30_00".len();
+/// Free space logging always needs, whatever the minimum is set to. Logging pauses below it, but
+/// logs are only deleted for the limits that were asked for.
+const RESERVED_SPACE: u64 = 64 << 20;
+/// How often to look again for space while logging is paused
+const PAUSED_RECHECK: Duration = Duration::from_secs(10);
+
+/// The files of one log
+struct Log {
+    /// Time the log was started, which is also the start of its name
+    start: Duration,
+    files: Vec<PathBuf>,
+    size: u64,
+    /// Time of the last write to any of its files
+    modified: Duration,
+    keep: bool,
+}
+
+/// Time a log was started, from the start of its file name
+fn name_time(name: &str) -> Option<Duration> {
+    let end = NAME_TIME_LEN + name.get(NAME_TIME_LEN..)?.find('Z')?;
+    let time = NaiveDateTime::parse_from_str(&name[..end], "%Y-%m-%dT%H_%M_%S%.f").ok()?.and_utc();
+    return Some(Duration::new(time.timestamp().try_into().ok()?, time.timestamp_subsec_nanos()));
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(stats.f_bavail as u64 * stats.f_frsize as u64);
+}
+
+/// Times of the notes in a `time_marker` file. Each line reads `2024-05-01 12:30:00 (1714566600.25) note`.
+fn marker_times(path: &Path) -> io::Result<Vec<Duration>> {
+    let text = fs::read_to_string(path)?;
+    let times = text.lines()
+        .filter_map(|l| l.split_once('(')?.1.split_once(')')?.0.parse::<f64>().ok())
+        .filter(|t| t.is_finite() && *t >= 0.0)
+        .map(Duration::from_secs_f64)
+        .collect();
+    return Ok(times);
+}
+
+pub struct Retention {
+    dir: PathBuf,
+    /// Most bytes the logs can take up
+    max_total: Option<u64>,
+    /// Fewest bytes to leave free on the disk
+    min_free: u64,
+    /// Fewest bytes free to start a new log
+    reserved: u64,
+    /// `time_marker` file whose notes protect the logs they fall in
+    markers: Option<PathBuf>,
+    /// When logging was paused for lack of space
+    paused: Option<Instant>,
+}
+
+impl Retention {
+    pub fn new(dir: &Path, max_total: Option<u64>, min_free: u64, markers: Option<PathBuf>) -> Retention {
+        Retention { dir: dir.to_path_buf(), max_total, min_free, reserved: RESERVED_SPACE, markers, paused: None }
+    }
+
+    /// Deletes old logs until the limits hold, and says whether there's space for a new log.
+    /// While logging is paused the directory is only looked at again every `PAUSED_RECHECK`.
+    pub fn make_room(&mut self) -> bool {
+        if self.paused.is_some_and(|p| p.elapsed() < PAUSED_RECHECK) {
+            return false;
+        }
+        let room = match self.enforce() {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Could not check space in {}: {}", self.dir.display(), e);
+                // Try logging anyway rather than stopping over a failed check
+                true
+            }
+        };
+        if !room && self.paused.is_none() {
+            println!("Not enough free space in {} and no logs can be deleted; logging paused", self.dir.display());
+        } else if room && self.paused.is_some() {
+            println!("Logging resumed");
+        }
+        self.paused = if room { None } else { Some(Instant::now()) };
+        return room;
+    }
+
+    /// Pauses logging for `PAUSED_RECHECK` after a log couldn't be started, e.g. because the disk filled up
+    /// before the limits were reached
+    pub fn pause(&mut self) {
+        self.paused = Some(Instant::now());
+    }
+
+    fn enforce(&self) -> io::Result<bool> {
+        let mut logs = self.logs()?;
+        let markers = match &self.markers {
+            Some(path) => marker_times(path).unwrap_or_else(|e| {
+                println!("Could not read time markers from {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        let mut total: u64 = logs.iter().map(|l| l.size).sum();
+        let mut free = free_space(&self.dir)?;
+        // The newest log is left alone
+        logs.pop();
+        for log in logs.iter() {
+            let over_total = self.max_total.is_some_and(|max| total > max);
+            if !over_total && free >= self.min_free {
+                break;
+            }
+            if log.keep || markers.iter().any(|m| *m >= log.start && *m <= log.modified) {
+                continue;
+            }
+            for file in log.files.iter() {
+                fs::remove_file(file)?;
+            }
+            println!("Deleted log {} to free {} bytes", log.files[0].display(), log.size);
+            total -= log.size;
+            free = free_space(&self.dir)?;
+        }
+        return Ok(free >= self.min_free.max(self.reserved));
+    }
+
+    /// Logs in the directory, oldest first
+    fn logs(&self) -> io::Result<Vec<Log>> {
+        let mut logs: BTreeMap<Duration, Log> = BTreeMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let start = match name.to_str().and_then(name_time) {
+                Some(s) => s,
+                None => continue,
+            };
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let keep = name.to_string_lossy().ends_with(".keep");
+            let log = logs.entry(start).or_insert_with(|| Log { start, files: Vec::new(), size: 0, modified, keep: false });
+            log.files.push(entry.path());
+            log.size += metadata.len();
+            log.modified = log.modified.max(modified);
+            log.keep |= keep;
+        }
+        let mut logs: Vec<Log> = logs.into_values().collect();
+        for log in logs.iter_mut() {
+            // The log itself first, then its sidecars
+            log.files.sort_by_key(|f| f.as_os_str().len());
+        }
+        return Ok(logs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for a test's logs
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("car_logger_retention_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    fn write(dir: &Path, name: &str, size: usize) {
+        fs::write(dir.join(name), vec![0u8; size]).unwrap();
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        names.sort();
+        return names;
+    }
+
+    #[test]
+    fn oldest_logs_are_deleted_with_their_sidecars_until_under_the_maximum() {
+        let dir = log_dir("oldest");
+        write(&dir, "2024-05-01T10_00_00.000Z.bin", 1000);
+        write(&dir, "2024-05-01T10_00_00.000Z.bin.idx", 100);
+        write(&dir, "2024-05-01T10_00_00.000Z.bin.meta.toml", 50);
+        write(&dir, "2024-05-01T11_00_00.000Z.log", 1000);
+        write(&dir, "2024-05-01T12_00_00.000Z.log", 1000);
+        write(&dir, "notes.txt", 5000);
+
+        let mut retention = Retention::new(&dir, Some(2100), 0, None);
+        assert!(retention.make_room());
+        assert_eq!(names(&dir), ["2024-05-01T11_00_00.000Z.log", "2024-05-01T12_00_00.000Z.log", "notes.txt"]);
+
+        // Under the maximum, nothing more goes
+        assert!(retention.make_room());
+        assert_eq!(names(&dir).len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn kept_and_marked_logs_are_skipped() {
+        let dir = log_dir("kept");
+        write(&dir, "2024-05-01T10_00_00.000Z.log", 1000);
+        write(&dir, "2024-05-01T10_00_00.000Z.keep", 0);
+        write(&dir, "2024-05-01T11_00_00.000Z.log", 1000);
+        write(&dir, "2024-05-01T12_00_00.000Z.log", 1000);
+        write(&dir, "2024-05-01T13_00_00.000Z.log", 1000);
+        // Falls between the start of the 11:00 log and its last write
+        let markers = dir.join("markers.txt");
+        fs::write(&markers, "2024-05-01 11:30:00 (1714563000.0) pothole\n").unwrap();
+
+        let mut retention = Retention::new(&dir, Some(0), 0, Some(markers));
+        assert!(retention.make_room());
+        assert_eq!(names(&dir), [
+            "2024-05-01T10_00_00.000Z.keep",
+            "2024-05-01T10_00_00.000Z.log",
+            "2024-05-01T11_00_00.000Z.log",
+            "2024-05-01T13_00_00.000Z.log",
+            "markers.txt",
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_newest_log_is_never_deleted() {
+        let dir = log_dir("newest");
+        write(&dir, "2024-05-01T10_00_00.000Z.log", 1000);
+        write(&dir, "2024-05-01T10_00_00.000Z.log.meta.toml", 50);
+
+        // No disk has this much free, and the only log can't go
+        let mut retention = Retention::new(&dir, Some(0), u64::MAX, None);
+        assert!(!retention.make_room());
+        assert_eq!(names(&dir).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nothing_is_deleted_without_limits() {
+        let dir = log_dir("no_limits");
+        write(&dir, "2024-05-01T10_00_00.000Z.log", 1000);
+        write(&dir, "2024-05-01T11_00_00.000Z.log", 1000);
+
+        // Short on space, but with no limits set logging pauses rather than deleting
+        let mut retention = Retention::new(&dir, None, 0, None);
+        retention.reserved = u64::MAX;
+        assert!(!retention.make_room());
+        assert_eq!(names(&dir).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_names_give_their_start_time() {
+        assert_eq!(name_time("2024-05-01T12_30_00Z.log.gz"), Some(Duration::from_secs(1714566600)));
+        assert_eq!(name_time("2024-05-01T12_30_00.250Z.log.gz"), Some(Duration::from_millis(1714566600250)));
+        assert_eq!(name_time("2024-05-01T12_30_00.250Z.keep"), Some(Duration::from_millis(1714566600250)));
+        assert_eq!(name_time("2024-05-01T12_30_00.log"), None);
+        assert_eq!(name_time("notes.txt"), None);
+    }
+}
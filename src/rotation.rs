@@ -0,0 +1,290 @@
+//! Rotating logs on something other than the bus going quiet.
+//!
+//! Each `RotationPolicy` sees the frames written to the current log, and about once a second the
+//! time and the log's size on disk. The first policy to ask for it closes the log. Sizes are read
+//! from the file, so they trail what's been logged by whatever is still in the write buffer.
+
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use socketcan::{CanAnyFrame, CanFrame, Frame};
+
+use super::dbc::Database;
+use super::RotationReason;
+
+/// How often the time and size are checked while logging
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+fn now() -> Duration {
+    return SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+}
+
+/// Decides when the current log should be closed
+pub trait RotationPolicy {
+    /// A new log was opened at `now`, the time since the UNIX epoch
+    fn start(&mut self, _now: Duration) {}
+
+    /// A frame was written to the log
+    fn frame(&mut self, _frame: &CanAnyFrame) -> Option<RotationReason> {
+        return None;
+    }
+
+    /// Regular check with the time since the UNIX epoch and the size of the log in bytes
+    fn check(&mut self, _now: Duration, _size: u64) -> Option<RotationReason> {
+        return None;
+    }
+}
+
+/// Rotates every `period`, on multiples of it since the UNIX epoch, so hourly logs start on the hour
+pub struct Interval {
+    period: Duration,
+    next: Duration,
+}
+
+impl Interval {
+    pub fn new(period: Duration) -> Interval {
+        Interval { period, next: Duration::MAX }
+    }
+}
+
+impl RotationPolicy for Interval {
+    fn start(&mut self, now: Duration) {
+        let period = self.period.as_nanos();
+        self.next = Duration::from_nanos(((now.as_nanos() / period + 1) * period) as u64);
+    }
+
+    fn check(&mut self, now: Duration, _size: u64) -> Option<RotationReason> {
+        if now >= self.next {
+            return Some(RotationReason::Interval);
+        }
+        return None;
+    }
+}
+
+/// Rotates once the log reaches a size on disk
+pub struct MaxSize {
+    max: u64,
+}
+
+impl MaxSize {
+    pub fn new(max: u64) -> MaxSize {
+        MaxSize { max }
+    }
+}
+
+impl RotationPolicy for MaxSize {
+    fn check(&mut self, _now: Duration, size: u64) -> Option<RotationReason> {
+        if size >= self.max {
+            return Some(RotationReason::MaxSize);
+        }
+        return None;
+    }
+}
+
+/// A signal of a message and a value it can take. Written as `MESSAGE.SIGNAL[=VALUE]`.
+#[derive(Clone, Debug)]
+pub struct SignalValue {
+    message: String,
+    signal: String,
+    value: f64,
+}
+
+impl SignalValue {
+    /// Parses `MESSAGE.SIGNAL[=VALUE]`, with the value defaulting to 0
+    pub fn parse(s: &str) -> Result<SignalValue, String> {
+        let (name, value) = match s.split_once('=') {
+            Some((n, v)) => (n, v.trim().parse::<f64>().map_err(|_| format!("'{}' is not a number", v))?),
+            None => (s, 0.0),
+        };
+        match name.split_once('.') {
+            Some((message, signal)) if !message.is_empty() && !signal.is_empty() => {
+                return Ok(SignalValue { message: message.to_string(), signal: signal.to_string(), value });
+            },
+            _ => return Err(String::from("must be in the form MESSAGE.SIGNAL[=VALUE]")),
+        }
+    }
+
+    /// Checks that the message and its signal can be decoded, from `database` or else the built-in decoders
+    pub fn check(&self, database: Option<&Database>) -> Result<(), String> {
+        let built_in = Database::built_in();
+        // Built-in messages whose ID is in the database are decoded from the database
+        let message = database.and_then(|db| db.messages().find(|m| m.name == self.message))
+            .or_else(|| built_in.messages().find(|m| m.name == self.message && database.is_none_or(|db| db.message(m.id, m.extended).is_none())))
+            .ok_or_else(|| format!("there's no message named {}", self.message))?;
+        if !message.signals.iter().any(|s| s.name == self.signal) {
+            return Err(format!("message {} has no signal named {}", self.message, self.signal));
+        }
+        return Ok(());
+    }
+}
+
+impl fmt::Display for SignalValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}={}", self.message, self.signal, self.value)
+    }
+}
+
+/// Rotates when a decoded signal changes to its ignition off value, so a drive and what comes after it end up in separate logs
+pub struct Ignition {
+    off: SignalValue,
+    database: Option<Database>,
+    /// ID of the message, once a frame of it has been decoded
+    id: Option<u32>,
+    /// Whether the ignition was last seen off
+    was_off: Option<bool>,
+}
+
+impl Ignition {
+    /// Decodes messages with `database` if there is one, then the built-in decoders
+    pub fn new(off: SignalValue, database: Option<Database>) -> Ignition {
+        Ignition { off, database, id: None, was_off: None }
+    }
+}
+
+impl RotationPolicy for Ignition {
+    fn frame(&mut self, frame: &CanAnyFrame) -> Option<RotationReason> {
+        let f = match frame {
+            CanAnyFrame::Normal(f) => CanFrame::Data(*f),
+            _ => return None,
+        };
+        if self.id.is_some_and(|id| id != f.raw_id()) {
+            return None;
+        }
+        let parsed = match &self.database {
+            Some(db) => db.parse_frame(f),
+            None => super::parse_frame(f),
+        };
+        let parsed = match parsed {
+            Ok(p) if p.name() == self.off.message => p,
+            _ => return None,
+        };
+        self.id = Some(f.raw_id());
+        let value = parsed.values().into_iter().find(|v| v.name == self.off.signal)?.value;
+        let off = value == self.off.value;
+        let was_off = self.was_off.replace(off);
+        if off && was_off == Some(false) {
+            return Some(RotationReason::Ignition);
+        }
+        return None;
+    }
+}
+
+/// The rotation policies in use for the current log
+pub struct Rotation {
+    policies: Vec<Box<dyn RotationPolicy>>,
+    path: PathBuf,
+    last_check: Instant,
+}
+
+impl Rotation {
+    pub fn new(policies: Vec<Box<dyn RotationPolicy>>) -> Rotation {
+        Rotation { policies, path: PathBuf::new(), last_check: Instant::now() }
+    }
+
+    /// A new log was opened at `path`
+    pub fn start(&mut self, path: &str) {
+        self.path = PathBuf::from(path);
+        self.last_check = Instant::now();
+        let now = now();
+        for p in self.policies.iter_mut() {
+            p.start(now);
+        }
+    }
+
+    /// A frame was written to the log. Also checks the time and size when they're due.
+    pub fn frame(&mut self, frame: &CanAnyFrame) -> Option<RotationReason> {
+        for p in self.policies.iter_mut() {
+            if let Some(r) = p.frame(frame) {
+                return Some(r);
+            }
+        }
+        if self.last_check.elapsed() >= CHECK_INTERVAL {
+            return self.check();
+        }
+        return None;
+    }
+
+    /// Checks the time and the size of the log
+    pub fn check(&mut self) -> Option<RotationReason> {
+        self.last_check = Instant::now();
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        let now = now();
+        return self.policies.iter_mut().find_map(|p| p.check(now, size));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socketcan::{EmbeddedFrame, StandardId};
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn frame(id: u16, data: &[u8]) -> CanAnyFrame {
+        return CanFrame::new(StandardId::new(id).unwrap(), data).unwrap().into();
+    }
+
+    #[test]
+    fn intervals_end_on_multiples_of_the_period() {
+        let mut interval = Interval::new(HOUR);
+        // Nothing is due before the first log starts
+        assert_eq!(interval.check(Duration::MAX - Duration::from_secs(1), 0), None);
+
+        let start = HOUR * 100_000 + Duration::from_secs(1234);
+        interval.start(start);
+        assert_eq!(interval.check(start, 0), None);
+        assert_eq!(interval.check(HOUR * 100_001 - Duration::from_nanos(1), 0), None);
+        assert_eq!(interval.check(HOUR * 100_001, 0), Some(RotationReason::Interval));
+
+        // A log started right on the hour runs for the whole hour
+        interval.start(HOUR * 100_001);
+        assert_eq!(interval.check(HOUR * 100_001 + Duration::from_secs(1), 0), None);
+        assert_eq!(interval.check(HOUR * 100_002, 0), Some(RotationReason::Interval));
+    }
+
+    #[test]
+    fn max_size_rotates_at_the_threshold() {
+        let mut size = MaxSize::new(1000);
+        assert_eq!(size.check(Duration::ZERO, 0), None);
+        assert_eq!(size.check(Duration::ZERO, 999), None);
+        assert_eq!(size.check(Duration::ZERO, 1000), Some(RotationReason::MaxSize));
+        assert_eq!(size.check(Duration::ZERO, 1001), Some(RotationReason::MaxSize));
+    }
+
+    #[test]
+    fn ignition_rotates_when_switched_off() {
+        // Two messages with the same name, so only the ID locked in on tells them apart
+        let db = Database::parse(concat!(
+            "BO_ 256 Ignition: 1 Vector__XXX\n SG_ On : 0|1@1+ (1,0) [0|1] \"\" Vector__XXX\n",
+            "BO_ 512 Ignition: 1 Vector__XXX\n SG_ On : 0|1@1+ (1,0) [0|1] \"\" Vector__XXX\n",
+        )).unwrap();
+        let mut ignition = Ignition::new(SignalValue::parse("Ignition.On").unwrap(), Some(db));
+
+        // Off from the start isn't a change
+        assert_eq!(ignition.frame(&frame(0x100, &[0])), None);
+        assert_eq!(ignition.frame(&frame(0x100, &[1])), None);
+        assert_eq!(ignition.frame(&frame(0x100, &[1])), None);
+        // The other message is ignored now that 0x100 is locked in
+        assert_eq!(ignition.frame(&frame(0x200, &[0])), None);
+        assert_eq!(ignition.frame(&frame(0x100, &[0])), Some(RotationReason::Ignition));
+        // Staying off doesn't rotate again
+        assert_eq!(ignition.frame(&frame(0x100, &[0])), None);
+        assert_eq!(ignition.frame(&frame(0x100, &[1])), None);
+        assert_eq!(ignition.frame(&frame(0x100, &[0])), Some(RotationReason::Ignition));
+    }
+
+    #[test]
+    fn signals_are_checked_against_the_database_and_built_in_decoders() {
+        assert!(SignalValue::parse("GpsHeading.Speed").unwrap().check(None).is_ok());
+        assert!(SignalValue::parse("GpsHeadng.Speed").unwrap().check(None).is_err());
+        assert!(SignalValue::parse("GpsHeading.Sped").unwrap().check(None).is_err());
+
+        let db = Database::parse("BO_ 256 Ignition: 1 Vector__XXX\n SG_ On : 0|1@1+ (1,0) [0|1] \"\" Vector__XXX\n").unwrap();
+        assert!(SignalValue::parse("Ignition.On").unwrap().check(Some(&db)).is_ok());
+        assert!(SignalValue::parse("Ignition.On").unwrap().check(None).is_err());
+        // Messages the database lacks still come from the built-in decoders
+        assert!(SignalValue::parse("GpsHeading.Speed").unwrap().check(Some(&db)).is_ok());
+    }
+}
@@ -0,0 +1,181 @@
+//! Running a service that listens to the car on one interface and reacts to what it decodes.
+//!
+//! `Service` opens the interface with a filter for the IDs the service needs, then reads frames
+//! until SIGTERM, decoding each one and handing it to a `Handler`. Read timeouts, retries and
+//! interrupted reads are dealt with here, as are the systemd notifications: the service is ready
+//! once it's reading, and the watchdog is kept happy even while the bus is quiet.
+//!
+//! Frames are decoded with the built-in decoders, and IDs they don't know with a DBC file given
+//! to `database`, so a service can pick up a new ID without being rebuilt.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use libc::{CAN_EFF_FLAG, CAN_RTR_FLAG, CAN_SFF_MASK};
+use signal_hook::consts::{SIGHUP, SIGTERM, SIGUSR2};
+use socketcan::{CanFilter, CanFrame, CanSocket, ShouldRetry, Socket, SocketOptions};
+
+use super::{parse_frame, DecodeError, ParsedFrame};
+use super::dbc::Database;
+use super::systemd::Notifier;
+
+/// Longest wait for a frame by default, which is also how long a signal can wait to be handled
+pub const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a service does with the frames it receives
+pub trait Handler {
+    /// Called once the interface is open, before the first frame is read
+    fn start(&mut self, _notifier: &Notifier) {}
+
+    /// Called with each frame that decodes, and the system time when it was read
+    fn frame(&mut self, frame: ParsedFrame, received: DateTime<Utc>, notifier: &Notifier);
+
+    /// Called with each frame that doesn't decode, e.g. one with no decoder for its ID
+    fn undecoded(&mut self, _frame: CanFrame, error: DecodeError) {
+        println!("Skipping bad frame: {}", error);
+    }
+
+    /// Called whenever no frame has come in for the read timeout
+    fn idle(&mut self, _notifier: &Notifier) {}
+
+    /// Called on SIGHUP or SIGUSR2, if the service reloads on them
+    fn reload(&mut self) {}
+}
+
+pub struct Service {
+    socket: CanSocket,
+    read_timeout: Duration,
+    reload: bool,
+    notifier: Notifier,
+    database: Option<Database>,
+}
+
+impl Service {
+    /// Opens `interface`, receiving only the standard frames with the IDs in `ids`
+    pub fn open(interface: &str, ids: &[u32]) -> io::Result<Service> {
+        let socket = CanSocket::open(interface)?;
+        // The EFF and RTR bits are in the mask so extended and remote frames with the same low bits are left out
+        let filters: Vec<CanFilter> = ids.iter().map(|id| CanFilter::new(*id, CAN_EFF_FLAG | CAN_RTR_FLAG | CAN_SFF_MASK)).collect();
+        socket.set_filters(&filters)?;
+        return Ok(Service { socket, read_timeout: READ_TIMEOUT, reload: false, notifier: Notifier::from_env(), database: None });
+    }
+
+    /// Waits up to `timeout` for a frame before calling `Handler::idle`. It's cut short if it's
+    /// too long for the systemd watchdog.
+    pub fn read_timeout(mut self, timeout: Duration) -> Service {
+        self.read_timeout = timeout;
+        return self;
+    }
+
+    /// Calls `Handler::reload` on SIGHUP and SIGUSR2, instead of letting SIGHUP end the service
+    pub fn reload_on_signal(mut self) -> Service {
+        self.reload = true;
+        return self;
+    }
+
+    /// Decodes the IDs the built-in decoders don't know with `database`, if there is one. Messages
+    /// with a built-in decoder still reach the handler as their own variant.
+    pub fn database(mut self, database: Option<Database>) -> Service {
+        self.database = database;
+        return self;
+    }
+
+    /// Reads and decodes frames for `handler` until SIGTERM. Fails if reading from the interface does.
+    pub fn run<H: Handler>(&mut self, handler: &mut H) -> io::Result<()> {
+        let sig_term = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGTERM, Arc::clone(&sig_term))?;
+        let sig_reload = Arc::new(AtomicBool::new(false));
+        if self.reload {
+            signal_hook::flag::register(SIGHUP, Arc::clone(&sig_reload))?;
+            signal_hook::flag::register(SIGUSR2, Arc::clone(&sig_reload))?;
+        }
+        self.socket.set_read_timeout(self.notifier.read_timeout(self.read_timeout))?;
+
+        handler.start(&self.notifier);
+        self.notifier.ready();
+        while !sig_term.load(Ordering::Relaxed) {
+            self.notifier.watchdog();
+            if sig_reload.swap(false, Ordering::Relaxed) {
+                handler.reload();
+            }
+            match self.socket.read_frame() {
+                Ok(frame) => {
+                    let received: DateTime<Utc> = Utc::now();
+                    dispatch(handler, self.database.as_ref(), frame, received, &self.notifier);
+                },
+                Err(e) => {
+                    if e.should_retry() {
+                        // Read timed out
+                        handler.idle(&self.notifier);
+                    } else if e.kind() == io::ErrorKind::Interrupted {
+                        // Interrupted by signal
+                        continue;
+                    } else {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        self.notifier.stopping();
+        return Ok(());
+    }
+}
+
+/// Decodes `frame` and hands it to `handler`
+fn dispatch<H: Handler>(handler: &mut H, database: Option<&Database>, frame: CanFrame, received: DateTime<Utc>, notifier: &Notifier) {
+    let parsed = match database {
+        Some(db) => db.parse_unknown(frame),
+        None => parse_frame(frame),
+    };
+    match parsed {
+        Ok(parsed) => handler.frame(parsed, received, notifier),
+        Err(e) => handler.undecoded(frame, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socketcan::{EmbeddedFrame, StandardId};
+
+    /// Names of the frames it's handed
+    #[derive(Default)]
+    struct Names(Vec<String>);
+
+    impl Handler for Names {
+        fn frame(&mut self, frame: ParsedFrame, _received: DateTime<Utc>, _notifier: &Notifier) {
+            let name = match frame {
+                ParsedFrame::_465(_) => String::from("_465"),
+                ParsedFrame::_466(_) => String::from("_466"),
+                ParsedFrame::Signals(m) => m.name,
+                other => format!("{:?}", other),
+            };
+            self.0.push(name);
+        }
+
+        fn undecoded(&mut self, _frame: CanFrame, error: DecodeError) {
+            self.0.push(error.to_string());
+        }
+    }
+
+    #[test]
+    fn handlers_get_built_in_variants_with_car_dbc_loaded() {
+        // car.dbc with a message the built-in decoders don't know added, as the README suggests
+        let text = format!("{}\nBO_ 1536 Other: 1 Vector__XXX\n SG_ Value : 7|8@0+ (1,0) [0|255] \"\" Vector__XXX\n", include_str!("../dbc/car.dbc"));
+        let db = Database::parse(&text).unwrap();
+        let time = super::super::encode_frame(&ParsedFrame::_466(Utc::now())).unwrap();
+        let location = super::super::encode_frame(&ParsedFrame::_465(geoutils::Location::new(47.5, -122.25))).unwrap();
+        let other = CanFrame::new(StandardId::new(0x600).unwrap(), &[1]).unwrap();
+        let unknown = CanFrame::new(StandardId::new(0x601).unwrap(), &[1]).unwrap();
+
+        let mut names = Names::default();
+        let notifier = Notifier::new(None, None);
+        for frame in [time, location, other, unknown] {
+            dispatch(&mut names, Some(&db), frame, Utc::now(), &notifier);
+        }
+        assert_eq!(names.0, vec!["_466", "_465", "Other", "no decoder for frame 0x601"]);
+    }
+}
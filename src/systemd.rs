@@ -0,0 +1,176 @@
+//! Telling systemd how a service is doing, through the socket in `NOTIFY_SOCKET` (see sd_notify(3)).
+//!
+//! A service started with `Type=notify` sends `READY=1` once it's set up. With `WatchdogSec=`
+//! it also has to send `WATCHDOG=1` regularly from its main loop, or systemd restarts it, so a
+//! loop that hangs gets restarted. `STATUS=` text shows up in `systemctl status`. Without a
+//! `NOTIFY_SOCKET`, e.g. when run by hand, nothing is sent.
+
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// Time systemd waits for a watchdog message before restarting the service
+    watchdog: Option<Duration>,
+    last_watchdog: Instant,
+}
+
+/// Socket address for `path`, where a leading `@` means an abstract socket
+fn socket_addr(path: &OsStr) -> io::Result<SocketAddr> {
+    let bytes = path.as_bytes();
+    match bytes.strip_prefix(b"@") {
+        Some(name) => return SocketAddr::from_abstract_name(name),
+        None => return SocketAddr::from_pathname(path),
+    }
+}
+
+/// The watchdog time systemd set for this process, if any
+fn watchdog_from_env() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok().filter(|u| *u > 0)?;
+    // The variables may have been inherited from a parent that's the one being watched
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    return Some(Duration::from_micros(usec));
+}
+
+impl Notifier {
+    /// Notifies the socket systemd gave the service, if there is one
+    pub fn from_env() -> Notifier {
+        let path = env::var_os("NOTIFY_SOCKET");
+        return Notifier::new(path.as_deref(), watchdog_from_env());
+    }
+
+    /// Notifies the socket at `path`, starting with `@` for an abstract socket, e.g. a stand-in for systemd
+    pub fn new(path: Option<&OsStr>, watchdog: Option<Duration>) -> Notifier {
+        let socket = path.and_then(|p| {
+            let socket = UnixDatagram::unbound().and_then(|s| Ok((s, socket_addr(p)?)));
+            if let Err(e) = &socket {
+                println!("Could not set up notifications to systemd at {}: {}", p.to_string_lossy(), e);
+            }
+            socket.ok()
+        });
+        return Notifier { socket, watchdog, last_watchdog: Instant::now() };
+    }
+
+    fn send(&self, message: &str) {
+        if let Some((socket, addr)) = &self.socket {
+            // systemd may not be listening any more; there's nothing to do about it
+            let _ = socket.send_to_addr(message.as_bytes(), addr);
+        }
+    }
+
+    /// The service is set up and running
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    /// The service is shutting down
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// A line describing what the service is doing
+    pub fn status(&self, text: &str) {
+        self.send(&format!("STATUS={}", text));
+    }
+
+    /// Tells systemd the service is alive. Call it on every pass of the main loop; it's only sent once half
+    /// the watchdog time has gone by.
+    pub fn watchdog(&mut self) {
+        if let Some(watchdog) = self.watchdog {
+            if self.last_watchdog.elapsed() >= watchdog / 2 {
+                self.send("WATCHDOG=1");
+                self.last_watchdog = Instant::now();
+            }
+        }
+    }
+
+    /// `longest`, cut down so that a loop waiting this long between passes still keeps the watchdog happy
+    pub fn read_timeout(&self, longest: Duration) -> Duration {
+        return self.watchdog.map_or(longest, |w| longest.min(w / 4));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn receive(listener: &UnixDatagram) -> Option<String> {
+        let mut buffer = [0u8; 256];
+        match listener.recv(&mut buffer) {
+            Ok(len) => return Some(String::from_utf8_lossy(&buffer[..len]).into_owned()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+            Err(e) => panic!("receive failed: {}", e),
+        }
+    }
+
+    /// Runs a service's worth of notifications and checks what arrives at `listener`
+    fn check_messages(listener: &UnixDatagram, path: &OsStr) {
+        listener.set_nonblocking(true).unwrap();
+        let mut notifier = Notifier::new(Some(path), Some(Duration::from_millis(200)));
+        notifier.ready();
+        assert_eq!(receive(listener).as_deref(), Some("READY=1"));
+        notifier.status("Waiting for GPS time");
+        assert_eq!(receive(listener).as_deref(), Some("STATUS=Waiting for GPS time"));
+
+        // Nothing until half the watchdog time has gone by, then once per half
+        notifier.watchdog();
+        assert_eq!(receive(listener), None);
+        thread::sleep(Duration::from_millis(110));
+        notifier.watchdog();
+        notifier.watchdog();
+        assert_eq!(receive(listener).as_deref(), Some("WATCHDOG=1"));
+        assert_eq!(receive(listener), None);
+        thread::sleep(Duration::from_millis(110));
+        notifier.watchdog();
+        assert_eq!(receive(listener).as_deref(), Some("WATCHDOG=1"));
+
+        notifier.stopping();
+        assert_eq!(receive(listener).as_deref(), Some("STOPPING=1"));
+        assert_eq!(receive(listener), None);
+    }
+
+    #[test]
+    fn notifies_a_socket_at_a_path() {
+        let path = env::temp_dir().join(format!("car_logger_notify_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+        check_messages(&listener, path.as_os_str());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn notifies_an_abstract_socket() {
+        let name = format!("car_logger_notify_{}", std::process::id());
+        let listener = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+        check_messages(&listener, OsStr::new(&format!("@{}", name)));
+    }
+
+    #[test]
+    fn watchdog_is_quiet_without_a_watchdog_time() {
+        let name = format!("car_logger_no_watchdog_{}", std::process::id());
+        let listener = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut notifier = Notifier::new(Some(OsStr::new(&format!("@{}", name))), None);
+        thread::sleep(Duration::from_millis(10));
+        notifier.watchdog();
+        assert_eq!(receive(&listener), None);
+        assert_eq!(notifier.read_timeout(Duration::from_secs(1)), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn read_timeout_keeps_the_watchdog_fed() {
+        let notifier = Notifier::new(None, Some(Duration::from_secs(2)));
+        assert_eq!(notifier.read_timeout(Duration::from_secs(60)), Duration::from_millis(500));
+        assert_eq!(notifier.read_timeout(Duration::from_millis(100)), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,308 @@
+//! Receive timestamps for frames read from a CAN socket.
+//!
+//! The kernel stamps each frame as it arrives (`SO_TIMESTAMPNS`), and adapters with their own
+//! clock stamp it as it comes off the bus (`SO_TIMESTAMPING`). Both are much closer to the real
+//! arrival time than reading the clock after `read_frame()` returns, which includes however long
+//! the recorder took to be scheduled.
+//!
+//! Adapter clocks are usually free-running rather than wall clock time, so hardware times are
+//! shifted by the offset to the kernel timestamp of the first frame. That keeps the adapter's
+//! accuracy between frames while the log still has real dates. The offset is measured again if
+//! the two clocks end up more than `MAX_HARDWARE_DRIFT` apart, e.g. after the adapter resets.
+//!
+//! Frames are read with `recvmsg()` directly rather than through socketcan, because the kernel's
+//! count of frames dropped for a full receive buffer (`SO_RXQ_OVFL`) arrives alongside the
+//! timestamps and socketcan doesn't pass it on.
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use socketcan::{CanAnyFrame, CanFdSocket, CanTimestamps, SocketOptions};
+use socketcan::{SOF_TIMESTAMPING_OPT_CMSG, SOF_TIMESTAMPING_RAW_HARDWARE, SOF_TIMESTAMPING_RX_HARDWARE, SOF_TIMESTAMPING_RX_SOFTWARE, SOF_TIMESTAMPING_SOFTWARE};
+
+const MAX_HARDWARE_DRIFT: Duration = Duration::from_secs(1);
+/// Room for the timestamp and drop count control messages
+const CONTROL_LEN: usize = 256;
+
+/// Control message buffer, aligned for `cmsghdr`
+#[repr(C, align(8))]
+struct Control([u8; CONTROL_LEN]);
+
+/// Where the time of a frame came from, most accurate first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum ClockSource {
+    /// The adapter's clock, when the frame came off the bus
+    Hardware,
+    /// The kernel's clock, when the frame arrived from the driver
+    Kernel,
+    /// The system clock, after the recorder read the frame
+    Userspace,
+}
+
+impl fmt::Display for ClockSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ClockSource::Hardware => "hardware",
+            ClockSource::Kernel => "kernel",
+            ClockSource::Userspace => "userspace",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn since_epoch(t: SystemTime) -> Duration {
+    return t.duration_since(UNIX_EPOCH).unwrap_or_default();
+}
+
+fn timespec_duration(ts: libc::timespec) -> Option<Duration> {
+    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+        // Sources that weren't asked for are filled with zeros
+        return None;
+    }
+    return Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+}
+
+/// Reads a frame with its timestamps and the socket's running count of dropped frames
+fn recv_frame(socket: &CanFdSocket) -> io::Result<(CanAnyFrame, CanTimestamps, Option<u32>)> {
+    let mut frame: libc::canfd_frame = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec { iov_base: &mut frame as *mut libc::canfd_frame as *mut libc::c_void, iov_len: mem::size_of::<libc::canfd_frame>() };
+    let mut control = Control([0; CONTROL_LEN]);
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.0.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = CONTROL_LEN as _;
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let any_frame = match n as usize {
+        libc::CAN_MTU => {
+            let mut classic: libc::can_frame = unsafe { mem::zeroed() };
+            unsafe { ptr::copy_nonoverlapping(&frame as *const libc::canfd_frame as *const u8, &mut classic as *mut libc::can_frame as *mut u8, libc::CAN_MTU) };
+            CanAnyFrame::from(classic)
+        },
+        libc::CANFD_MTU => CanAnyFrame::from(frame),
+        _ => return Err(io::ErrorKind::InvalidData.into()),
+    };
+
+    let (stamps, dropped) = parse_control(&control.0[..msg.msg_controllen as usize]);
+    return Ok((any_frame, stamps, dropped));
+}
+
+/// Reads the timestamps and the running count of dropped frames from the control messages `recvmsg()` filled `buf` with
+fn parse_control(buf: &[u8]) -> (CanTimestamps, Option<u32>) {
+    let mut control = Control([0; CONTROL_LEN]);
+    let len = buf.len().min(CONTROL_LEN);
+    control.0[..len].copy_from_slice(&buf[..len]);
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_control = control.0.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = len as _;
+
+    let mut stamps = CanTimestamps::default();
+    let mut dropped = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, kind, len) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type, (*cmsg).cmsg_len as usize) };
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+        let fits = |size: usize| len >= unsafe { libc::CMSG_LEN(size as u32) } as usize;
+        match (level, kind) {
+            (libc::SOL_SOCKET, libc::SO_TIMESTAMPNS) if fits(mem::size_of::<libc::timespec>()) => {
+                let ts = unsafe { ptr::read_unaligned(data as *const libc::timespec) };
+                stamps.socket = timespec_duration(ts).map(|d| UNIX_EPOCH + d);
+            },
+            (libc::SOL_SOCKET, libc::SO_TIMESTAMPING) if fits(3 * mem::size_of::<libc::timespec>()) => {
+                // Software, a deprecated field, then hardware
+                let ts = unsafe { ptr::read_unaligned(data as *const [libc::timespec; 3]) };
+                stamps.sw = timespec_duration(ts[0]).map(|d| UNIX_EPOCH + d);
+                stamps.hw = timespec_duration(ts[2]);
+            },
+            (libc::SOL_SOCKET, libc::SO_RXQ_OVFL) if fits(mem::size_of::<u32>()) => {
+                dropped = Some(unsafe { ptr::read_unaligned(data as *const u32) });
+            },
+            _ => (),
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    return (stamps, dropped);
+}
+
+/// Reads frames along with the best timestamp available for each one
+pub struct Timestamper {
+    /// Most accurate source enabled on the socket
+    source: ClockSource,
+    /// Wall clock minus adapter clock, in nanoseconds
+    hardware_offset: Option<i128>,
+    /// Frames stamped by each source since the last `take_counts`
+    counts: [u64; 3],
+    /// The socket's count of dropped frames as of the last frame read
+    dropped: u32,
+}
+
+impl Timestamper {
+    /// Enables timestamps from `preferred` on the socket, or the best source the interface supports if it's `None`.
+    /// A source that can't be enabled falls back to the next one. Also asks the kernel to report dropped frames.
+    pub fn new(socket: &CanFdSocket, preferred: Option<ClockSource>) -> Timestamper {
+        if let Err(e) = socket.set_socket_option(libc::SOL_SOCKET, libc::SO_RXQ_OVFL, &1 as &libc::c_int) {
+            println!("Could not enable drop reporting ({}); frames dropped by the kernel won't be noticed", e);
+        }
+        let mut source = preferred.unwrap_or(if socket.has_hw_timestamps() { ClockSource::Hardware } else { ClockSource::Kernel });
+        if source == ClockSource::Hardware {
+            if !socket.has_hw_timestamps() {
+                println!("Interface doesn't support hardware timestamps; using kernel timestamps");
+                source = ClockSource::Kernel;
+            } else {
+                // Software timestamps as well, for frames the adapter doesn't stamp and to line the adapter clock up with
+                let flags = SOF_TIMESTAMPING_RX_HARDWARE | SOF_TIMESTAMPING_RAW_HARDWARE | SOF_TIMESTAMPING_RX_SOFTWARE | SOF_TIMESTAMPING_SOFTWARE | SOF_TIMESTAMPING_OPT_CMSG;
+                if let Err(e) = socket.set_timestamping(flags) {
+                    println!("Could not enable hardware timestamps ({}); using kernel timestamps", e);
+                    source = ClockSource::Kernel;
+                }
+            }
+        }
+        if source <= ClockSource::Kernel {
+            if let Err(e) = socket.set_recv_timestamp(true) {
+                println!("Could not enable kernel timestamps ({}); using userspace timestamps", e);
+                source = ClockSource::Userspace;
+            }
+        }
+        return Timestamper { source, hardware_offset: None, counts: [0; 3], dropped: 0 };
+    }
+
+    /// Most accurate source enabled. Individual frames can still fall back to a less accurate one.
+    pub fn source(&self) -> ClockSource {
+        return self.source;
+    }
+
+    /// Reads a frame with its time since the UNIX epoch, where that time came from,
+    /// and how many frames the kernel dropped since the last one read
+    pub fn read_frame(&mut self, socket: &CanFdSocket) -> io::Result<(CanAnyFrame, Duration, ClockSource, u32)> {
+        let (frame, stamps, total_dropped) = recv_frame(socket)?;
+        let dropped = match total_dropped {
+            Some(total) => total.wrapping_sub(mem::replace(&mut self.dropped, total)),
+            None => 0,
+        };
+        let (time, source) = self.time(&stamps, since_epoch(SystemTime::now()));
+        return Ok((frame, time, source, dropped));
+    }
+
+    /// Picks the most accurate of a frame's timestamps, falling back from hardware to kernel to `now`
+    fn time(&mut self, stamps: &CanTimestamps, now: Duration) -> (Duration, ClockSource) {
+        let kernel = stamps.socket.or(stamps.sw).map(since_epoch);
+        let (time, source) = match (stamps.hw, kernel) {
+            (Some(hw), _) => (self.hardware_time(hw, kernel.unwrap_or(now)), ClockSource::Hardware),
+            (None, Some(k)) => (k, ClockSource::Kernel),
+            (None, None) => (now, ClockSource::Userspace),
+        };
+        self.counts[source as usize] += 1;
+        return (time, source);
+    }
+
+    /// Moves an adapter clock time onto the wall clock, given the wall clock time of the same frame
+    fn hardware_time(&mut self, hw: Duration, reference: Duration) -> Duration {
+        let hw = hw.as_nanos() as i128;
+        let reference = reference.as_nanos() as i128;
+        let offset = match self.hardware_offset {
+            Some(o) if (hw + o - reference).unsigned_abs() <= MAX_HARDWARE_DRIFT.as_nanos() => o,
+            _ => reference - hw,
+        };
+        self.hardware_offset = Some(offset);
+        return Duration::from_nanos((hw + offset).max(0) as u64);
+    }
+
+    /// How many frames each source stamped since the last call, e.g. "1200 hardware, 3 kernel"
+    pub fn take_counts(&mut self) -> String {
+        let sources = [ClockSource::Hardware, ClockSource::Kernel, ClockSource::Userspace];
+        let counts: Vec<String> = sources.iter()
+            .filter(|s| self.counts[**s as usize] > 0)
+            .map(|s| format!("{} {}", self.counts[*s as usize], s))
+            .collect();
+        self.counts = [0; 3];
+        if counts.is_empty() {
+            return String::from("none");
+        }
+        return counts.join(", ");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: Duration = Duration::from_secs(1714566600);
+
+    /// Appends a control message holding `data` to `buf`
+    fn cmsg(buf: &mut Vec<u8>, kind: libc::c_int, data: &[u8]) {
+        let start = buf.len();
+        buf.resize(start + unsafe { libc::CMSG_SPACE(data.len() as u32) } as usize, 0);
+        let mut header: libc::cmsghdr = unsafe { mem::zeroed() };
+        header.cmsg_len = unsafe { libc::CMSG_LEN(data.len() as u32) } as _;
+        header.cmsg_level = libc::SOL_SOCKET;
+        header.cmsg_type = kind;
+        unsafe { ptr::write_unaligned(buf[start..].as_mut_ptr() as *mut libc::cmsghdr, header) };
+        let data_start = start + unsafe { libc::CMSG_LEN(0) } as usize;
+        buf[data_start..data_start + data.len()].copy_from_slice(data);
+    }
+
+    fn timespecs(times: &[Duration]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        for t in times {
+            let mut ts: libc::timespec = unsafe { mem::zeroed() };
+            ts.tv_sec = t.as_secs() as _;
+            ts.tv_nsec = t.subsec_nanos() as _;
+            let raw = unsafe { std::slice::from_raw_parts(&ts as *const libc::timespec as *const u8, mem::size_of::<libc::timespec>()) };
+            bytes.extend_from_slice(raw);
+        }
+        return bytes;
+    }
+
+    fn timestamper() -> Timestamper {
+        return Timestamper { source: ClockSource::Hardware, hardware_offset: None, counts: [0; 3], dropped: 0 };
+    }
+
+    #[test]
+    fn kernel_timestamps_and_drop_counts_are_read() {
+        let kernel = NOW - Duration::from_micros(1500);
+        let mut buf: Vec<u8> = Vec::new();
+        cmsg(&mut buf, libc::SO_TIMESTAMPNS, &timespecs(&[kernel]));
+        cmsg(&mut buf, libc::SO_RXQ_OVFL, &42u32.to_ne_bytes());
+        let (stamps, dropped) = parse_control(&buf);
+        assert_eq!(dropped, Some(42));
+        assert_eq!(timestamper().time(&stamps, NOW), (kernel, ClockSource::Kernel));
+    }
+
+    #[test]
+    fn hardware_timestamps_are_moved_onto_the_wall_clock() {
+        let mut stamper = timestamper();
+        // The adapter clock counts from when it was powered up
+        for (hw, sw) in [(5_000, NOW), (5_010, NOW + Duration::from_micros(10_200))] {
+            let mut buf: Vec<u8> = Vec::new();
+            cmsg(&mut buf, libc::SO_TIMESTAMPING, &timespecs(&[sw, Duration::ZERO, Duration::from_millis(hw)]));
+            let (stamps, dropped) = parse_control(&buf);
+            assert_eq!(dropped, None);
+            // Lined up with the kernel time of the first frame, then keeping the adapter's spacing
+            assert_eq!(stamper.time(&stamps, NOW + Duration::from_secs(1)), (NOW + Duration::from_millis(hw - 5_000), ClockSource::Hardware));
+        }
+        assert_eq!(stamper.take_counts(), "2 hardware");
+    }
+
+    #[test]
+    fn missing_hardware_timestamps_fall_back_to_kernel_then_userspace() {
+        let kernel = NOW - Duration::from_micros(700);
+        let mut buf: Vec<u8> = Vec::new();
+        cmsg(&mut buf, libc::SO_TIMESTAMPING, &timespecs(&[kernel, Duration::ZERO, Duration::ZERO]));
+        let (stamps, _) = parse_control(&buf);
+        assert_eq!(stamps.hw, None);
+        let mut stamper = timestamper();
+        assert_eq!(stamper.time(&stamps, NOW), (kernel, ClockSource::Kernel));
+
+        let (stamps, dropped) = parse_control(&[]);
+        assert_eq!(dropped, None);
+        assert_eq!(stamper.time(&stamps, NOW), (NOW, ClockSource::Userspace));
+        assert_eq!(stamper.take_counts(), "1 kernel, 1 userspace");
+    }
+}
@@ -0,0 +1,274 @@
+//! Starting a log on an event, with the frames from the seconds before it.
+//!
+//! The recorder keeps recent frames in a `History`, and a log started by a trigger begins with
+//! them so it shows what led up to the event. A trigger is a SIGUSR1, a rising edge on a GPIO
+//! input, or a frame matching a `FrameMatch`.
+//!
+//! A frame match fires when frames with its ID start matching, not on every matching frame, so a
+//! periodic message holding the value doesn't keep starting new logs. It's armed again by a frame
+//! with the ID that doesn't match, or when the bus goes idle.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libc::{CLOCK_MONOTONIC, clock_gettime, timespec};
+use socketcan::{CanAnyFrame, EmbeddedFrame, Frame};
+
+use super::buses::Received;
+
+/// What set off a trigger
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriggerSource {
+    Signal,
+    Gpio,
+    Frame,
+}
+
+impl fmt::Display for TriggerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriggerSource::Signal => write!(f, "SIGUSR1"),
+            TriggerSource::Gpio => write!(f, "GPIO input"),
+            TriggerSource::Frame => write!(f, "frame match"),
+        }
+    }
+}
+
+/// The most recent frames, up to a given length of time before the newest one
+pub struct History {
+    length: Duration,
+    frames: VecDeque<Received>,
+}
+
+impl History {
+    pub fn new(length: Duration) -> History {
+        History { length, frames: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, received: Received) {
+        let oldest = received.time.saturating_sub(self.length);
+        self.frames.push_back(received);
+        while self.frames.front().is_some_and(|r| r.time < oldest) {
+            self.frames.pop_front();
+        }
+    }
+
+    /// Empties the history, returning the frames from `start` on
+    pub fn take_since(&mut self, start: Duration) -> Vec<Received> {
+        return self.frames.drain(..).filter(|r| r.time >= start).collect();
+    }
+}
+
+/// Frames with an ID and, optionally, data bytes under a mask. Written as `ID[#DATA[/MASK]]` in hex, e.g.
+/// `1A0#0100/FF00` for frames 0x1A0 whose first byte is 01. Bits outside the mask, and bytes after the
+/// given data, can hold anything. As in candump logs, IDs written with more than 3 digits are extended,
+/// so `000001A0` only matches the extended ID 0x1A0.
+#[derive(Clone, Debug)]
+pub struct FrameMatch {
+    id: u32,
+    extended: bool,
+    data: Vec<u8>,
+    mask: Vec<u8>,
+}
+
+impl FrameMatch {
+    pub fn parse(s: &str) -> Result<FrameMatch, String> {
+        let (id_text, rest) = match s.split_once('#') {
+            Some((i, r)) => (i, Some(r)),
+            None => (s, None),
+        };
+        let digits = id_text.trim_start_matches("0x").trim_start_matches("0X");
+        let id = u32::from_str_radix(digits, 16).ok().filter(|id| *id <= 0x1FFF_FFFF).ok_or_else(|| format!("'{}' is not a hex CAN ID", id_text))?;
+        let extended = digits.len() > 3 || id > 0x7FF;
+        let (data, mask) = match rest.map(|r| r.split_once('/').unwrap_or((r, ""))) {
+            Some((data, mask)) => {
+                let data = hex::decode(data).map_err(|_| format!("'{}' is not hex data", data))?;
+                let mask = if mask.is_empty() { vec![0xFF; data.len()] } else { hex::decode(mask).map_err(|_| format!("'{}' is not a hex mask", mask))? };
+                if mask.len() != data.len() {
+                    return Err(String::from("the mask must be as long as the data"));
+                }
+                (data, mask)
+            },
+            None => (Vec::new(), Vec::new()),
+        };
+        if data.len() > 64 {
+            return Err(String::from("frames hold at most 64 data bytes"));
+        }
+        return Ok(FrameMatch { id, extended, data, mask });
+    }
+
+    /// Whether the frame has this ID, standard or extended as written. Error frames never match.
+    pub fn matches_id(&self, frame: &CanAnyFrame) -> bool {
+        if let CanAnyFrame::Error(_) = frame {
+            return false;
+        }
+        return frame.raw_id() == self.id && frame.is_extended() == self.extended;
+    }
+
+    /// Whether the match has data bytes to compare, rather than just an ID
+    pub fn has_data(&self) -> bool {
+        return !self.data.is_empty();
+    }
+
+    /// `None` if the frame has a different ID
+    fn matches(&self, frame: &CanAnyFrame) -> Option<bool> {
+        if !self.matches_id(frame) {
+            return None;
+        }
+        let data = frame.data();
+        if data.len() < self.data.len() {
+            return Some(false);
+        }
+        return Some(self.data.iter().zip(self.mask.iter()).zip(data.iter()).all(|((d, m), b)| b & m == d & m));
+    }
+}
+
+impl fmt::Display for FrameMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.extended {
+            write!(f, "{:08X}", self.id)?;
+        } else {
+            write!(f, "{:03X}", self.id)?;
+        }
+        if !self.data.is_empty() {
+            write!(f, "#{}/{}", hex::encode_upper(&self.data), hex::encode_upper(&self.mask))?;
+        }
+        return Ok(());
+    }
+}
+
+/// Converts the time of a GPIO edge event to time since the UNIX epoch, so the history starts from
+/// when the edge happened rather than when it was read. The kernel stamps events with the monotonic
+/// clock, except for the old GPIO interface on kernels before 5.7 which uses the real time clock.
+fn wall_time(event_time: Duration) -> Duration {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut ts = timespec { tv_sec: 0, tv_nsec: 0 };
+    let r = unsafe {
+        clock_gettime(CLOCK_MONOTONIC, &mut ts)
+    };
+    if r != 0 {
+        return now;
+    }
+    let monotonic = Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+    match monotonic.checked_sub(event_time) {
+        Some(age) => return now.saturating_sub(age),
+        // Later than the monotonic clock can be, so it's already real time
+        None => return event_time,
+    }
+}
+
+/// Watches for triggers
+pub struct Trigger {
+    /// Set by SIGUSR1
+    signal: Arc<AtomicBool>,
+    /// Time of the last GPIO edge that hasn't been taken, in nanoseconds since the UNIX epoch. 0 if there's none.
+    gpio: Arc<AtomicU64>,
+    frames: Vec<FrameMatch>,
+    /// Whether each frame match matched the last frame with its ID
+    matched: Vec<bool>,
+}
+
+impl Trigger {
+    /// Triggers on SIGUSR1, on a rising edge of `gpio_pin` if given, and on frames matching any of `frames`.
+    /// GPIO edges are watched from a thread of their own.
+    pub fn new(frames: Vec<FrameMatch>, gpio_pin: Option<u32>) -> io::Result<Trigger> {
+        let signal = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&signal))?;
+        let gpio = Arc::new(AtomicU64::new(0));
+        if let Some(pin) = gpio_pin {
+            let chip = gpiod::Chip::new("gpiochip0")?;
+            let opts = gpiod::Options::input([pin]).edge(gpiod::EdgeDetect::Rising).consumer("recorder trigger");
+            let mut lines = chip.request_lines(opts)?;
+            let edge_time = Arc::clone(&gpio);
+            std::thread::Builder::new().name("Trigger".to_string()).spawn(move || {
+                while let Ok(event) = lines.read_event() {
+                    if event.edge == gpiod::Edge::Rising {
+                        let time = wall_time(event.time);
+                        // Keep the first edge until it's taken so the history goes back far enough
+                        let _ = edge_time.compare_exchange(0, time.as_nanos() as u64, Ordering::Relaxed, Ordering::Relaxed);
+                    }
+                }
+                println!("Stopped watching GPIO trigger input");
+            })?;
+        }
+        let matched = vec![false; frames.len()];
+        return Ok(Trigger { signal, gpio, frames, matched });
+    }
+
+    pub fn frames(&self) -> &[FrameMatch] {
+        return &self.frames;
+    }
+
+    /// Whether `frame` sets off a frame match
+    pub fn check_frame(&mut self, frame: &CanAnyFrame) -> bool {
+        let mut fired = false;
+        for (m, matched) in self.frames.iter().zip(self.matched.iter_mut()) {
+            if let Some(now) = m.matches(frame) {
+                fired |= now && !*matched;
+                *matched = now;
+            }
+        }
+        return fired;
+    }
+
+    /// Arms every frame match again, e.g. once the bus has gone idle
+    pub fn rearm(&mut self) {
+        self.matched.iter_mut().for_each(|m| *m = false);
+    }
+
+    /// A SIGUSR1 or GPIO trigger since the last call, with the time it happened
+    pub fn take(&self) -> Option<(TriggerSource, Duration)> {
+        let edge = self.gpio.swap(0, Ordering::Relaxed);
+        if edge != 0 {
+            self.signal.store(false, Ordering::Relaxed);
+            return Some((TriggerSource::Gpio, Duration::from_nanos(edge)));
+        }
+        if self.signal.swap(false, Ordering::Relaxed) {
+            return Some((TriggerSource::Signal, SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()));
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socketcan::{CanFrame, ExtendedId, StandardId};
+
+    #[test]
+    fn frame_matches_tell_standard_and_extended_ids_apart() {
+        let standard: CanAnyFrame = CanFrame::new(StandardId::new(0x1A0).unwrap(), &[1, 0]).unwrap().into();
+        let extended: CanAnyFrame = CanFrame::new(ExtendedId::new(0x1A0).unwrap(), &[1, 0]).unwrap().into();
+
+        let m = FrameMatch::parse("1A0#0100/FF00").unwrap();
+        assert_eq!(m.matches(&standard), Some(true));
+        assert_eq!(m.matches(&extended), None);
+
+        let m = FrameMatch::parse("000001A0#01").unwrap();
+        assert_eq!(m.matches(&standard), None);
+        assert_eq!(m.matches(&extended), Some(true));
+        assert_eq!(m.to_string(), "000001A0#01/FF");
+        assert!(!m.matches_id(&standard));
+        assert!(m.matches_id(&extended));
+        assert!(FrameMatch::parse("1A0").unwrap().matches_id(&standard));
+        assert!(FrameMatch::parse("18DAF110").unwrap().extended);
+        assert!(FrameMatch::parse("20000000").is_err());
+    }
+
+    #[test]
+    fn edge_times_are_moved_to_the_real_time_clock() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut ts = timespec { tv_sec: 0, tv_nsec: 0 };
+        assert_eq!(unsafe { clock_gettime(CLOCK_MONOTONIC, &mut ts) }, 0);
+        let edge = Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32).saturating_sub(Duration::from_millis(500));
+        let time = wall_time(edge);
+        assert!(time <= now && now - time < Duration::from_millis(600), "{:?} is not half a second before {:?}", time, now);
+
+        // Real time stamps are kept
+        assert_eq!(wall_time(now), now);
+    }
+}